@@ -17,6 +17,50 @@ function memcpy(memory, source, dest) {
 
 const PATH = 'build/wasm_c64.wasm';
 
+// The version of the export layer this file is written against. The build
+// describes its exports in rust/lib/c64/src/abi.json, and a build with a
+// different version is refused rather than half working.
+const ABI_VERSION = 1;
+
+function checkABI(exports) {
+  const version = exports.get_abi_version ? exports.get_abi_version() : 0;
+  if (version !== ABI_VERSION) {
+    throw new Error(`${PATH} has ABI version ${version}, expected ${ABI_VERSION}`);
+  }
+  const text = new Uint8Array(
+    exports.memory.buffer,
+    exports.get_abi_description_pointer(),
+    exports.get_abi_description_length(),
+  );
+  const abi = JSON.parse(new TextDecoder().decode(text));
+  const missing = Object.keys(abi.exports).filter(name => typeof exports[name] !== 'function');
+  if (missing.length) {
+    throw new Error(`${PATH} is missing exports: ${missing.join(', ')}`);
+  }
+  return abi;
+}
+
+// A panic in the wasm build traps instead of returning an error, and leaves
+// the instance unusable: every later call traps too. Each export is wrapped to
+// report a trap to the VM, which starts over with a new instance.
+function guardExports(vm, exports) {
+  const guarded = {};
+  Object.keys(exports).forEach(name => {
+    const fn = exports[name];
+    guarded[name] = typeof fn !== 'function' ? fn : (...args) => {
+      try {
+        return fn(...args);
+      } catch (e) {
+        if (e instanceof WebAssembly.RuntimeError) {
+          vm.crashed(e);
+        }
+        throw e;
+      }
+    };
+  });
+  return guarded;
+}
+
 function loadWASM(vm) {
   return fetchAndInstantiate(PATH, {
     env: {
@@ -26,23 +70,70 @@ function loadWASM(vm) {
       console_error: err => console.error(err),
     },
   }).then(instance => {
+    const abi = checkABI(instance.exports);
+    const exports = guardExports(vm, instance.exports);
     return {
-      memory: instance.exports.memory,
-      createVM: instance.exports.create_vm,
-      getCharPointer: instance.exports.get_char_pointer,
-      getKernalPointer: instance.exports.get_kernal_pointer,
-      getBasicPointer: instance.exports.get_basic_pointer,
-      getColorPointer: instance.exports.get_color_pointer,
-      getRAMPointer: instance.exports.get_ram_pointer,
-      stepVM: instance.exports.step_vm,
-      runVMFor: instance.exports.run_vm,
-      reset: instance.exports.reset,
-      getRegister: instance.exports.get_register,
-      keydown: instance.exports.keydown,
-      keyup: instance.exports.keyup,
-      getBorderColor: instance.exports.get_border_color,
-      getBgColor: instance.exports.get_bg_color,
-      getGraphicsMode: instance.exports.get_graphics_mode,
+      abi,
+      memory: exports.memory,
+      createVM: exports.create_vm,
+      destroyVM: exports.destroy_vm,
+      getCharPointer: exports.get_char_pointer,
+      getKernalPointer: exports.get_kernal_pointer,
+      getBasicPointer: exports.get_basic_pointer,
+      getColorPointer: exports.get_color_pointer,
+      getRAMPointer: exports.get_ram_pointer,
+      getScreenPointer: exports.get_screen_pointer,
+      getCharsetPointer: exports.get_charset_pointer,
+      stepVM: exports.step_vm,
+      runVMFor: exports.run_vm,
+      reset: exports.reset,
+      getRegister: exports.get_register,
+      getFileBufferPointer: exports.get_file_buffer_pointer,
+      loadPRG: exports.load_prg,
+      attachDisk: exports.attach_disk,
+      isDiskModified: exports.is_disk_modified,
+      getDiskPointer: exports.get_disk_pointer,
+      getDiskLength: exports.get_disk_length,
+      attachTape: exports.attach_tape,
+      attachCartridge: exports.attach_cartridge,
+      typeText: exports.type_text,
+      enterBasic: exports.enter_basic,
+      listBasic: exports.list_basic,
+      getOutputPointer: exports.get_output_pointer,
+      saveSnapshot: exports.save_snapshot,
+      loadSnapshot: exports.load_snapshot,
+      enterMonitor: exports.enter_monitor,
+      isMonitorActive: exports.is_monitor_active,
+      monitorCommand: exports.monitor_command,
+      loadSymbols: exports.load_symbols,
+      detachCartridge: exports.detach_cartridge,
+      freezeCartridge: exports.freeze_cartridge,
+      attachREU: exports.attach_reu,
+      runFrame: exports.run_frame,
+      getFrameCount: exports.get_frame_count,
+      getFramePointer: exports.get_frame_pointer,
+      getRGBAFramePointer: exports.get_rgba_frame_pointer,
+      getFrameWidth: exports.get_frame_width,
+      getFrameHeight: exports.get_frame_height,
+      detachREU: exports.detach_reu,
+      tapePlay: exports.tape_play,
+      tapeStop: exports.tape_stop,
+      tapeRewind: exports.tape_rewind,
+      getTapeMotor: exports.get_tape_motor,
+      enableTrueDrive: exports.enable_true_drive,
+      getDriveLED: exports.get_drive_led,
+      keydown: exports.keydown,
+      keyup: exports.keyup,
+      joystickDown: exports.joystick_down,
+      joystickUp: exports.joystick_up,
+      hostKeyDown: exports.host_key_down,
+      hostKeyUp: exports.host_key_up,
+      releaseHostKeys: exports.release_host_keys,
+      loadKeymap: exports.load_keymap,
+      setKeymapMode: exports.set_keymap_mode,
+      getBorderColor: exports.get_border_color,
+      getBgColor: exports.get_bg_color,
+      getGraphicsMode: exports.get_graphics_mode,
     };
   });
 }
@@ -57,71 +148,45 @@ function b64ToByteArray(str) {
   return arr;
 }
 
-const KEYBOARD = {
-  Escape: 63,
-  Backquote: 57,
-  Digit1: 56,
-  Digit2: 59,
-  Digit3: 8,
-  Digit4: 11,
-  Digit5: 16,
-  Digit6: 19,
-  Digit7: 24,
-  Digit8: 27,
-  Digit9: 32,
-  Digit0: 35,
-  Minus: 40,
-  Equal: 53,
-  Backspace: 0,
-  Tab: 58,
-  KeyQ: 62,
-  KeyW: 9,
-  KeyE: 14,
-  KeyR: 17,
-  KeyT: 22,
-  KeyY: 25,
-  KeyU: 30,
-  KeyI: 33,
-  KeyO: 38,
-  KeyP: 41,
-  BracketLeft: 46,
-  BracketRight: 49,
-  Enter: 1,
-  CapsLock: 52,
-  KeyA: 10,
-  KeyS: 13,
-  KeyD: 18,
-  KeyF: 21,
-  KeyG: 26,
-  KeyH: 29,
-  KeyJ: 34,
-  KeyK: 37,
-  KeyL: 42,
-  Semicolon: 45,
-  Quote: 50,
-  ShiftLeft: 15,
-  KeyZ: 12,
-  KeyX: 23,
-  KeyC: 20,
-  KeyV: 31,
-  KeyB: 28,
-  KeyN: 39,
-  KeyM: 36,
-  Comma: 47,
-  Period: 44,
-  Slash: 55,
-  ControlLeft: 61,
-  Space: 60,
-};
-
 class VM {
-  constructor(gl) {
+  // model is VM.PAL or VM.NTSC
+  constructor(gl, model = VM.PAL) {
     this.graphics = new Graphics(gl);
 
-    this._ready = loadWASM(this);
-    this._ready.then(mod => {
+    this.model = model;
+    this._ready = this._start();
+
+    this._lastFrame = 0;
+
+    this.frame = this.frame.bind(this);
+    this.keydown = this.keydown.bind(this);
+    this.keyup = this.keyup.bind(this);
+
+    // The keymap in the VM decides what each key presses. e.key is a single
+    // character only when the key types one, which symbolic mode uses.
+    window.addEventListener('keydown', e => {
+      this.keydown(e.code, [...e.key].length === 1 ? e.key : null);
+      e.preventDefault();
+    });
+    window.addEventListener('keyup', e => {
+      this.keyup(e.code);
+    });
+    // Keys let go of while the page is in the background never send keyup
+    window.addEventListener('blur', () => {
+      if (this.mod) {
+        this.mod.releaseHostKeys(this.c64);
+      }
+    });
+  }
+
+  // Load the wasm module and start a machine in it, with its ROMs
+  _start() {
+    return loadWASM(this).then(mod => {
       this.mod = mod;
-      this.c64 = mod.createVM();
+      this.c64 = mod.createVM(this.model);
+      if (this.c64 === 0) {
+        throw new Error(`Unknown model ${this.model}`);
+      }
       const mem = {
         charPtr: mod.getCharPointer(this.c64),
         kernalPtr: mod.getKernalPointer(this.c64),
@@ -134,44 +199,213 @@ class VM {
       mem.kernal = new Uint8Array(buffer, mem.kernalPtr, 0x2000);
       mem.basic = new Uint8Array(buffer, mem.basicPtr, 0x2000);
       mem.color = new Uint8Array(buffer, mem.colorPtr, 0x400);
-      mem.screen = new Uint8Array(buffer, mem.ramPtr + 0x400, 1000);
       this.mem = mem;
 
       memcpy(this.mem.char, b64ToByteArray(ROM.CHAR), 0);
       memcpy(this.mem.kernal, b64ToByteArray(ROM.KERNAL), 0);
       memcpy(this.mem.basic, b64ToByteArray(ROM.BASIC), 0);
 
-      mod.reset(this.c64);
+      mod.reset(this.c64, true);
       this.printRegisters();
+      return mod;
     });
+  }
 
-    this._lastFrame = 0;
-
-    this.frame = this.frame.bind(this);
-    this.keydown = this.keydown.bind(this);
-    this.keyup = this.keyup.bind(this);
-
-    window.addEventListener('keydown', e => {
-      this.keydown(e.code);
-      e.preventDefault();
-    });
-    window.addEventListener('keyup', e => {
-      this.keyup(e.code);
+  // Called when an export traps. The instance is unusable, so whatever the
+  // machine was doing is lost, and it starts over in a new one.
+  crashed(error) {
+    if (this._crashed) {
+      return;
+    }
+    console.error('The emulator crashed and is restarting', error);
+    this._crashed = true;
+    this.c64 = 0;
+    const running = this._frameRequest !== undefined;
+    cancelAnimationFrame(this._frameRequest);
+    this._ready = this._start().then(mod => {
+      this._crashed = false;
+      this._lastFrame = 0;
+      if (running) {
+        this._frameRequest = requestAnimationFrame(this.frame);
+      }
+      return mod;
     });
   }
 
-  keydown(key) {
-    if (key in KEYBOARD) {
-      const code = KEYBOARD[key];
-      this.mod.keydown(this.c64, code);
-    }
+  // key is a KeyboardEvent.code like 'KeyA', and text the character it
+  // typed, if any
+  keydown(key, text = null) {
+    const bytes = new TextEncoder().encode(key);
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    this.mod.hostKeyDown(this.c64, text === null ? 0 : text.codePointAt(0));
   }
 
   keyup(key) {
-    if (key in KEYBOARD) {
-      const code = KEYBOARD[key];
-      this.mod.keyup(this.c64, code);
+    const bytes = new TextEncoder().encode(key);
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    this.mod.hostKeyUp(this.c64);
+  }
+
+  // Load a keymap file over the current bindings. Returns how many bindings
+  // it had, or a negative error if any line is invalid.
+  loadKeymap(text) {
+    const bytes = new TextEncoder().encode(text);
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    return this.mod.loadKeymap(this.c64);
+  }
+
+  // In symbolic mode keys type the symbols printed on them, rather than
+  // pressing the C64 keys in the same place
+  setKeymapMode(symbolic) {
+    this.mod.setKeymapMode(this.c64, symbolic);
+  }
+
+  loadPRG(bytes, autostart = true) {
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    return this.mod.loadPRG(this.c64, autostart);
+  }
+
+  attachDisk(bytes, autostart = true) {
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    return this.mod.attachDisk(this.c64, autostart) === 0;
+  }
+
+  attachTape(bytes, autostart = true) {
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    return this.mod.attachTape(this.c64, autostart) === 0;
+  }
+
+  // Type text into the C64. By default it goes through the KERNAL keyboard
+  // buffer; pressKeys holds down each key in turn instead, for programs that
+  // read the keyboard themselves.
+  typeText(text, pressKeys = false) {
+    const bytes = new TextEncoder().encode(text);
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    this.mod.typeText(this.c64, pressKeys);
+  }
+
+  // Load a BASIC program written as text, with {clr} style escapes for
+  // control codes. Returns false if the source has errors.
+  enterBasic(source, autostart = false) {
+    const bytes = new TextEncoder().encode(source);
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    return this.mod.enterBasic(this.c64, autostart) === 0;
+  }
+
+  listBasic() {
+    const len = this.mod.listBasic(this.c64);
+    const ptr = this.mod.getOutputPointer(this.c64);
+    return new TextDecoder().decode(new Uint8Array(this.mod.memory.buffer, ptr, len));
+  }
+
+  // Run a VICE-style monitor command, like 'm c000' or 'break 0810', and
+  // return what it printed. The machine stays stopped until 'g' or 'x'.
+  monitor(command = '') {
+    const bytes = new TextEncoder().encode(command);
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    const len = this.mod.monitorCommand(this.c64);
+    const out = this.mod.getOutputPointer(this.c64);
+    return new TextDecoder().decode(new Uint8Array(this.mod.memory.buffer, out, len));
+  }
+
+  // Save the machine as a VICE snapshot (.vsf)
+  saveSnapshot() {
+    const len = this.mod.saveSnapshot(this.c64);
+    const ptr = this.mod.getOutputPointer(this.c64);
+    return new Uint8Array(this.mod.memory.buffer, ptr, len).slice();
+  }
+
+  loadSnapshot(bytes) {
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    return this.mod.loadSnapshot(this.c64) === 0;
+  }
+
+  // Load labels for the monitor from the text of a VICE label file, a ca65
+  // debug file, or an FCEUX name list. Returns how many were loaded, or -1.
+  loadSymbols(text) {
+    const bytes = new TextEncoder().encode(text);
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    return this.mod.loadSymbols(this.c64);
+  }
+
+  enterMonitor() {
+    this.mod.enterMonitor(this.c64);
+  }
+
+  isMonitorActive() {
+    return this.mod.isMonitorActive(this.c64) === 1;
+  }
+
+  // A soft reset keeps the contents of RAM, like the reset button on a
+  // cartridge; a hard reset is a power cycle.
+  reset(hard = false) {
+    this.mod.reset(this.c64, hard);
+  }
+
+  attachCartridge(bytes) {
+    const ptr = this.mod.getFileBufferPointer(this.c64, bytes.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, bytes.length), bytes, 0);
+    return this.mod.attachCartridge(this.c64) === 0;
+  }
+
+  detachCartridge() {
+    this.mod.detachCartridge(this.c64);
+  }
+
+  freezeCartridge() {
+    this.mod.freezeCartridge(this.c64);
+  }
+
+  // Plug in a RAM Expansion Unit: 128 for a 1700, 256 for a 1764, 512 for a
+  // 1750, or any larger power of two up to 16384
+  attachREU(sizeKB = 512) {
+    return this.mod.attachREU(this.c64, sizeKB) === 0;
+  }
+
+  detachREU() {
+    this.mod.detachREU(this.c64);
+  }
+
+  // Datasette buttons
+  tapePlay() {
+    this.mod.tapePlay(this.c64);
+  }
+
+  tapeStop() {
+    this.mod.tapeStop(this.c64);
+  }
+
+  tapeRewind() {
+    this.mod.tapeRewind(this.c64);
+  }
+
+  // Connect an emulated 1541, given its 16K DOS ROM
+  enableTrueDrive(rom) {
+    const ptr = this.mod.getFileBufferPointer(this.c64, rom.length);
+    memcpy(new Uint8Array(this.mod.memory.buffer, ptr, rom.length), rom, 0);
+    return this.mod.enableTrueDrive(this.c64) === 0;
+  }
+
+  // Returns a copy of the disk image if a program has saved to it since the
+  // last call, or null
+  getModifiedDisk() {
+    if (this.mod.isDiskModified(this.c64) !== 1) {
+      return null;
     }
+    const ptr = this.mod.getDiskPointer(this.c64);
+    const len = this.mod.getDiskLength(this.c64);
+    return new Uint8Array(this.mod.memory.buffer, ptr, len).slice();
   }
 
   step() {
@@ -179,6 +413,10 @@ class VM {
   }
 
   frame(ms) {
+    if (this.c64 === 0) {
+      // destroyed
+      return;
+    }
     if (this._lastFrame === 0) {
       this._lastFrame = ms;
     }
@@ -188,11 +426,19 @@ class VM {
     // collect input
 
     // update cpu state
+    const wasInMonitor = this.isMonitorActive();
     this.mod.runVMFor(this.c64, delta);
+    if (!wasInMonitor && this.isMonitorActive()) {
+      // stopped on a breakpoint
+      console.log(this.monitor());
+    }
 
-    // draw screen
-    this.graphics.loadCharMem(this.mem.char);
-    this.graphics.loadScreenMem(this.mem.screen);
+    // draw screen, using whichever screen and character memory the VIC sees
+    const buffer = this.mod.memory.buffer;
+    const charset = new Uint8Array(buffer, this.mod.getCharsetPointer(this.c64), 0x800);
+    const screen = new Uint8Array(buffer, this.mod.getScreenPointer(this.c64), 1000);
+    this.graphics.loadCharMem(charset);
+    this.graphics.loadScreenMem(screen);
     this.graphics.loadColorMem(this.mem.color);
     this.graphics.setColors(
       this.mod.getBorderColor(this.c64),
@@ -203,13 +449,38 @@ class VM {
     this.graphics.setMode(this.mod.getGraphicsMode(this.c64));
     this.graphics.draw();
 
-    requestAnimationFrame(this.frame);
+    this._frameRequest = requestAnimationFrame(this.frame);
+  }
+
+  // The last complete frame, including the border, as an ImageData ready to
+  // be drawn with putImageData. Pass indexed = true for one color index per
+  // pixel instead, as { width, height, data }.
+  renderFrame(indexed = false) {
+    const width = this.mod.getFrameWidth();
+    const height = this.mod.getFrameHeight();
+    const buffer = this.mod.memory.buffer;
+    if (indexed) {
+      const ptr = this.mod.getFramePointer(this.c64);
+      return { width, height, data: new Uint8Array(buffer, ptr, width * height).slice() };
+    }
+    const ptr = this.mod.getRGBAFramePointer(this.c64);
+    const pixels = new Uint8ClampedArray(buffer, ptr, width * height * 4).slice();
+    return new ImageData(pixels, width, height);
   }
 
   ready() {
     return this._ready;
   }
 
+  // Free the machine inside the wasm module. Any later call on this VM
+  // fails harmlessly with an invalid handle error.
+  destroy() {
+    if (this.c64) {
+      this.mod.destroyVM(this.c64);
+      this.c64 = 0;
+    }
+  }
+
   printRegisters() {
     console.table({
       Acc: this.mod.getRegister(this.c64, 0).toString(16),
@@ -222,5 +493,8 @@ class VM {
   }
 }
 
+VM.PAL = 0;
+VM.NTSC = 1;
+
 window.VM = VM;
 })();
\ No newline at end of file
@@ -1,13 +1,19 @@
-use glutin::{VirtualKeyCode};
+use glutin::{MouseButton, VirtualKeyCode};
+use c64memmap::keymap::{KeymapError, Mode};
+use c64memmap::model::Model;
 use gllite::gli;
 use gllite::uniforms::UniformValue;
 use std::rc::Rc;
 use std::cmp;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process;
 use std::thread;
 use std::time::{self, SystemTime};
 
-mod vm;
-use vm::VM;
+use c64vm::roms::Roms;
+use c64vm::vm::VM;
 
 fn main() {
   let mut shell = emushell::EmuShell::with_size_and_scale(384, 272, 2);
@@ -52,7 +58,134 @@ fn main() {
   color_mem_tex.set_wrap_mode(gli::CLAMP_TO_EDGE, gli::CLAMP_TO_EDGE);
   color_mem_tex.set_filter_mode(gli::NEAREST, gli::NEAREST);
 
-  let mut vm = VM::new();
+  // Usage: c64 [--no-autostart] [--ntsc] [--drive-rom dos1541.rom] [--reu 512] [--mouse | --light-pen] [--keymap keys.txt] [--symbolic] [--symbols labels.txt] [--trace trace.log] [program.prg | disk.d64 | disk.g64 | tape.t64 | tape.tap | cartridge.crt | program.bas | snapshot.vsf]
+  let mut autostart = true;
+  let mut program_path = None;
+  let mut disk_path = None;
+  let mut drive_rom_path = None;
+  let mut reu_size = None;
+  let mut symbols_path = None;
+  let mut trace_path = None;
+  let mut mouse = false;
+  let mut light_pen = false;
+  let mut keymap_path = None;
+  let mut symbolic = false;
+  let mut model = Model::Pal;
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    if arg == "--no-autostart" {
+      autostart = false;
+    } else if arg == "--ntsc" {
+      model = Model::Ntsc;
+    } else if arg == "--drive-rom" {
+      drive_rom_path = args.next();
+    } else if arg == "--reu" {
+      reu_size = args.next();
+    } else if arg == "--mouse" {
+      mouse = true;
+    } else if arg == "--light-pen" {
+      light_pen = true;
+    } else if arg == "--keymap" {
+      keymap_path = args.next();
+    } else if arg == "--symbolic" {
+      symbolic = true;
+    } else if arg == "--symbols" {
+      symbols_path = args.next();
+    } else if arg == "--trace" {
+      trace_path = args.next();
+    } else {
+      program_path = Some(arg);
+    }
+  }
+  let mut vm = VM::with_roms(model, &Roms::embedded());
+  if let Some(path) = drive_rom_path {
+    let rom = fs::read(&path).unwrap_or_default();
+    if !vm.enable_true_drive(&rom) {
+      eprintln!("{} is not a valid 1541 DOS ROM", path);
+      process::exit(1);
+    }
+  }
+  if let Some(size) = reu_size {
+    if !vm.attach_reu(size.parse().unwrap_or(0)) {
+      eprintln!("The REU size must be a power of two from 128 to 16384 kilobytes");
+      process::exit(1);
+    }
+  }
+  // The host mouse drives a 1351 or a light pen in control port 1
+  if mouse {
+    vm.mem.attach_mouse(Some(0));
+  }
+  let mut mouse_remainder = (0.0, 0.0);
+  if let Some(path) = keymap_path {
+    let text = fs::read_to_string(&path).unwrap_or_default();
+    if let Err(KeymapError::InvalidLine(line)) = vm.keymap.load(&text) {
+      eprintln!("Line {} of {} is not a valid key binding", line, path);
+      process::exit(1);
+    }
+  }
+  if symbolic {
+    vm.keymap.mode = Mode::Symbolic;
+  }
+  if let Some(path) = symbols_path {
+    let text = fs::read_to_string(&path).unwrap_or_default();
+    if vm.monitor.symbols.load(&text).is_err() {
+      eprintln!("{} is not a supported symbol file", path);
+      process::exit(1);
+    }
+  }
+  if let Some(path) = trace_path {
+    match fs::File::create(&path) {
+      Ok(file) => vm.trace = Some(Box::new(io::BufWriter::new(file))),
+      Err(e) => {
+        eprintln!("Unable to create {}: {}", path, e);
+        process::exit(1);
+      },
+    }
+  }
+  if let Some(path) = program_path {
+    let data = match fs::read(&path) {
+      Ok(data) => data,
+      Err(e) => {
+        eprintln!("Unable to read {}: {}", path, e);
+        process::exit(1);
+      },
+    };
+    let lower = path.to_lowercase();
+    if lower.ends_with(".d64") || lower.ends_with(".g64") {
+      if !vm.attach_disk(data, autostart) {
+        eprintln!("{} is not a valid disk image, or needs --drive-rom", path);
+        process::exit(1);
+      }
+      disk_path = Some(path);
+    } else if lower.ends_with(".crt") {
+      if !vm.attach_cartridge(&data) {
+        eprintln!("{} is not a supported cartridge image", path);
+        process::exit(1);
+      }
+    } else if lower.ends_with(".t64") || lower.ends_with(".tap") {
+      if !vm.attach_tape(data, autostart) {
+        eprintln!("{} is not a valid tape image", path);
+        process::exit(1);
+      }
+    } else if lower.ends_with(".vsf") {
+      if !vm.load_snapshot(&data) {
+        eprintln!("{} is not a valid C64 snapshot", path);
+        process::exit(1);
+      }
+    } else if lower.ends_with(".bas") {
+      if !vm.enter_basic(&String::from_utf8_lossy(&data), autostart) {
+        eprintln!("{} is not a valid BASIC program", path);
+        process::exit(1);
+      }
+    } else if vm.load_prg(data, autostart).is_none() {
+      eprintln!("{} is not a valid PRG file", path);
+      process::exit(1);
+    }
+  }
+
+  let mut audio = emuaudio::EmuAudio::new();
+  audio.start();
+  vm.set_audio_sample_rate(audio.get_sample_rate());
 
   let mut last_frame_time = SystemTime::now();
   loop {
@@ -88,21 +221,58 @@ fn main() {
     }
 
     if shell.in_foreground() {
-      for key in shell.keys_down.iter() {
-        let code = derive_keycode(key);
-        if code != 255 {
-          vm.mem.cia.keydown(code);
+      for event in shell.key_events.iter() {
+        if event.pressed && event.code == VirtualKeyCode::F12 {
+          vm.enter_monitor();
         }
-      }
-      for key in shell.keys_up.iter() {
-        let code = derive_keycode(key);
-        if code != 255 {
-          vm.mem.cia.keyup(code);
+        if event.pressed && event.code == VirtualKeyCode::F11 {
+          if let Err(e) = fs::write(SNAPSHOT_PATH, vm.save_snapshot()) {
+            eprintln!("Unable to write {}: {}", SNAPSHOT_PATH, e);
+          }
+        }
+        if let Some(name) = key_name(&event.code) {
+          if event.pressed {
+            vm.host_key_down(name, event.text);
+          } else {
+            vm.host_key_up(name);
+          }
         }
       }
 
+      if mouse {
+        let (dx, dy) = (shell.mouse_motion.0 + mouse_remainder.0, shell.mouse_motion.1 + mouse_remainder.1);
+        mouse_remainder = (dx.fract(), dy.fract());
+        vm.mem.move_mouse(dx.trunc() as i32, dy.trunc() as i32);
+        let left = shell.mouse_buttons_down.contains(&MouseButton::Left);
+        let right = shell.mouse_buttons_down.contains(&MouseButton::Right);
+        vm.mem.set_mouse_buttons(left, right);
+      }
+      if light_pen {
+        // The pen only sees the screen while the button is held
+        let position = match shell.mouse_position {
+          Some((x, y)) if shell.mouse_buttons_down.contains(&MouseButton::Left) => Some((x as usize, y as usize)),
+          _ => None,
+        };
+        vm.mem.vic.set_light_pen(position);
+      }
+
       // run vm for delta ms
       vm.run_for_ms(delta as u32);
+      audio.queue_samples(vm.mem.sid.take_samples());
+      if vm.monitor.active {
+        run_monitor(&mut vm);
+        last_frame_time = SystemTime::now();
+      }
+
+      // write saved files back to the disk image
+      if vm.disk.modified {
+        vm.disk.modified = false;
+        if let (Some(path), Some(image)) = (&disk_path, &vm.disk.image) {
+          if let Err(e) = fs::write(path, image.as_bytes()) {
+            eprintln!("Unable to write {}: {}", path, e);
+          }
+        }
+      }
 
       // load char mem
       load_char_mem(&mut vm, &mut char_mem_tex);
@@ -123,12 +293,18 @@ fn main() {
         gl::Clear(gl::COLOR_BUFFER_BIT);
       }
       screen.draw();
+    } else {
+      // Keys let go of in another window never reach this one
+      vm.release_host_keys();
     }
 
     shell.swap_buffers();
   }
 }
 
+// F11 saves a snapshot here
+const SNAPSHOT_PATH: &str = "snapshot.vsf";
+
 const COLORS: [u8; 4 * 16] = [
   0x00, 0x00, 0x00, 0x00,
   0xff, 0xff, 0xff, 0x00,
@@ -148,74 +324,138 @@ const COLORS: [u8; 4 * 16] = [
   0x95, 0x95, 0x95, 0x00,
 ];
 
+/**
+ * Talk to the monitor on the terminal until a command resumes the machine.
+ * The window stops updating in the meantime. Closing stdin resumes too.
+ */
+fn run_monitor(vm: &mut VM) {
+  let stdin = io::stdin();
+  let mut output = vm.monitor.take_output();
+  while vm.monitor.active {
+    print!("{}{}", output, vm.monitor.prompt());
+    io::stdout().flush().unwrap_or(());
+    let mut line = String::new();
+    let command = match stdin.lock().read_line(&mut line) {
+      Ok(0) | Err(_) => "x",
+      Ok(_) => &line[..],
+    };
+    output = vm.monitor_command(command);
+  }
+  print!("{}", output);
+}
+
 fn load_char_mem(vm: &mut VM, tex: &gllite::texture::Texture) {
-  tex.set_from_bytes(gl::R8UI, 8, 256, gl::RED_INTEGER, vm.mem.ram_rom.char_ptr())
+  tex.set_from_bytes(gl::R8UI, 8, 256, gl::RED_INTEGER, vm.mem.char_ptr())
 }
 
 fn load_screen_mem(vm: &mut VM, tex: &gllite::texture::Texture) {
-  tex.set_from_bytes(gl::R8UI, 40, 25, gl::RED_INTEGER, vm.mem.ram_rom.screen_ptr())
+  tex.set_from_bytes(gl::R8UI, 40, 25, gl::RED_INTEGER, vm.mem.screen_ptr())
 }
 
 fn load_color_mem(vm: &mut VM, tex: &gllite::texture::Texture) {
   tex.set_from_bytes(gl::R8UI, 1024, 1, gl::RED_INTEGER, vm.mem.ram_rom.color_ptr())
 }
 
-fn derive_keycode(code: &VirtualKeyCode) -> u8 {
-  match code {
-    VirtualKeyCode::Escape => 63,
-    VirtualKeyCode::Grave => 57,
-    VirtualKeyCode::Key1 => 56,
-    VirtualKeyCode::Key2 => 59,
-    VirtualKeyCode::Key3 => 8,
-    VirtualKeyCode::Key4 => 11,
-    VirtualKeyCode::Key5 => 16,
-    VirtualKeyCode::Key6 => 19,
-    VirtualKeyCode::Key7 => 24,
-    VirtualKeyCode::Key8 => 27,
-    VirtualKeyCode::Key9 => 32,
-    VirtualKeyCode::Key0 => 35,
-    VirtualKeyCode::Minus => 40,
-    VirtualKeyCode::Equals => 53,
-    VirtualKeyCode::Back => 0,
-    VirtualKeyCode::Tab => 58,
-    VirtualKeyCode::Q => 62,
-    VirtualKeyCode::W => 9,
-    VirtualKeyCode::E => 14,
-    VirtualKeyCode::R => 17,
-    VirtualKeyCode::T => 22,
-    VirtualKeyCode::Y => 25,
-    VirtualKeyCode::U => 30,
-    VirtualKeyCode::I => 33,
-    VirtualKeyCode::O => 38,
-    VirtualKeyCode::P => 41,
-    VirtualKeyCode::LBracket => 46,
-    VirtualKeyCode::RBracket => 49,
-    VirtualKeyCode::Return => 1,
-    VirtualKeyCode::Capital => 52,
-    VirtualKeyCode::A => 10,
-    VirtualKeyCode::S => 13,
-    VirtualKeyCode::D => 18,
-    VirtualKeyCode::F => 21,
-    VirtualKeyCode::G => 26,
-    VirtualKeyCode::H => 29,
-    VirtualKeyCode::J => 34,
-    VirtualKeyCode::K => 37,
-    VirtualKeyCode::L => 42,
-    VirtualKeyCode::Semicolon => 45,
-    VirtualKeyCode::Apostrophe => 50,
-    VirtualKeyCode::LShift => 15,
-    VirtualKeyCode::Z => 12,
-    VirtualKeyCode::X => 23,
-    VirtualKeyCode::C => 20,
-    VirtualKeyCode::V => 31,
-    VirtualKeyCode::B => 28,
-    VirtualKeyCode::N => 39,
-    VirtualKeyCode::M => 36,
-    VirtualKeyCode::Comma => 47,
-    VirtualKeyCode::Period => 44,
-    VirtualKeyCode::Slash => 55,
-    VirtualKeyCode::LControl => 61,
-    VirtualKeyCode::Space => 60,
-    _ => 255,
-  }
-}
+// The name the keymap knows a key by, which is its KeyboardEvent.code in a
+// browser
+fn key_name(code: &VirtualKeyCode) -> Option<&'static str> {
+  let name = match code {
+    VirtualKeyCode::Escape => "Escape",
+    VirtualKeyCode::PageUp => "PageUp",
+    VirtualKeyCode::PageDown => "PageDown",
+    VirtualKeyCode::Home => "Home",
+    VirtualKeyCode::End => "End",
+    VirtualKeyCode::Insert => "Insert",
+    VirtualKeyCode::Delete => "Delete",
+    VirtualKeyCode::Grave => "Backquote",
+    VirtualKeyCode::Key1 => "Digit1",
+    VirtualKeyCode::Key2 => "Digit2",
+    VirtualKeyCode::Key3 => "Digit3",
+    VirtualKeyCode::Key4 => "Digit4",
+    VirtualKeyCode::Key5 => "Digit5",
+    VirtualKeyCode::Key6 => "Digit6",
+    VirtualKeyCode::Key7 => "Digit7",
+    VirtualKeyCode::Key8 => "Digit8",
+    VirtualKeyCode::Key9 => "Digit9",
+    VirtualKeyCode::Key0 => "Digit0",
+    VirtualKeyCode::Minus => "Minus",
+    VirtualKeyCode::Equals => "Equal",
+    VirtualKeyCode::Back => "Backspace",
+    VirtualKeyCode::Tab => "Tab",
+    VirtualKeyCode::Q => "KeyQ",
+    VirtualKeyCode::W => "KeyW",
+    VirtualKeyCode::E => "KeyE",
+    VirtualKeyCode::R => "KeyR",
+    VirtualKeyCode::T => "KeyT",
+    VirtualKeyCode::Y => "KeyY",
+    VirtualKeyCode::U => "KeyU",
+    VirtualKeyCode::I => "KeyI",
+    VirtualKeyCode::O => "KeyO",
+    VirtualKeyCode::P => "KeyP",
+    VirtualKeyCode::LBracket => "BracketLeft",
+    VirtualKeyCode::RBracket => "BracketRight",
+    VirtualKeyCode::Backslash => "Backslash",
+    VirtualKeyCode::Return => "Enter",
+    VirtualKeyCode::Capital => "CapsLock",
+    VirtualKeyCode::A => "KeyA",
+    VirtualKeyCode::S => "KeyS",
+    VirtualKeyCode::D => "KeyD",
+    VirtualKeyCode::F => "KeyF",
+    VirtualKeyCode::G => "KeyG",
+    VirtualKeyCode::H => "KeyH",
+    VirtualKeyCode::J => "KeyJ",
+    VirtualKeyCode::K => "KeyK",
+    VirtualKeyCode::L => "KeyL",
+    VirtualKeyCode::Semicolon => "Semicolon",
+    VirtualKeyCode::Apostrophe => "Quote",
+    VirtualKeyCode::LShift => "ShiftLeft",
+    VirtualKeyCode::Z => "KeyZ",
+    VirtualKeyCode::X => "KeyX",
+    VirtualKeyCode::C => "KeyC",
+    VirtualKeyCode::V => "KeyV",
+    VirtualKeyCode::B => "KeyB",
+    VirtualKeyCode::N => "KeyN",
+    VirtualKeyCode::M => "KeyM",
+    VirtualKeyCode::Comma => "Comma",
+    VirtualKeyCode::Period => "Period",
+    VirtualKeyCode::Slash => "Slash",
+    VirtualKeyCode::RShift => "ShiftRight",
+    VirtualKeyCode::LControl => "ControlLeft",
+    VirtualKeyCode::RControl => "ControlRight",
+    VirtualKeyCode::LAlt => "AltLeft",
+    VirtualKeyCode::RAlt => "AltRight",
+    VirtualKeyCode::Space => "Space",
+    VirtualKeyCode::Up => "ArrowUp",
+    VirtualKeyCode::Down => "ArrowDown",
+    VirtualKeyCode::Left => "ArrowLeft",
+    VirtualKeyCode::Right => "ArrowRight",
+    VirtualKeyCode::F1 => "F1",
+    VirtualKeyCode::F2 => "F2",
+    VirtualKeyCode::F3 => "F3",
+    VirtualKeyCode::F4 => "F4",
+    VirtualKeyCode::F5 => "F5",
+    VirtualKeyCode::F6 => "F6",
+    VirtualKeyCode::F7 => "F7",
+    VirtualKeyCode::F8 => "F8",
+    VirtualKeyCode::F9 => "F9",
+    VirtualKeyCode::F10 => "F10",
+    VirtualKeyCode::Numpad0 => "Numpad0",
+    VirtualKeyCode::Numpad1 => "Numpad1",
+    VirtualKeyCode::Numpad2 => "Numpad2",
+    VirtualKeyCode::Numpad3 => "Numpad3",
+    VirtualKeyCode::Numpad4 => "Numpad4",
+    VirtualKeyCode::Numpad5 => "Numpad5",
+    VirtualKeyCode::Numpad6 => "Numpad6",
+    VirtualKeyCode::Numpad7 => "Numpad7",
+    VirtualKeyCode::Numpad8 => "Numpad8",
+    VirtualKeyCode::Numpad9 => "Numpad9",
+    VirtualKeyCode::Add => "NumpadAdd",
+    VirtualKeyCode::Subtract => "NumpadSubtract",
+    VirtualKeyCode::Multiply => "NumpadMultiply",
+    VirtualKeyCode::Divide => "NumpadDivide",
+    VirtualKeyCode::Decimal => "NumpadDecimal",
+    VirtualKeyCode::NumpadEnter => "NumpadEnter",
+    _ => return None,
+  };
+  return Some(name);
+}
\ No newline at end of file
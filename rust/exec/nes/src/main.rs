@@ -7,24 +7,40 @@ use gllite::uniforms::UniformValue;
 use std::env;
 use std::error::Error;
 use std::ffi::c_void;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
 use std::path::Path;
 use std::rc::Rc;
 use std::thread;
 use std::time::{self, SystemTime};
 
-mod apu;
-use nesmemmap::mapper;
+use emuaudio::EmuAudio;
 use nesmemmap::ppu::SpriteTableAddress;
-use mos6510::memory::Memory;
-mod palette;
+use nesmemmap::palette;
+use nesvm::vm::VM;
 mod sprites;
-mod vm;
-use vm::VM;
+
+const FRAMES_PER_MS: f32 = 60.0988 / 1000.0;
 
 fn main() {
-  let mapper = load_mapper();
+  // Usage: nes [--symbols rom.nes.0.nl] [--trace trace.log] rom.nes
+  let mut rom_path = None;
+  let mut symbols_paths = Vec::new();
+  let mut trace_path = None;
+  let mut args = env::args().skip(1);
+  while let Some(arg) = args.next() {
+    if arg == "--symbols" {
+      symbols_paths.extend(args.next());
+    } else if arg == "--trace" {
+      trace_path = args.next();
+    } else {
+      rom_path = Some(arg);
+    }
+  }
+  let rom = match rom_path {
+    Some(path) => load_rom_data(&path),
+    None => panic!("Must load a ROM file"),
+  };
 
   let mut shell = emushell::EmuShell::with_size_and_scale(256, 240, 2);
   shell.make_active_gl_context();
@@ -138,9 +154,26 @@ fn main() {
     gl::BlendFuncSeparate(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA, gl::ONE, gl::ZERO);
   }
 
-  let mut vm = VM::new(mapper);
-  vm.mem.ppu.set_scanline(241);
-
+  let mut audio = EmuAudio::new();
+  audio.start();
+  let mut vm = VM::new(audio.get_sample_rate());
+  if let Err(err) = vm.load_rom(&rom) {
+    panic!("Couldn't load ROM: {:?}", err);
+  }
+  // FCEUX writes a name list for RAM and each PRG bank, so several may be given
+  for path in symbols_paths {
+    let text = fs::read_to_string(&path).unwrap_or_default();
+    if vm.symbols.load(&text).is_err() {
+      panic!("{} is not a supported symbol file", path);
+    }
+  }
+  if let Some(path) = trace_path {
+    match File::create(&path) {
+      Err(msg) => panic!("Couldn't create trace file: {}", msg),
+      Ok(file) => vm.trace = Some(Box::new(std::io::BufWriter::new(file))),
+    }
+  }
+  let mut frames_due = 0.0;
   let mut last_frame_time = SystemTime::now();
   loop {
     let now = SystemTime::now();
@@ -166,63 +199,41 @@ fn main() {
     }
 
     if shell.in_foreground() {
+      let mut buttons = 0;
       for key in shell.keys_down.iter() {
-        match key {
-          VirtualKeyCode::Return => vm.mem.controller_0.start = true,
-          VirtualKeyCode::RShift => vm.mem.controller_0.select = true,
-          VirtualKeyCode::X => vm.mem.controller_0.a = true,
-          VirtualKeyCode::Z => vm.mem.controller_0.b = true,
-          VirtualKeyCode::Left => vm.mem.controller_0.left = true,
-          VirtualKeyCode::Right => vm.mem.controller_0.right = true,
-          VirtualKeyCode::Up => vm.mem.controller_0.up = true,
-          VirtualKeyCode::Down => vm.mem.controller_0.down = true,
-          VirtualKeyCode::A => {
-            if !a_press {
-              vm.mem.apu.test_note();
-              a_press = true;
-            }
-          },
-          _ => (),
-        }
+        buttons |= match key {
+          VirtualKeyCode::X => 0x01,
+          VirtualKeyCode::Z => 0x02,
+          VirtualKeyCode::RShift => 0x04,
+          VirtualKeyCode::Return => 0x08,
+          VirtualKeyCode::Up => 0x10,
+          VirtualKeyCode::Down => 0x20,
+          VirtualKeyCode::Left => 0x40,
+          VirtualKeyCode::Right => 0x80,
+          _ => 0,
+        };
       }
-      for key in shell.keys_up.iter() {
-        match key {
-          VirtualKeyCode::Return => vm.mem.controller_0.start = false,
-          VirtualKeyCode::RShift => vm.mem.controller_0.select = false,
-          VirtualKeyCode::X => vm.mem.controller_0.a = false,
-          VirtualKeyCode::Z => vm.mem.controller_0.b = false,
-          VirtualKeyCode::Left => vm.mem.controller_0.left = false,
-          VirtualKeyCode::Right => vm.mem.controller_0.right = false,
-          VirtualKeyCode::Up => vm.mem.controller_0.up = false,
-          VirtualKeyCode::Down => vm.mem.controller_0.down = false,
-          VirtualKeyCode::A => a_press = false,
-          _ => (),
+      vm.set_controller(0, buttons);
+      if shell.keys_down.contains(&VirtualKeyCode::A) {
+        if !a_press {
+          if let Some(console) = vm.console.as_mut() {
+            console.mem.apu.test_note();
+          }
+          a_press = true;
         }
+      } else {
+        a_press = false;
       }
 
-      let mut copied_scanline = false;
-      // Run VM, draw result
-      let cycles_for_this_frame = (delta * 1790) as u32;
-      let mut total: u32 = 0;
-      while total < cycles_for_this_frame {
-        let mut cycles = vm.step() as u32;
-        if vm.mem.dma_requested() {
-          vm.mem.dma_copy();
-          cycles += 514;
-        }
-
-        for _ in 0..(cycles * 3) {
-          vm.mem.increment_clock();
-          if !copied_scanline && vm.mem.ppu2.in_vblank() {
-            copied_scanline = true;
-            scanline_tex.set_from_bytes(gli::R8UI, 256, 240, gli::RED_INTEGER, vm.mem.ppu2.buffer_ptr());
-          }
-        }
-        if vm.mem.ppu2.should_interrupt() {
-          vm.cpu.nonmaskable_interrupt(&mut vm.mem);
-        }
-        total += cycles;
+      // Run as many frames as have come due, and draw the last
+      frames_due += delta as f32 * FRAMES_PER_MS;
+      while frames_due >= 1.0 {
+        vm.run_frame();
+        frames_due -= 1.0;
       }
+      audio.queue_samples(vm.samples().to_vec());
+      vm.clear_samples();
+      scanline_tex.set_from_bytes(gli::R8UI, 256, 240, gli::RED_INTEGER, vm.frame().as_ptr());
 
       unsafe {
         gl::Clear(gl::COLOR_BUFFER_BIT);
@@ -236,23 +247,8 @@ fn main() {
   }
 }
 
-fn load_mapper() -> Box<mapper::Mapper> {
-  let mut bin_seen = false;
-  let mut file_seen = false;
-  let mut file_name = String::new();
-  for arg in env::args() {
-    if !bin_seen {
-      bin_seen = true;
-    } else {
-      file_name = arg;
-      file_seen = true;
-    }
-  }
-  if !file_seen {
-    panic!("Must load a ROM file");
-  }
-
-  let path = Path::new(&file_name);
+fn load_rom_data(file_name: &str) -> Vec<u8> {
+  let path = Path::new(file_name);
   let mut file = match File::open(&path) {
     Err(msg) => panic!("Couldn't open file: {}", msg.description()),
     Ok(file) => file,
@@ -263,5 +259,5 @@ fn load_mapper() -> Box<mapper::Mapper> {
     Ok(_) => (),
   };
 
-  mapper::create_mapper(&buffer)
+  buffer
 }
\ No newline at end of file
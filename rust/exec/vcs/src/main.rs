@@ -12,11 +12,8 @@ use std::rc::Rc;
 use std::thread;
 use std::time::{self, SystemTime};
 
-mod palette;
-use mos6510::memory::Memory;
-use vcsmemmap::tia::{ExecState, ScanlineState};
-mod vm;
-use vm::VM;
+use vcsmemmap::palette;
+use vcsvm::vm::VM;
 
 fn main() {
   let rom_data = load_rom_data();
@@ -59,11 +56,10 @@ fn main() {
 
   screen.set_uniform(String::from("screen"), screen_tex.as_uniform_value());
 
-  let mut screen_tex_buffer: Box<[u8; 160 * 192]> = Box::new([0; 160 * 192]);
-
-  let mut vm = VM::new();
-  vm.mem.load_rom(rom_data.into_boxed_slice());
-  vm.reset();
+  let mut vm = VM::new(44100);
+  if !vm.load_rom(&rom_data) {
+    panic!("Only 2K, 4K and 8K cartridges are supported");
+  }
 
   let mut last_frame_time = SystemTime::now();
   loop {
@@ -87,81 +83,27 @@ fn main() {
     }
 
     if shell.in_foreground() {
+      let mut joystick = 0;
       for key in shell.keys_down.iter() {
-        match key {
-          VirtualKeyCode::Left => {
-            vm.mem.riot.joystick_0_left = true;
-            vm.mem.riot.joystick_0_right = false;
-          },
-          VirtualKeyCode::Right => {
-            vm.mem.riot.joystick_0_right = true;
-            vm.mem.riot.joystick_0_left = false;
-          },
-          VirtualKeyCode::Up => {
-            vm.mem.riot.joystick_0_up = true;
-            vm.mem.riot.joystick_0_down = true;
-          },
-          VirtualKeyCode::Down => {
-            vm.mem.riot.joystick_0_down = true;
-            vm.mem.riot.joystick_0_up = false;
-          },
-          _ => (),
-        }
-      }
-      for key in shell.keys_up.iter() {
-        match key {
-          VirtualKeyCode::Left => {
-            vm.mem.riot.joystick_0_left = false;
-          },
-          VirtualKeyCode::Right => {
-            vm.mem.riot.joystick_0_right = false;
-          },
-          VirtualKeyCode::Up => {
-            vm.mem.riot.joystick_0_up = false;
-          },
-          VirtualKeyCode::Down => {
-            vm.mem.riot.joystick_0_down = false;
-          },
-          _ => (),
-        }
-      }
-
-      while let ScanlineState::VSync = vm.mem.tia.get_scanline_state() {
-        let cycles = match vm.mem.tia.get_exec_state() {
-          ExecState::Run => vm.step() * 3,
-          ExecState::Block => 1,
-        };
-        vm.mem.tia.increment_clock(cycles);
-        for _ in 0..cycles {
-          vm.mem.riot.increment_clock();
-        }
-      }
-      let mut vsync = false;
-      while !vsync {
-        let cycles = match vm.mem.tia.get_exec_state() {
-          ExecState::Run => vm.step() * 3,
-          ExecState::Block => 1,
+        joystick |= match key {
+          VirtualKeyCode::Up => 0x01,
+          VirtualKeyCode::Down => 0x02,
+          VirtualKeyCode::Left => 0x04,
+          VirtualKeyCode::Right => 0x08,
+          _ => 0,
         };
-        for _ in 0..cycles {
-          vm.mem.tia.increment_clock(1);
-          vm.mem.riot.increment_clock();
-
-          match vm.mem.tia.get_scanline_state() {
-            ScanlineState::Pixel(x, y, color) => {
-              let addr = (y as u16 * 160) + (x as u16);
-              screen_tex_buffer[addr as usize] = color;
-            },
-            ScanlineState::VSync => vsync = true,
-            _ => (),
-          }
-        }
       }
+      vm.set_joystick(0, joystick);
+
+      vm.run_frame();
+      // There's no audio output yet
+      vm.mem.tia.audio.clear_samples();
 
       unsafe {
         gl::Clear(gl::COLOR_BUFFER_BIT);
       }
 
-      screen_tex.set_from_bytes(gli::R8UI, 160, 192, gli::RED_INTEGER, &screen_tex_buffer[0] as *const u8);
+      screen_tex.set_from_bytes(gli::R8UI, 160, 192, gli::RED_INTEGER, vm.frame().as_ptr());
       screen.draw();
 
       shell.swap_buffers();
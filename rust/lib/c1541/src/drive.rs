@@ -0,0 +1,373 @@
+use mos6510::cpu::CPU;
+use mos6510::flags::FLAG_OVERFLOW;
+use mos6510::memory::Memory;
+use crate::gcr::{GcrDisk, HALF_TRACKS};
+use crate::via::VIA;
+
+pub const ROM_SIZE: usize = 0x4000;
+const RAM_SIZE: usize = 0x800;
+
+// VIA 1 port B: the serial bus. Inputs read 1 while a line is pulled low.
+const IEC_DATA_IN: u8 = 0x01;
+const IEC_DATA_OUT: u8 = 0x02;
+const IEC_CLK_IN: u8 = 0x04;
+const IEC_CLK_OUT: u8 = 0x08;
+const IEC_ATN_ACK: u8 = 0x10;
+const IEC_ATN_IN: u8 = 0x80;
+
+// VIA 2 port B: the disk mechanism
+const DISK_STEPPER: u8 = 0x03;
+const DISK_MOTOR: u8 = 0x04;
+const DISK_LED: u8 = 0x08;
+const DISK_WRITE_ENABLE: u8 = 0x10;
+const DISK_DENSITY_SHIFT: u8 = 5;
+const DISK_SYNC: u8 = 0x80;
+
+pub struct DriveMemory {
+  ram: Box<[u8; RAM_SIZE]>,
+  rom: Box<[u8; ROM_SIZE]>,
+  pub via1: VIA,
+  pub via2: VIA,
+}
+
+impl Memory for DriveMemory {
+  fn get_byte(&mut self, addr: u16) -> u8 {
+    if addr & 0x8000 != 0 {
+      return self.rom[(addr & 0x3fff) as usize];
+    }
+    match addr & 0x1c00 {
+      0x0000 | 0x0400 => self.ram[(addr & 0x7ff) as usize],
+      0x1800 => self.via1.get_byte(addr),
+      0x1c00 => self.via2.get_byte(addr),
+      // Nothing responds, the bus holds the high byte of the address
+      _ => (addr >> 8) as u8,
+    }
+  }
+
+  fn set_byte(&mut self, addr: u16, value: u8) {
+    if addr & 0x8000 != 0 {
+      return;
+    }
+    match addr & 0x1c00 {
+      0x0000 | 0x0400 => self.ram[(addr & 0x7ff) as usize] = value,
+      0x1800 => self.via1.set_byte(addr, value),
+      0x1c00 => self.via2.set_byte(addr, value),
+      _ => (),
+    }
+  }
+}
+
+/**
+ * A 1541 disk drive running its own DOS on a second 6502, connected to the
+ * C64 over the IEC serial bus.
+ */
+pub struct Drive1541 {
+  pub cpu: CPU,
+  pub mem: DriveMemory,
+  pub disk: Option<GcrDisk>,
+
+  // Cycles owed to the drive CPU; it may run ahead by part of an instruction
+  cycle_balance: i32,
+
+  // Serial lines the C64 is pulling low
+  c64_atn: bool,
+  c64_clk: bool,
+  c64_data: bool,
+
+  half_track: usize,
+  stepper_phase: u8,
+  head_position: usize,
+  byte_cycles: u16,
+}
+
+impl Drive1541 {
+  pub fn new() -> Drive1541 {
+    Drive1541 {
+      cpu: CPU::new(),
+      mem: DriveMemory {
+        ram: box [0; RAM_SIZE],
+        rom: box [0; ROM_SIZE],
+        via1: VIA::new(),
+        via2: VIA::new(),
+      },
+      disk: None,
+
+      cycle_balance: 0,
+
+      c64_atn: false,
+      c64_clk: false,
+      c64_data: false,
+
+      // The head starts on track 18, where the directory lives
+      half_track: 34,
+      stepper_phase: 0,
+      head_position: 0,
+      byte_cycles: 0,
+    }
+  }
+
+  /**
+   * Install the 16K DOS ROM, which occupies $C000-$FFFF. Returns false if the
+   * ROM is the wrong size.
+   */
+  pub fn load_rom(&mut self, rom: &[u8]) -> bool {
+    if rom.len() != ROM_SIZE {
+      return false;
+    }
+    self.mem.rom.copy_from_slice(rom);
+    return true;
+  }
+
+  pub fn reset(&mut self) {
+    self.mem.via1.reset();
+    self.mem.via2.reset();
+    self.cycle_balance = 0;
+    self.cpu.reset(&mut self.mem);
+  }
+
+  pub fn insert_disk(&mut self, disk: GcrDisk) {
+    self.disk = Some(disk);
+  }
+
+  pub fn eject_disk(&mut self) -> Option<GcrDisk> {
+    self.disk.take()
+  }
+
+  pub fn led_on(&self) -> bool {
+    self.mem.via2.port_b_output() & DISK_LED != 0
+  }
+
+  pub fn motor_on(&self) -> bool {
+    self.mem.via2.port_b_output() & DISK_MOTOR != 0
+  }
+
+  /**
+   * The track under the head, counting in half-tracks from 1.0.
+   */
+  pub fn current_track(&self) -> f32 {
+    (self.half_track as f32) / 2.0 + 1.0
+  }
+
+  /**
+   * Set which serial lines the C64 is pulling low, through CIA 2 port A.
+   */
+  pub fn set_c64_lines(&mut self, atn: bool, clk: bool, data: bool) {
+    self.c64_atn = atn;
+    self.c64_clk = clk;
+    self.c64_data = data;
+    self.update_bus_inputs();
+  }
+
+  /**
+   * The drive pulls DATA low on its own whenever ATN is asserted and the DOS
+   * hasn't acknowledged it through ATNA, so the C64 can tell a drive is
+   * present before any code runs.
+   */
+  fn drive_pulls(&self) -> (bool, bool) {
+    let port = self.mem.via1.port_b_output();
+    let clk = port & IEC_CLK_OUT != 0;
+    let ack = port & IEC_ATN_ACK != 0;
+    let data = port & IEC_DATA_OUT != 0 || (self.c64_atn != ack);
+    return (clk, data);
+  }
+
+  /**
+   * The levels of the CLK and DATA lines, true when high. Every device on the
+   * bus can pull a line low.
+   */
+  pub fn bus_lines(&self) -> (bool, bool) {
+    let (clk, data) = self.drive_pulls();
+    return (!(clk || self.c64_clk), !(data || self.c64_data));
+  }
+
+  fn update_bus_inputs(&mut self) {
+    let (clk, data) = self.bus_lines();
+    let mut input = 0;
+    if !data {
+      input |= IEC_DATA_IN;
+    }
+    if !clk {
+      input |= IEC_CLK_IN;
+    }
+    if self.c64_atn {
+      input |= IEC_ATN_IN;
+    }
+    // Bits 5 and 6 are the device number jumpers, both open for device 8
+    self.mem.via1.port_b_in = input;
+    self.mem.via1.set_ca1(self.c64_atn);
+  }
+
+  /**
+   * Run the drive for the given number of cycles, to keep it in step with the
+   * C64 CPU.
+   */
+  pub fn run(&mut self, cycles: u8) {
+    self.cycle_balance += cycles as i32;
+    while self.cycle_balance > 0 {
+      let step_time = self.cpu.step(&mut self.mem);
+      self.mem.via1.update_timers(step_time);
+      self.mem.via2.update_timers(step_time);
+      self.update_bus_inputs();
+      self.update_disk(step_time);
+      if self.mem.via1.irq() || self.mem.via2.irq() {
+        self.cpu.interrupt_request(&mut self.mem);
+      }
+      self.cycle_balance -= step_time as i32;
+    }
+  }
+
+  fn update_disk(&mut self, cycles: u8) {
+    let control = self.mem.via2.port_b_output();
+
+    // Energizing the next stepper phase moves the head half a track
+    let phase = control & DISK_STEPPER;
+    if phase == (self.stepper_phase + 1) & 3 && self.half_track < HALF_TRACKS - 1 {
+      self.half_track += 1;
+    } else if phase == (self.stepper_phase + 3) & 3 && self.half_track > 0 {
+      self.half_track -= 1;
+    }
+    self.stepper_phase = phase;
+
+    let mut status = DISK_SYNC | DISK_WRITE_ENABLE | 0x60;
+    let disk = match self.disk {
+      Some(ref mut disk) => disk,
+      None => {
+        self.mem.via2.port_b_in = status;
+        return;
+      },
+    };
+    if disk.write_protected {
+      status &= !DISK_WRITE_ENABLE;
+    }
+    if control & DISK_MOTOR == 0 {
+      self.mem.via2.port_b_in = status;
+      return;
+    }
+
+    // Each speed zone clocks bits at a different rate, from 26 cycles per
+    // byte on the outer tracks to 32 on the inner ones
+    let density = (control >> DISK_DENSITY_SHIFT) & 3;
+    let cycles_per_byte = 32 - 2 * density as u16;
+    self.byte_cycles += cycles as u16;
+    let writing = !self.mem.via2.cb2_output();
+    while self.byte_cycles >= cycles_per_byte {
+      self.byte_cycles -= cycles_per_byte;
+      let track = &mut disk.tracks[self.half_track];
+      let len = track.len();
+      if len == 0 {
+        // Unformatted: no sync, and no bytes worth reading
+        continue;
+      }
+      self.head_position = (self.head_position + 1) % len;
+      if writing {
+        if disk.write_protected {
+          continue;
+        }
+        track[self.head_position] = self.mem.via2.port_a_output();
+        disk.modified = true;
+      } else {
+        let byte = track[self.head_position];
+        let previous = track[(self.head_position + len - 1) % len];
+        if byte == 0xff && previous == 0xff {
+          // Inside a sync mark, which the drive doesn't deliver as data
+          status &= !DISK_SYNC;
+          continue;
+        }
+        self.mem.via2.port_a_in = byte;
+      }
+      // BYTE READY sets the CPU's overflow flag through its SO pin when CA2
+      // enables it
+      if self.mem.via2.ca2_output() {
+        self.cpu.status |= FLAG_OVERFLOW;
+      }
+      self.mem.via2.pulse_ca1();
+    }
+    if !writing {
+      if let Some(track) = disk.tracks.get(self.half_track) {
+        let len = track.len();
+        if len > 0 {
+          let pos = self.head_position % len;
+          if track[pos] == 0xff && track[(pos + len - 1) % len] == 0xff {
+            status &= !DISK_SYNC;
+          }
+        }
+      }
+    }
+    self.mem.via2.port_b_in = status;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::drive::{Drive1541, ROM_SIZE};
+  use crate::gcr::GcrDisk;
+  use c64memmap::d64::D64;
+  use mos6510::memory::Memory;
+
+  fn drive_with_program(program: &[u8]) -> Drive1541 {
+    let mut rom = vec![0xea; ROM_SIZE];
+    for i in 0..program.len() {
+      rom[i] = program[i];
+    }
+    // reset vector at $C000
+    rom[0x3ffc] = 0x00;
+    rom[0x3ffd] = 0xc0;
+    let mut drive = Drive1541::new();
+    assert!(drive.load_rom(&rom));
+    drive.reset();
+    drive
+  }
+
+  #[test]
+  fn memory_map() {
+    let mut drive = drive_with_program(&[]);
+    assert_eq!(drive.cpu.pc, 0xc000);
+    drive.mem.set_byte(0x0010, 0x42);
+    assert_eq!(drive.mem.get_byte(0x2010), 0x42);
+    assert_eq!(drive.mem.get_byte(0x8000), 0xea);
+    drive.mem.set_byte(0x1802, 0xff);
+    assert_eq!(drive.mem.get_byte(0x1802), 0xff);
+  }
+
+  #[test]
+  fn atn_acknowledge() {
+    let mut drive = drive_with_program(&[]);
+    // VIA 1 pins are inputs after reset and float high, pulling both lines
+    drive.set_c64_lines(false, false, false);
+    assert_eq!(drive.bus_lines(), (false, false));
+    // DDR for DATA OUT, CLK OUT and ATNA, all released
+    drive.mem.set_byte(0x1802, 0x1a);
+    drive.mem.set_byte(0x1800, 0x00);
+    drive.set_c64_lines(false, false, false);
+    assert_eq!(drive.bus_lines(), (true, true));
+    // ATN pulls DATA low until the drive sets ATNA
+    drive.set_c64_lines(true, false, false);
+    assert_eq!(drive.bus_lines(), (true, false));
+    assert_eq!(drive.mem.get_byte(0x1800) & 0x80, 0x80);
+    drive.mem.set_byte(0x1800, 0x10);
+    drive.set_c64_lines(true, false, false);
+    assert_eq!(drive.bus_lines(), (true, true));
+  }
+
+  #[test]
+  fn reads_disk() {
+    // LDA #$ee : STA $1c0c (CA2 high enables byte ready)
+    // LDA #$04 : STA $1c00 : STA $1c02 (motor on)
+    // wait: BIT $1c00 : BMI wait (sync)
+    // LDA $1c01 : CLV : BVC * : LDA $1c01 : STA $00 : JMP *
+    let program = [
+      0xa9, 0xee, 0x8d, 0x0c, 0x1c,
+      0xa9, 0x6c, 0x8d, 0x00, 0x1c, 0xa9, 0x6f, 0x8d, 0x02, 0x1c,
+      0x2c, 0x00, 0x1c, 0x30, 0xfb,
+      0xad, 0x01, 0x1c, 0xb8, 0x50, 0xfe, 0xad, 0x01, 0x1c, 0x85, 0x00,
+      0x4c, 0x22, 0xc0,
+    ];
+    let mut drive = drive_with_program(&program);
+    drive.insert_disk(GcrDisk::from_d64(&D64::blank(b"READ", b"01")));
+    for _ in 0..2000 {
+      drive.run(10);
+    }
+    // The first GCR byte after a sync is the header block marker
+    assert_eq!(drive.mem.get_byte(0x00), 0x52);
+  }
+}
@@ -0,0 +1,297 @@
+use c64memmap::d64::{D64, sectors_per_track};
+
+// Group Code Recording: each 4-bit nybble is stored as 5 bits, chosen so the
+// disk never has more than two 0 bits in a row
+const GCR_ENCODE: [u8; 16] = [
+  0x0a, 0x0b, 0x12, 0x13, 0x0e, 0x0f, 0x16, 0x17,
+  0x09, 0x19, 0x1a, 0x1b, 0x0d, 0x1d, 0x1e, 0x15,
+];
+
+pub const HALF_TRACKS: usize = 84;
+
+const SYNC_LENGTH: usize = 5;
+const HEADER_GAP: usize = 9;
+const HEADER_BLOCK_ID: u8 = 0x08;
+const DATA_BLOCK_ID: u8 = 0x07;
+const HEADER_GCR_SIZE: usize = 10;
+const DATA_GCR_SIZE: usize = 325;
+const GAP_BYTE: u8 = 0x55;
+
+// D64 error codes that change how a sector is laid down
+const ERROR_HEADER_NOT_FOUND: u8 = 0x02;
+const ERROR_NO_SYNC: u8 = 0x03;
+const ERROR_DATA_NOT_FOUND: u8 = 0x04;
+const ERROR_DATA_CHECKSUM: u8 = 0x05;
+const ERROR_HEADER_CHECKSUM: u8 = 0x09;
+
+const G64_SIGNATURE: &[u8] = b"GCR-1541";
+
+/**
+ * The four speed zones spin different amounts of data past the head; outer
+ * tracks hold more.
+ */
+pub fn speed_zone(track: u8) -> u8 {
+  match track {
+    1..=17 => 3,
+    18..=24 => 2,
+    25..=30 => 1,
+    _ => 0,
+  }
+}
+
+pub fn track_length(zone: u8) -> usize {
+  match zone {
+    3 => 7692,
+    2 => 7142,
+    1 => 6666,
+    _ => 6250,
+  }
+}
+
+fn decode_nybble(gcr: u8) -> Option<u8> {
+  GCR_ENCODE.iter().position(|&g| g == gcr).map(|n| n as u8)
+}
+
+/**
+ * Encode 4 bytes into 5 GCR bytes.
+ */
+pub fn encode_group(input: &[u8], output: &mut Vec<u8>) {
+  let mut bits: u64 = 0;
+  for i in 0..4 {
+    let byte = input[i];
+    bits = (bits << 10)
+      | ((GCR_ENCODE[(byte >> 4) as usize] as u64) << 5)
+      | (GCR_ENCODE[(byte & 0xf) as usize] as u64);
+  }
+  for i in 0..5 {
+    output.push((bits >> (32 - i * 8)) as u8);
+  }
+}
+
+/**
+ * Decode 5 GCR bytes back into 4 bytes, or None if any of the 5-bit codes is
+ * invalid.
+ */
+pub fn decode_group(input: &[u8]) -> Option<[u8; 4]> {
+  let mut bits: u64 = 0;
+  for i in 0..5 {
+    bits = (bits << 8) | (input[i] as u64);
+  }
+  let mut output = [0; 4];
+  for i in 0..4 {
+    let high = decode_nybble(((bits >> (35 - i * 10)) & 0x1f) as u8)?;
+    let low = decode_nybble(((bits >> (30 - i * 10)) & 0x1f) as u8)?;
+    output[i] = (high << 4) | low;
+  }
+  return Some(output);
+}
+
+fn encode_bytes(input: &[u8], output: &mut Vec<u8>) {
+  for group in input.chunks(4) {
+    encode_group(group, output);
+  }
+}
+
+pub struct GcrDisk {
+  // The raw bytes passing under the head on each half-track. Half-tracks
+  // with no data are unformatted.
+  pub tracks: Vec<Vec<u8>>,
+  pub write_protected: bool,
+  // Set when the drive writes to the disk
+  pub modified: bool,
+}
+
+impl GcrDisk {
+  pub fn from_d64(image: &D64) -> GcrDisk {
+    let mut tracks = vec![Vec::new(); HALF_TRACKS];
+    let id = image.disk_id();
+    for track in 1..(image.get_tracks() + 1) {
+      tracks[(track as usize - 1) * 2] = GcrDisk::encode_track(image, track, id[0], id[1]);
+    }
+    GcrDisk {
+      tracks: tracks,
+      write_protected: false,
+      modified: false,
+    }
+  }
+
+  fn encode_track(image: &D64, track: u8, id1: u8, id2: u8) -> Vec<u8> {
+    let length = track_length(speed_zone(track));
+    let sectors = sectors_per_track(track);
+    let mut data = Vec::with_capacity(length);
+    let sector_size = SYNC_LENGTH * 2 + HEADER_GCR_SIZE + HEADER_GAP + DATA_GCR_SIZE;
+    let gap = (length - sectors as usize * sector_size) / sectors as usize;
+
+    for sector in 0..sectors {
+      let error = image.sector_error(track, sector);
+      let contents = image.raw_sector(track, sector).unwrap();
+      let sync = if error == ERROR_NO_SYNC { GAP_BYTE } else { 0xff };
+
+      let mut header_checksum = sector ^ track ^ id2 ^ id1;
+      if error == ERROR_HEADER_CHECKSUM {
+        header_checksum ^= 0xff;
+      }
+      let header_id = if error == ERROR_HEADER_NOT_FOUND { 0 } else { HEADER_BLOCK_ID };
+      let header = [header_id, header_checksum, sector, track, id2, id1, 0x0f, 0x0f];
+      for _ in 0..SYNC_LENGTH {
+        data.push(sync);
+      }
+      encode_bytes(&header, &mut data);
+      for _ in 0..HEADER_GAP {
+        data.push(GAP_BYTE);
+      }
+
+      let mut block = Vec::with_capacity(260);
+      block.push(if error == ERROR_DATA_NOT_FOUND { 0 } else { DATA_BLOCK_ID });
+      block.extend_from_slice(contents);
+      let mut checksum = contents.iter().fold(0, |acc, &b| acc ^ b);
+      if error == ERROR_DATA_CHECKSUM {
+        checksum ^= 0xff;
+      }
+      block.push(checksum);
+      block.push(0);
+      block.push(0);
+      for _ in 0..SYNC_LENGTH {
+        data.push(sync);
+      }
+      encode_bytes(&block, &mut data);
+      for _ in 0..gap {
+        data.push(GAP_BYTE);
+      }
+    }
+    while data.len() < length {
+      data.push(GAP_BYTE);
+    }
+    return data;
+  }
+
+  /**
+   * A G64 file stores the raw GCR data of each half-track, so it can carry
+   * the non-standard formats used for copy protection.
+   */
+  pub fn from_g64(bytes: &[u8]) -> Option<GcrDisk> {
+    if bytes.len() < 12 || &bytes[0..8] != G64_SIGNATURE {
+      return None;
+    }
+    let count = (bytes[9] as usize).min(HALF_TRACKS);
+    let read_u32 = |offset: usize| -> Option<usize> {
+      if offset + 4 > bytes.len() {
+        return None;
+      }
+      Some(
+        (bytes[offset] as usize)
+          | ((bytes[offset + 1] as usize) << 8)
+          | ((bytes[offset + 2] as usize) << 16)
+          | ((bytes[offset + 3] as usize) << 24)
+      )
+    };
+    let mut tracks = vec![Vec::new(); HALF_TRACKS];
+    for i in 0..count {
+      let offset = read_u32(12 + i * 4)?;
+      if offset == 0 {
+        continue;
+      }
+      if offset + 2 > bytes.len() {
+        return None;
+      }
+      let length = (bytes[offset] as usize) | ((bytes[offset + 1] as usize) << 8);
+      let start = offset + 2;
+      if start + length > bytes.len() {
+        return None;
+      }
+      tracks[i] = bytes[start..(start + length)].to_vec();
+    }
+    Some(GcrDisk {
+      tracks: tracks,
+      write_protected: false,
+      modified: false,
+    })
+  }
+
+  /**
+   * Decode every readable sector on the full tracks and store them in a D64
+   * image, so changes made by the drive can be saved in that format.
+   */
+  pub fn write_to_d64(&self, image: &mut D64) {
+    for track in 1..(image.get_tracks() + 1) {
+      let data = &self.tracks[(track as usize - 1) * 2];
+      if data.len() == 0 {
+        continue;
+      }
+      let len = data.len();
+      let mut pos = 0;
+      let mut pending_sector = None;
+      while pos < len {
+        // Find the end of a sync mark
+        if !(data[pos] == 0xff && data[(pos + len - 1) % len] == 0xff) {
+          pos += 1;
+          continue;
+        }
+        while pos < len * 2 && data[pos % len] == 0xff {
+          pos += 1;
+        }
+        let block_start = pos % len;
+        let read = |count: usize| -> Vec<u8> {
+          (0..count).map(|i| data[(block_start + i) % len]).collect()
+        };
+        let first = match decode_group(&read(5)) {
+          Some(group) => group,
+          None => continue,
+        };
+        if first[0] == HEADER_BLOCK_ID {
+          let header = read(HEADER_GCR_SIZE);
+          pending_sector = decode_group(&header[5..]).map(|_| first[2]).filter(|_| first[3] == track);
+        } else if first[0] == DATA_BLOCK_ID {
+          if let Some(sector) = pending_sector.take() {
+            let gcr = read(DATA_GCR_SIZE);
+            let mut block = Vec::with_capacity(260);
+            let mut valid = true;
+            for group in gcr.chunks(5) {
+              match decode_group(group) {
+                Some(bytes) => block.extend_from_slice(&bytes),
+                None => {
+                  valid = false;
+                  break;
+                },
+              }
+            }
+            if valid {
+              let _ = image.write_sector(track, sector, &block[1..257]);
+            }
+          }
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::gcr::{GcrDisk, encode_group, decode_group, track_length};
+  use c64memmap::d64::D64;
+
+  #[test]
+  fn groups() {
+    let mut out = Vec::new();
+    encode_group(&[0x08, 0x00, 0x01, 0x12], &mut out);
+    assert_eq!(out, vec![0x52, 0x54, 0xa5, 0x2d, 0x72]);
+    assert_eq!(decode_group(&out), Some([0x08, 0x00, 0x01, 0x12]));
+    assert_eq!(decode_group(&[0, 0, 0, 0, 0]), None);
+  }
+
+  #[test]
+  fn round_trip() {
+    let mut image = D64::blank(b"GCR", b"AB");
+    image.save_file(b"FILE", &[1, 8, 0xaa, 0x55], false).unwrap();
+    let disk = GcrDisk::from_d64(&image);
+    assert_eq!(disk.tracks[0].len(), track_length(3));
+    assert_eq!(disk.tracks[1].len(), 0);
+    assert_eq!(disk.tracks[68].len(), track_length(0));
+    // every track starts with a sync mark and a header block
+    assert_eq!(&disk.tracks[34][0..6], &[0xff, 0xff, 0xff, 0xff, 0xff, 0x52]);
+
+    let mut copy = D64::blank(b"", b"");
+    disk.write_to_d64(&mut copy);
+    assert_eq!(copy.as_bytes(), image.as_bytes());
+  }
+}
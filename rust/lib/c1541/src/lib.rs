@@ -0,0 +1,5 @@
+#![feature(box_syntax)]
+
+pub mod drive;
+pub mod gcr;
+pub mod via;
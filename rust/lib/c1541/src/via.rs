@@ -0,0 +1,297 @@
+// MOS 6522 Versatile Interface Adapter. The 1541 has two: one facing the
+// serial bus, one driving the disk mechanism.
+
+pub const INTERRUPT_CA2: u8 = 0x01;
+pub const INTERRUPT_CA1: u8 = 0x02;
+pub const INTERRUPT_SR: u8 = 0x04;
+pub const INTERRUPT_CB2: u8 = 0x08;
+pub const INTERRUPT_CB1: u8 = 0x10;
+pub const INTERRUPT_T2: u8 = 0x20;
+pub const INTERRUPT_T1: u8 = 0x40;
+
+pub struct VIA {
+  port_a: u8,
+  port_b: u8,
+  ddr_a: u8,
+  ddr_b: u8,
+  // Levels driven onto the pins by the outside world
+  pub port_a_in: u8,
+  pub port_b_in: u8,
+
+  t1_counter: u16,
+  t1_latch: u16,
+  t1_armed: bool,
+  t2_counter: u16,
+  t2_latch_low: u8,
+  t2_armed: bool,
+
+  shift: u8,
+  aux_control: u8,
+  peripheral_control: u8,
+  interrupt_flags: u8,
+  interrupt_enabled: u8,
+
+  ca1: bool,
+}
+
+impl VIA {
+  pub fn new() -> VIA {
+    VIA {
+      port_a: 0,
+      port_b: 0,
+      ddr_a: 0,
+      ddr_b: 0,
+      port_a_in: 0xff,
+      port_b_in: 0xff,
+
+      t1_counter: 0xffff,
+      t1_latch: 0xffff,
+      t1_armed: false,
+      t2_counter: 0xffff,
+      t2_latch_low: 0xff,
+      t2_armed: false,
+
+      shift: 0,
+      aux_control: 0,
+      peripheral_control: 0,
+      interrupt_flags: 0,
+      interrupt_enabled: 0,
+
+      ca1: false,
+    }
+  }
+
+  pub fn reset(&mut self) {
+    *self = VIA {
+      port_a_in: self.port_a_in,
+      port_b_in: self.port_b_in,
+      ca1: self.ca1,
+      ..VIA::new()
+    };
+  }
+
+  pub fn get_byte(&mut self, addr: u16) -> u8 {
+    match addr & 0xf {
+      0x0 => {
+        self.interrupt_flags &= !(INTERRUPT_CB1 | INTERRUPT_CB2);
+        (self.port_b & self.ddr_b) | (self.port_b_in & !self.ddr_b)
+      },
+      0x1 => {
+        self.interrupt_flags &= !(INTERRUPT_CA1 | INTERRUPT_CA2);
+        (self.port_a & self.ddr_a) | (self.port_a_in & !self.ddr_a)
+      },
+      0x2 => self.ddr_b,
+      0x3 => self.ddr_a,
+      0x4 => {
+        self.interrupt_flags &= !INTERRUPT_T1;
+        (self.t1_counter & 0xff) as u8
+      },
+      0x5 => (self.t1_counter >> 8) as u8,
+      0x6 => (self.t1_latch & 0xff) as u8,
+      0x7 => (self.t1_latch >> 8) as u8,
+      0x8 => {
+        self.interrupt_flags &= !INTERRUPT_T2;
+        (self.t2_counter & 0xff) as u8
+      },
+      0x9 => (self.t2_counter >> 8) as u8,
+      0xa => self.shift,
+      0xb => self.aux_control,
+      0xc => self.peripheral_control,
+      0xd => {
+        let mut flags = self.interrupt_flags;
+        if self.irq() {
+          flags |= 0x80;
+        }
+        flags
+      },
+      0xe => self.interrupt_enabled | 0x80,
+      // Port A without handshaking
+      _ => (self.port_a & self.ddr_a) | (self.port_a_in & !self.ddr_a),
+    }
+  }
+
+  pub fn set_byte(&mut self, addr: u16, value: u8) {
+    match addr & 0xf {
+      0x0 => {
+        self.interrupt_flags &= !(INTERRUPT_CB1 | INTERRUPT_CB2);
+        self.port_b = value;
+      },
+      0x1 => {
+        self.interrupt_flags &= !(INTERRUPT_CA1 | INTERRUPT_CA2);
+        self.port_a = value;
+      },
+      0x2 => self.ddr_b = value,
+      0x3 => self.ddr_a = value,
+      0x4 | 0x6 => self.t1_latch = (self.t1_latch & 0xff00) | (value as u16),
+      0x5 => {
+        self.t1_latch = (self.t1_latch & 0xff) | ((value as u16) << 8);
+        self.t1_counter = self.t1_latch;
+        self.t1_armed = true;
+        self.interrupt_flags &= !INTERRUPT_T1;
+      },
+      0x7 => {
+        self.t1_latch = (self.t1_latch & 0xff) | ((value as u16) << 8);
+        self.interrupt_flags &= !INTERRUPT_T1;
+      },
+      0x8 => self.t2_latch_low = value,
+      0x9 => {
+        self.t2_counter = ((value as u16) << 8) | (self.t2_latch_low as u16);
+        self.t2_armed = true;
+        self.interrupt_flags &= !INTERRUPT_T2;
+      },
+      0xa => self.shift = value,
+      0xb => self.aux_control = value,
+      0xc => self.peripheral_control = value,
+      0xd => self.interrupt_flags &= !(value & 0x7f),
+      0xe => {
+        if value & 0x80 != 0 {
+          self.interrupt_enabled |= value & 0x7f;
+        } else {
+          self.interrupt_enabled &= !(value & 0x7f);
+        }
+      },
+      _ => self.port_a = value,
+    }
+  }
+
+  /**
+   * The levels on the port pins: driven outputs, and inputs pulled up.
+   */
+  pub fn port_a_output(&self) -> u8 {
+    self.port_a | !self.ddr_a
+  }
+
+  pub fn port_b_output(&self) -> u8 {
+    self.port_b | !self.ddr_b
+  }
+
+  /**
+   * CA2 and CB2 can be used as manual outputs, selected by writing %110 (low)
+   * or %111 (high) to their bits of the peripheral control register. In any
+   * other mode they idle high.
+   */
+  pub fn ca2_output(&self) -> bool {
+    self.peripheral_control & 0x0e != 0x0c
+  }
+
+  pub fn cb2_output(&self) -> bool {
+    self.peripheral_control & 0xe0 != 0xc0
+  }
+
+  /**
+   * Drive the CA1 input, flagging an interrupt on the edge selected by bit 0
+   * of the peripheral control register.
+   */
+  pub fn set_ca1(&mut self, level: bool) {
+    if level == self.ca1 {
+      return;
+    }
+    self.ca1 = level;
+    let positive_edge = self.peripheral_control & 1 != 0;
+    if level == positive_edge {
+      self.interrupt_flags |= INTERRUPT_CA1;
+    }
+  }
+
+  /**
+   * Latch the CA1 interrupt flag directly, for a pulse too short to be seen
+   * as two separate level changes.
+   */
+  pub fn pulse_ca1(&mut self) {
+    self.interrupt_flags |= INTERRUPT_CA1;
+  }
+
+  pub fn irq(&self) -> bool {
+    self.interrupt_flags & self.interrupt_enabled & 0x7f != 0
+  }
+
+  pub fn update_timers(&mut self, cycles: u8) {
+    let cycles = cycles as u16;
+    let (t1, underflow) = self.t1_counter.overflowing_sub(cycles);
+    if underflow {
+      if self.aux_control & 0x40 != 0 {
+        // Free-running: reload from the latch and keep interrupting
+        let period = self.t1_latch as u32 + 2;
+        let over = (cycles - self.t1_counter - 1) as u32 % period;
+        self.t1_counter = (self.t1_latch as u32).saturating_sub(over) as u16;
+        self.interrupt_flags |= INTERRUPT_T1;
+      } else {
+        self.t1_counter = t1;
+        if self.t1_armed {
+          self.t1_armed = false;
+          self.interrupt_flags |= INTERRUPT_T1;
+        }
+      }
+    } else {
+      self.t1_counter = t1;
+    }
+
+    // Timer 2 in pulse counting mode counts PB6 pulses, which nothing in the
+    // drive produces
+    if self.aux_control & 0x20 == 0 {
+      let (t2, underflow) = self.t2_counter.overflowing_sub(cycles);
+      self.t2_counter = t2;
+      if underflow && self.t2_armed {
+        self.t2_armed = false;
+        self.interrupt_flags |= INTERRUPT_T2;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::via::{VIA, INTERRUPT_CA1, INTERRUPT_T1};
+
+  #[test]
+  fn ports() {
+    let mut via = VIA::new();
+    via.port_b_in = 0b1010_0101;
+    via.set_byte(2, 0x0f);
+    via.set_byte(0, 0b0000_1010);
+    assert_eq!(via.get_byte(0), 0b1010_1010);
+    assert_eq!(via.port_b_output(), 0b1111_1010);
+  }
+
+  #[test]
+  fn timer_1() {
+    let mut via = VIA::new();
+    via.set_byte(0xe, 0x80 | INTERRUPT_T1);
+    // one-shot
+    via.set_byte(4, 10);
+    via.set_byte(5, 0);
+    via.update_timers(10);
+    assert!(!via.irq());
+    via.update_timers(1);
+    assert!(via.irq());
+    assert_eq!(via.get_byte(0xd), 0x80 | INTERRUPT_T1);
+    via.get_byte(4);
+    assert!(!via.irq());
+    via.update_timers(200);
+    assert!(!via.irq());
+
+    // free-running
+    via.set_byte(0xb, 0x40);
+    via.set_byte(4, 10);
+    via.set_byte(5, 0);
+    via.update_timers(11);
+    assert!(via.irq());
+    via.set_byte(0xd, INTERRUPT_T1);
+    via.update_timers(11);
+    assert!(via.irq());
+  }
+
+  #[test]
+  fn ca1_edges() {
+    let mut via = VIA::new();
+    via.set_byte(0xe, 0x80 | INTERRUPT_CA1);
+    via.set_ca1(true);
+    assert!(!via.irq());
+    via.set_ca1(false);
+    assert!(via.irq());
+    via.get_byte(1);
+    via.set_byte(0xc, 0x01);
+    via.set_ca1(true);
+    assert!(via.irq());
+  }
+}
@@ -1,160 +1,612 @@
+// The C64's wasm exports. Each takes a handle from create_vm, and abi.json
+// lists them all along with what they return on error. The handle table,
+// error codes and the exports every emulator has come from wasm-abi.
+
+#[macro_use]
+extern crate wasmabi;
+
 pub mod vm;
 
+use std::char;
 use std::mem;
+use wasmabi::Handle;
+use wasmabi::{OK, ERROR_INVALID_DATA, ERROR_INVALID_ARGUMENT};
 use vm::VM;
+use vm::Model;
+use vm::KeymapMode;
+use vm::{FRAME_WIDTH, FRAME_HEIGHT};
 
-#[no_mangle]
-pub fn create_vm() -> *mut VM {
-  let vm = VM::new();
-  let b = Box::new(vm);
-  return Box::into_raw(b);
+// The largest file JS can copy in, which is enough for a snapshot with a full
+// 16MB REU
+const MAX_FILE_SIZE: u32 = 32 * 1024 * 1024;
+
+wasm_exports!(VM, "abi.json", 1, MAX_FILE_SIZE);
+
+fn status(success: bool) -> i32 {
+  if success { OK } else { ERROR_INVALID_DATA }
 }
 
+fn flag(value: bool) -> i32 {
+  if value { 1 } else { 0 }
+}
+
+/**
+ * Create a machine, choosing the model by number: 0 for PAL, or 1 for NTSC.
+ * Returns its handle, or 0 for any other model.
+ */
 #[no_mangle]
-pub fn reset(raw: *mut VM) {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    vm.reset();
-    mem::forget(vm);
+pub fn create_vm(model: u32) -> Handle {
+  match Model::from_index(model) {
+    Some(model) => VMS.with(|vms| vms.borrow_mut().insert(VM::new(model))),
+    None => 0,
   }
 }
 
+/**
+ * Press the reset button, or with `hard`, switch the machine off and on,
+ * which also clears RAM to its power-on pattern.
+ */
 #[no_mangle]
-pub fn get_char_pointer(raw: *mut VM) -> *mut u8 {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    let ptr = vm.mem.ram_rom.char_ptr();
-    mem::forget(vm);
-    return ptr;
-  }
+pub fn reset(handle: Handle, hard: u32) -> i32 {
+  call(handle, |vm| { vm.reset(hard != 0); OK })
 }
 
 #[no_mangle]
-pub fn get_kernal_pointer(raw: *mut VM) -> *mut u8 {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    let ptr = vm.mem.ram_rom.kernal_ptr();
-    mem::forget(vm);
-    return ptr;
-  }
+pub fn get_char_pointer(handle: Handle) -> *mut u8 {
+  pointer(handle, |vm| vm.mem.ram_rom.char_ptr()) as *mut u8
 }
 
 #[no_mangle]
-pub fn get_basic_pointer(raw: *mut VM) -> *mut u8 {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    let ptr = vm.mem.ram_rom.basic_ptr();
-    mem::forget(vm);
-    return ptr;
-  }
+pub fn get_kernal_pointer(handle: Handle) -> *mut u8 {
+  pointer(handle, |vm| vm.mem.ram_rom.kernal_ptr()) as *mut u8
 }
 
 #[no_mangle]
-pub fn get_ram_pointer(raw: *mut VM) -> *mut u8 {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    let ptr = vm.mem.ram_rom.ram_ptr();
-    mem::forget(vm);
-    return ptr;
-  }
+pub fn get_basic_pointer(handle: Handle) -> *mut u8 {
+  pointer(handle, |vm| vm.mem.ram_rom.basic_ptr()) as *mut u8
 }
 
 #[no_mangle]
-pub fn get_color_pointer(raw: *mut VM) -> *mut u8 {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    let ptr = vm.mem.ram_rom.color_ptr();
-    mem::forget(vm);
-    return ptr;
-  }
+pub fn get_ram_pointer(handle: Handle) -> *mut u8 {
+  pointer(handle, |vm| vm.mem.ram_rom.ram_ptr()) as *mut u8
 }
 
 #[no_mangle]
-pub fn step_vm(raw: *mut VM) -> u8 {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    let time = vm.step();
-    mem::forget(vm);
-    return time;
-  }
+pub fn get_color_pointer(handle: Handle) -> *mut u8 {
+  pointer(handle, |vm| vm.mem.ram_rom.color_ptr()) as *mut u8
+}
+
+// Take the file JS copied into the file buffer
+fn take_file(vm: &mut VM) -> Vec<u8> {
+  return mem::replace(&mut vm.file_buffer, Vec::new());
+}
+
+// Leave output in the file buffer for get_output_pointer, returning its length
+fn leave_output(vm: &mut VM, output: Vec<u8>) -> i32 {
+  vm.file_buffer = output;
+  return vm.file_buffer.len() as i32;
 }
 
+/**
+ * Load the PRG file in the file buffer, returning its load address.
+ */
 #[no_mangle]
-pub fn run_vm(raw: *mut VM, ms: u32) {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    vm.run_ms(ms);
-    mem::forget(vm);
-  }
+pub fn load_prg(handle: Handle, autostart: u32) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    match vm.load_prg(data, autostart != 0) {
+      Some(address) => address as i32,
+      None => ERROR_INVALID_DATA,
+    }
+  })
 }
 
+/**
+ * Insert the D64 image in the file buffer as drive 8.
+ */
 #[no_mangle]
-pub fn get_register(raw: *mut VM, register: u32) -> u16 {
-  unsafe {
-    let vm = Box::from_raw(raw);
-    let value = match register {
-      0 => vm.cpu.acc as u16,
-      1 => vm.cpu.x as u16,
-      2 => vm.cpu.y as u16,
-      3 => vm.cpu.status as u16,
-      4 => vm.cpu.stack as u16,
-      5 => vm.cpu.pc,
-      _ => 0,
-    };
-    mem::forget(vm);
-    return value;
-  }
+pub fn attach_disk(handle: Handle, autostart: u32) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    status(vm.attach_disk(data, autostart != 0))
+  })
 }
 
+/**
+ * Insert the T64 or TAP image in the file buffer.
+ */
 #[no_mangle]
-pub fn keydown(raw: *mut VM, key: u8) {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    vm.mem.cia.keydown(key);
-    mem::forget(vm);
-  }
+pub fn attach_tape(handle: Handle, autostart: u32) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    status(vm.attach_tape(data, autostart != 0))
+  })
 }
 
 #[no_mangle]
-pub fn keyup(raw: *mut VM, key: u8) {
-  unsafe {
-    let mut vm = Box::from_raw(raw);
-    vm.mem.cia.keyup(key);
-    mem::forget(vm);
-  }
+pub fn tape_play(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.mem.datasette.play(); OK })
 }
 
 #[no_mangle]
-pub fn get_border_color(raw: *mut VM) -> u8 {
-  unsafe {
-    let vm = Box::from_raw(raw);
-    let value = vm.mem.vic.border_color;
-    mem::forget(vm);
-    value
-  }
+pub fn tape_stop(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.mem.datasette.stop(); OK })
+}
+
+#[no_mangle]
+pub fn tape_rewind(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.mem.datasette.rewind(); OK })
+}
+
+#[no_mangle]
+pub fn get_tape_motor(handle: Handle) -> i32 {
+  call(handle, |vm| flag(vm.mem.tape_motor_on()))
+}
+
+/**
+ * Type the UTF-8 text in the file buffer into the machine.
+ */
+#[no_mangle]
+pub fn type_text(handle: Handle, press_keys: u32) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    vm.type_text(&String::from_utf8_lossy(&data), press_keys != 0);
+    OK
+  })
+}
+
+/**
+ * Tokenize the BASIC source text in the file buffer and load it. Fails with
+ * ERROR_INVALID_DATA if the source has errors.
+ */
+#[no_mangle]
+pub fn enter_basic(handle: Handle, autostart: u32) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    status(vm.enter_basic(&String::from_utf8_lossy(&data), autostart != 0))
+  })
+}
+
+/**
+ * List the BASIC program in memory into the file buffer as UTF-8 text,
+ * returning its length. Read it from get_output_pointer.
+ */
+#[no_mangle]
+pub fn list_basic(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let listing = vm.list_basic().into_bytes();
+    leave_output(vm, listing)
+  })
+}
+
+/**
+ * Save the machine as a VICE snapshot into the file buffer, returning its
+ * length. Read it from get_output_pointer.
+ */
+#[no_mangle]
+pub fn save_snapshot(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let snapshot = vm.save_snapshot();
+    leave_output(vm, snapshot)
+  })
+}
+
+/**
+ * Load the VICE snapshot in the file buffer.
+ */
+#[no_mangle]
+pub fn load_snapshot(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    status(vm.load_snapshot(&data))
+  })
 }
 
+/**
+ * Where the output of list_basic, monitor_command and save_snapshot is left
+ */
 #[no_mangle]
-pub fn get_bg_color(raw: *mut VM, index: u8) -> u8 {
-  unsafe {
-    let vm = Box::from_raw(raw);
-    let value = match index {
+pub fn get_output_pointer(handle: Handle) -> *const u8 {
+  pointer(handle, |vm| vm.file_buffer.as_ptr())
+}
+
+#[no_mangle]
+pub fn enter_monitor(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.enter_monitor(); OK })
+}
+
+#[no_mangle]
+pub fn is_monitor_active(handle: Handle) -> i32 {
+  call(handle, |vm| flag(vm.monitor.active))
+}
+
+/**
+ * Run the monitor command in the file buffer, stopping the machine if it is
+ * running. The output replaces the command in the file buffer, and its
+ * length is returned. Read it from get_output_pointer.
+ */
+#[no_mangle]
+pub fn monitor_command(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    let output = vm.monitor_command(&String::from_utf8_lossy(&data)).into_bytes();
+    leave_output(vm, output)
+  })
+}
+
+/**
+ * Load the labels in the file buffer, from a VICE label file, a ca65 debug
+ * file, or an FCEUX name list, for the monitor to use. Returns how many were
+ * loaded.
+ */
+#[no_mangle]
+pub fn load_symbols(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    match vm.monitor.symbols.load(&String::from_utf8_lossy(&data)) {
+      Ok(count) => count as i32,
+      Err(_) => ERROR_INVALID_DATA,
+    }
+  })
+}
+
+/**
+ * Plug in the CRT cartridge in the file buffer, and reset. Fails with
+ * ERROR_INVALID_DATA if the image is invalid or unsupported.
+ */
+#[no_mangle]
+pub fn attach_cartridge(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    status(vm.attach_cartridge(&data))
+  })
+}
+
+#[no_mangle]
+pub fn detach_cartridge(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.detach_cartridge(); OK })
+}
+
+/**
+ * Plug in a RAM Expansion Unit of `size_kb` kilobytes, a power of two from
+ * 128 to 16384, and reset.
+ */
+#[no_mangle]
+pub fn attach_reu(handle: Handle, size_kb: u32) -> i32 {
+  call(handle, |vm| {
+    if vm.attach_reu(size_kb) { OK } else { ERROR_INVALID_ARGUMENT }
+  })
+}
+
+#[no_mangle]
+pub fn detach_reu(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.detach_reu(); OK })
+}
+
+#[no_mangle]
+pub fn freeze_cartridge(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.freeze_cartridge(); OK })
+}
+
+/**
+ * Connect a true 1541 drive, using the 16K DOS ROM in the file buffer.
+ */
+#[no_mangle]
+pub fn enable_true_drive(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let rom = take_file(vm);
+    status(vm.enable_true_drive(&rom))
+  })
+}
+
+#[no_mangle]
+pub fn get_drive_led(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    flag(match vm.drive {
+      Some(ref drive) => drive.led_on(),
+      None => false,
+    })
+  })
+}
+
+/**
+ * Returns 1 if a SAVE has changed the attached disk since it was inserted
+ * or last fetched with get_disk_pointer.
+ */
+#[no_mangle]
+pub fn is_disk_modified(handle: Handle) -> i32 {
+  call(handle, |vm| flag(vm.disk.modified))
+}
+
+/**
+ * The attached disk image, or null if there isn't one
+ */
+#[no_mangle]
+pub fn get_disk_pointer(handle: Handle) -> *const u8 {
+  pointer(handle, |vm| {
+    vm.disk.modified = false;
+    match vm.disk.image {
+      Some(ref image) => image.as_bytes().as_ptr(),
+      None => 0 as *const u8,
+    }
+  })
+}
+
+#[no_mangle]
+pub fn get_disk_length(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    match vm.disk.image {
+      Some(ref image) => image.as_bytes().len() as i32,
+      None => 0,
+    }
+  })
+}
+
+#[no_mangle]
+pub fn get_screen_pointer(handle: Handle) -> *mut u8 {
+  pointer(handle, |vm| vm.mem.screen_ptr()) as *mut u8
+}
+
+#[no_mangle]
+pub fn get_charset_pointer(handle: Handle) -> *mut u8 {
+  pointer(handle, |vm| vm.mem.char_ptr()) as *mut u8
+}
+
+#[no_mangle]
+pub fn get_bitmap_pointer(handle: Handle) -> *mut u8 {
+  pointer(handle, |vm| vm.mem.bitmap_ptr()) as *mut u8
+}
+
+/**
+ * Run the machine until the VIC-II completes a frame. Hosts that draw the
+ * frame themselves can call this once per display refresh instead of
+ * run_vm.
+ */
+#[no_mangle]
+pub fn run_frame(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.run_frame(); OK })
+}
+
+/**
+ * How many frames the VIC-II has completed, which wraps around. A host can
+ * compare it between calls to tell whether there is a new frame to draw.
+ */
+#[no_mangle]
+pub fn get_frame_count(handle: Handle) -> u32 {
+  with_vm(handle, |vm| vm.mem.frame_count()).unwrap_or(0)
+}
+
+/**
+ * The last complete frame, with one byte per pixel giving its color index
+ * from 0 to 15. It includes the visible part of the border.
+ */
+#[no_mangle]
+pub fn get_frame_pointer(handle: Handle) -> *const u8 {
+  pointer(handle, |vm| vm.mem.frame().as_ptr())
+}
+
+/**
+ * Convert the last complete frame to RGBA, 4 bytes per pixel, and return
+ * where it is. It stays valid until the next call.
+ */
+#[no_mangle]
+pub fn get_rgba_frame_pointer(handle: Handle) -> *const u8 {
+  pointer(handle, |vm| vm.render_rgba().as_ptr())
+}
+
+#[no_mangle]
+pub fn get_frame_width() -> u32 {
+  FRAME_WIDTH as u32
+}
+
+#[no_mangle]
+pub fn get_frame_height() -> u32 {
+  FRAME_HEIGHT as u32
+}
+
+/**
+ * Run a single instruction, returning how many cycles it took.
+ */
+#[no_mangle]
+pub fn step_vm(handle: Handle) -> i32 {
+  call(handle, |vm| vm.step() as i32)
+}
+
+#[no_mangle]
+pub fn run_vm(handle: Handle, ms: u32) -> i32 {
+  call(handle, |vm| { vm.run_for_ms(ms); OK })
+}
+
+#[no_mangle]
+pub fn set_audio_sample_rate(handle: Handle, rate: u32) -> i32 {
+  call(handle, |vm| {
+    if rate == 0 {
+      return ERROR_INVALID_ARGUMENT;
+    }
+    vm.set_audio_sample_rate(rate);
+    OK
+  })
+}
+
+#[no_mangle]
+pub fn get_audio_buffer_pointer(handle: Handle) -> *const f32 {
+  pointer(handle, |vm| vm.mem.sid.samples_ptr())
+}
+
+#[no_mangle]
+pub fn get_audio_buffer_length(handle: Handle) -> i32 {
+  call(handle, |vm| vm.mem.sid.samples_len() as i32)
+}
+
+#[no_mangle]
+pub fn clear_audio_buffer(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.mem.sid.clear_samples(); OK })
+}
+
+/**
+ * Read a CPU register: 0 for A, 1 for X, 2 for Y, 3 for the status flags, 4
+ * for the stack pointer, or 5 for the program counter.
+ */
+#[no_mangle]
+pub fn get_register(handle: Handle, register: u32) -> i32 {
+  call(handle, |vm| {
+    match register {
+      0 => vm.cpu.acc as i32,
+      1 => vm.cpu.x as i32,
+      2 => vm.cpu.y as i32,
+      3 => vm.cpu.status as i32,
+      4 => vm.cpu.stack as i32,
+      5 => vm.cpu.pc as i32,
+      _ => ERROR_INVALID_ARGUMENT,
+    }
+  })
+}
+
+/**
+ * Press a key by its keyboard matrix index, 0 to 63, or 64 for RESTORE.
+ */
+#[no_mangle]
+pub fn keydown(handle: Handle, key: u32) -> i32 {
+  call(handle, |vm| {
+    if key > 64 {
+      return ERROR_INVALID_ARGUMENT;
+    }
+    vm.keydown(key as u8);
+    OK
+  })
+}
+
+#[no_mangle]
+pub fn keyup(handle: Handle, key: u32) -> i32 {
+  call(handle, |vm| {
+    if key > 64 {
+      return ERROR_INVALID_ARGUMENT;
+    }
+    vm.keyup(key as u8);
+    OK
+  })
+}
+
+/**
+ * Push joystick directions on control port 0 or 1. Bits 0 to 3 are up, down,
+ * left and right, and bit 4 is fire.
+ */
+#[no_mangle]
+pub fn joystick_down(handle: Handle, port: u32, bits: u32) -> i32 {
+  call(handle, |vm| {
+    if port > 1 {
+      return ERROR_INVALID_ARGUMENT;
+    }
+    vm.joystick_down(port as usize, bits as u8);
+    OK
+  })
+}
+
+#[no_mangle]
+pub fn joystick_up(handle: Handle, port: u32, bits: u32) -> i32 {
+  call(handle, |vm| {
+    if port > 1 {
+      return ERROR_INVALID_ARGUMENT;
+    }
+    vm.joystick_up(port as usize, bits as u8);
+    OK
+  })
+}
+
+/**
+ * Press the host key named in the file buffer, as in KeyboardEvent.code,
+ * through the keymap. `text` is the Unicode character the key typed, or 0.
+ */
+#[no_mangle]
+pub fn host_key_down(handle: Handle, text: u32) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    let code = String::from_utf8_lossy(&data);
+    let text = if text == 0 { None } else { char::from_u32(text) };
+    vm.host_key_down(&code, text);
+    OK
+  })
+}
+
+#[no_mangle]
+pub fn host_key_up(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    vm.host_key_up(&String::from_utf8_lossy(&data));
+    OK
+  })
+}
+
+#[no_mangle]
+pub fn release_host_keys(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.release_host_keys(); OK })
+}
+
+/**
+ * Load the keymap file in the file buffer over the current bindings. Returns
+ * how many bindings it had.
+ */
+#[no_mangle]
+pub fn load_keymap(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let data = take_file(vm);
+    match vm.keymap.load(&String::from_utf8_lossy(&data)) {
+      Ok(count) => count as i32,
+      Err(_) => ERROR_INVALID_DATA,
+    }
+  })
+}
+
+#[no_mangle]
+pub fn set_keymap_mode(handle: Handle, symbolic: u32) -> i32 {
+  call(handle, |vm| {
+    vm.keymap.mode = if symbolic != 0 { KeymapMode::Symbolic } else { KeymapMode::Positional };
+    OK
+  })
+}
+
+#[no_mangle]
+pub fn set_paddle(handle: Handle, port: u32, paddle: u32, value: u32) -> i32 {
+  call(handle, |vm| {
+    if port > 1 || paddle > 1 || value > 255 {
+      return ERROR_INVALID_ARGUMENT;
+    }
+    vm.mem.set_paddle(port as usize, paddle as usize, value as u8);
+    OK
+  })
+}
+
+#[no_mangle]
+pub fn set_mouse_position(handle: Handle, port: u32, x: u32, y: u32) -> i32 {
+  call(handle, |vm| {
+    if port > 1 {
+      return ERROR_INVALID_ARGUMENT;
+    }
+    vm.mem.set_mouse_position(port as usize, x as u16, y as u16);
+    OK
+  })
+}
+
+#[no_mangle]
+pub fn get_border_color(handle: Handle) -> i32 {
+  call(handle, |vm| vm.mem.vic.border_color as i32)
+}
+
+#[no_mangle]
+pub fn get_bg_color(handle: Handle, index: u32) -> i32 {
+  call(handle, |vm| {
+    let color = match index {
+      0 => vm.mem.vic.background_color,
       1 => vm.mem.vic.background_color_e1,
       2 => vm.mem.vic.background_color_e2,
       3 => vm.mem.vic.background_color_e3,
-      _ => vm.mem.vic.background_color,
+      _ => return ERROR_INVALID_ARGUMENT,
     };
-    mem::forget(vm);
-    value
-  }
+    color as i32
+  })
 }
 
 #[no_mangle]
-pub fn get_graphics_mode(raw: *mut VM) -> u8 {
-  unsafe {
-    let vm = Box::from_raw(raw);
-    let value = vm.mem.vic.get_graphics_mode_bits();
-    mem::forget(vm);
-    value
-  }
+pub fn get_graphics_mode(handle: Handle) -> i32 {
+  call(handle, |vm| vm.mem.vic.get_graphics_mode_bits() as i32)
 }
\ No newline at end of file
@@ -1,78 +1,42 @@
-extern crate mos6510;
-extern crate c64memmap;
+// The machine itself lives in the c64vm crate, where the native front-end
+// shares it. The wasm build adds the buffer that files and text are copied
+// through to and from JS, and otherwise hands everything to the machine.
 
-use self::mos6510::cpu::CPU;
-use self::c64memmap::memmap::MemMap;
+extern crate c64vm;
 
-const CYCLES_PER_MS: u32 = 1023;
+use std::ops::{Deref, DerefMut};
+use self::c64vm::vm::VM as Machine;
+pub use self::c64vm::vm::{Model, KeymapMode, FRAME_WIDTH, FRAME_HEIGHT};
 
 pub struct VM {
-  pub cpu: CPU,
-  pub mem: MemMap,
+  pub machine: Machine,
+  // Files are copied here from JS before being handed to the VM
+  pub file_buffer: Vec<u8>,
 }
 
 impl VM {
-pub fn new() -> VM {
-  VM {
-    cpu: CPU::new(),
-    mem: MemMap::new(),
+  /**
+   * The ROMs start out empty. JS copies them in through the ROM pointers,
+   * then does a hard reset.
+   */
+  pub fn new(model: Model) -> VM {
+    return VM {
+      machine: Machine::new(model),
+      file_buffer: Vec::new(),
+    };
   }
 }
 
-pub fn step(&mut self) -> u8 {
-  return self.cpu.step(&mut self.mem);
-}
+impl Deref for VM {
+  type Target = Machine;
 
-pub fn run_ms(&mut self, ms: u32) {
-  let cycles = CYCLES_PER_MS * ms;
-  let mut ran = 0;
-  while ran < cycles {
-    let step_time = self.step();
-    if self.mem.cia.update_timers(step_time) {
-      // cia interrupt fired
-      self.cpu.interrupt_request(&mut self.mem);
-    }
-    ran += step_time as u32;
+  fn deref(&self) -> &Machine {
+    &self.machine
   }
 }
 
-pub fn reset(&mut self) {
-  self.cpu.reset(&mut self.mem);
-}
-}
-
-#[cfg(test)]
-mod tests {
-  use vm::VM;
-  use vm::mos6510::memory::Memory;
-
-  #[test]
-  fn basic_ops() {
-    let mut vm = VM::new();
-    vm.mem.set_basic_rom(vec![
-      0xa9, 0x22, // LDA #$22
-      0x69, 0x11, // ADC #$11
-    ], 0);
-    vm.cpu.pc = 0xa000;
-    vm.cpu.step(&mut vm.mem);
-    assert_eq!(vm.cpu.acc, 0x22);
-    vm.cpu.step(&mut vm.mem);
-    assert_eq!(vm.cpu.acc, 0x33);
-  }
-
-  #[test]
-  fn memory_ops() {
-    let mut vm = VM::new();
-    vm.mem.set_basic_rom(vec![
-      0xa9, 0x40, // LDA #$40
-      0x8d, 0x05, 0x20, // STA #$2005
-      0xac, 0x05, 0x20, // LDY #$2005
-    ], 0);
-    vm.cpu.pc = 0xa000;
-    vm.cpu.step(&mut vm.mem);
-    vm.cpu.step(&mut vm.mem);
-    assert_eq!(vm.mem.get_byte(0x2005), 0x40);
-    vm.cpu.step(&mut vm.mem);
-    assert_eq!(vm.cpu.y, 0x40);
+impl DerefMut for VM {
+  fn deref_mut(&mut self) -> &mut Machine {
+    &mut self.machine
   }
 }
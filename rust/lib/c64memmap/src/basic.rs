@@ -0,0 +1,342 @@
+// BASIC V2 programs as text.
+//
+// In memory, a program is a chain of lines. Each starts with a pointer to the
+// next line and the line number, followed by the text with keywords replaced
+// by single-byte tokens, and a zero byte. A null pointer ends the chain.
+// Characters that can't be written in plain text, like colors and cursor
+// movement in strings, are written as {name} or {$xx} escapes.
+
+use std::collections::BTreeMap;
+use memmap::MemMap;
+use prg;
+use typing::ascii_to_petscii;
+
+// Keywords for tokens $80-$CB, in the order the BASIC ROM searches them
+const KEYWORDS: [&str; 76] = [
+  "END", "FOR", "NEXT", "DATA", "INPUT#", "INPUT", "DIM", "READ",
+  "LET", "GOTO", "RUN", "IF", "RESTORE", "GOSUB", "RETURN", "REM",
+  "STOP", "ON", "WAIT", "LOAD", "SAVE", "VERIFY", "DEF", "POKE",
+  "PRINT#", "PRINT", "CONT", "LIST", "CLR", "CMD", "SYS", "OPEN",
+  "CLOSE", "GET", "NEW", "TAB(", "TO", "FN", "SPC(", "THEN",
+  "NOT", "STEP", "+", "-", "*", "/", "↑", "AND",
+  "OR", ">", "=", "<", "SGN", "INT", "ABS", "USR",
+  "FRE", "POS", "SQR", "RND", "LOG", "EXP", "COS", "SIN",
+  "TAN", "ATN", "PEEK", "LEN", "STR$", "VAL", "ASC", "CHR$",
+  "LEFT$", "RIGHT$", "MID$", "GO",
+];
+
+const FIRST_TOKEN: u8 = 0x80;
+const TOKEN_DATA: u8 = 0x83;
+const TOKEN_REM: u8 = 0x8f;
+const TOKEN_PRINT: u8 = 0x99;
+const TOKEN_PI: u8 = 0xff;
+
+// Names of the control codes, as used by petcat and most listings
+const ESCAPES: [(u8, &str); 34] = [
+  (0x05, "wht"), (0x11, "down"), (0x12, "rvon"), (0x13, "home"),
+  (0x14, "del"), (0x1c, "red"), (0x1d, "rght"), (0x1e, "grn"),
+  (0x1f, "blu"), (0x81, "orng"), (0x85, "f1"), (0x86, "f3"),
+  (0x87, "f5"), (0x88, "f7"), (0x89, "f2"), (0x8a, "f4"),
+  (0x8b, "f6"), (0x8c, "f8"), (0x90, "blk"), (0x91, "up"),
+  (0x92, "rvof"), (0x93, "clr"), (0x94, "inst"), (0x95, "brn"),
+  (0x96, "lred"), (0x97, "gry1"), (0x98, "gry2"), (0x99, "lgrn"),
+  (0x9a, "lblu"), (0x9b, "gry3"), (0x9c, "pur"), (0x9d, "left"),
+  (0x9e, "yel"), (0x9f, "cyn"),
+];
+
+const MAX_LINE_NUMBER: u32 = 63999;
+// BASIC can't edit lines longer than this
+const MAX_LINE_LENGTH: usize = 250;
+// Programs have to fit below the BASIC ROM
+const BASIC_END: usize = 0xa000;
+
+/**
+ * Errors carry the number of the source line they were found on, counting
+ * from 1.
+ */
+#[derive(Debug, PartialEq)]
+pub enum BasicError {
+  MissingLineNumber(usize),
+  LineNumberTooLarge(usize),
+  LineTooLong(usize),
+  UnknownEscape(usize),
+  InvalidCharacter(usize),
+  ProgramTooLarge,
+}
+
+fn petscii_to_text(code: u8, out: &mut String) {
+  match code {
+    0x20..=0x5b | 0x5d => out.push(code as char),
+    0x5c => out.push('£'),
+    0x5e => out.push('↑'),
+    0x5f => out.push('←'),
+    _ => {
+      match ESCAPES.iter().find(|&&(c, _)| c == code) {
+        Some(&(_, name)) => out.push_str(&format!("{{{}}}", name)),
+        None => out.push_str(&format!("{{${:02x}}}", code)),
+      }
+    },
+  }
+}
+
+/**
+ * Parse the inside of a {} escape, either a control code name or a hex code.
+ */
+fn parse_escape(name: &str) -> Option<u8> {
+  let name = name.trim().to_lowercase();
+  if name.starts_with('$') {
+    return u8::from_str_radix(&name[1..], 16).ok();
+  }
+  ESCAPES.iter().find(|&&(_, n)| n == name).map(|&(c, _)| c)
+}
+
+/**
+ * Convert a line of text to PETSCII. Each code is paired with a flag that is
+ * set for escapes, which are never part of a keyword.
+ */
+fn text_to_petscii(text: &str, line: usize) -> Result<Vec<(u8, bool)>, BasicError> {
+  let mut codes = Vec::with_capacity(text.len());
+  let mut chars = text.chars();
+  while let Some(c) = chars.next() {
+    if c == '{' {
+      let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+      match parse_escape(&name) {
+        Some(code) => codes.push((code, true)),
+        None => return Err(BasicError::UnknownEscape(line)),
+      }
+    } else if c == 'π' {
+      codes.push((TOKEN_PI, true));
+    } else {
+      match ascii_to_petscii(c) {
+        Some(code) => codes.push((code, false)),
+        None => return Err(BasicError::InvalidCharacter(line)),
+      }
+    }
+  }
+  return Ok(codes);
+}
+
+/**
+ * Find the keyword at the start of some text, returning its token and length.
+ */
+fn match_keyword(codes: &[(u8, bool)]) -> Option<(u8, usize)> {
+  for (index, keyword) in KEYWORDS.iter().enumerate() {
+    let length = keyword.chars().count();
+    if length > codes.len() {
+      continue;
+    }
+    let matches = keyword.chars().zip(codes.iter()).all(|(k, &(code, escaped))| {
+      !escaped && ascii_to_petscii(k) == Some(code)
+    });
+    if matches {
+      return Some((FIRST_TOKEN + index as u8, length));
+    }
+  }
+  return None;
+}
+
+/**
+ * Replace keywords with tokens, the way BASIC does when a line is entered.
+ * Strings, comments and DATA statements are left alone.
+ */
+fn crunch(codes: &[(u8, bool)]) -> Vec<u8> {
+  let mut out = Vec::with_capacity(codes.len());
+  let mut quoted = false;
+  let mut data = false;
+  let mut rem = false;
+  let mut i = 0;
+  while i < codes.len() {
+    let (code, escaped) = codes[i];
+    i += 1;
+    if rem || escaped {
+      out.push(code);
+      continue;
+    }
+    if code == b'"' {
+      quoted = !quoted;
+    }
+    if quoted || code == b'"' {
+      out.push(code);
+      continue;
+    }
+    if data {
+      data = code != b':';
+      out.push(code);
+      continue;
+    }
+    if code == b'?' {
+      out.push(TOKEN_PRINT);
+      continue;
+    }
+    match match_keyword(&codes[(i - 1)..]) {
+      Some((token, length)) => {
+        out.push(token);
+        i += length - 1;
+        rem = token == TOKEN_REM;
+        data = token == TOKEN_DATA;
+      },
+      None => out.push(code),
+    }
+  }
+  return out;
+}
+
+/**
+ * Tokenize a program written as text, one numbered line per line of source,
+ * and link it to run from `start`. As when typing a program in, lines are
+ * sorted by number, a repeated number replaces the earlier line, and a number
+ * on its own deletes the line.
+ */
+pub fn tokenize(source: &str, start: u16) -> Result<Vec<u8>, BasicError> {
+  let mut lines = BTreeMap::new();
+  for (index, text) in source.lines().enumerate() {
+    let line = index + 1;
+    let text = text.trim_start();
+    if text.trim().len() == 0 {
+      continue;
+    }
+    let digits = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    if digits == 0 {
+      return Err(BasicError::MissingLineNumber(line));
+    }
+    let number = match text[..digits].parse::<u32>() {
+      Ok(number) if number <= MAX_LINE_NUMBER => number as u16,
+      _ => return Err(BasicError::LineNumberTooLarge(line)),
+    };
+    let body = text[digits..].trim_start().trim_end_matches('\r');
+    if body.len() == 0 {
+      lines.remove(&number);
+      continue;
+    }
+    let tokens = crunch(&text_to_petscii(body, line)?);
+    if tokens.len() > MAX_LINE_LENGTH {
+      return Err(BasicError::LineTooLong(line));
+    }
+    lines.insert(number, tokens);
+  }
+
+  let mut program = Vec::new();
+  for (number, tokens) in lines.iter() {
+    let next = start as usize + program.len() + tokens.len() + 5;
+    if next >= BASIC_END {
+      return Err(BasicError::ProgramTooLarge);
+    }
+    program.push((next & 0xff) as u8);
+    program.push((next >> 8) as u8);
+    program.push((number & 0xff) as u8);
+    program.push((number >> 8) as u8);
+    program.extend_from_slice(tokens);
+    program.push(0);
+  }
+  program.push(0);
+  program.push(0);
+  return Ok(program);
+}
+
+/**
+ * List a tokenized program, where `program` holds the memory from `start`
+ * onwards. The line pointers are followed until the end of the chain, the end
+ * of the data, or a pointer that doesn't move forward.
+ */
+pub fn detokenize(program: &[u8], start: u16) -> String {
+  let mut listing = String::new();
+  let mut addr = start as usize;
+  loop {
+    let offset = addr - start as usize;
+    if offset + 4 > program.len() {
+      break;
+    }
+    let link = (program[offset] as usize) | ((program[offset + 1] as usize) << 8);
+    if link == 0 {
+      break;
+    }
+    let number = (program[offset + 2] as u16) | ((program[offset + 3] as u16) << 8);
+    listing.push_str(&format!("{} ", number));
+    let mut quoted = false;
+    let mut data = false;
+    let mut rem = false;
+    for &code in program[(offset + 4)..].iter().take_while(|&&c| c != 0) {
+      if code == b'"' {
+        quoted = !quoted;
+      }
+      if quoted || rem || code < FIRST_TOKEN {
+        if data && !quoted && code == b':' {
+          data = false;
+        }
+        petscii_to_text(code, &mut listing);
+      } else if data {
+        petscii_to_text(code, &mut listing);
+      } else if code == TOKEN_PI {
+        listing.push('π');
+      } else if let Some(keyword) = KEYWORDS.get((code - FIRST_TOKEN) as usize) {
+        listing.push_str(keyword);
+        rem = code == TOKEN_REM;
+        data = code == TOKEN_DATA;
+      } else {
+        petscii_to_text(code, &mut listing);
+      }
+    }
+    listing.push('\n');
+    if link <= addr {
+      break;
+    }
+    addr = link;
+  }
+  return listing;
+}
+
+/**
+ * List the program in BASIC memory.
+ */
+pub fn list(mem: &MemMap) -> String {
+  let start = prg::BASIC_START as usize;
+  detokenize(&mem.ram_rom.ram[start..BASIC_END], prg::BASIC_START)
+}
+
+/**
+ * Tokenize a program into a PRG file that loads at the start of BASIC memory.
+ */
+pub fn to_prg(source: &str) -> Result<Vec<u8>, BasicError> {
+  let program = tokenize(source, prg::BASIC_START)?;
+  let mut data = vec![(prg::BASIC_START & 0xff) as u8, (prg::BASIC_START >> 8) as u8];
+  data.extend_from_slice(&program);
+  return Ok(data);
+}
+
+#[cfg(test)]
+mod tests {
+  use memmap::MemMap;
+  use prg;
+  use basic::{BasicError, tokenize, detokenize, to_prg, list};
+
+  #[test]
+  fn tokenize_lines() {
+    let program = tokenize("20 goto 10\n10 ?\"HI\";:rem print\n", 0x0801).unwrap();
+    assert_eq!(program, vec![
+      0x14, 0x08, 0x0a, 0x00, 0x99, 0x22, 0x48, 0x49, 0x22, 0x3b, 0x3a, 0x8f, 0x20, 0x50, 0x52, 0x49, 0x4e, 0x54, 0x00,
+      0x1d, 0x08, 0x14, 0x00, 0x89, 0x20, 0x31, 0x30, 0x00,
+      0x00, 0x00,
+    ]);
+    // INPUT# is found before INPUT, and DATA is left as it is up to a colon
+    let program = tokenize("1 input#1,a:data print,to:end", 0x0801).unwrap();
+    assert_eq!(&program[4..], b"\x84\x31,A:\x83 PRINT,TO:\x80\x00\x00\x00");
+    assert_eq!(tokenize("print", 0x0801), Err(BasicError::MissingLineNumber(1)));
+    assert_eq!(tokenize("\n70000 end", 0x0801), Err(BasicError::LineNumberTooLarge(2)));
+    assert_eq!(tokenize("1 ?\"{bogus}\"", 0x0801), Err(BasicError::UnknownEscape(1)));
+  }
+
+  #[test]
+  fn list_program() {
+    let source = "10 PRINT \"{clr}{wht}HELLO{$c1}\"\n20 IF X=π THEN 10\n30 REM GOTO\n";
+    let mut mem = MemMap::new();
+    let data = to_prg(source).unwrap();
+    assert_eq!(data.len(), 0x2f);
+    prg::load(&mut mem, &data);
+    assert_eq!(mem.ram_rom.ram[0x2d], 0x2e);
+    assert_eq!(mem.ram_rom.ram[0x0808], 0x93);
+    assert_eq!(list(&mem), source);
+    // A pointer back to an earlier line stops the listing
+    let looped = [0x01, 0x08, 0x0a, 0x00, 0x80, 0x00];
+    assert_eq!(detokenize(&looped, 0x0801), "10 END\n");
+  }
+}
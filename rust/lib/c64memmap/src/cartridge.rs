@@ -0,0 +1,346 @@
+// Cartridges in the CRT format. A CRT file has a header describing the
+// cartridge hardware and the initial state of its GAME and EXROM lines,
+// followed by CHIP packets holding the contents of each ROM bank.
+
+const CRT_SIGNATURE: &[u8] = b"C64 CARTRIDGE   ";
+const CHIP_SIGNATURE: &[u8] = b"CHIP";
+const CHIP_HEADER_SIZE: usize = 0x10;
+const BANK_SIZE: usize = 0x2000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Hardware {
+  // Plain 8K, 16K or Ultimax ROM
+  Normal,
+  // Action Replay: 4 banks of ROM, 8K of RAM and a freeze button
+  ActionReplay,
+  // Ocean: up to 64 banks, selected by writing to $DE00
+  Ocean,
+  // Magic Desk: 8K banks selected at $DE00, with bit 7 hiding the cartridge
+  MagicDesk,
+  // EasyFlash: 64 banks each of ROML and ROMH, and 256 bytes of RAM at $DF00
+  EasyFlash,
+}
+
+impl Hardware {
+  fn from_id(id: u16) -> Option<Hardware> {
+    match id {
+      0 => Some(Hardware::Normal),
+      1 => Some(Hardware::ActionReplay),
+      5 => Some(Hardware::Ocean),
+      19 => Some(Hardware::MagicDesk),
+      32 => Some(Hardware::EasyFlash),
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CartridgeError {
+  InvalidImage,
+  UnsupportedHardware(u16),
+}
+
+fn read_u16_be(bytes: &[u8], offset: usize) -> usize {
+  ((bytes[offset] as usize) << 8) | (bytes[offset + 1] as usize)
+}
+
+fn read_u32_be(bytes: &[u8], offset: usize) -> usize {
+  (read_u16_be(bytes, offset) << 16) | read_u16_be(bytes, offset + 2)
+}
+
+pub struct Cartridge {
+  pub hardware: Hardware,
+  pub name: Vec<u8>,
+  // Levels of the EXROM and GAME lines. The cartridge pulls them low to map
+  // itself into memory.
+  pub exrom: bool,
+  pub game: bool,
+  initial_exrom: bool,
+  initial_game: bool,
+
+  // ROM contents of each bank, empty where the image has no chip
+  roml: Vec<Vec<u8>>,
+  romh: Vec<Vec<u8>>,
+  bank: usize,
+
+  ram: Vec<u8>,
+  ram_enabled: bool,
+  // The Action Replay ignores its control register once disabled, until the
+  // next reset
+  disabled: bool,
+}
+
+impl Cartridge {
+  pub fn from_bytes(bytes: &[u8]) -> Result<Cartridge, CartridgeError> {
+    if bytes.len() < 0x40 || &bytes[0..16] != CRT_SIGNATURE {
+      return Err(CartridgeError::InvalidImage);
+    }
+    let id = read_u16_be(bytes, 0x16) as u16;
+    let hardware = match Hardware::from_id(id) {
+      Some(hardware) => hardware,
+      None => return Err(CartridgeError::UnsupportedHardware(id)),
+    };
+    let mut name = bytes[0x20..0x40].to_vec();
+    while name.last() == Some(&0) {
+      name.pop();
+    }
+
+    let mut roml = Vec::new();
+    let mut romh = Vec::new();
+    let mut offset = read_u32_be(bytes, 0x10).max(0x40);
+    while offset + CHIP_HEADER_SIZE <= bytes.len() {
+      if &bytes[offset..(offset + 4)] != CHIP_SIGNATURE {
+        return Err(CartridgeError::InvalidImage);
+      }
+      let length = read_u32_be(bytes, offset + 4);
+      let bank = read_u16_be(bytes, offset + 0xa);
+      let load_address = read_u16_be(bytes, offset + 0xc);
+      let size = read_u16_be(bytes, offset + 0xe);
+      let start = offset + CHIP_HEADER_SIZE;
+      if length < CHIP_HEADER_SIZE || start + size > bytes.len() {
+        return Err(CartridgeError::InvalidImage);
+      }
+      let data = &bytes[start..(start + size)];
+      while roml.len() <= bank {
+        roml.push(Vec::new());
+        romh.push(Vec::new());
+      }
+      match load_address {
+        0x8000 => {
+          // A 16K chip covers both ROML and ROMH
+          let split = size.min(BANK_SIZE);
+          roml[bank] = data[..split].to_vec();
+          if size > BANK_SIZE {
+            romh[bank] = data[split..].to_vec();
+          }
+        },
+        0xa000 | 0xe000 => romh[bank] = data.to_vec(),
+        _ => return Err(CartridgeError::InvalidImage),
+      }
+      offset += length;
+    }
+    if roml.len() == 0 {
+      return Err(CartridgeError::InvalidImage);
+    }
+
+    let ram_size = match hardware {
+      Hardware::ActionReplay => 0x2000,
+      Hardware::EasyFlash => 0x100,
+      _ => 0,
+    };
+    let exrom = bytes[0x18] != 0;
+    let game = bytes[0x19] != 0;
+    Ok(Cartridge {
+      hardware: hardware,
+      name: name,
+      exrom: exrom,
+      game: game,
+      initial_exrom: exrom,
+      initial_game: game,
+
+      roml: roml,
+      romh: romh,
+      bank: 0,
+
+      ram: vec![0; ram_size],
+      ram_enabled: false,
+      disabled: false,
+    })
+  }
+
+  pub fn reset(&mut self) {
+    self.exrom = self.initial_exrom;
+    self.game = self.initial_game;
+    self.bank = 0;
+    self.ram_enabled = false;
+    self.disabled = false;
+  }
+
+  /**
+   * Press the freeze button, if the cartridge has one. It switches to Ultimax
+   * mode so the cartridge's ROM takes over the NMI vector, which the caller
+   * is expected to trigger. Returns false if nothing happened.
+   */
+  pub fn freeze(&mut self) -> bool {
+    if self.hardware != Hardware::ActionReplay {
+      return false;
+    }
+    self.exrom = true;
+    self.game = false;
+    self.bank = 0;
+    self.ram_enabled = false;
+    self.disabled = false;
+    return true;
+  }
+
+  fn read_rom(&self, high: bool, offset: u16) -> u8 {
+    let (chips, other) = if high {
+      (&self.romh, &self.roml)
+    } else {
+      (&self.roml, &self.romh)
+    };
+    let mut chip = &chips[self.bank.min(chips.len() - 1)];
+    // Some cartridges show the same bank through ROML and ROMH
+    let shared = self.hardware == Hardware::Ocean || self.hardware == Hardware::ActionReplay;
+    if chip.len() == 0 && shared {
+      chip = &other[self.bank.min(other.len() - 1)];
+    }
+    if chip.len() == 0 {
+      return 0;
+    }
+    // Smaller chips are mirrored across the 8K window
+    return chip[offset as usize % chip.len()];
+  }
+
+  pub fn read_roml(&self, offset: u16) -> u8 {
+    if self.ram_enabled {
+      return self.ram[offset as usize];
+    }
+    return self.read_rom(false, offset);
+  }
+
+  pub fn read_romh(&self, offset: u16) -> u8 {
+    return self.read_rom(true, offset);
+  }
+
+  /**
+   * Returns true if the cartridge took the write, rather than letting it
+   * fall through to the RAM underneath.
+   */
+  pub fn write_roml(&mut self, offset: u16, value: u8) -> bool {
+    if self.ram_enabled {
+      self.ram[offset as usize] = value;
+      return true;
+    }
+    return false;
+  }
+
+  /**
+   * Reads from I/O 1 ($DE00-$DEFF) and I/O 2 ($DF00-$DFFF).
+   */
+  pub fn read_io(&self, addr: u16) -> u8 {
+    match self.hardware {
+      Hardware::ActionReplay if addr >= 0xdf00 => {
+        // I/O 2 is a window onto the last page of the ROML area
+        self.read_roml(0x1f00 | (addr & 0xff))
+      },
+      Hardware::EasyFlash if addr >= 0xdf00 => self.ram[(addr & 0xff) as usize],
+      _ => 0,
+    }
+  }
+
+  pub fn write_io(&mut self, addr: u16, value: u8) {
+    match self.hardware {
+      Hardware::Normal => (),
+      Hardware::ActionReplay => {
+        if addr >= 0xdf00 {
+          if self.ram_enabled {
+            self.ram[(0x1f00 | (addr & 0xff)) as usize] = value;
+          }
+        } else if !self.disabled {
+          self.game = value & 1 == 0;
+          self.exrom = value & 2 != 0;
+          self.disabled = value & 4 != 0;
+          self.bank = ((value >> 3) & 3) as usize;
+          self.ram_enabled = value & 0x20 != 0;
+        }
+      },
+      Hardware::Ocean => {
+        if addr < 0xdf00 {
+          self.bank = (value & 0x3f) as usize;
+        }
+      },
+      Hardware::MagicDesk => {
+        if addr < 0xdf00 {
+          self.bank = (value & 0x3f) as usize;
+          self.exrom = value & 0x80 != 0;
+        }
+      },
+      Hardware::EasyFlash => {
+        if addr >= 0xdf00 {
+          self.ram[(addr & 0xff) as usize] = value;
+        } else if addr & 0xff == 0 {
+          self.bank = (value & 0x3f) as usize;
+        } else if addr & 0xff == 2 {
+          self.exrom = value & 2 == 0;
+          // With bit 2 clear, GAME follows the boot jumper, which holds it low
+          self.game = value & 4 != 0 && value & 1 == 0;
+        }
+      },
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use cartridge::{Cartridge, CartridgeError, Hardware};
+
+  fn crt(hardware: u8, exrom: u8, game: u8, chips: &[(u8, u16, usize)]) -> Vec<u8> {
+    let mut data = b"C64 CARTRIDGE   ".to_vec();
+    data.extend_from_slice(&[0, 0, 0, 0x40, 1, 0, 0, hardware, exrom, game]);
+    data.resize(0x40, 0);
+    for &(bank, load, size) in chips {
+      let length = size + 0x10;
+      data.extend_from_slice(b"CHIP");
+      data.extend_from_slice(&[0, 0, (length >> 8) as u8, length as u8, 0, 0, 0, bank]);
+      data.extend_from_slice(&[(load >> 8) as u8, 0, (size >> 8) as u8, size as u8]);
+      for i in 0..size {
+        data.push(bank.wrapping_add((i >> 8) as u8));
+      }
+    }
+    return data;
+  }
+
+  #[test]
+  fn normal_16k() {
+    let cart = Cartridge::from_bytes(&crt(0, 0, 0, &[(0, 0x8000, 0x4000)])).unwrap();
+    assert_eq!(cart.hardware, Hardware::Normal);
+    assert!(!cart.exrom && !cart.game);
+    assert_eq!(cart.read_roml(0x100), 1);
+    assert_eq!(cart.read_romh(0x100), 0x21);
+    assert_eq!(Cartridge::from_bytes(&crt(99, 0, 0, &[])).err(), Some(CartridgeError::UnsupportedHardware(99)));
+  }
+
+  #[test]
+  fn bank_switching() {
+    let chips: Vec<(u8, u16, usize)> = (0..4).map(|bank| (bank, 0x8000, 0x2000)).collect();
+    let mut cart = Cartridge::from_bytes(&crt(19, 0, 1, &chips)).unwrap();
+    assert_eq!(cart.read_roml(0), 0);
+    cart.write_io(0xde00, 2);
+    assert_eq!(cart.read_roml(0), 2);
+    // Bit 7 hides a Magic Desk cartridge
+    cart.write_io(0xde00, 0x80);
+    assert!(cart.exrom);
+    cart.reset();
+    assert!(!cart.exrom);
+    assert_eq!(cart.read_roml(0), 0);
+  }
+
+  #[test]
+  fn easyflash() {
+    let mut cart = Cartridge::from_bytes(&crt(32, 1, 0, &[(0, 0x8000, 0x2000), (0, 0xe000, 0x2000)])).unwrap();
+    // Boots in Ultimax mode
+    assert!(cart.exrom && !cart.game);
+    cart.write_io(0xde02, 0x07);
+    assert!(!cart.exrom && !cart.game);
+    cart.write_io(0xde02, 0x04);
+    assert!(cart.exrom && cart.game);
+    cart.write_io(0xdf10, 0x5a);
+    assert_eq!(cart.read_io(0xdf10), 0x5a);
+  }
+
+  #[test]
+  fn action_replay() {
+    let chips: Vec<(u8, u16, usize)> = (0..4).map(|bank| (bank, 0x8000, 0x2000)).collect();
+    let mut cart = Cartridge::from_bytes(&crt(1, 0, 1, &chips)).unwrap();
+    cart.write_io(0xde00, 0x10);
+    assert_eq!(cart.read_roml(0), 2);
+    // RAM replaces the ROM at ROML and I/O 2
+    cart.write_io(0xde00, 0x20);
+    assert!(cart.write_roml(0x1f05, 0x77));
+    assert_eq!(cart.read_io(0xdf05), 0x77);
+    assert!(cart.freeze());
+    assert!(cart.exrom && !cart.game);
+    assert_eq!(cart.read_romh(0x1f05), 0x1f);
+  }
+}
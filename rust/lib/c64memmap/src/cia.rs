@@ -1,8 +1,160 @@
+use model::Model;
+
+pub const JOYSTICK_UP: u8 = 1;
+pub const JOYSTICK_DOWN: u8 = 2;
+pub const JOYSTICK_LEFT: u8 = 4;
+pub const JOYSTICK_RIGHT: u8 = 8;
+pub const JOYSTICK_FIRE: u8 = 16;
+
+// RESTORE isn't part of the keyboard matrix; it drives the NMI line directly.
+// It takes the next index after the matrix so front-ends can map it along
+// with the other keys.
+pub const KEY_RESTORE: u8 = 64;
+
+fn bcd_increment(value: u8) -> u8 {
+  if value & 0x0f == 9 {
+    (value & 0xf0) + 0x10
+  } else {
+    value + 1
+  }
+}
+
+/**
+ * A time of day clock, which counts tenths of a second, seconds, minutes and
+ * hours in BCD from ticks of the mains frequency. Reading the hours freezes
+ * what the other registers read until the tenths are read, and writing the
+ * hours stops the clock until the tenths are written.
+ */
+struct Tod {
+  // Tenths, seconds, minutes, and hours with the PM flag in bit 7
+  time: [u8; 4],
+  alarm: [u8; 4],
+  latch: Option<[u8; 4]>,
+  running: bool,
+  // Control register A bit 7: count a tenth every 5 ticks instead of 6
+  fifty_hz: bool,
+  // Control register B bit 7: writes set the alarm instead of the time
+  write_alarm: bool,
+  ticks: u8,
+}
+
+impl Tod {
+  fn new() -> Tod {
+    return Tod {
+      time: [0, 0, 0, 1],
+      alarm: [0, 0, 0, 0],
+      latch: None,
+      running: true,
+      fifty_hz: false,
+      write_alarm: false,
+      ticks: 0,
+    };
+  }
+
+  fn read(&mut self, register: usize) -> u8 {
+    if register == 3 && self.latch.is_none() {
+      self.latch = Some(self.time);
+    }
+    let value = match self.latch {
+      Some(latch) => latch[register],
+      None => self.time[register],
+    };
+    if register == 0 {
+      self.latch = None;
+    }
+    return value;
+  }
+
+  fn peek(&self, register: usize) -> u8 {
+    match self.latch {
+      Some(latch) => latch[register],
+      None => self.time[register],
+    }
+  }
+
+  fn write(&mut self, register: usize, value: u8) {
+    let value = value & [0x0f, 0x7f, 0x7f, 0x9f][register];
+    if self.write_alarm {
+      self.alarm[register] = value;
+      return;
+    }
+    self.time[register] = value;
+    if register == 3 {
+      self.running = false;
+    } else if register == 0 {
+      self.running = true;
+      self.ticks = 0;
+    }
+  }
+
+  /**
+   * Count a tick of the mains frequency. Returns true if the time has
+   * reached the alarm.
+   */
+  fn tick(&mut self) -> bool {
+    if !self.running {
+      return false;
+    }
+    self.ticks += 1;
+    let ticks_per_tenth = if self.fifty_hz { 5 } else { 6 };
+    if self.ticks < ticks_per_tenth {
+      return false;
+    }
+    self.ticks = 0;
+    self.time[0] += 1;
+    if self.time[0] == 10 {
+      self.time[0] = 0;
+      self.time[1] = bcd_increment(self.time[1]);
+      if self.time[1] == 0x60 {
+        self.time[1] = 0;
+        self.time[2] = bcd_increment(self.time[2]);
+        if self.time[2] == 0x60 {
+          self.time[2] = 0;
+          let pm = self.time[3] & 0x80;
+          self.time[3] = match self.time[3] & 0x1f {
+            // The AM/PM flag changes going from 11 to 12
+            0x11 => 0x12 | (pm ^ 0x80),
+            0x12 => 0x01 | pm,
+            hours => bcd_increment(hours) | pm,
+          };
+        }
+      }
+    }
+    return self.time == self.alarm;
+  }
+}
+
+/**
+ * The registers and internal state of one CIA, as saved in a snapshot
+ */
+#[derive(Clone, PartialEq, Debug)]
+pub struct CiaState {
+  pub port_a: u8,
+  pub port_b: u8,
+  pub ddr_a: u8,
+  pub ddr_b: u8,
+  pub timer_a: u16,
+  pub timer_b: u16,
+  pub timer_a_latch: u16,
+  pub timer_b_latch: u16,
+  pub control_a: u8,
+  pub control_b: u8,
+  // Tenths, seconds, minutes and hours
+  pub tod: [u8; 4],
+  pub tod_alarm: [u8; 4],
+  pub tod_latch: Option<[u8; 4]>,
+  // Sources enabled in the ICR, and the ones that have occurred
+  pub interrupt_mask: u8,
+  pub interrupt_status: u8,
+}
+
 pub struct CIA {
   // CIA 1
   keys: [u8;8], // 64 bits for key matrix, in 8 8-bit rows
   port_a_1: u8,
   mask_a_1: u8,
+  // Pressed directions for control ports 1 and 2, using the JOYSTICK_ bits
+  joysticks: [u8;2],
 
   timer_a_1_interrupt: bool,
   timer_a_1_interrupt_enabled: bool,
@@ -11,8 +163,44 @@ pub struct CIA {
   timer_a_1_value: u16,
   timer_a_1_restart: bool,
   timer_a_1_register: u8,
+  timer_b_1_interrupt: bool,
+  timer_b_1_interrupt_enabled: bool,
+  timer_b_1_enabled: bool,
+  timer_b_1_latch: u16,
+  timer_b_1_value: u16,
+  timer_b_1_restart: bool,
+  timer_b_1_register: u8,
+  // The FLAG pin is connected to the cassette read line
+  flag_1_interrupt: bool,
+  flag_1_interrupt_enabled: bool,
+  tod_1: Tod,
+  tod_1_interrupt: bool,
+  tod_1_interrupt_enabled: bool,
 
   // CIA 2
+  port_a_2: u8,
+  mask_a_2: u8,
+  // Serial bus CLK (bit 6) and DATA (bit 7) line levels
+  serial_in: u8,
+  timer_a_2_enabled: bool,
+  timer_a_2_latch: u16,
+  timer_a_2_value: u16,
+  timer_a_2_restart: bool,
+  timer_a_2_register: u8,
+  timer_b_2_enabled: bool,
+  timer_b_2_latch: u16,
+  timer_b_2_value: u16,
+  timer_b_2_restart: bool,
+  timer_b_2_register: u8,
+  tod_2: Tod,
+  // Interrupt sources latched in the ICR, and the ones allowed to pull the
+  // NMI line low
+  interrupt_status_2: u8,
+  interrupt_mask_2: u8,
+
+  // Both TOD clocks count ticks of the mains frequency
+  cycles_per_tod_tick: u32,
+  tod_cycles: u32,
 }
 
 impl CIA {
@@ -21,6 +209,7 @@ impl CIA {
       keys: [0, 0, 0, 0, 0, 0, 0, 0],
       port_a_1: 0,
       mask_a_1: 0xff,
+      joysticks: [0, 0],
       timer_a_1_interrupt: false,
       timer_a_1_interrupt_enabled: false,
       timer_a_1_enabled: false,
@@ -28,49 +217,172 @@ impl CIA {
       timer_a_1_value: 0,
       timer_a_1_restart: false,
       timer_a_1_register: 0,
+      timer_b_1_interrupt: false,
+      timer_b_1_interrupt_enabled: false,
+      timer_b_1_enabled: false,
+      timer_b_1_latch: 0xffff,
+      timer_b_1_value: 0xffff,
+      timer_b_1_restart: false,
+      timer_b_1_register: 0,
+      flag_1_interrupt: false,
+      flag_1_interrupt_enabled: false,
+      tod_1: Tod::new(),
+      tod_1_interrupt: false,
+      tod_1_interrupt_enabled: false,
+
+      port_a_2: 0,
+      mask_a_2: 0,
+      // Nothing on the bus, the lines are pulled high
+      serial_in: 0xc0,
+      timer_a_2_enabled: false,
+      timer_a_2_latch: 0xffff,
+      timer_a_2_value: 0xffff,
+      timer_a_2_restart: false,
+      timer_a_2_register: 0,
+      timer_b_2_enabled: false,
+      timer_b_2_latch: 0xffff,
+      timer_b_2_value: 0xffff,
+      timer_b_2_restart: false,
+      timer_b_2_register: 0,
+      tod_2: Tod::new(),
+      interrupt_status_2: 0,
+      interrupt_mask_2: 0,
+
+      cycles_per_tod_tick: Model::Pal.clock_rate() / Model::Pal.tod_frequency(),
+      tod_cycles: 0,
     };
   }
 
+  pub fn set_model(&mut self, model: Model) {
+    self.cycles_per_tod_tick = model.clock_rate() / model.tod_frequency();
+  }
+
+  /**
+   * Return both chips to their power-on state, as the RESET line does. What
+   * the host is pressing on the keyboard, joysticks and serial bus stays.
+   */
+  pub fn reset(&mut self) {
+    let keys = self.keys;
+    let joysticks = self.joysticks;
+    let serial_in = self.serial_in;
+    let cycles_per_tod_tick = self.cycles_per_tod_tick;
+    *self = CIA::new();
+    self.keys = keys;
+    self.joysticks = joysticks;
+    self.serial_in = serial_in;
+    self.cycles_per_tod_tick = cycles_per_tod_tick;
+  }
+
   pub fn get_byte(&mut self, addr: u16) -> u8 {
     if addr & 0x100 == 0 {
       // CIA 1
       match addr % 16 {
-        0x00 => self.port_a_1,
-        0x01 => {
-          let port_inv = !self.port_a_1;
-          let mut col = 0;
-          let mut row = 0;
-          while col < 8 {
-            let shift = port_inv >> col;
-            if shift & 1 == 1 {
-              row |= self.keys[col as usize];
-            }
-            col += 1;
-          }
-          !row
-        },
+        // Control port 2 shares port A with the keyboard columns, and a
+        // closed joystick switch pulls its line low
+        0x00 => self.port_a_1 & !self.joysticks[1],
+        0x01 => self.read_port_b_1(),
         0x02 => self.mask_a_1,
 
         0x04 => (self.timer_a_1_value & 0xff) as u8,
         0x05 => ((self.timer_a_1_value & 0xff00) >> 8) as u8,
-        
+        0x06 => (self.timer_b_1_value & 0xff) as u8,
+        0x07 => ((self.timer_b_1_value & 0xff00) >> 8) as u8,
+        0x08..=0x0b => self.tod_1.read((addr % 16 - 8) as usize),
+
         0x0d => {
-          let mut status = 0;
-          if self.timer_a_1_interrupt {
-            status = status | 1 | 128;
-            self.timer_a_1_interrupt = false;
-          }
+          let status = self.icr_1();
+          self.timer_a_1_interrupt = false;
+          self.timer_b_1_interrupt = false;
+          self.tod_1_interrupt = false;
+          self.flag_1_interrupt = false;
           status
         },
         0x0e => self.timer_a_1_register,
+        0x0f => self.timer_b_1_register,
 
         _ => 0,
       }
     } else {
-      0
+      // CIA 2
+      match addr % 16 {
+        // Lines configured as inputs are pulled high, unless a device on the
+        // serial bus pulls CLK or DATA low
+        0x00 => (self.port_a_2 & self.mask_a_2) | (!self.mask_a_2 & (self.serial_in | 0x3f)),
+        0x02 => self.mask_a_2,
+
+        0x04 => (self.timer_a_2_value & 0xff) as u8,
+        0x05 => (self.timer_a_2_value >> 8) as u8,
+        0x06 => (self.timer_b_2_value & 0xff) as u8,
+        0x07 => (self.timer_b_2_value >> 8) as u8,
+        0x08..=0x0b => self.tod_2.read((addr % 16 - 8) as usize),
+        // Reading the ICR acknowledges the interrupt, and releases NMI
+        0x0d => {
+          let status = self.icr_2();
+          self.interrupt_status_2 = 0;
+          status
+        },
+        0x0e => self.timer_a_2_register,
+        0x0f => self.timer_b_2_register,
+
+        _ => 0,
+      }
+    }
+  }
+
+  /**
+   * Read a register without acknowledging interrupts or latching the time
+   * of day, for debuggers
+   */
+  pub fn peek(&mut self, addr: u16) -> u8 {
+    let cia_2 = addr & 0x100 != 0;
+    match addr % 16 {
+      0x08..=0x0b => {
+        let tod = if cia_2 { &self.tod_2 } else { &self.tod_1 };
+        tod.peek((addr % 16 - 8) as usize)
+      },
+      0x0d if cia_2 => self.icr_2(),
+      0x0d => self.icr_1(),
+      _ => self.get_byte(addr),
     }
   }
 
+  fn icr_1(&self) -> u8 {
+    let mut status = 0;
+    if self.timer_a_1_interrupt {
+      status |= 1;
+      if self.timer_a_1_interrupt_enabled {
+        status |= 128;
+      }
+    }
+    if self.timer_b_1_interrupt {
+      status |= 2;
+      if self.timer_b_1_interrupt_enabled {
+        status |= 128;
+      }
+    }
+    if self.tod_1_interrupt {
+      status |= 4;
+      if self.tod_1_interrupt_enabled {
+        status |= 128;
+      }
+    }
+    if self.flag_1_interrupt {
+      status |= 0x10;
+      if self.flag_1_interrupt_enabled {
+        status |= 128;
+      }
+    }
+    return status;
+  }
+
+  fn icr_2(&self) -> u8 {
+    let mut status = self.interrupt_status_2;
+    if status & self.interrupt_mask_2 != 0 {
+      status |= 0x80;
+    }
+    return status;
+  }
+
   pub fn set_byte(&mut self, addr: u16, value: u8) {
     if addr & 0x100 == 0 {
       // CIA 1
@@ -92,12 +404,34 @@ impl CIA {
           let low = self.timer_a_1_latch & 0xff;
           self.timer_a_1_latch = ((value as u16) << 8) | low;
         },
+        0x06 => {
+          let high = self.timer_b_1_latch & 0xff00;
+          self.timer_b_1_latch = high | (value as u16);
+        },
+        0x07 => {
+          let low = self.timer_b_1_latch & 0xff;
+          self.timer_b_1_latch = ((value as u16) << 8) | low;
+          // Writing the high byte of a stopped timer also loads it
+          if !self.timer_b_1_enabled {
+            self.timer_b_1_value = self.timer_b_1_latch;
+          }
+        },
+        0x08..=0x0b => self.tod_1.write((addr % 16 - 8) as usize, value),
 
         0x0d => {
           let set = value & 0x80 != 0;
           if value & 1 == 1 {
             self.timer_a_1_interrupt_enabled = set;
           }
+          if value & 2 != 0 {
+            self.timer_b_1_interrupt_enabled = set;
+          }
+          if value & 4 != 0 {
+            self.tod_1_interrupt_enabled = set;
+          }
+          if value & 0x10 != 0 {
+            self.flag_1_interrupt_enabled = set;
+          }
         },
         0x0e => {
           self.timer_a_1_enabled = value & 1 != 0;
@@ -106,12 +440,231 @@ impl CIA {
             self.timer_a_1_value = self.timer_a_1_latch;
           }
           self.timer_a_1_register = value;
+          self.tod_1.fifty_hz = value & 0x80 != 0;
+        },
+        0x0f => {
+          self.timer_b_1_enabled = value & 1 != 0;
+          self.timer_b_1_restart = value & 8 == 0;
+          if value & 0x10 != 0 {
+            self.timer_b_1_value = self.timer_b_1_latch;
+          }
+          self.timer_b_1_register = value & 0xef;
+          self.tod_1.write_alarm = value & 0x80 != 0;
+        },
+        _ => (),
+      };
+    } else {
+      // CIA 2
+      match addr % 16 {
+        0x00 => self.port_a_2 = value,
+        0x02 => self.mask_a_2 = value,
+
+        0x04 => self.timer_a_2_latch = (self.timer_a_2_latch & 0xff00) | (value as u16),
+        0x05 => {
+          self.timer_a_2_latch = (self.timer_a_2_latch & 0xff) | ((value as u16) << 8);
+          if !self.timer_a_2_enabled {
+            self.timer_a_2_value = self.timer_a_2_latch;
+          }
+        },
+        0x06 => self.timer_b_2_latch = (self.timer_b_2_latch & 0xff00) | (value as u16),
+        0x07 => {
+          self.timer_b_2_latch = (self.timer_b_2_latch & 0xff) | ((value as u16) << 8);
+          if !self.timer_b_2_enabled {
+            self.timer_b_2_value = self.timer_b_2_latch;
+          }
+        },
+        0x08..=0x0b => self.tod_2.write((addr % 16 - 8) as usize, value),
+        0x0d => {
+          if value & 0x80 != 0 {
+            self.interrupt_mask_2 |= value & 0x1f;
+          } else {
+            self.interrupt_mask_2 &= !value;
+          }
+        },
+        0x0e => {
+          self.timer_a_2_enabled = value & 1 != 0;
+          self.timer_a_2_restart = value & 8 == 0;
+          if value & 0x10 != 0 {
+            self.timer_a_2_value = self.timer_a_2_latch;
+          }
+          self.timer_a_2_register = value & 0xef;
+          self.tod_2.fifty_hz = value & 0x80 != 0;
+        },
+        0x0f => {
+          self.timer_b_2_enabled = value & 1 != 0;
+          self.timer_b_2_restart = value & 8 == 0;
+          if value & 0x10 != 0 {
+            self.timer_b_2_value = self.timer_b_2_latch;
+          }
+          self.timer_b_2_register = value & 0xef;
+          self.tod_2.write_alarm = value & 0x80 != 0;
         },
         _ => (),
       };
     }
   }
 
+  /**
+   * Capture the state of CIA 1 (chip 0) or CIA 2 (chip 1)
+   */
+  pub fn save_state(&self, chip: usize) -> CiaState {
+    if chip == 0 {
+      let mut mask = 0;
+      for &(enabled, bit) in [
+        (self.timer_a_1_interrupt_enabled, 1),
+        (self.timer_b_1_interrupt_enabled, 2),
+        (self.tod_1_interrupt_enabled, 4),
+        (self.flag_1_interrupt_enabled, 0x10),
+      ].iter() {
+        if enabled {
+          mask |= bit;
+        }
+      }
+      return CiaState {
+        port_a: self.port_a_1,
+        // Port B only reads the keyboard rows, and is never driven
+        port_b: 0xff,
+        ddr_a: self.mask_a_1,
+        ddr_b: 0,
+        timer_a: self.timer_a_1_value,
+        timer_b: self.timer_b_1_value,
+        timer_a_latch: self.timer_a_1_latch,
+        timer_b_latch: self.timer_b_1_latch,
+        control_a: self.timer_a_1_register,
+        control_b: self.timer_b_1_register,
+        tod: self.tod_1.time,
+        tod_alarm: self.tod_1.alarm,
+        tod_latch: self.tod_1.latch,
+        interrupt_mask: mask,
+        interrupt_status: self.icr_1() & 0x7f,
+      };
+    }
+    return CiaState {
+      port_a: self.port_a_2,
+      port_b: 0xff,
+      ddr_a: self.mask_a_2,
+      ddr_b: 0,
+      timer_a: self.timer_a_2_value,
+      timer_b: self.timer_b_2_value,
+      timer_a_latch: self.timer_a_2_latch,
+      timer_b_latch: self.timer_b_2_latch,
+      control_a: self.timer_a_2_register,
+      control_b: self.timer_b_2_register,
+      tod: self.tod_2.time,
+      tod_alarm: self.tod_2.alarm,
+      tod_latch: self.tod_2.latch,
+      interrupt_mask: self.interrupt_mask_2,
+      interrupt_status: self.interrupt_status_2,
+    };
+  }
+
+  /**
+   * Restore CIA 1 (chip 0) or CIA 2 (chip 1) from a saved state. The
+   * registers are written as the CPU would, and then the counters and flags
+   * that writes can't reach are set directly.
+   */
+  pub fn load_state(&mut self, chip: usize, state: &CiaState) {
+    let base = if chip == 0 { 0 } else { 0x100 };
+    self.set_byte(base + 0x02, 0xff);
+    self.set_byte(base, state.port_a);
+    self.set_byte(base + 0x02, state.ddr_a);
+    self.set_byte(base + 0x04, state.timer_a_latch as u8);
+    self.set_byte(base + 0x05, (state.timer_a_latch >> 8) as u8);
+    self.set_byte(base + 0x06, state.timer_b_latch as u8);
+    self.set_byte(base + 0x07, (state.timer_b_latch >> 8) as u8);
+    self.set_byte(base + 0x0d, 0x7f);
+    self.set_byte(base + 0x0d, 0x80 | state.interrupt_mask);
+    self.set_byte(base + 0x0e, state.control_a & 0xef);
+    self.set_byte(base + 0x0f, state.control_b & 0xef);
+    let tod = if chip == 0 { &mut self.tod_1 } else { &mut self.tod_2 };
+    tod.time = state.tod;
+    tod.alarm = state.tod_alarm;
+    tod.latch = state.tod_latch;
+    tod.running = true;
+    if chip == 0 {
+      self.timer_a_1_value = state.timer_a;
+      self.timer_b_1_value = state.timer_b;
+      self.timer_a_1_interrupt = state.interrupt_status & 1 != 0;
+      self.timer_b_1_interrupt = state.interrupt_status & 2 != 0;
+      self.tod_1_interrupt = state.interrupt_status & 4 != 0;
+      self.flag_1_interrupt = state.interrupt_status & 0x10 != 0;
+    } else {
+      self.timer_a_2_value = state.timer_a;
+      self.timer_b_2_value = state.timer_b;
+      self.interrupt_status_2 = state.interrupt_status & 0x1f;
+    }
+  }
+
+  fn read_port_b_1(&self) -> u8 {
+    let port_inv = !(self.port_a_1 & !self.joysticks[1]);
+    let mut col = 0;
+    let mut row = 0;
+    while col < 8 {
+      let shift = port_inv >> col;
+      if shift & 1 == 1 {
+        row |= self.keys[col as usize];
+      }
+      col += 1;
+    }
+    !row & !self.joysticks[0]
+  }
+
+  /**
+   * Bit 4 of CIA 1 port B is also the VIC-II's light pen input. Returns true
+   * while the fire button on control port 1, or a key in that row of the
+   * matrix, is pulling it low.
+   */
+  pub fn light_pen_line(&self) -> bool {
+    self.read_port_b_1() & 0x10 == 0
+  }
+
+  /**
+   * Bits 6-7 of CIA 1 port A select which control port's paddles are
+   * connected to the SID. Returns the port index, or None if the selection
+   * doesn't connect exactly one port.
+   */
+  pub fn get_paddle_port(&self) -> Option<usize> {
+    match self.port_a_1 >> 6 {
+      1 => Some(0),
+      2 => Some(1),
+      _ => None,
+    }
+  }
+
+  /**
+   * Bits 0-1 of CIA 2 port A select which 16K bank the VIC-II sees. The lines
+   * are inverted, so an output of %11 selects bank 0 at $0000.
+   */
+  pub fn get_vic_bank(&self) -> u16 {
+    let bits = (self.port_a_2 | !self.mask_a_2) & 3;
+    ((3 - bits) as u16) << 14
+  }
+
+  /**
+   * Bits 3-5 of CIA 2 port A drive the serial bus ATN, CLK and DATA lines
+   * through inverters, so writing a 1 pulls the line low. Returns whether
+   * each of (ATN, CLK, DATA) is being pulled low.
+   */
+  pub fn get_serial_outputs(&self) -> (bool, bool, bool) {
+    let port = self.port_a_2 | !self.mask_a_2;
+    (port & 0x08 != 0, port & 0x10 != 0, port & 0x20 != 0)
+  }
+
+  /**
+   * Bits 6 and 7 of CIA 2 port A read the CLK and DATA lines directly, with
+   * true meaning the line is high.
+   */
+  pub fn set_serial_inputs(&mut self, clk: bool, data: bool) {
+    let mut lines = 0;
+    if clk {
+      lines |= 0x40;
+    }
+    if data {
+      lines |= 0x80;
+    }
+    self.serial_in = lines;
+  }
+
   pub fn keydown(&mut self, index: u8) {
     if index > 63 {
       return;
@@ -132,7 +685,134 @@ impl CIA {
     self.keys[col] = orig & !(1 << shift);
   }
 
+  /**
+   * Port 0 is control port 1, read through CIA 1 port B. Port 1 is control
+   * port 2, read through port A.
+   */
+  pub fn joystick_down(&mut self, port: usize, bits: u8) {
+    if port > 1 {
+      return;
+    }
+    self.joysticks[port] |= bits & 0x1f;
+  }
+
+  pub fn joystick_up(&mut self, port: usize, bits: u8) {
+    if port > 1 {
+      return;
+    }
+    self.joysticks[port] &= !bits;
+  }
+
+  /**
+   * A negative edge on the FLAG pin of CIA 1, which latches bit 4 of the
+   * interrupt status until it's read.
+   */
+  pub fn trigger_flag(&mut self) {
+    self.flag_1_interrupt = true;
+  }
+
+  /**
+   * Unlike the timer, the FLAG interrupt holds the IRQ line low until the
+   * status register is read, so it can't be lost while interrupts are
+   * disabled.
+   */
+  pub fn flag_interrupt_pending(&self) -> bool {
+    self.flag_1_interrupt && self.flag_1_interrupt_enabled
+  }
+
+  /**
+   * CIA 2 signals its interrupts on the NMI line, holding it low until the
+   * ICR is read.
+   */
+  pub fn nmi_pending(&self) -> bool {
+    self.interrupt_status_2 & self.interrupt_mask_2 != 0
+  }
+
+  /**
+   * Run both chips for the given number of cycles, returning true while CIA 1
+   * is asserting the IRQ line.
+   */
   pub fn update_timers(&mut self, cycles: u8) -> bool {
+    self.update_timers_2(cycles);
+    let tod = self.update_tod(cycles);
+    let timer_b = self.update_timer_b_1(cycles);
+    return self.update_timer_a_1(cycles) || timer_b || tod;
+  }
+
+  fn update_tod(&mut self, cycles: u8) -> bool {
+    self.tod_cycles += cycles as u32;
+    if self.tod_cycles < self.cycles_per_tod_tick {
+      return false;
+    }
+    self.tod_cycles -= self.cycles_per_tod_tick;
+    if self.tod_2.tick() {
+      self.interrupt_status_2 |= 4;
+    }
+    if self.tod_1.tick() {
+      self.tod_1_interrupt = true;
+      return self.tod_1_interrupt_enabled;
+    }
+    return false;
+  }
+
+  /**
+   * The CIA 2 timers pace the KERNAL's RS-232 routines, and are used by some
+   * programs as an NMI source. Like CIA 1 timer B, they only count clock
+   * cycles.
+   */
+  fn update_timers_2(&mut self, cycles: u8) {
+    if self.timer_a_2_enabled && self.timer_a_2_register & 0x20 == 0 {
+      let (value, underflow) = self.timer_a_2_value.overflowing_sub(cycles as u16);
+      if underflow {
+        self.timer_a_2_value = self.timer_a_2_latch;
+        if !self.timer_a_2_restart {
+          self.timer_a_2_enabled = false;
+          self.timer_a_2_register &= !1;
+        }
+        self.interrupt_status_2 |= 1;
+      } else {
+        self.timer_a_2_value = value;
+      }
+    }
+    if self.timer_b_2_enabled && self.timer_b_2_register & 0x60 == 0 {
+      let (value, underflow) = self.timer_b_2_value.overflowing_sub(cycles as u16);
+      if underflow {
+        self.timer_b_2_value = self.timer_b_2_latch;
+        if !self.timer_b_2_restart {
+          self.timer_b_2_enabled = false;
+          self.timer_b_2_register &= !1;
+        }
+        self.interrupt_status_2 |= 2;
+      } else {
+        self.timer_b_2_value = value;
+      }
+    }
+  }
+
+  /**
+   * Timer B of CIA 1 is used by the tape routines to measure the time between
+   * pulses on the FLAG line. It only counts clock cycles; chaining it to
+   * timer A or the CNT pin isn't supported.
+   */
+  fn update_timer_b_1(&mut self, cycles: u8) -> bool {
+    if !self.timer_b_1_enabled || self.timer_b_1_register & 0x60 != 0 {
+      return false;
+    }
+    let (value, underflow) = self.timer_b_1_value.overflowing_sub(cycles as u16);
+    if !underflow {
+      self.timer_b_1_value = value;
+      return false;
+    }
+    self.timer_b_1_value = self.timer_b_1_latch;
+    if !self.timer_b_1_restart {
+      self.timer_b_1_enabled = false;
+      self.timer_b_1_register &= !1;
+    }
+    self.timer_b_1_interrupt = true;
+    return self.timer_b_1_interrupt_enabled;
+  }
+
+  fn update_timer_a_1(&mut self, cycles: u8) -> bool {
     if !self.timer_a_1_enabled {
       return false;
     }
@@ -144,7 +824,9 @@ impl CIA {
       } else {
         self.timer_a_1_enabled = false;
       }
-      return true;
+      // The tape routines mask this interrupt while they use the FLAG line
+      self.timer_a_1_interrupt = true;
+      return self.timer_a_1_interrupt_enabled;
     }
     self.timer_a_1_value = value;
     return false;
@@ -153,7 +835,8 @@ impl CIA {
 
 #[cfg(test)]
 mod tests {
-  use cia::CIA;
+  use cia::{CIA, JOYSTICK_UP, JOYSTICK_RIGHT, JOYSTICK_FIRE};
+  use model::Model;
 
   #[test]
   fn port_a_masking() {
@@ -181,4 +864,151 @@ mod tests {
     cia.set_byte(0, 0b01111111);
     assert_eq!(cia.get_byte(1), 0b11111110);
   }
+
+  #[test]
+  fn joysticks() {
+    let mut cia = CIA::new();
+    cia.set_byte(0, 0xff);
+    assert_eq!(cia.get_byte(1), 0xff);
+    cia.joystick_down(0, JOYSTICK_UP | JOYSTICK_FIRE);
+    assert_eq!(cia.get_byte(1), 0b11101110);
+    cia.joystick_down(1, JOYSTICK_RIGHT);
+    assert_eq!(cia.get_byte(0), 0b11110111);
+    // port 2 pulling column 3 low is seen by the keyboard scan
+    cia.joystick_up(0, JOYSTICK_UP | JOYSTICK_FIRE);
+    cia.keydown(29);
+    assert_eq!(cia.get_byte(1), 0b11011111);
+    cia.joystick_up(1, JOYSTICK_RIGHT);
+    assert_eq!(cia.get_byte(1), 0xff);
+  }
+
+  #[test]
+  fn vic_bank() {
+    let mut cia = CIA::new();
+    assert_eq!(cia.get_vic_bank(), 0x0000);
+    cia.set_byte(0x102, 0x3f);
+    cia.set_byte(0x100, 0x97);
+    assert_eq!(cia.get_vic_bank(), 0x0000);
+    cia.set_byte(0x100, 0x96);
+    assert_eq!(cia.get_vic_bank(), 0x4000);
+    cia.set_byte(0x100, 0x95);
+    assert_eq!(cia.get_vic_bank(), 0x8000);
+    cia.set_byte(0x100, 0x94);
+    assert_eq!(cia.get_vic_bank(), 0xc000);
+    assert_eq!(cia.get_byte(0x100), 0xd4);
+  }
+
+  #[test]
+  fn timer_b() {
+    let mut cia = CIA::new();
+    cia.set_byte(0x06, 100);
+    cia.set_byte(0x07, 0);
+    assert_eq!(cia.get_byte(0x06), 100);
+    // One-shot, counting clock cycles
+    cia.set_byte(0x0f, 0x09);
+    assert!(!cia.update_timers(50));
+    assert_eq!(cia.get_byte(0x06), 50);
+    assert!(!cia.update_timers(60));
+    assert_eq!(cia.get_byte(0x0d), 0x02);
+    assert_eq!(cia.get_byte(0x0f) & 1, 0);
+    assert_eq!(cia.get_byte(0x06), 100);
+
+    cia.set_byte(0x0d, 0x82);
+    cia.set_byte(0x0f, 0x01);
+    assert!(cia.update_timers(101));
+    assert!(cia.update_timers(101));
+    assert_eq!(cia.get_byte(0x0d), 0x82);
+  }
+
+  #[test]
+  fn masked_interrupts() {
+    let mut cia = CIA::new();
+    cia.set_byte(0x04, 50);
+    cia.set_byte(0x05, 0);
+    cia.set_byte(0x0e, 0x11);
+    // Sources that aren't enabled show up in the status without an IRQ
+    assert!(!cia.update_timers(51));
+    cia.trigger_flag();
+    assert_eq!(cia.get_byte(0x0d), 0x11);
+    // Only FLAG is enabled, so a masked timer A doesn't add to it
+    cia.set_byte(0x0d, 0x90);
+    assert!(!cia.update_timers(51));
+    cia.trigger_flag();
+    assert_eq!(cia.get_byte(0x0d), 0x91);
+  }
+
+  #[test]
+  fn time_of_day() {
+    let mut cia = CIA::new();
+    cia.set_model(Model::Ntsc);
+    // Set 11:59:59.9 PM, counting at 60Hz
+    cia.set_byte(0x0b, 0x91);
+    cia.set_byte(0x0a, 0x59);
+    cia.set_byte(0x09, 0x59);
+    cia.set_byte(0x08, 0x09);
+    // Alarm at midnight
+    cia.set_byte(0x0f, 0x80);
+    cia.set_byte(0x0b, 0x12);
+    cia.set_byte(0x0a, 0);
+    cia.set_byte(0x09, 0);
+    cia.set_byte(0x08, 0);
+    cia.set_byte(0x0f, 0);
+    cia.set_byte(0x0d, 0x84);
+    // A tenth of a second is 6 ticks of 1022727 / 60 cycles
+    let mut interrupt = false;
+    for _ in 0..(6 * 17045 / 200) {
+      interrupt |= cia.update_timers(200);
+    }
+    assert!(!interrupt);
+    assert_eq!(cia.get_byte(0x08), 9);
+    for _ in 0..10 {
+      interrupt |= cia.update_timers(200);
+    }
+    assert!(interrupt);
+    assert_eq!(cia.get_byte(0x0d) & 0x84, 0x84);
+    // Reading the hours holds the time until the tenths are read
+    assert_eq!(cia.get_byte(0x0b), 0x12);
+    for _ in 0..1000 {
+      cia.update_timers(200);
+    }
+    assert_eq!(cia.get_byte(0x09), 0);
+    assert_eq!(cia.get_byte(0x08), 0);
+    assert_eq!(cia.get_byte(0x08), 1);
+  }
+
+  #[test]
+  fn nmi_timer() {
+    let mut cia = CIA::new();
+    cia.set_byte(0x104, 50);
+    cia.set_byte(0x105, 0);
+    cia.set_byte(0x10e, 0x11);
+    cia.update_timers(51);
+    // Latched, but not enabled as an NMI source
+    assert!(!cia.nmi_pending());
+    cia.set_byte(0x10d, 0x81);
+    assert!(cia.nmi_pending());
+    // Held until the ICR is read
+    cia.update_timers(51);
+    assert!(cia.nmi_pending());
+    assert_eq!(cia.get_byte(0x10d), 0x81);
+    assert!(!cia.nmi_pending());
+    cia.set_byte(0x10d, 0x01);
+    cia.update_timers(51);
+    assert!(!cia.nmi_pending());
+    cia.reset();
+    assert_eq!(cia.get_byte(0x10e), 0);
+  }
+
+  #[test]
+  fn serial_bus() {
+    let mut cia = CIA::new();
+    cia.set_byte(0x102, 0x3f);
+    cia.set_byte(0x100, 0x07);
+    assert_eq!(cia.get_byte(0x100), 0xc7);
+    assert_eq!(cia.get_serial_outputs(), (false, false, false));
+    cia.set_serial_inputs(true, false);
+    assert_eq!(cia.get_byte(0x100), 0x47);
+    cia.set_byte(0x100, 0x2f);
+    assert_eq!(cia.get_serial_outputs(), (true, false, true));
+  }
 }
\ No newline at end of file
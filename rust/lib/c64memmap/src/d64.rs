@@ -0,0 +1,658 @@
+// A D64 image is a sector dump of a 1541 disk, track by track, optionally
+// followed by one error code byte per sector
+
+const SECTOR_SIZE: usize = 256;
+const DIRECTORY_TRACK: u8 = 18;
+const BAM_SECTOR: u8 = 0;
+const DIRECTORY_INTERLEAVE: u8 = 3;
+const FILE_INTERLEAVE: u8 = 10;
+const ENTRY_SIZE: usize = 32;
+const NAME_LENGTH: usize = 16;
+// Only the first 35 tracks are tracked by the standard BAM
+const BAM_TRACKS: u8 = 35;
+
+const IMAGE_SIZE_35: usize = 683 * SECTOR_SIZE;
+const IMAGE_SIZE_35_ERRORS: usize = 683 * (SECTOR_SIZE + 1);
+const IMAGE_SIZE_40: usize = 768 * SECTOR_SIZE;
+const IMAGE_SIZE_40_ERRORS: usize = 768 * (SECTOR_SIZE + 1);
+
+pub const FILE_TYPE_DEL: u8 = 0;
+pub const FILE_TYPE_SEQ: u8 = 1;
+pub const FILE_TYPE_PRG: u8 = 2;
+pub const FILE_TYPE_USR: u8 = 3;
+pub const FILE_TYPE_REL: u8 = 4;
+// Set on files that were properly closed after writing
+const FILE_CLOSED: u8 = 0x80;
+const FILE_LOCKED: u8 = 0x40;
+
+const FILE_TYPE_NAMES: [&str; 8] = ["DEL", "SEQ", "PRG", "USR", "REL", "???", "???", "???"];
+
+#[derive(Debug, PartialEq)]
+pub enum DiskError {
+  InvalidImage,
+  IllegalTrackOrSector,
+  ReadError,
+  FileNotFound,
+  FileExists,
+  DiskFull,
+  MissingFilename,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DirEntry {
+  pub name: Vec<u8>,
+  pub file_type: u8,
+  pub track: u8,
+  pub sector: u8,
+  pub blocks: u16,
+
+  // Location of the entry itself, so it can be rewritten
+  entry_track: u8,
+  entry_sector: u8,
+  entry_offset: usize,
+}
+
+pub fn sectors_per_track(track: u8) -> u8 {
+  match track {
+    1..=17 => 21,
+    18..=24 => 19,
+    25..=30 => 18,
+    31..=40 => 17,
+    _ => 0,
+  }
+}
+
+/**
+ * Compare a filename against a pattern, where ? matches any character and *
+ * matches the rest of the name.
+ */
+pub fn name_matches(pattern: &[u8], name: &[u8]) -> bool {
+  let mut i = 0;
+  while i < pattern.len() {
+    if pattern[i] == b'*' {
+      return true;
+    }
+    if i >= name.len() {
+      return false;
+    }
+    if pattern[i] != b'?' && pattern[i] != name[i] {
+      return false;
+    }
+    i += 1;
+  }
+  return i == name.len();
+}
+
+pub struct D64 {
+  bytes: Vec<u8>,
+  tracks: u8,
+  has_errors: bool,
+}
+
+impl D64 {
+  pub fn from_bytes(bytes: Vec<u8>) -> Result<D64, DiskError> {
+    let (tracks, has_errors) = match bytes.len() {
+      IMAGE_SIZE_35 => (35, false),
+      IMAGE_SIZE_35_ERRORS => (35, true),
+      IMAGE_SIZE_40 => (40, false),
+      IMAGE_SIZE_40_ERRORS => (40, true),
+      _ => return Err(DiskError::InvalidImage),
+    };
+    return Ok(D64 {
+      bytes: bytes,
+      tracks: tracks,
+      has_errors: has_errors,
+    });
+  }
+
+  /**
+   * Create an empty, formatted 35-track disk.
+   */
+  pub fn blank(name: &[u8], id: &[u8]) -> D64 {
+    let mut disk = D64 {
+      bytes: vec![0; IMAGE_SIZE_35],
+      tracks: 35,
+      has_errors: false,
+    };
+    let mut bam = [0; SECTOR_SIZE];
+    bam[0] = DIRECTORY_TRACK;
+    bam[1] = 1;
+    bam[2] = 0x41; // DOS version 'A'
+    for track in 1..(BAM_TRACKS + 1) {
+      let count = sectors_per_track(track);
+      let offset = 4 * track as usize;
+      bam[offset] = count;
+      for sector in 0..count {
+        bam[offset + 1 + (sector as usize >> 3)] |= 1 << (sector & 7);
+      }
+    }
+    for i in 0x90..0xab {
+      bam[i] = 0xa0;
+    }
+    for i in 0..name.len().min(NAME_LENGTH) {
+      bam[0x90 + i] = name[i];
+    }
+    for i in 0..id.len().min(2) {
+      bam[0xa2 + i] = id[i];
+    }
+    bam[0xa5] = b'2';
+    bam[0xa6] = b'A';
+    disk.write_sector(DIRECTORY_TRACK, BAM_SECTOR, &bam).unwrap();
+    let mut dir = [0; SECTOR_SIZE];
+    dir[1] = 0xff;
+    disk.write_sector(DIRECTORY_TRACK, 1, &dir).unwrap();
+    disk.set_sector_used(DIRECTORY_TRACK, BAM_SECTOR, true);
+    disk.set_sector_used(DIRECTORY_TRACK, 1, true);
+    return disk;
+  }
+
+  /**
+   * The full image, including any error bytes, for writing back to a file.
+   */
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  pub fn get_tracks(&self) -> u8 {
+    self.tracks
+  }
+
+  fn sector_index(&self, track: u8, sector: u8) -> Option<usize> {
+    if track < 1 || track > self.tracks || sector >= sectors_per_track(track) {
+      return None;
+    }
+    let mut index = 0;
+    for t in 1..track {
+      index += sectors_per_track(t) as usize;
+    }
+    return Some(index + sector as usize);
+  }
+
+  fn sector_offset(&self, track: u8, sector: u8) -> Option<usize> {
+    self.sector_index(track, sector).map(|index| index * SECTOR_SIZE)
+  }
+
+  /**
+   * The error code recorded for a sector. 1 means no error; images without
+   * error information report 1 everywhere.
+   */
+  pub fn sector_error(&self, track: u8, sector: u8) -> u8 {
+    if !self.has_errors {
+      return 1;
+    }
+    match self.sector_index(track, sector) {
+      Some(index) => {
+        let count = self.bytes.len() / (SECTOR_SIZE + 1);
+        self.bytes[count * SECTOR_SIZE + index]
+      },
+      None => 1,
+    }
+  }
+
+  /**
+   * The stored contents of a sector, even if it is marked with an error.
+   */
+  pub fn raw_sector(&self, track: u8, sector: u8) -> Option<&[u8]> {
+    self.sector_offset(track, sector).map(|offset| &self.bytes[offset..(offset + SECTOR_SIZE)])
+  }
+
+  pub fn read_sector(&self, track: u8, sector: u8) -> Result<&[u8], DiskError> {
+    let data = match self.raw_sector(track, sector) {
+      Some(data) => data,
+      None => return Err(DiskError::IllegalTrackOrSector),
+    };
+    match self.sector_error(track, sector) {
+      0 | 1 => (),
+      _ => return Err(DiskError::ReadError),
+    }
+    return Ok(data);
+  }
+
+  pub fn write_sector(&mut self, track: u8, sector: u8, data: &[u8]) -> Result<(), DiskError> {
+    let index = match self.sector_index(track, sector) {
+      Some(index) => index,
+      None => return Err(DiskError::IllegalTrackOrSector),
+    };
+    let offset = index * SECTOR_SIZE;
+    let len = data.len().min(SECTOR_SIZE);
+    self.bytes[offset..(offset + len)].copy_from_slice(&data[..len]);
+    if self.has_errors {
+      // A freshly written sector reads back cleanly
+      let count = self.bytes.len() / (SECTOR_SIZE + 1);
+      self.bytes[count * SECTOR_SIZE + index] = 1;
+    }
+    return Ok(());
+  }
+
+  fn bam_offset(&self) -> usize {
+    self.sector_offset(DIRECTORY_TRACK, BAM_SECTOR).unwrap()
+  }
+
+  pub fn disk_name(&self) -> Vec<u8> {
+    let offset = self.bam_offset() + 0x90;
+    self.bytes[offset..(offset + NAME_LENGTH)].to_vec()
+  }
+
+  pub fn disk_id(&self) -> Vec<u8> {
+    let offset = self.bam_offset() + 0xa2;
+    self.bytes[offset..(offset + 5)].to_vec()
+  }
+
+  pub fn is_sector_free(&self, track: u8, sector: u8) -> bool {
+    if track < 1 || track > BAM_TRACKS || sector >= sectors_per_track(track) {
+      return false;
+    }
+    let offset = self.bam_offset() + 4 * track as usize + 1 + (sector as usize >> 3);
+    return self.bytes[offset] & (1 << (sector & 7)) != 0;
+  }
+
+  fn set_sector_used(&mut self, track: u8, sector: u8, used: bool) {
+    if track < 1 || track > BAM_TRACKS || sector >= sectors_per_track(track) {
+      return;
+    }
+    if self.is_sector_free(track, sector) != used {
+      return;
+    }
+    let count_offset = self.bam_offset() + 4 * track as usize;
+    let offset = count_offset + 1 + (sector as usize >> 3);
+    if used {
+      self.bytes[offset] &= !(1 << (sector & 7));
+      self.bytes[count_offset] = self.bytes[count_offset].wrapping_sub(1);
+    } else {
+      self.bytes[offset] |= 1 << (sector & 7);
+      self.bytes[count_offset] = self.bytes[count_offset].wrapping_add(1);
+    }
+  }
+
+  /**
+   * Free blocks, as reported in the directory listing. The directory track is
+   * never counted.
+   */
+  pub fn blocks_free(&self) -> u16 {
+    let mut free = 0;
+    for track in 1..(BAM_TRACKS + 1) {
+      if track != DIRECTORY_TRACK {
+        free += self.bytes[self.bam_offset() + 4 * track as usize] as u16;
+      }
+    }
+    return free;
+  }
+
+  /**
+   * Find a free sector for file data. Like the 1541, files are placed as
+   * close to the directory track as possible, spreading sectors within a
+   * track by the interleave so the drive can keep up.
+   */
+  fn allocate_data_sector(&mut self, previous: Option<(u8, u8)>) -> Option<(u8, u8)> {
+    if let Some((track, sector)) = previous {
+      if let Some(next) = self.allocate_on_track(track, sector + FILE_INTERLEAVE) {
+        return Some((track, next));
+      }
+    }
+    for distance in 1..BAM_TRACKS {
+      for track in [DIRECTORY_TRACK.wrapping_sub(distance), DIRECTORY_TRACK + distance].iter() {
+        if let Some(sector) = self.allocate_on_track(*track, 0) {
+          return Some((*track, sector));
+        }
+      }
+    }
+    return None;
+  }
+
+  fn allocate_on_track(&mut self, track: u8, start: u8) -> Option<u8> {
+    let count = sectors_per_track(track);
+    if count == 0 || track > BAM_TRACKS {
+      return None;
+    }
+    for i in 0..count {
+      let sector = (start + i) % count;
+      if self.is_sector_free(track, sector) {
+        self.set_sector_used(track, sector, true);
+        return Some(sector);
+      }
+    }
+    return None;
+  }
+
+  fn read_entry(&self, track: u8, sector: u8, offset: usize, data: &[u8]) -> DirEntry {
+    let entry = &data[offset..(offset + ENTRY_SIZE)];
+    let mut name = entry[5..(5 + NAME_LENGTH)].to_vec();
+    while name.last() == Some(&0xa0) {
+      name.pop();
+    }
+    DirEntry {
+      name: name,
+      file_type: entry[2],
+      track: entry[3],
+      sector: entry[4],
+      blocks: (entry[0x1e] as u16) | ((entry[0x1f] as u16) << 8),
+
+      entry_track: track,
+      entry_sector: sector,
+      entry_offset: offset,
+    }
+  }
+
+  /**
+   * Walk the directory chain, returning every slot, including empty ones.
+   */
+  fn directory_slots(&self) -> Vec<DirEntry> {
+    let mut slots = Vec::new();
+    let bam = self.bam_offset();
+    let mut track = self.bytes[bam];
+    let mut sector = self.bytes[bam + 1];
+    let mut visited = 0;
+    while track != 0 && visited < sectors_per_track(DIRECTORY_TRACK) {
+      let data = match self.read_sector(track, sector) {
+        Ok(data) => data,
+        Err(_) => break,
+      };
+      for i in 0..(SECTOR_SIZE / ENTRY_SIZE) {
+        slots.push(self.read_entry(track, sector, i * ENTRY_SIZE, data));
+      }
+      track = data[0];
+      sector = data[1];
+      visited += 1;
+    }
+    return slots;
+  }
+
+  pub fn directory(&self) -> Vec<DirEntry> {
+    self.directory_slots().into_iter().filter(|e| e.file_type != 0).collect()
+  }
+
+  pub fn find_file(&self, pattern: &[u8]) -> Option<DirEntry> {
+    for entry in self.directory() {
+      if entry.file_type & 7 != FILE_TYPE_DEL && name_matches(pattern, &entry.name) {
+        return Some(entry);
+      }
+    }
+    return None;
+  }
+
+  /**
+   * Follow a file's sector chain, returning its contents. Each sector starts
+   * with a link to the next one; the last sector instead stores the index of
+   * its final byte.
+   */
+  pub fn read_file(&self, entry: &DirEntry) -> Result<Vec<u8>, DiskError> {
+    let mut data = Vec::new();
+    let mut track = entry.track;
+    let mut sector = entry.sector;
+    let mut remaining = self.bytes.len() / SECTOR_SIZE;
+    loop {
+      if remaining == 0 {
+        // A chain longer than the disk must loop back on itself
+        return Err(DiskError::ReadError);
+      }
+      remaining -= 1;
+      let block = self.read_sector(track, sector)?;
+      if block[0] == 0 {
+        let last = block[1] as usize;
+        if last >= 2 {
+          data.extend_from_slice(&block[2..(last + 1)]);
+        }
+        return Ok(data);
+      }
+      data.extend_from_slice(&block[2..]);
+      track = block[0];
+      sector = block[1];
+    }
+  }
+
+  fn free_chain(&mut self, track: u8, sector: u8) {
+    let mut track = track;
+    let mut sector = sector;
+    let mut remaining = self.bytes.len() / SECTOR_SIZE;
+    while track != 0 && remaining > 0 && !self.is_sector_free(track, sector) {
+      let (next_track, next_sector) = match self.read_sector(track, sector) {
+        Ok(block) => (block[0], block[1]),
+        Err(_) => (0, 0),
+      };
+      self.set_sector_used(track, sector, false);
+      track = next_track;
+      sector = next_sector;
+      remaining -= 1;
+    }
+  }
+
+  pub fn delete_file(&mut self, entry: &DirEntry) {
+    self.free_chain(entry.track, entry.sector);
+    let offset = self.sector_offset(entry.entry_track, entry.entry_sector).unwrap();
+    self.bytes[offset + entry.entry_offset + 2] = 0;
+  }
+
+  /**
+   * Find an empty directory slot, extending the directory chain onto another
+   * sector of the directory track if every existing slot is taken.
+   */
+  fn free_directory_slot(&mut self) -> Result<DirEntry, DiskError> {
+    let slots = self.directory_slots();
+    for slot in slots.iter() {
+      if slot.file_type == 0 {
+        return Ok(slot.clone());
+      }
+    }
+    let last = match slots.last() {
+      Some(slot) => slot.clone(),
+      None => return Err(DiskError::InvalidImage),
+    };
+    let sector = match self.allocate_on_track(DIRECTORY_TRACK, last.entry_sector + DIRECTORY_INTERLEAVE) {
+      Some(sector) => sector,
+      None => return Err(DiskError::DiskFull),
+    };
+    let mut block = [0; SECTOR_SIZE];
+    block[1] = 0xff;
+    self.write_sector(DIRECTORY_TRACK, sector, &block)?;
+    let link = self.sector_offset(last.entry_track, last.entry_sector).unwrap();
+    self.bytes[link] = DIRECTORY_TRACK;
+    self.bytes[link + 1] = sector;
+    return Ok(self.read_entry(DIRECTORY_TRACK, sector, 0, &block));
+  }
+
+  /**
+   * Write a new PRG file. An existing file with the same name is only
+   * replaced if `replace` is set, matching the drive's @: prefix.
+   */
+  pub fn save_file(&mut self, name: &[u8], data: &[u8], replace: bool) -> Result<(), DiskError> {
+    if name.len() == 0 {
+      return Err(DiskError::MissingFilename);
+    }
+    let name = &name[..name.len().min(NAME_LENGTH)];
+    if let Some(existing) = self.directory().into_iter().find(|e| e.file_type != 0 && e.name == name) {
+      if !replace {
+        return Err(DiskError::FileExists);
+      }
+      self.delete_file(&existing);
+    }
+    let blocks = ((data.len() + 253) / 254).max(1);
+    if (self.blocks_free() as usize) < blocks {
+      return Err(DiskError::DiskFull);
+    }
+    let slot = self.free_directory_slot()?;
+
+    let mut sectors = Vec::with_capacity(blocks);
+    let mut previous = None;
+    for _ in 0..blocks {
+      match self.allocate_data_sector(previous) {
+        Some(ts) => {
+          sectors.push(ts);
+          previous = Some(ts);
+        },
+        None => {
+          for &(track, sector) in sectors.iter() {
+            self.set_sector_used(track, sector, false);
+          }
+          return Err(DiskError::DiskFull);
+        },
+      }
+    }
+    for i in 0..blocks {
+      let mut block = [0; SECTOR_SIZE];
+      let chunk = &data[(i * 254)..data.len().min((i + 1) * 254)];
+      block[2..(2 + chunk.len())].copy_from_slice(chunk);
+      if i + 1 < blocks {
+        block[0] = sectors[i + 1].0;
+        block[1] = sectors[i + 1].1;
+      } else {
+        block[1] = (chunk.len() + 1) as u8;
+      }
+      self.write_sector(sectors[i].0, sectors[i].1, &block)?;
+    }
+
+    let offset = self.sector_offset(slot.entry_track, slot.entry_sector).unwrap() + slot.entry_offset;
+    let entry = &mut self.bytes[offset..(offset + ENTRY_SIZE)];
+    for i in 2..ENTRY_SIZE {
+      entry[i] = 0;
+    }
+    entry[2] = FILE_CLOSED | FILE_TYPE_PRG;
+    entry[3] = sectors[0].0;
+    entry[4] = sectors[0].1;
+    for i in 0..NAME_LENGTH {
+      entry[5 + i] = if i < name.len() { name[i] } else { 0xa0 };
+    }
+    entry[0x1e] = (blocks & 0xff) as u8;
+    entry[0x1f] = (blocks >> 8) as u8;
+    return Ok(());
+  }
+
+  /**
+   * Build the BASIC program a 1541 produces when "$" is loaded, with one line
+   * per file. The links are placeholders; BASIC relinks the lines after
+   * loading.
+   */
+  pub fn directory_listing(&self) -> Vec<u8> {
+    let mut listing = vec![0x01, 0x04];
+    let mut header = vec![0x12, b'"'];
+    for &c in self.disk_name().iter() {
+      header.push(if c == 0xa0 { b' ' } else { c });
+    }
+    header.push(b'"');
+    header.push(b' ');
+    for &c in self.disk_id().iter() {
+      header.push(if c == 0xa0 { b' ' } else { c });
+    }
+    D64::push_listing_line(&mut listing, 0, &header);
+
+    for entry in self.directory() {
+      let mut line = Vec::new();
+      let padding = if entry.blocks < 10 { 3 } else if entry.blocks < 100 { 2 } else { 1 };
+      for _ in 0..padding {
+        line.push(b' ');
+      }
+      line.push(b'"');
+      line.extend_from_slice(&entry.name);
+      line.push(b'"');
+      for _ in entry.name.len()..NAME_LENGTH {
+        line.push(b' ');
+      }
+      line.push(if entry.file_type & FILE_CLOSED == 0 { b'*' } else { b' ' });
+      line.extend_from_slice(FILE_TYPE_NAMES[(entry.file_type & 7) as usize].as_bytes());
+      if entry.file_type & FILE_LOCKED != 0 {
+        line.push(b'<');
+      }
+      D64::push_listing_line(&mut listing, entry.blocks, &line);
+    }
+
+    D64::push_listing_line(&mut listing, self.blocks_free(), b"BLOCKS FREE.");
+    listing.push(0);
+    listing.push(0);
+    return listing;
+  }
+
+  fn push_listing_line(listing: &mut Vec<u8>, number: u16, text: &[u8]) {
+    listing.push(0x01);
+    listing.push(0x01);
+    listing.push((number & 0xff) as u8);
+    listing.push((number >> 8) as u8);
+    listing.extend_from_slice(text);
+    listing.push(0);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use d64::{D64, DiskError, name_matches};
+
+  #[test]
+  fn geometry() {
+    assert!(D64::from_bytes(vec![0; 1000]).is_err());
+    let disk = D64::from_bytes(vec![0; 174848]).unwrap();
+    assert_eq!(disk.get_tracks(), 35);
+    assert_eq!(disk.sector_offset(1, 0), Some(0));
+    assert_eq!(disk.sector_offset(18, 0), Some(0x16500));
+    assert_eq!(disk.sector_offset(35, 16), Some(174848 - 256));
+    assert_eq!(disk.sector_offset(18, 19), None);
+    assert_eq!(disk.sector_offset(36, 0), None);
+  }
+
+  #[test]
+  fn error_bytes() {
+    let mut bytes = vec![0; 175531];
+    for i in 0..683 {
+      bytes[174848 + i] = 1;
+    }
+    // track 1 sector 3 is unreadable
+    bytes[174848 + 3] = 5;
+    let disk = D64::from_bytes(bytes).unwrap();
+    assert!(disk.read_sector(1, 2).is_ok());
+    assert_eq!(disk.read_sector(1, 3).unwrap_err(), DiskError::ReadError);
+  }
+
+  #[test]
+  fn patterns() {
+    assert!(name_matches(b"GAME", b"GAME"));
+    assert!(!name_matches(b"GAME", b"GAMES"));
+    assert!(name_matches(b"GA*", b"GAMES"));
+    assert!(name_matches(b"G?ME", b"GAME"));
+    assert!(name_matches(b"*", b"ANYTHING"));
+  }
+
+  #[test]
+  fn save_and_load() {
+    let mut disk = D64::blank(b"TEST DISK", b"01");
+    assert_eq!(disk.blocks_free(), 664);
+    let data: Vec<u8> = (0..600).map(|i| (i & 0xff) as u8).collect();
+    disk.save_file(b"FIRST", &data, false).unwrap();
+    assert_eq!(disk.blocks_free(), 661);
+    assert_eq!(disk.save_file(b"FIRST", &data, false), Err(DiskError::FileExists));
+    disk.save_file(b"SECOND", &[1, 8, 0, 0], false).unwrap();
+
+    let entry = disk.find_file(b"FIRST").unwrap();
+    assert_eq!(entry.blocks, 3);
+    assert_eq!(disk.read_file(&entry).unwrap(), data);
+    let entry = disk.find_file(b"SEC*").unwrap();
+    assert_eq!(disk.read_file(&entry).unwrap(), vec![1, 8, 0, 0]);
+    assert_eq!(disk.find_file(b"THIRD"), None);
+
+    disk.save_file(b"FIRST", &[1, 2, 3], true).unwrap();
+    assert_eq!(disk.blocks_free(), 662);
+    assert_eq!(disk.directory().len(), 2);
+  }
+
+  #[test]
+  fn directory_grows() {
+    let mut disk = D64::blank(b"FULL", b"02");
+    for i in 0..20 {
+      let name = format!("FILE{}", i);
+      disk.save_file(name.as_bytes(), &[i as u8], false).unwrap();
+    }
+    assert_eq!(disk.directory().len(), 20);
+    let entry = disk.find_file(b"FILE19").unwrap();
+    assert_eq!(disk.read_file(&entry).unwrap(), vec![19]);
+  }
+
+  #[test]
+  fn listing() {
+    let mut disk = D64::blank(b"LISTING", b"03");
+    disk.save_file(b"PROG", &[1, 8, 0, 0], false).unwrap();
+    let listing = disk.directory_listing();
+    assert_eq!(&listing[0..2], &[0x01, 0x04]);
+    // header line: link, line 0, reverse on, quote, name
+    assert_eq!(&listing[2..9], &[0x01, 0x01, 0x00, 0x00, 0x12, b'"', b'L']);
+    assert_eq!(&listing[(listing.len() - 2)..], &[0, 0]);
+    let text = String::from_utf8_lossy(&listing);
+    assert!(text.contains("   \"PROG\"             PRG"));
+    assert!(text.contains("BLOCKS FREE."));
+  }
+}
@@ -0,0 +1,288 @@
+use memmap::MemMap;
+use memmap::mos6510::cpu::CPU;
+use memmap::mos6510::memory::Memory;
+use d64::{D64, DiskError};
+
+// Default targets of the KERNAL's ILOAD ($0330) and ISAVE ($0332) vectors.
+// Trapping here rather than at $FFD5/$FFD8 leaves programs that install their
+// own loaders through the vectors untouched.
+pub const LOAD_ENTRY: u16 = 0xf4a5;
+pub const SAVE_ENTRY: u16 = 0xf5ed;
+
+const DEVICE_NUMBER: u8 = 8;
+
+// KERNAL zero page locations describing the current file
+const STATUS: u16 = 0x90;
+const FILENAME_LENGTH: u16 = 0xb7;
+const SECONDARY_ADDRESS: u16 = 0xb9;
+const DEVICE: u16 = 0xba;
+const FILENAME_POINTER: u16 = 0xbb;
+const SAVE_START: u16 = 0xc1;
+const LOAD_ADDRESS: u16 = 0xc3;
+const END_ADDRESS: u16 = 0xae;
+
+const STATUS_VERIFY_ERROR: u8 = 0x10;
+const STATUS_EOF: u8 = 0x40;
+
+// KERNAL error numbers, returned in A with carry set
+const ERROR_FILE_NOT_FOUND: u8 = 4;
+const ERROR_DEVICE_NOT_PRESENT: u8 = 5;
+const ERROR_MISSING_FILENAME: u8 = 8;
+
+/**
+ * Serves LOAD and SAVE for device 8 directly from a D64 image, without
+ * emulating the drive or the serial bus.
+ */
+pub struct DiskTrap {
+  pub image: Option<D64>,
+  // Set whenever a SAVE changes the image, so front-ends know to write it back
+  pub modified: bool,
+  // Cleared while a true drive is connected to the serial bus instead
+  pub enabled: bool,
+}
+
+impl DiskTrap {
+  pub fn new() -> DiskTrap {
+    return DiskTrap {
+      image: None,
+      modified: false,
+      enabled: true,
+    };
+  }
+
+  pub fn attach(&mut self, image: D64) {
+    self.image = Some(image);
+    self.modified = false;
+  }
+
+  pub fn detach(&mut self) -> Option<D64> {
+    self.image.take()
+  }
+
+  /**
+   * Called before each instruction. If the CPU is entering the KERNAL LOAD or
+   * SAVE routine for the drive, the whole operation is performed at once and
+   * the CPU returns to the caller. Returns true if a call was handled.
+   */
+  pub fn check(&mut self, cpu: &mut CPU, mem: &mut MemMap) -> bool {
+    let pc = cpu.pc;
+    if pc != LOAD_ENTRY && pc != SAVE_ENTRY {
+      return false;
+    }
+    if !self.enabled || self.image.is_none() || mem.get_byte(DEVICE) != DEVICE_NUMBER {
+      return false;
+    }
+    let result = if pc == LOAD_ENTRY {
+      self.load(cpu, mem)
+    } else {
+      self.save(mem)
+    };
+    match result {
+      Ok(()) => cpu.set_flag_carry(false),
+      Err(code) => {
+        cpu.acc = code;
+        cpu.set_flag_carry(true);
+      },
+    }
+    cpu.rts(mem);
+    cpu.pc = cpu.pc.wrapping_add(1);
+    return true;
+  }
+
+  fn read_filename(mem: &mut MemMap) -> Vec<u8> {
+    let len = mem.get_byte(FILENAME_LENGTH) as u16;
+    let ptr = (mem.get_byte(FILENAME_POINTER) as u16) | ((mem.get_byte(FILENAME_POINTER + 1) as u16) << 8);
+    let mut name = Vec::with_capacity(len as usize);
+    for i in 0..len {
+      name.push(mem.get_byte(ptr.wrapping_add(i)));
+    }
+    return name;
+  }
+
+  /**
+   * Split a drive filename like "@0:NAME" into the name and whether it asks
+   * to replace an existing file.
+   */
+  fn parse_filename(name: &[u8]) -> (Vec<u8>, bool) {
+    let replace = name.first() == Some(&b'@');
+    let name = if replace { &name[1..] } else { name };
+    let name = match name.iter().position(|&c| c == b':') {
+      Some(colon) => &name[(colon + 1)..],
+      None => name,
+    };
+    return (name.to_vec(), replace);
+  }
+
+  fn error_code(err: DiskError) -> u8 {
+    match err {
+      DiskError::FileNotFound => ERROR_FILE_NOT_FOUND,
+      DiskError::MissingFilename => ERROR_MISSING_FILENAME,
+      // The drive would report these on its error channel, which the KERNAL
+      // can't see; the closest KERNAL error is a failed device
+      _ => ERROR_DEVICE_NOT_PRESENT,
+    }
+  }
+
+  fn load(&mut self, cpu: &mut CPU, mem: &mut MemMap) -> Result<(), u8> {
+    let verify = cpu.acc != 0;
+    let (name, _) = DiskTrap::parse_filename(&DiskTrap::read_filename(mem));
+    if name.len() == 0 {
+      return Err(ERROR_MISSING_FILENAME);
+    }
+    let data = {
+      let image = self.image.as_ref().unwrap();
+      if name == b"$" {
+        image.directory_listing()
+      } else {
+        let entry = match image.find_file(&name) {
+          Some(entry) => entry,
+          None => return Err(ERROR_FILE_NOT_FOUND),
+        };
+        match image.read_file(&entry) {
+          Ok(data) => data,
+          Err(err) => return Err(DiskTrap::error_code(err)),
+        }
+      }
+    };
+    if data.len() < 2 {
+      return Err(ERROR_FILE_NOT_FOUND);
+    }
+    // Secondary address 0 relocates the file to the address passed in X/Y
+    let start = if mem.get_byte(SECONDARY_ADDRESS) == 0 {
+      (mem.get_byte(LOAD_ADDRESS) as u16) | ((mem.get_byte(LOAD_ADDRESS + 1) as u16) << 8)
+    } else {
+      (data[0] as u16) | ((data[1] as u16) << 8)
+    };
+    let mut status = STATUS_EOF;
+    let mut addr = start as usize;
+    for byte in data[2..].iter() {
+      if addr > 0xffff {
+        break;
+      }
+      if verify {
+        if mem.ram_rom.ram[addr] != *byte {
+          status |= STATUS_VERIFY_ERROR;
+        }
+      } else {
+        mem.ram_rom.ram[addr] = *byte;
+      }
+      addr += 1;
+    }
+    let end = addr as u16;
+    mem.set_byte(END_ADDRESS, (end & 0xff) as u8);
+    mem.set_byte(END_ADDRESS + 1, (end >> 8) as u8);
+    mem.set_byte(STATUS, status);
+    cpu.x = (end & 0xff) as u8;
+    cpu.y = (end >> 8) as u8;
+    return Ok(());
+  }
+
+  fn save(&mut self, mem: &mut MemMap) -> Result<(), u8> {
+    let (name, replace) = DiskTrap::parse_filename(&DiskTrap::read_filename(mem));
+    let start = (mem.get_byte(SAVE_START) as u16) | ((mem.get_byte(SAVE_START + 1) as u16) << 8);
+    let end = (mem.get_byte(END_ADDRESS) as u16) | ((mem.get_byte(END_ADDRESS + 1) as u16) << 8);
+    let mut data = vec![(start & 0xff) as u8, (start >> 8) as u8];
+    let mut addr = start;
+    while addr < end {
+      data.push(mem.ram_rom.ram[addr as usize]);
+      addr += 1;
+    }
+    let result = self.image.as_mut().unwrap().save_file(&name, &data, replace);
+    if let Err(err) = result {
+      return Err(DiskTrap::error_code(err));
+    }
+    self.modified = true;
+    mem.set_byte(STATUS, 0);
+    return Ok(());
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use memmap::MemMap;
+  use memmap::mos6510::cpu::CPU;
+  use memmap::mos6510::memory::Memory;
+  use d64::D64;
+  use disktrap::{DiskTrap, LOAD_ENTRY, SAVE_ENTRY};
+
+  fn call(cpu: &mut CPU, mem: &mut MemMap, entry: u16) {
+    // JSR from $1000
+    cpu.stack = 0xff;
+    cpu.push(mem, 0x10);
+    cpu.push(mem, 0x02);
+    cpu.pc = entry;
+  }
+
+  fn set_filename(mem: &mut MemMap, name: &[u8]) {
+    for i in 0..name.len() {
+      mem.set_byte(0x2000 + i as u16, name[i]);
+    }
+    mem.set_byte(0xb7, name.len() as u8);
+    mem.set_byte(0xbb, 0x00);
+    mem.set_byte(0xbc, 0x20);
+    mem.set_byte(0xba, 8);
+  }
+
+  #[test]
+  fn save_then_load() {
+    let mut cpu = CPU::new();
+    let mut mem = MemMap::new();
+    let mut trap = DiskTrap::new();
+    trap.attach(D64::blank(b"TRAP", b"01"));
+
+    for i in 0..4 {
+      mem.set_byte(0x3000 + i, 0xa0 + i as u8);
+    }
+    set_filename(&mut mem, b"@0:DATA");
+    mem.set_byte(0xc1, 0x00);
+    mem.set_byte(0xc2, 0x30);
+    mem.set_byte(0xae, 0x04);
+    mem.set_byte(0xaf, 0x30);
+    call(&mut cpu, &mut mem, SAVE_ENTRY);
+    assert!(trap.check(&mut cpu, &mut mem));
+    assert_eq!(cpu.pc, 0x1003);
+    assert_eq!(cpu.status & 1, 0);
+    assert!(trap.modified);
+
+    // LOAD"DATA",8,1 returns to the original address
+    set_filename(&mut mem, b"DATA");
+    mem.set_byte(0xb9, 1);
+    for i in 0..4 {
+      mem.set_byte(0x3000 + i, 0);
+    }
+    cpu.acc = 0;
+    call(&mut cpu, &mut mem, LOAD_ENTRY);
+    assert!(trap.check(&mut cpu, &mut mem));
+    assert_eq!(cpu.status & 1, 0);
+    assert_eq!(mem.get_byte(0x3003), 0xa3);
+    assert_eq!((cpu.x, cpu.y), (0x04, 0x30));
+
+    // LOAD"DATA",8 relocates to X/Y
+    mem.set_byte(0xb9, 0);
+    mem.set_byte(0xc3, 0x00);
+    mem.set_byte(0xc4, 0x40);
+    call(&mut cpu, &mut mem, LOAD_ENTRY);
+    assert!(trap.check(&mut cpu, &mut mem));
+    assert_eq!(mem.get_byte(0x4000), 0xa0);
+
+    set_filename(&mut mem, b"MISSING");
+    call(&mut cpu, &mut mem, LOAD_ENTRY);
+    assert!(trap.check(&mut cpu, &mut mem));
+    assert_eq!(cpu.status & 1, 1);
+    assert_eq!(cpu.acc, 4);
+  }
+
+  #[test]
+  fn other_devices() {
+    let mut cpu = CPU::new();
+    let mut mem = MemMap::new();
+    let mut trap = DiskTrap::new();
+    set_filename(&mut mem, b"$");
+    cpu.pc = LOAD_ENTRY;
+    // Nothing attached
+    assert!(!trap.check(&mut cpu, &mut mem));
+    trap.attach(D64::blank(b"TRAP", b"01"));
+    mem.set_byte(0xba, 1);
+    assert!(!trap.check(&mut cpu, &mut mem));
+  }
+}
@@ -0,0 +1,315 @@
+// Maps the host's keys onto the C64's keyboard and joysticks. Host keys are
+// named as in the DOM's KeyboardEvent.code, like "KeyA" or "ArrowUp", so the
+// native and browser front-ends share one set of bindings.
+//
+// In positional mode a host key presses the C64 keys bound to it, so keys
+// sit where they do on a C64, whatever is printed on them. In symbolic mode a
+// host key that types a symbol presses whatever types that symbol on the
+// C64, holding or releasing SHIFT to suit: " on a US keyboard is SHIFT and ',
+// and becomes SHIFT and 2. Letters, digits and keys without a symbol keep
+// their positional bindings.
+//
+// Keymap files have a binding on each line: the host key, "=", then the C64
+// keys and joystick switches it presses, separated by spaces. Nothing after
+// the "=" unbinds the key. A "mode positional" or "mode symbolic" line picks
+// the mode, and "#" starts a comment:
+//
+//   mode symbolic
+//   # CRSR UP is SHIFT and CRSR DOWN
+//   ArrowUp = RSHIFT CRSR_DOWN
+//   Numpad8 = JOY2_UP
+
+use std::collections::HashMap;
+use cia::{JOYSTICK_UP, JOYSTICK_DOWN, JOYSTICK_LEFT, JOYSTICK_RIGHT, JOYSTICK_FIRE};
+use typing::{KEY_LEFT_SHIFT, KEY_RIGHT_SHIFT, Modifier, ascii_to_petscii, key_for_petscii};
+
+// The names of the keys in the matrix, by index, followed by RESTORE
+const KEY_NAMES: [&str; 65] = [
+  "DEL", "RETURN", "CRSR_RIGHT", "F7", "F1", "F3", "F5", "CRSR_DOWN",
+  "3", "W", "A", "4", "Z", "S", "E", "LSHIFT",
+  "5", "R", "D", "6", "C", "F", "T", "X",
+  "7", "Y", "G", "8", "B", "H", "U", "V",
+  "9", "I", "J", "0", "M", "K", "O", "N",
+  "+", "P", "L", "-", ".", ":", "@", ",",
+  "POUND", "*", ";", "HOME", "RSHIFT", "=", "UP_ARROW", "/",
+  "1", "LEFT_ARROW", "CTRL", "2", "SPACE", "COMMODORE", "Q", "RUN_STOP",
+  "RESTORE",
+];
+
+const JOYSTICK_NAMES: [(&str, u8); 5] = [
+  ("UP", JOYSTICK_UP),
+  ("DOWN", JOYSTICK_DOWN),
+  ("LEFT", JOYSTICK_LEFT),
+  ("RIGHT", JOYSTICK_RIGHT),
+  ("FIRE", JOYSTICK_FIRE),
+];
+
+const DEFAULT_KEYMAP: &str = include_str!("keymap.txt");
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mode {
+  Positional,
+  Symbolic,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Target {
+  // An index in the keyboard matrix, or KEY_RESTORE
+  Key(u8),
+  // A switch on control port 0 or 1, as one of the JOYSTICK_ bits
+  Joystick(usize, u8),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum KeymapError {
+  // The line number, counting from 1, of a line that couldn't be read
+  InvalidLine(usize),
+}
+
+// A host key being held down, and what it pressed. A symbol typed in
+// symbolic mode also decides whether SHIFT is held.
+struct Held {
+  code: String,
+  targets: Vec<Target>,
+  shift: Option<bool>,
+}
+
+pub struct Keymap {
+  pub mode: Mode,
+  bindings: HashMap<String, Vec<Target>>,
+  held: Vec<Held>,
+}
+
+impl Keymap {
+  /**
+   * The default positional keymap
+   */
+  pub fn new() -> Keymap {
+    let mut keymap = Keymap {
+      mode: Mode::Positional,
+      bindings: HashMap::new(),
+      held: Vec::new(),
+    };
+    keymap.load(DEFAULT_KEYMAP).unwrap();
+    return keymap;
+  }
+
+  /**
+   * Load a keymap file. Its bindings replace the current ones for the keys
+   * it names, and the rest stay. Returns how many bindings were read, and
+   * changes nothing if any line is invalid.
+   */
+  pub fn load(&mut self, text: &str) -> Result<usize, KeymapError> {
+    let mut mode = self.mode;
+    let mut bindings = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+      let line = line.splitn(2, '#').next().unwrap().trim();
+      if line.len() == 0 {
+        continue;
+      }
+      let invalid = KeymapError::InvalidLine(index + 1);
+      let mut parts = line.splitn(2, '=');
+      let code = parts.next().unwrap().trim();
+      let targets = match parts.next() {
+        Some(targets) => targets,
+        None => {
+          let words: Vec<&str> = line.split_whitespace().collect();
+          mode = match (words.len(), words[0], words.get(1)) {
+            (2, "mode", Some(&"positional")) => Mode::Positional,
+            (2, "mode", Some(&"symbolic")) => Mode::Symbolic,
+            _ => return Err(invalid),
+          };
+          continue;
+        },
+      };
+      if code.len() == 0 || code.contains(char::is_whitespace) {
+        return Err(invalid);
+      }
+      let mut parsed = Vec::new();
+      for name in targets.split_whitespace() {
+        match parse_target(name) {
+          Some(target) => parsed.push(target),
+          None => return Err(invalid),
+        }
+      }
+      bindings.push((String::from(code), parsed));
+    }
+    self.mode = mode;
+    let count = bindings.len();
+    for (code, targets) in bindings {
+      if targets.len() > 0 {
+        self.bindings.insert(code, targets);
+      } else {
+        self.bindings.remove(&code);
+      }
+    }
+    return Ok(count);
+  }
+
+  /**
+   * What a host key is bound to, if anything
+   */
+  pub fn binding(&self, code: &str) -> Option<&[Target]> {
+    self.bindings.get(code).map(|targets| &targets[..])
+  }
+
+  /**
+   * A host key was pressed, typing `text` if it's a character key. Returns
+   * the keys and switches to release and then press, in that order. Repeats
+   * from a key that's already down change nothing.
+   */
+  pub fn key_down(&mut self, code: &str, text: Option<char>) -> Vec<(Target, bool)> {
+    if self.held.iter().any(|held| held.code == code) {
+      return Vec::new();
+    }
+    let symbol = match (self.mode, text) {
+      (Mode::Symbolic, Some(c)) => key_for_symbol(c),
+      _ => None,
+    };
+    let held = match symbol {
+      Some((key, shift)) => Held {
+        code: String::from(code),
+        targets: vec![Target::Key(key)],
+        shift: Some(shift),
+      },
+      None => match self.bindings.get(code) {
+        Some(targets) => Held {
+          code: String::from(code),
+          targets: targets.clone(),
+          shift: None,
+        },
+        None => return Vec::new(),
+      },
+    };
+    return self.change(|keymap| keymap.held.push(held));
+  }
+
+  /**
+   * A host key was released, letting go of whatever it pressed
+   */
+  pub fn key_up(&mut self, code: &str) -> Vec<(Target, bool)> {
+    return self.change(|keymap| keymap.held.retain(|held| held.code != code));
+  }
+
+  /**
+   * Let go of everything, for when the host stops sending key events, like
+   * when the window loses focus
+   */
+  pub fn release_all(&mut self) -> Vec<(Target, bool)> {
+    return self.change(|keymap| keymap.held.clear());
+  }
+
+  // Everything the held keys are pressing. The last symbol typed decides
+  // whether SHIFT is down.
+  fn pressed(&self) -> Vec<Target> {
+    let mut pressed: Vec<Target> = Vec::new();
+    for held in self.held.iter() {
+      for target in held.targets.iter() {
+        if !pressed.contains(target) {
+          pressed.push(*target);
+        }
+      }
+    }
+    if let Some(shift) = self.held.iter().rev().filter_map(|held| held.shift).next() {
+      pressed.retain(|&t| t != Target::Key(KEY_LEFT_SHIFT) && t != Target::Key(KEY_RIGHT_SHIFT));
+      if shift {
+        pressed.push(Target::Key(KEY_LEFT_SHIFT));
+      }
+    }
+    return pressed;
+  }
+
+  fn change<F: FnOnce(&mut Keymap)>(&mut self, f: F) -> Vec<(Target, bool)> {
+    let before = self.pressed();
+    f(self);
+    let after = self.pressed();
+    let mut changes: Vec<(Target, bool)> = before.iter()
+      .filter(|target| !after.contains(target))
+      .map(|&target| (target, false))
+      .collect();
+    changes.extend(after.iter().filter(|target| !before.contains(target)).map(|&target| (target, true)));
+    return changes;
+  }
+}
+
+fn parse_target(name: &str) -> Option<Target> {
+  if let Some(index) = KEY_NAMES.iter().position(|&key| key == name) {
+    return Some(Target::Key(index as u8));
+  }
+  let port = if name.starts_with("JOY1_") {
+    0
+  } else if name.starts_with("JOY2_") {
+    1
+  } else {
+    return None;
+  };
+  return JOYSTICK_NAMES.iter()
+    .find(|&&(switch, _)| switch == &name[5..])
+    .map(|&(_, bits)| Target::Joystick(port, bits));
+}
+
+// The key, and whether SHIFT goes with it, that types a symbol
+fn key_for_symbol(c: char) -> Option<(u8, bool)> {
+  if c.is_alphanumeric() || c.is_whitespace() || c.is_control() {
+    return None;
+  }
+  match ascii_to_petscii(c).and_then(key_for_petscii) {
+    Some((key, Modifier::None)) => Some((key, false)),
+    Some((key, Modifier::Shift)) => Some((key, true)),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use cia::{JOYSTICK_UP, JOYSTICK_FIRE, KEY_RESTORE};
+  use keymap::{Keymap, KeymapError, Mode, Target};
+
+  #[test]
+  fn positional() {
+    let mut keymap = Keymap::new();
+    assert_eq!(keymap.binding("KeyA"), Some(&[Target::Key(10)][..]));
+    assert_eq!(keymap.binding("PageUp"), Some(&[Target::Key(KEY_RESTORE)][..]));
+    assert_eq!(keymap.binding("End"), Some(&[Target::Key(7)][..]));
+    assert_eq!(keymap.binding("ArrowUp"), Some(&[Target::Joystick(1, JOYSTICK_UP)][..]));
+    assert_eq!(keymap.binding("Numpad0"), Some(&[Target::Joystick(0, JOYSTICK_FIRE)][..]));
+    // F2 is SHIFT and F1
+    assert_eq!(keymap.key_down("F2", None), vec![(Target::Key(15), true), (Target::Key(4), true)]);
+    assert_eq!(keymap.key_down("F2", None), vec![]);
+    // Symbols are ignored
+    assert_eq!(keymap.key_down("Quote", Some('"')), vec![(Target::Key(50), true)]);
+    assert_eq!(keymap.key_up("F2"), vec![(Target::Key(15), false), (Target::Key(4), false)]);
+    assert_eq!(keymap.release_all(), vec![(Target::Key(50), false)]);
+    assert_eq!(keymap.key_down("Pause", None), vec![]);
+  }
+
+  #[test]
+  fn symbolic() {
+    let mut keymap = Keymap::new();
+    keymap.mode = Mode::Symbolic;
+    assert_eq!(keymap.key_down("ShiftLeft", None), vec![(Target::Key(15), true)]);
+    // " is SHIFT and 2, and SHIFT is already down
+    assert_eq!(keymap.key_down("Quote", Some('"')), vec![(Target::Key(59), true)]);
+    assert_eq!(keymap.key_up("Quote"), vec![(Target::Key(59), false)]);
+    // @ has a key of its own, so SHIFT is let go while it's held
+    assert_eq!(keymap.key_down("Digit2", Some('@')), vec![(Target::Key(15), false), (Target::Key(46), true)]);
+    assert_eq!(keymap.key_up("Digit2"), vec![(Target::Key(46), false), (Target::Key(15), true)]);
+    // Letters stay where they are, so SHIFT still gives the graphics
+    assert_eq!(keymap.key_down("KeyA", Some('A')), vec![(Target::Key(10), true)]);
+  }
+
+  #[test]
+  fn load() {
+    let mut keymap = Keymap::new();
+    let text = "# A joystick in port 1\nmode symbolic\nKeyW = JOY1_UP\nKeyA =\n\nKeyS = LSHIFT RETURN  # shifted RETURN\n";
+    assert_eq!(keymap.load(text), Ok(3));
+    assert_eq!(keymap.mode, Mode::Symbolic);
+    assert_eq!(keymap.binding("KeyW"), Some(&[Target::Joystick(0, 1)][..]));
+    assert_eq!(keymap.binding("KeyA"), None);
+    assert_eq!(keymap.binding("KeyS"), Some(&[Target::Key(15), Target::Key(1)][..]));
+    assert_eq!(keymap.binding("KeyD"), Some(&[Target::Key(18)][..]));
+    assert_eq!(keymap.load("KeyD = D\nKeyF = FOO\n"), Err(KeymapError::InvalidLine(2)));
+    assert_eq!(keymap.load("mode sideways"), Err(KeymapError::InvalidLine(1)));
+    assert_eq!(keymap.binding("KeyD"), Some(&[Target::Key(18)][..]));
+  }
+}
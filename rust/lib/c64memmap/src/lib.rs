@@ -1,7 +1,22 @@
 #![feature(box_syntax)]
 
 pub mod memmap;
-mod cia;
+pub mod basic;
+pub mod cartridge;
+pub mod d64;
+pub mod disktrap;
+pub mod keymap;
+pub mod prg;
+pub mod cia;
+pub mod pla;
+pub mod model;
+pub mod monitor;
+pub mod mouse;
+pub mod palette;
 mod ramrom;
-mod sid;
+pub mod reu;
+pub mod sid;
+pub mod snapshot;
+pub mod tape;
+pub mod typing;
 mod vic;
\ No newline at end of file
@@ -1,148 +1,84 @@
-extern crate mos6510;
+pub extern crate mos6510;
 
 use cia::CIA;
 use ramrom::RamRom;
 use sid::SID;
+use tape::Datasette;
+use pla;
+use pla::Bank;
+use cartridge::Cartridge;
+use reu::Reu;
 use vic::VIC;
+pub use vic::{FRAME_WIDTH, FRAME_HEIGHT};
+use model::Model;
+use monitor::Checkpoint;
+use mouse::Mouse;
+use cia::{JOYSTICK_FIRE, JOYSTICK_UP};
 use self::mos6510::memory::Memory;
+use self::mos6510::instructions::{decode, operand_length};
 
 pub struct MemMap {
   pub ram_rom: RamRom,
   pub cia: CIA,
   pub sid: SID,
   pub vic: VIC,
+  pub datasette: Datasette,
+  pub cartridge: Option<Cartridge>,
+  pub reu: Option<Reu>,
+  // Cycles left before the CPU gets the bus back from a DMA transfer
+  dma_stall: u32,
+
+  // Potentiometer readings for the X and Y lines of each control port
+  pots: [[u8; 2]; 2],
+  // A 1351 mouse takes the place of the paddles on its port
+  pub mouse: Option<Mouse>,
+
+  // Ranges the monitor is watching for loads and stores, and the first
+  // access to one since the last check
+  watches: Vec<Checkpoint>,
+  watch_hit: Option<(u16, bool)>,
+  // The address and length of the instruction the CPU is running. Fetching
+  // it isn't a data read, so load watches skip it.
+  instruction: (u16, u16),
 }
 
 impl Memory for MemMap {
   fn get_byte(&mut self, addr: u16) -> u8 {
-    let port = self.ram_rom.ram[1];
-    if addr < 0xa000 { // 0x0000 - 0x9fff
-      return self.ram_rom.ram[addr as usize];
+    if self.watches.len() > 0 {
+      self.check_watches(addr, false);
     }
-    if addr < 0xc000 { // 0xa000 - 0xbfff
-      // BASIC ROM or RAM
-      if port & 3 == 3 {
-        return self.ram_rom.basic[(addr - 0xa000) as usize];
-      }
-      return self.ram_rom.ram[addr as usize];
+    if addr == 1 {
+      return self.read_processor_port();
     }
-    if addr < 0xd000 { // 0xc000 - 0xcfff
-      return self.ram_rom.ram[addr as usize];
-    }
-    if addr < 0xe000 { // 0xd000 - 0xdfff
-      // RAM, CHARGEN ROM, I/O
-      if port & 3 > 0 {
-        if port & 4 == 0 {
-          return self.ram_rom.char_gen[(addr - 0xd000) as usize];
-        }
-        if port & 4 == 4 {
-          // I/O
-          if addr < 0xd400 {
-            // VIC II
-            return self.vic.get_byte(addr - 0xd000);
-          }
-          if addr < 0xd800 {
-            // SID
-            return self.sid.get_byte(addr - 0xd400);
-          }
-          if addr < 0xdc00 {
-            // Color RAM
-            return self.ram_rom.color_ram[(addr - 0xd800) as usize];
-          }
-          if addr < 0xdd00 {
-            // CIA 1
-            return self.cia.get_byte(addr - 0xdc00);
-          }
-          if addr < 0xde00 {
-            // CIA 2
-            return self.cia.get_byte(addr - 0xdc00);
-          }
-          if addr < 0xdf00 {
-            // I/O 1
-            return 0;
-          }
-          // I/O 2
-          return 0;
-        }
-      }
-      return self.ram_rom.ram[addr as usize];
-    }
-    // 0xe000 - 0xffff
-    // KERNAL ROM or RAM
-    if port & 2 == 2 {
-      return self.ram_rom.kernal[(addr - 0xe000) as usize];
-    }
-    return self.ram_rom.ram[addr as usize];
+    let bank = self.bank_at(addr);
+    self.read_bank(bank, addr, false)
   }
 
   fn set_byte(&mut self, addr: u16, value: u8) {
-    let port = self.ram_rom.ram[1];
-    if addr < 0xa000 { // 0x0000 - 0x9fff
-      self.ram_rom.ram[addr as usize] = value;
-      return;
-    }
-    if addr < 0xc000 { // 0xa000 - 0xbfff
-      // BASIC ROM or RAM
-      if port & 3 == 3 {
-        return;
-      }
-      self.ram_rom.ram[addr as usize] = value;
-      return;
-    }
-    if addr < 0xd000 { // 0xc000 - 0xcfff
-      self.ram_rom.ram[addr as usize] = value;
-      return;
+    if self.watches.len() > 0 {
+      self.check_watches(addr, true);
     }
-    if addr < 0xe000 { // 0xd000 - 0xdfff
-      // RAM, CHARGEN ROM, I/O
-      if port & 3 > 0 {
-        if port & 4 == 0 {
-          return;
-        }
-        if port & 4 == 4 {
-          // I/O
-          if addr < 0xd400 {
-            // VIC II
-            self.vic.set_byte(addr - 0xd000, value);
+    let mode = self.pla_mode();
+    match pla::bank(mode, addr) {
+      Bank::Io => self.set_io_byte(addr, value),
+      Bank::RomL | Bank::RomH => {
+        if let Some(ref mut cart) = self.cartridge {
+          if addr < 0xa000 && cart.write_roml(addr & 0x1fff, value) {
             return;
           }
-          if addr < 0xd800 {
-            // SID
-            self.sid.set_byte(addr - 0xd400, value);
-            return;
-          }
-          if addr < 0xdc00 {
-            // Color RAM
-            self.ram_rom.color_ram[(addr - 0xd800) as usize] = value;
-            return;
-          }
-          if addr < 0xdd00 {
-            // CIA 1
-            self.cia.set_byte(addr - 0xdc00, value);
-            return;
-          }
-          if addr < 0xde00 {
-            // CIA 2
-            self.cia.set_byte(addr - 0xdc00, value);
-            return;
-          }
-          if addr < 0xdf00 {
-            // I/O 1
-            return;
-          }
-          // I/O 2
-          return;
         }
-      }
-      self.ram_rom.ram[addr as usize] = value;
-      return;
+        // In Ultimax mode there is no RAM behind the cartridge
+        if !pla::is_ultimax(mode) {
+          self.ram_rom.ram[addr as usize] = value;
+        }
+      },
+      Bank::Open => (),
+      // Writes to ROM land in the RAM underneath
+      _ => self.ram_rom.ram[addr as usize] = value,
     }
-    // 0xe000 - 0xffff
-    // KERNAL ROM or RAM
-    if port & 2 == 2 {
-      return;
+    if addr == 0xff00 && self.reu.as_ref().map_or(false, |reu| reu.write_ff00()) {
+      self.run_reu_transfer();
     }
-    self.ram_rom.ram[addr as usize] = value;
   }
 }
 
@@ -153,6 +89,18 @@ impl MemMap {
       cia: CIA::new(),
       sid: SID::new(),
       vic: VIC::new(),
+      datasette: Datasette::new(),
+      cartridge: None,
+      reu: None,
+      dma_stall: 0,
+
+      // Nothing connected, the inputs read as fully charged
+      pots: [[0xff, 0xff], [0xff, 0xff]],
+      mouse: None,
+
+      watches: Vec::new(),
+      watch_hit: None,
+      instruction: (0, 0),
     };
     // Init directional and port bits
     map.set_byte(0, 0x2f);
@@ -160,9 +108,641 @@ impl MemMap {
     return map;
   }
 
+  /**
+   * Pull the RESET line low. The CIAs, SID and cartridge return to their
+   * power-on state, and the processor port lines become inputs. The VIC-II
+   * has no reset input, and RAM keeps its contents.
+   */
+  pub fn reset(&mut self) {
+    self.cia.reset();
+    self.sid.reset();
+    self.ram_rom.ram[0] = 0;
+    if let Some(ref mut cartridge) = self.cartridge {
+      cartridge.reset();
+    }
+    if let Some(ref mut reu) = self.reu {
+      reu.reset();
+    }
+    self.dma_stall = 0;
+  }
+
+  /**
+   * Switch the timing of the VIC-II, CIAs and SID to a PAL or NTSC machine.
+   */
+  pub fn set_model(&mut self, model: Model) {
+    self.vic.set_model(model);
+    self.cia.set_model(model);
+    self.sid.set_clock_rate(model.clock_rate());
+  }
+
+  /**
+   * Run the VIC-II for the given number of CPU cycles, returning true while
+   * it is asserting the IRQ line.
+   */
+  pub fn update_vic(&mut self, cycles: u8) -> bool {
+    let light_pen = self.cia.light_pen_line();
+    self.vic.set_light_pen_line(light_pen);
+    let bank = self.cia.get_vic_bank();
+    self.vic.update_raster(cycles, &self.ram_rom, bank);
+    self.vic.interrupt_pending()
+  }
+
+  /**
+   * The PLA sees the LORAM, HIRAM and CHAREN lines rather than the port
+   * register, and lines set as inputs are pulled high.
+   */
+  fn pla_mode(&self) -> usize {
+    let (exrom, game) = match self.cartridge {
+      Some(ref cart) => (cart.exrom, cart.game),
+      None => (true, true),
+    };
+    let lines = self.ram_rom.ram[1] | !self.ram_rom.ram[0];
+    pla::mode(exrom, game, lines)
+  }
+
+  /**
+   * Which bank the CPU currently sees at an address
+   */
+  pub fn bank_at(&self, addr: u16) -> Bank {
+    pla::bank(self.pla_mode(), addr)
+  }
+
+  /**
+   * Read what the CPU would see at an address, without the side effects a
+   * read has on the I/O chips, like acknowledging interrupts. For debuggers.
+   */
+  pub fn peek(&mut self, addr: u16) -> u8 {
+    if addr == 1 {
+      return self.read_processor_port();
+    }
+    let bank = self.bank_at(addr);
+    self.read_bank(bank, addr, true)
+  }
+
+  /**
+   * Read an address from a particular bank, whether or not it is visible to
+   * the CPU, without side effects.
+   */
+  pub fn peek_bank(&mut self, bank: Bank, addr: u16) -> u8 {
+    self.read_bank(bank, addr, true)
+  }
+
+  /**
+   * Write to an address in a particular bank. Writes to a ROM bank change
+   * the ROM itself.
+   */
+  pub fn poke_bank(&mut self, bank: Bank, addr: u16, value: u8) {
+    match bank {
+      Bank::Basic => self.ram_rom.basic[(addr & 0x1fff) as usize] = value,
+      Bank::Kernal => self.ram_rom.kernal[(addr & 0x1fff) as usize] = value,
+      Bank::CharRom => self.ram_rom.char_gen[(addr & 0x0fff) as usize] = value,
+      Bank::Io => self.set_io_byte(addr, value),
+      Bank::Open => (),
+      _ => self.ram_rom.ram[addr as usize] = value,
+    }
+  }
+
+  fn read_bank(&mut self, bank: Bank, addr: u16, peek: bool) -> u8 {
+    match bank {
+      Bank::Ram => self.ram_rom.ram[addr as usize],
+      Bank::Basic => self.ram_rom.basic[(addr & 0x1fff) as usize],
+      Bank::Kernal => self.ram_rom.kernal[(addr & 0x1fff) as usize],
+      Bank::CharRom => self.ram_rom.char_gen[(addr & 0x0fff) as usize],
+      Bank::Io => self.get_io_byte(addr, peek),
+      Bank::RomL => match self.cartridge {
+        Some(ref cart) => cart.read_roml(addr & 0x1fff),
+        None => 0,
+      },
+      Bank::RomH => match self.cartridge {
+        Some(ref cart) => cart.read_romh(addr & 0x1fff),
+        None => 0,
+      },
+      Bank::Open => 0,
+    }
+  }
+
+  /**
+   * Replace the list of watchpoints checked on every load and store
+   */
+  pub fn set_watches(&mut self, watches: Vec<Checkpoint>) {
+    self.watches = watches;
+    self.watch_hit = None;
+  }
+
+  /**
+   * The address of the first watched access since the last call, and
+   * whether it was a store
+   */
+  pub fn take_watch_hit(&mut self) -> Option<(u16, bool)> {
+    self.watch_hit.take()
+  }
+
+  /**
+   * Mark the instruction at `pc` as the one the CPU is about to run, so
+   * fetching its opcode and operand doesn't trigger load watches
+   */
+  pub fn begin_instruction(&mut self, pc: u16) {
+    if self.watches.len() > 0 {
+      let (_, mode) = decode(self.peek(pc));
+      self.instruction = (pc, operand_length(mode) + 1);
+    }
+  }
+
+  pub fn end_instruction(&mut self) {
+    self.instruction = (0, 0);
+  }
+
+  fn check_watches(&mut self, addr: u16, store: bool) {
+    if !store && addr.wrapping_sub(self.instruction.0) < self.instruction.1 {
+      return;
+    }
+    if self.watch_hit.is_none() && self.watches.iter().any(|w| w.matches_access(addr, store)) {
+      self.watch_hit = Some((addr, store));
+    }
+  }
+
+  fn get_io_byte(&mut self, addr: u16, peek: bool) -> u8 {
+    if addr < 0xd400 {
+      // VIC II
+      if peek {
+        return self.vic.peek(addr - 0xd000);
+      }
+      return self.vic.get_byte(addr - 0xd000);
+    }
+    if addr < 0xd800 {
+      // SID
+      return self.sid.get_byte(addr - 0xd400);
+    }
+    if addr < 0xdc00 {
+      // Color RAM
+      return self.ram_rom.color_ram[(addr - 0xd800) as usize];
+    }
+    if addr < 0xdd00 {
+      // CIA 1
+      if peek {
+        return self.cia.peek(addr - 0xdc00);
+      }
+      return self.cia.get_byte(addr - 0xdc00);
+    }
+    if addr < 0xde00 {
+      // CIA 2
+      if peek {
+        return self.cia.peek(addr - 0xdc00);
+      }
+      return self.cia.get_byte(addr - 0xdc00);
+    }
+    // I/O 1 and I/O 2. An REU takes over I/O 2 from the cartridge.
+    if addr >= 0xdf00 {
+      if let Some(ref mut reu) = self.reu {
+        return reu.read(addr, peek);
+      }
+    }
+    match self.cartridge {
+      Some(ref cart) => cart.read_io(addr),
+      None => 0,
+    }
+  }
+
+  fn set_io_byte(&mut self, addr: u16, value: u8) {
+    if addr < 0xd400 {
+      // VIC II
+      self.vic.set_byte(addr - 0xd000, value);
+      return;
+    }
+    if addr < 0xd800 {
+      // SID
+      self.sid.set_byte(addr - 0xd400, value);
+      return;
+    }
+    if addr < 0xdc00 {
+      // Color RAM
+      self.ram_rom.color_ram[(addr - 0xd800) as usize] = value;
+      return;
+    }
+    if addr < 0xdd00 {
+      // CIA 1
+      self.cia.set_byte(addr - 0xdc00, value);
+      return;
+    }
+    if addr < 0xde00 {
+      // CIA 2
+      self.cia.set_byte(addr - 0xdc00, value);
+      return;
+    }
+    // I/O 1 and I/O 2
+    if addr >= 0xdf00 && self.reu.is_some() {
+      if self.reu.as_mut().map_or(false, |reu| reu.write(addr, value)) {
+        self.run_reu_transfer();
+      }
+      return;
+    }
+    if let Some(ref mut cart) = self.cartridge {
+      cart.write_io(addr, value);
+    }
+  }
+
+  /**
+   * The REU reads and writes memory through the bus, so it is taken out of
+   * the map while it runs.
+   */
+  fn run_reu_transfer(&mut self) {
+    if let Some(mut reu) = self.reu.take() {
+      self.dma_stall += reu.transfer(self);
+      self.reu = Some(reu);
+    }
+  }
+
+  pub fn reu_interrupt_pending(&self) -> bool {
+    self.reu.as_ref().map_or(false, |reu| reu.interrupt_pending())
+  }
+
+  /**
+   * True while a DMA transfer is holding the CPU
+   */
+  pub fn dma_stalled(&self) -> bool {
+    self.dma_stall > 0
+  }
+
+  /**
+   * Take up to 255 cycles of a DMA transfer, for the rest of the machine to
+   * run while the CPU waits.
+   */
+  pub fn take_dma_stall(&mut self) -> u8 {
+    let cycles = if self.dma_stall > 0xff { 0xff } else { self.dma_stall };
+    self.dma_stall -= cycles;
+    return cycles as u8;
+  }
+
+  /**
+   * Bits of the processor port set as outputs read back the value written.
+   * Inputs read their pull-ups, except bit 4, which senses the Datasette
+   * buttons, and bit 5, which the motor circuit holds low. Bits 6 and 7 are
+   * not connected, and keep the last value written.
+   */
+  fn read_processor_port(&self) -> u8 {
+    let ddr = self.ram_rom.ram[0];
+    let port = self.ram_rom.ram[1];
+    let inputs = (port & 0xc0) | 0x0f | self.datasette.sense();
+    return (port & ddr) | (inputs & !ddr);
+  }
+
+  /**
+   * The cassette motor runs while bit 5 of the processor port is an output
+   * driven low.
+   */
+  pub fn tape_motor_on(&self) -> bool {
+    self.ram_rom.ram[0] & 0x20 != 0 && self.ram_rom.ram[1] & 0x20 == 0
+  }
+
+  /**
+   * Play the tape for the given number of CPU cycles, returning true while
+   * the CIA 1 FLAG interrupt is asserting the IRQ line.
+   */
+  pub fn update_tape(&mut self, cycles: u8) -> bool {
+    let motor = self.tape_motor_on();
+    if self.datasette.update(cycles, motor) {
+      self.cia.trigger_flag();
+    }
+    self.cia.flag_interrupt_pending()
+  }
+
+  /**
+   * Run the SID, and the mouse if one is plugged in, for the given number of
+   * CPU cycles.
+   */
+  pub fn update_sid(&mut self, cycles: u8) {
+    if let Some(ref mut mouse) = self.mouse {
+      mouse.update(cycles);
+    }
+    let (x, y) = match self.cia.get_paddle_port() {
+      Some(port) => match self.mouse {
+        Some(ref mouse) if mouse.port == port => mouse.pots(),
+        _ => (self.pots[port][0], self.pots[port][1]),
+      },
+      None => (0xff, 0xff),
+    };
+    self.sid.set_pots(x, y);
+    self.sid.clock(cycles);
+  }
+
+  /**
+   * Set the position of one of the two paddles on a control port (0 or 1).
+   * Paddles report 0 at one end of their travel and 255 at the other.
+   */
+  pub fn set_paddle(&mut self, port: usize, paddle: usize, value: u8) {
+    if port > 1 || paddle > 1 {
+      return;
+    }
+    self.pots[port][paddle] = value;
+  }
+
+  /**
+   * Plug a 1351 mouse into a control port (0 or 1), or unplug it with None
+   */
+  pub fn attach_mouse(&mut self, port: Option<usize>) {
+    if let Some(mouse) = self.mouse.take() {
+      self.cia.joystick_up(mouse.port, JOYSTICK_FIRE | JOYSTICK_UP);
+    }
+    self.mouse = match port {
+      Some(port) if port <= 1 => Some(Mouse::new(port)),
+      _ => None,
+    };
+  }
+
+  /**
+   * Move the mouse to counts on each axis. A mouse is plugged into the port
+   * first if there isn't one there, starting at that position. The counts
+   * catch up at the rate a real mouse can be read.
+   */
+  pub fn set_mouse_position(&mut self, port: usize, x: u16, y: u16) {
+    if port > 1 {
+      return;
+    }
+    if self.mouse.as_ref().map_or(true, |mouse| mouse.port != port) {
+      self.attach_mouse(Some(port));
+      if let Some(ref mut mouse) = self.mouse {
+        mouse.place(x, y);
+      }
+      return;
+    }
+    if let Some(ref mut mouse) = self.mouse {
+      mouse.move_to(x, y);
+    }
+  }
+
+  /**
+   * Move the mouse by some number of counts, with y growing down the screen
+   */
+  pub fn move_mouse(&mut self, x: i32, y: i32) {
+    if let Some(ref mut mouse) = self.mouse {
+      mouse.move_by(x, y);
+    }
+  }
+
+  pub fn set_mouse_buttons(&mut self, left: bool, right: bool) {
+    let port = match self.mouse {
+      Some(ref mouse) => mouse.port,
+      None => return,
+    };
+    for &(pressed, bits) in [(left, JOYSTICK_FIRE), (right, JOYSTICK_UP)].iter() {
+      if pressed {
+        self.cia.joystick_down(port, bits);
+      } else {
+        self.cia.joystick_up(port, bits);
+      }
+    }
+  }
+
+  /**
+   * The last frame the VIC-II completed, FRAME_WIDTH by FRAME_HEIGHT pixels
+   * of color indexes, including the visible part of the border
+   */
+  pub fn frame(&self) -> &[u8] {
+    self.vic.frame()
+  }
+
+  pub fn frame_count(&self) -> u32 {
+    self.vic.frame_count()
+  }
+
+  pub fn get_vic_byte(&self, offset: u16) -> u8 {
+    let bank = self.cia.get_vic_bank();
+    self.ram_rom.vic_get_byte(bank, offset)
+  }
+
+  pub fn screen_ptr(&mut self) -> *mut u8 {
+    let bank = self.cia.get_vic_bank();
+    let offset = self.vic.get_screen_offset();
+    self.ram_rom.vic_ptr(bank, offset)
+  }
+
+  pub fn char_ptr(&mut self) -> *mut u8 {
+    let bank = self.cia.get_vic_bank();
+    let offset = self.vic.get_char_offset();
+    self.ram_rom.vic_ptr(bank, offset)
+  }
+
+  pub fn bitmap_ptr(&mut self) -> *mut u8 {
+    let bank = self.cia.get_vic_bank();
+    let offset = self.vic.get_bitmap_offset();
+    self.ram_rom.vic_ptr(bank, offset)
+  }
+
   pub fn set_basic_rom(&mut self, bytes: Vec<u8>, offset: usize) {
     for i in 0..bytes.len() {
       self.ram_rom.basic[i + offset] = bytes[i];
     }
   }
+}
+
+#[cfg(test)]
+mod tests {
+  use memmap::MemMap;
+  use memmap::mos6510::memory::Memory;
+  use cia::JOYSTICK_FIRE;
+  use sid::POT_PERIOD;
+  use tape::Tap;
+  use cartridge::Cartridge;
+
+  #[test]
+  fn vic_char_rom_shadow() {
+    let mut mem = MemMap::new();
+    mem.ram_rom.char_gen[0x10] = 0x3c;
+    mem.ram_rom.ram[0x1010] = 0xaa;
+    mem.ram_rom.ram[0x5010] = 0x55;
+    mem.ram_rom.ram[0x9010] = 0x99;
+    // Bank 0 sees the character ROM at $1000
+    assert_eq!(mem.get_vic_byte(0x1010), 0x3c);
+    // The CPU still sees RAM
+    assert_eq!(mem.get_byte(0x1010), 0xaa);
+    // Bank 1 has no shadow
+    mem.set_byte(0xdd02, 0x03);
+    mem.set_byte(0xdd00, 0x02);
+    assert_eq!(mem.get_vic_byte(0x1010), 0x55);
+    // Bank 2 has the shadow at $9000
+    mem.set_byte(0xdd00, 0x01);
+    assert_eq!(mem.get_vic_byte(0x1010), 0x3c);
+  }
+
+  // Run the SID through a whole measurement of the POT inputs
+  fn measure_pots(mem: &mut MemMap) {
+    for _ in 0..(POT_PERIOD / 128) {
+      mem.update_sid(128);
+    }
+  }
+
+  #[test]
+  fn paddles() {
+    let mut mem = MemMap::new();
+    mem.set_paddle(0, 0, 0x20);
+    mem.set_paddle(0, 1, 0x40);
+    mem.set_paddle(1, 0, 0x60);
+    mem.set_byte(0xdc02, 0xff);
+    mem.set_byte(0xdc00, 0x7f);
+    measure_pots(&mut mem);
+    assert_eq!(mem.get_byte(0xd419), 0x20);
+    assert_eq!(mem.get_byte(0xd41a), 0x40);
+    // The registers only change once the next measurement is done
+    mem.set_byte(0xdc00, 0xbf);
+    assert_eq!(mem.get_byte(0xd419), 0x20);
+    measure_pots(&mut mem);
+    assert_eq!(mem.get_byte(0xd419), 0x60);
+    assert_eq!(mem.get_byte(0xd41a), 0xff);
+    mem.set_mouse_position(1, 0x141, 0x7);
+    measure_pots(&mut mem);
+    assert_eq!(mem.get_byte(0xd419), 0x02);
+    assert_eq!(mem.get_byte(0xd41a), 0x0e);
+    mem.set_mouse_buttons(true, false);
+    assert_eq!(mem.get_byte(0xdc00) & 0x1f, 0x0f);
+  }
+
+  #[test]
+  fn light_pen() {
+    let mut mem = MemMap::new();
+    mem.set_byte(0xd01a, 0x08);
+    mem.update_vic(10);
+    assert!(!mem.update_vic(1));
+    // The fire button on control port 1 pulls the LP line low
+    mem.cia.joystick_down(0, JOYSTICK_FIRE);
+    assert!(mem.update_vic(1));
+    assert_eq!(mem.get_byte(0xd014), 0);
+    assert_eq!(mem.get_byte(0xd013), ((0x194 + 11 * 8) % 504 / 2) as u8);
+  }
+
+  fn banking_memory(mode: usize) -> MemMap {
+    let mut data = b"C64 CARTRIDGE   ".to_vec();
+    data.extend_from_slice(&[0, 0, 0, 0x40, 1, 0, 0, 0, 0, 0]);
+    data.resize(0x40, 0);
+    data.extend_from_slice(b"CHIP");
+    data.extend_from_slice(&[0, 0, 0x40, 0x10, 0, 0, 0, 0, 0x80, 0, 0x40, 0]);
+    data.extend_from_slice(&vec![0x66; 0x2000]);
+    data.extend_from_slice(&vec![0x77; 0x2000]);
+
+    let mut mem = MemMap::new();
+    for i in 0x1000..0x10000 {
+      mem.ram_rom.ram[i] = 0x11;
+    }
+    mem.ram_rom.basic[0] = 0x22;
+    mem.ram_rom.kernal[0] = 0x33;
+    mem.ram_rom.char_gen[0] = 0x44;
+    mem.vic.set_byte(0, 0x55);
+    if mode < 24 {
+      let mut cart = Cartridge::from_bytes(&data).unwrap();
+      cart.exrom = mode & 0x10 != 0;
+      cart.game = mode & 0x08 != 0;
+      mem.cartridge = Some(cart);
+    }
+    mem.set_byte(1, (mode & 7) as u8);
+    return mem;
+  }
+
+  #[test]
+  fn banking_modes() {
+    // What the CPU sees at $1000, $8000, $a000, $c000, $d000 and $e000:
+    // RAM, BASIC, KERNAL, Character ROM, I/O, ROML, ROMH or nothing
+    let modes = [
+      "RRRRRR", "RRRRRR", "RRHRCK", "RLHRCK", "RRRRRR", "RRRRIR", "RRHRIK", "RLHRIK",
+      "RRRRRR", "RRRRCR", "RRRRCK", "RLBRCK", "RRRRRR", "RRRRIR", "RRRRIK", "RLBRIK",
+      "-L--IH", "-L--IH", "-L--IH", "-L--IH", "-L--IH", "-L--IH", "-L--IH", "-L--IH",
+      "RRRRRR", "RRRRCR", "RRRRCK", "RRBRCK", "RRRRRR", "RRRRIR", "RRRRIK", "RRBRIK",
+    ];
+    for mode in 0..32 {
+      let mut mem = banking_memory(mode);
+      let mut seen = String::new();
+      for &addr in [0x1000, 0x8000, 0xa000, 0xc000, 0xd000, 0xe000].iter() {
+        seen.push(match mem.get_byte(addr) {
+          0x11 => 'R',
+          0x22 => 'B',
+          0x33 => 'K',
+          0x44 => 'C',
+          0x55 => 'I',
+          0x66 => 'L',
+          0x77 => 'H',
+          _ => '-',
+        });
+      }
+      assert_eq!(seen, modes[mode], "mode {}", mode);
+    }
+  }
+
+  #[test]
+  fn processor_port_inputs() {
+    let mut mem = MemMap::new();
+    assert_eq!(mem.get_byte(1), 0x37);
+    // With every line an input, the pull-ups select the default banks
+    mem.set_byte(1, 0x80);
+    mem.set_byte(0, 0x00);
+    assert_eq!(mem.get_byte(1), 0x9f);
+    mem.ram_rom.kernal[0] = 0xea;
+    assert_eq!(mem.get_byte(0xe000), 0xea);
+    // Outputs drive the lines
+    mem.set_byte(0, 0x07);
+    assert_eq!(mem.get_byte(1), 0x98);
+    mem.set_byte(0xe000, 0x60);
+    assert_eq!(mem.get_byte(0xe000), 0x60);
+  }
+
+  #[test]
+  fn cartridge_banking() {
+    let mut data = b"C64 CARTRIDGE   ".to_vec();
+    data.extend_from_slice(&[0, 0, 0, 0x40, 1, 0, 0, 5, 0, 0]);
+    data.resize(0x40, 0);
+    for bank in 0..2 {
+      data.extend_from_slice(b"CHIP");
+      data.extend_from_slice(&[0, 0, 0x20, 0x10, 0, 0, 0, bank, 0x80, 0, 0x20, 0]);
+      data.extend_from_slice(&vec![0xb0 + bank; 0x2000]);
+    }
+    let mut mem = MemMap::new();
+    mem.ram_rom.basic[0] = 0x94;
+    mem.ram_rom.ram[0x8000] = 0x12;
+    assert_eq!(mem.get_byte(0x8000), 0x12);
+    assert_eq!(mem.get_byte(0xde00), 0);
+
+    mem.cartridge = Some(Cartridge::from_bytes(&data).unwrap());
+    assert_eq!(mem.get_byte(0x8000), 0xb0);
+    assert_eq!(mem.get_byte(0xa000), 0xb0);
+    mem.set_byte(0xde00, 1);
+    assert_eq!(mem.get_byte(0x9fff), 0xb1);
+    // Writes go to the RAM underneath, which shows once ROML is banked out
+    mem.set_byte(0x8000, 0x34);
+    mem.set_byte(1, 0x36);
+    assert_eq!(mem.get_byte(0x8000), 0x34);
+    assert_eq!(mem.get_byte(0xa000), 0xb1);
+    mem.set_byte(1, 0x35);
+    assert_eq!(mem.get_byte(0xa000), 0);
+  }
+
+  #[test]
+  fn cassette_port() {
+    let mut data = b"C64-TAPE-RAW".to_vec();
+    data.extend_from_slice(&[0, 0, 0, 0, 1, 0, 0, 0, 0x10]);
+    let mut mem = MemMap::new();
+    mem.datasette.insert(Tap::from_bytes(&data).unwrap());
+    assert_eq!(mem.get_byte(1) & 0x30, 0x30);
+    mem.datasette.play();
+    assert_eq!(mem.get_byte(1) & 0x30, 0x20);
+    assert!(!mem.tape_motor_on());
+    mem.set_byte(1, 0x07);
+    assert!(mem.tape_motor_on());
+    // The FLAG interrupt is only raised once enabled, and stays raised until
+    // the status is read
+    mem.set_byte(0xdc0d, 0x90);
+    assert!(!mem.update_tape(100));
+    assert!(mem.update_tape(100));
+    assert!(mem.update_tape(1));
+    assert_eq!(mem.get_byte(0xdc0d), 0x90);
+    assert!(!mem.update_tape(1));
+  }
+
+  #[test]
+  fn vic_screen_location() {
+    let mut mem = MemMap::new();
+    mem.ram_rom.ram[0x400] = 1;
+    mem.ram_rom.ram[0xcc00] = 2;
+    assert_eq!(unsafe { *mem.screen_ptr() }, 1);
+    mem.set_byte(0xdd02, 0x03);
+    mem.set_byte(0xdd00, 0x00);
+    mem.set_byte(0xd018, 0x30);
+    assert_eq!(unsafe { *mem.screen_ptr() }, 2);
+  }
 }
\ No newline at end of file
@@ -0,0 +1,57 @@
+// The PAL and NTSC machines run from different crystals, which set the CPU
+// clock, and the VIC-II draws a different number of lines at a different
+// length for each TV standard. The CIA time of day clocks count ticks of the
+// mains frequency, which goes along with the TV standard.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+  Pal,
+  Ntsc,
+}
+
+impl Model {
+  /**
+   * Front-ends select the model by number: 0 for PAL, 1 for NTSC.
+   */
+  pub fn from_index(index: u32) -> Option<Model> {
+    match index {
+      0 => Some(Model::Pal),
+      1 => Some(Model::Ntsc),
+      _ => None,
+    }
+  }
+
+  /**
+   * CPU cycles per second
+   */
+  pub fn clock_rate(&self) -> u32 {
+    match *self {
+      Model::Pal => 985248,
+      Model::Ntsc => 1022727,
+    }
+  }
+
+  pub fn lines_per_frame(&self) -> u16 {
+    match *self {
+      Model::Pal => 312,
+      Model::Ntsc => 263,
+    }
+  }
+
+  pub fn cycles_per_line(&self) -> u16 {
+    match *self {
+      Model::Pal => 63,
+      Model::Ntsc => 65,
+    }
+  }
+
+  /**
+   * Mains frequency in Hz, fed to the TOD clock inputs
+   */
+  pub fn tod_frequency(&self) -> u32 {
+    match *self {
+      Model::Pal => 50,
+      Model::Ntsc => 60,
+    }
+  }
+}
@@ -0,0 +1,823 @@
+// A machine language monitor that understands the commands of the VICE
+// monitor, so it can be driven the same way. It works on the CPU and memory
+// of a stopped machine; the VM checks its breakpoints as it runs, and stops
+// to hand control back to it.
+
+use std::fs;
+use memmap::MemMap;
+use memmap::mos6510::cpu::CPU;
+use memmap::mos6510::instructions::{assemble, disassemble_with_symbols, parse_number, AssembleError};
+use memmap::mos6510::symbols::{SymbolTable, SymbolError};
+use pla::Bank;
+use snapshot::Snapshot;
+
+// How much m and d show when no end address is given
+const DUMP_LINES: u16 = 8;
+const DISASSEMBLE_LINES: u16 = 16;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Operation {
+  Exec,
+  Load,
+  Store,
+  LoadStore,
+}
+
+/**
+ * A breakpoint or watchpoint over a range of addresses
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Checkpoint {
+  pub number: u32,
+  pub start: u16,
+  pub end: u16,
+  pub operation: Operation,
+}
+
+impl Checkpoint {
+  fn contains(&self, addr: u16) -> bool {
+    addr >= self.start && addr <= self.end
+  }
+
+  pub fn matches_access(&self, addr: u16, store: bool) -> bool {
+    let operation = match self.operation {
+      Operation::Exec => false,
+      Operation::Load => !store,
+      Operation::Store => store,
+      Operation::LoadStore => true,
+    };
+    operation && self.contains(addr)
+  }
+
+  fn describe(&self) -> String {
+    let (kind, stop) = match self.operation {
+      Operation::Exec => ("BREAK", "exec"),
+      Operation::Load => ("WATCH", "load"),
+      Operation::Store => ("WATCH", "store"),
+      Operation::LoadStore => ("WATCH", "load store"),
+    };
+    let range = if self.start == self.end {
+      format!("C:${:04x}", self.start)
+    } else {
+      format!("C:${:04x}-${:04x}", self.start, self.end)
+    };
+    return format!("{}: {}  {}  (Stop on {})", kind, self.number, range, stop);
+  }
+}
+
+/**
+ * Which view of memory the monitor reads and writes, as chosen with the bank
+ * command
+ */
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MonitorBank {
+  // Whatever the CPU sees
+  Cpu,
+  Ram,
+  // The ROMs over RAM, ignoring the processor port
+  Rom,
+  // The ROMs, with I/O at $d000-$dfff
+  Io,
+}
+
+impl MonitorBank {
+  fn from_name(name: &str) -> Option<MonitorBank> {
+    match name {
+      "cpu" | "default" => Some(MonitorBank::Cpu),
+      "ram" => Some(MonitorBank::Ram),
+      "rom" => Some(MonitorBank::Rom),
+      "io" => Some(MonitorBank::Io),
+      _ => None,
+    }
+  }
+
+  fn name(&self) -> &'static str {
+    match *self {
+      MonitorBank::Cpu => "cpu",
+      MonitorBank::Ram => "ram",
+      MonitorBank::Rom => "rom",
+      MonitorBank::Io => "io",
+    }
+  }
+}
+
+pub struct Monitor {
+  // While active, the VM is stopped and waiting for commands
+  pub active: bool,
+  bank: MonitorBank,
+  checkpoints: Vec<Checkpoint>,
+  next_checkpoint: u32,
+  // Where m and d continue from when given no address
+  dump_address: u16,
+  disassemble_address: u16,
+  // Lines are assembled here until an empty line ends assembly
+  assemble_address: Option<u16>,
+  // The machine is resuming from this address, so a breakpoint on it should
+  // not stop it again straight away
+  resume_from: Option<u16>,
+  output: String,
+  // Labels shown in disassembly, which can be used in place of addresses
+  pub symbols: SymbolTable,
+  // A snapshot read by undump. The VM restores it, since loading a snapshot
+  // also resets state of its own.
+  undump: Option<Vec<u8>>,
+}
+
+impl Monitor {
+  pub fn new() -> Monitor {
+    return Monitor {
+      active: false,
+      bank: MonitorBank::Cpu,
+      checkpoints: Vec::new(),
+      next_checkpoint: 1,
+      dump_address: 0,
+      disassemble_address: 0,
+      assemble_address: None,
+      resume_from: None,
+      output: String::new(),
+      symbols: SymbolTable::new(),
+      undump: None,
+    };
+  }
+
+  /**
+   * Called before each instruction. Returns true if a breakpoint stops the
+   * machine at the current PC, after entering the monitor.
+   */
+  pub fn check_breakpoints(&mut self, cpu: &CPU, mem: &mut MemMap) -> bool {
+    if self.resume_from.take() == Some(cpu.pc) {
+      return false;
+    }
+    let hit = self.checkpoints.iter()
+      .find(|c| c.operation == Operation::Exec && c.contains(cpu.pc))
+      .map(|c| c.number);
+    if let Some(number) = hit {
+      self.stop(&format!("#{} (Stop on exec {:04x})", number, cpu.pc), cpu, mem);
+      return true;
+    }
+    return false;
+  }
+
+  /**
+   * Called after each instruction. Returns true if it touched a watched
+   * address, after entering the monitor.
+   */
+  pub fn check_watches(&mut self, cpu: &CPU, mem: &mut MemMap) -> bool {
+    let (addr, store) = match mem.take_watch_hit() {
+      Some(hit) => hit,
+      None => return false,
+    };
+    let number = self.checkpoints.iter()
+      .find(|c| c.matches_access(addr, store))
+      .map_or(0, |c| c.number);
+    let operation = if store { "store" } else { "load" };
+    self.stop(&format!("#{} (Stop on {} {:04x})", number, operation, addr), cpu, mem);
+    return true;
+  }
+
+  /**
+   * Stop the machine and enter the monitor, reporting why and where.
+   */
+  pub fn stop(&mut self, reason: &str, cpu: &CPU, mem: &mut MemMap) {
+    self.active = true;
+    self.assemble_address = None;
+    self.dump_address = cpu.pc;
+    self.disassemble_address = cpu.pc;
+    if reason.len() > 0 {
+      self.output.push_str(reason);
+      self.output.push('\n');
+    }
+    let (line, _) = self.disassemble_line(cpu.pc, mem);
+    self.output.push_str(&line);
+    self.output.push('\n');
+  }
+
+  /**
+   * Everything printed since the last call
+   */
+  pub fn take_output(&mut self) -> String {
+    let mut output = String::new();
+    ::std::mem::swap(&mut output, &mut self.output);
+    return output;
+  }
+
+  pub fn prompt(&self) -> String {
+    match self.assemble_address {
+      Some(addr) => format!(".{:04x}  ", addr),
+      None => format!("(C:${:04x}) ", self.disassemble_address),
+    }
+  }
+
+  /**
+   * Run one line of input. Commands that resume the machine leave the monitor
+   * inactive.
+   */
+  pub fn command(&mut self, line: &str, cpu: &mut CPU, mem: &mut MemMap) {
+    if let Some(addr) = self.assemble_address {
+      if line.trim().len() == 0 {
+        self.assemble_address = None;
+      } else {
+        self.assemble_at(addr, line, mem);
+      }
+      return;
+    }
+    let line = line.trim();
+    let (name, rest) = match line.find(char::is_whitespace) {
+      Some(space) => (&line[..space], line[space..].trim()),
+      None => (line, ""),
+    };
+    let result = match &name.to_lowercase()[..] {
+      "" => Ok(()),
+      "m" => self.memory_dump(rest, mem),
+      "d" => self.disassemble_range(rest, mem),
+      "r" => self.registers(rest, cpu),
+      "g" => self.go(rest, cpu),
+      "x" => self.go("", cpu),
+      "break" => self.add_checkpoint(rest, Operation::Exec, mem),
+      "watch" => self.add_watch(rest, mem),
+      "del" | "delete" => self.delete_checkpoint(rest, mem),
+      "a" => self.assemble_command(rest, mem),
+      "f" => self.fill(rest, mem),
+      "h" => self.hunt(rest, mem),
+      "t" => self.transfer(rest, mem),
+      "l" => self.load(rest, mem),
+      "s" => self.save(rest, mem),
+      "bank" => self.select_bank(rest),
+      "dump" => self.dump(rest, cpu, mem),
+      "undump" => self.undump(rest),
+      "ll" => self.load_labels(rest),
+      "sl" => self.save_labels(rest),
+      "al" => self.add_label(rest),
+      "shl" => self.show_labels(),
+      "cl" => self.clear_labels(),
+      _ => Err("Unknown command"),
+    };
+    if let Err(message) = result {
+      self.print(&format!("ERROR -- {}", message));
+    }
+  }
+
+  fn print(&mut self, line: &str) {
+    self.output.push_str(line);
+    self.output.push('\n');
+  }
+
+  fn to_pla_bank(&self, addr: u16, mem: &MemMap) -> Bank {
+    let rom = match addr >> 12 {
+      0xa..=0xb => Bank::Basic,
+      0xd if self.bank == MonitorBank::Io => Bank::Io,
+      0xd => Bank::CharRom,
+      0xe..=0xf => Bank::Kernal,
+      _ => Bank::Ram,
+    };
+    match self.bank {
+      MonitorBank::Cpu => mem.bank_at(addr),
+      MonitorBank::Ram => Bank::Ram,
+      _ => rom,
+    }
+  }
+
+  fn read(&self, addr: u16, mem: &mut MemMap) -> u8 {
+    if self.bank == MonitorBank::Cpu {
+      return mem.peek(addr);
+    }
+    let bank = self.to_pla_bank(addr, mem);
+    mem.peek_bank(bank, addr)
+  }
+
+  fn write(&self, addr: u16, value: u8, mem: &mut MemMap) {
+    let bank = self.to_pla_bank(addr, mem);
+    mem.poke_bank(bank, addr, value);
+  }
+
+  fn disassemble_line(&self, addr: u16, mem: &mut MemMap) -> (String, u16) {
+    let (bytes, text) = disassemble_with_symbols(addr, |a| self.read(a, mem), &self.symbols);
+    let hex = bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ");
+    let line = format!(".C:{:04x}  {:<9}  {}", addr, hex, text);
+    return (line, bytes.len() as u16);
+  }
+
+  fn memory_dump(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    let args = parse_addresses(args, &self.symbols)?;
+    let start = args.get(0).cloned().unwrap_or(self.dump_address);
+    let end = args.get(1).cloned().unwrap_or(start.saturating_add(DUMP_LINES * 16 - 1));
+    let mut addr = start as u32;
+    while addr <= end as u32 {
+      let mut hex = String::new();
+      let mut text = String::new();
+      for i in 0..16 {
+        if i > 0 && i % 4 == 0 {
+          hex.push(' ');
+        }
+        let value = self.read((addr + i) as u16, mem);
+        hex.push_str(&format!(" {:02x}", value));
+        text.push(if value >= 0x20 && value < 0x7f { value as char } else { '.' });
+      }
+      self.print(&format!(">C:{:04x} {}   {}", addr, hex, text));
+      addr += 16;
+    }
+    self.dump_address = addr as u16;
+    return Ok(());
+  }
+
+  fn disassemble_range(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    let args = parse_addresses(args, &self.symbols)?;
+    let start = args.get(0).cloned().unwrap_or(self.disassemble_address);
+    let mut addr = start as u32;
+    let mut lines = 0;
+    loop {
+      match args.get(1) {
+        Some(&end) if addr > end as u32 => break,
+        None if lines == DISASSEMBLE_LINES => break,
+        _ => (),
+      }
+      if let Some(label) = self.symbols.label(addr as u16) {
+        let line = format!("{}:", label);
+        self.print(&line);
+      }
+      let (line, length) = self.disassemble_line(addr as u16, mem);
+      self.print(&line);
+      addr += length as u32;
+      lines += 1;
+    }
+    self.disassemble_address = addr as u16;
+    return Ok(());
+  }
+
+  /**
+   * With no arguments, show the registers. Otherwise set them from a list
+   * like "a = 01, pc = c000".
+   */
+  fn registers(&mut self, args: &str, cpu: &mut CPU) -> Result<(), &'static str> {
+    if args.len() == 0 {
+      self.print("  ADDR A  X  Y  SP NV-BDIZC");
+      let line = format!(
+        ".;{:04x} {:02x} {:02x} {:02x} {:02x} {:08b}",
+        cpu.pc, cpu.acc, cpu.x, cpu.y, cpu.stack, cpu.status,
+      );
+      self.print(&line);
+      return Ok(());
+    }
+    for assignment in args.split(',') {
+      let mut parts = assignment.splitn(2, '=');
+      let name = parts.next().unwrap_or("").trim().to_lowercase();
+      let value = parts.next().and_then(|v| parse_number(v.trim())).ok_or("Invalid register value")?.0;
+      match &name[..] {
+        "pc" => cpu.pc = value,
+        "a" => cpu.acc = value as u8,
+        "x" => cpu.x = value as u8,
+        "y" => cpu.y = value as u8,
+        "sp" => cpu.stack = value as u8,
+        "fl" => cpu.status = value as u8,
+        _ => return Err("Unknown register"),
+      }
+    }
+    return Ok(());
+  }
+
+  fn go(&mut self, args: &str, cpu: &mut CPU) -> Result<(), &'static str> {
+    if let Some(&addr) = parse_addresses(args, &self.symbols)?.get(0) {
+      cpu.pc = addr;
+    }
+    self.active = false;
+    self.resume_from = Some(cpu.pc);
+    return Ok(());
+  }
+
+  fn add_checkpoint(&mut self, args: &str, operation: Operation, mem: &mut MemMap) -> Result<(), &'static str> {
+    let args = parse_addresses(args, &self.symbols)?;
+    if args.len() == 0 {
+      self.list_checkpoints();
+      return Ok(());
+    }
+    let checkpoint = Checkpoint {
+      number: self.next_checkpoint,
+      start: args[0],
+      end: args.get(1).cloned().unwrap_or(args[0]),
+      operation: operation,
+    };
+    self.next_checkpoint += 1;
+    self.print(&checkpoint.describe());
+    self.checkpoints.push(checkpoint);
+    self.update_watches(mem);
+    return Ok(());
+  }
+
+  fn add_watch(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    let mut words = args.splitn(2, char::is_whitespace);
+    let (operation, rest) = match words.next() {
+      Some("load") => (Operation::Load, words.next().unwrap_or("")),
+      Some("store") => (Operation::Store, words.next().unwrap_or("")),
+      _ => (Operation::LoadStore, args),
+    };
+    if rest.trim().len() == 0 {
+      self.list_checkpoints();
+      return Ok(());
+    }
+    return self.add_checkpoint(rest, operation, mem);
+  }
+
+  fn list_checkpoints(&mut self) {
+    if self.checkpoints.len() == 0 {
+      self.print("No breakpoints are set");
+    }
+    for i in 0..self.checkpoints.len() {
+      let description = self.checkpoints[i].describe();
+      self.print(&description);
+    }
+  }
+
+  fn delete_checkpoint(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    if args.len() == 0 {
+      self.checkpoints.clear();
+    } else {
+      let number = args.parse::<u32>().map_err(|_| "Invalid checkpoint number")?;
+      let before = self.checkpoints.len();
+      self.checkpoints.retain(|c| c.number != number);
+      if self.checkpoints.len() == before {
+        return Err("No such checkpoint");
+      }
+    }
+    self.update_watches(mem);
+    return Ok(());
+  }
+
+  /**
+   * The memory map only needs to check loads and stores while something is
+   * being watched
+   */
+  fn update_watches(&self, mem: &mut MemMap) {
+    let watches = self.checkpoints.iter()
+      .filter(|c| c.operation != Operation::Exec)
+      .cloned()
+      .collect();
+    mem.set_watches(watches);
+  }
+
+  fn assemble_command(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    let (addr, instruction) = match args.find(char::is_whitespace) {
+      Some(space) => (&args[..space], &args[space..]),
+      None => (args, ""),
+    };
+    let addr = match parse_address(addr, &self.symbols) {
+      Some(addr) => addr,
+      None => return Err("Invalid address"),
+    };
+    if instruction.trim().len() == 0 {
+      self.assemble_address = Some(addr);
+    } else {
+      self.assemble_at(addr, instruction, mem);
+    }
+    return Ok(());
+  }
+
+  fn assemble_at(&mut self, addr: u16, instruction: &str, mem: &mut MemMap) {
+    match assemble(instruction, addr) {
+      Ok(bytes) => {
+        for i in 0..bytes.len() {
+          self.write(addr.wrapping_add(i as u16), bytes[i], mem);
+        }
+        let next = addr.wrapping_add(bytes.len() as u16);
+        self.disassemble_address = next;
+        if self.assemble_address.is_some() {
+          self.assemble_address = Some(next);
+        }
+      },
+      Err(error) => {
+        let message = match error {
+          AssembleError::UnknownInstruction => "Unknown instruction",
+          AssembleError::InvalidOperand => "Invalid operand",
+          AssembleError::BranchOutOfRange => "Branch out of range",
+        };
+        self.print(&format!("ERROR -- {}", message));
+      },
+    }
+  }
+
+  fn fill(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    let args = parse_addresses(args, &self.symbols)?;
+    if args.len() < 3 {
+      return Err("Usage: f <start> <end> <byte> [<byte> ...]");
+    }
+    let pattern = &args[2..];
+    for addr in args[0]..=args[1] {
+      let value = pattern[(addr - args[0]) as usize % pattern.len()];
+      self.write(addr, value as u8, mem);
+    }
+    return Ok(());
+  }
+
+  fn hunt(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    let args = parse_addresses(args, &self.symbols)?;
+    if args.len() < 3 {
+      return Err("Usage: h <start> <end> <byte> [<byte> ...]");
+    }
+    let pattern = &args[2..];
+    let mut found = Vec::new();
+    for addr in args[0]..=args[1] {
+      let matches = (0..pattern.len())
+        .all(|i| self.read(addr.wrapping_add(i as u16), mem) == pattern[i] as u8);
+      if matches {
+        found.push(format!("{:04x}", addr));
+      }
+    }
+    for line in found.chunks(8) {
+      self.print(&line.join(" "));
+    }
+    return Ok(());
+  }
+
+  fn transfer(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    let args = parse_addresses(args, &self.symbols)?;
+    if args.len() != 3 || args[1] < args[0] {
+      return Err("Usage: t <start> <end> <destination>");
+    }
+    let bytes: Vec<u8> = (args[0]..=args[1]).map(|addr| self.read(addr, mem)).collect();
+    for i in 0..bytes.len() {
+      self.write(args[2].wrapping_add(i as u16), bytes[i], mem);
+    }
+    return Ok(());
+  }
+
+  /**
+   * l "file" 0 [address] loads a PRG file from the host, at the address in
+   * its header unless another is given.
+   */
+  fn load(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    let (filename, rest) = parse_filename(args)?;
+    let args = parse_addresses(rest, &self.symbols)?;
+    if args.get(0) != Some(&0) {
+      return Err("Only device 0, the host file system, is supported");
+    }
+    let data = fs::read(filename).map_err(|_| "Cannot read file")?;
+    if data.len() < 2 {
+      return Err("File is too short");
+    }
+    let start = args.get(1).cloned().unwrap_or((data[0] as u16) | ((data[1] as u16) << 8));
+    let mut addr = start;
+    for &value in data[2..].iter() {
+      self.write(addr, value, mem);
+      addr = addr.wrapping_add(1);
+    }
+    self.print(&format!("Loading {} from {:04X} to {:04X}", filename, start, addr.wrapping_sub(1)));
+    return Ok(());
+  }
+
+  /**
+   * s "file" 0 start end saves memory to a PRG file on the host.
+   */
+  fn save(&mut self, args: &str, mem: &mut MemMap) -> Result<(), &'static str> {
+    let (filename, rest) = parse_filename(args)?;
+    let args = parse_addresses(rest, &self.symbols)?;
+    if args.len() != 3 || args[2] < args[1] {
+      return Err("Usage: s \"<filename>\" 0 <start> <end>");
+    }
+    if args[0] != 0 {
+      return Err("Only device 0, the host file system, is supported");
+    }
+    let mut data = vec![(args[1] & 0xff) as u8, (args[1] >> 8) as u8];
+    for addr in args[1]..=args[2] {
+      data.push(self.read(addr, mem));
+    }
+    fs::write(filename, data).map_err(|_| "Cannot write file")?;
+    self.print(&format!("Saving {} from {:04X} to {:04X}", filename, args[1], args[2]));
+    return Ok(());
+  }
+
+  /**
+   * dump "file" saves the machine as a VICE snapshot, and undump "file"
+   * loads one.
+   */
+  fn dump(&mut self, args: &str, cpu: &CPU, mem: &mut MemMap) -> Result<(), &'static str> {
+    let (filename, _) = parse_filename(args)?;
+    let bytes = Snapshot::capture(cpu, mem).to_bytes();
+    fs::write(filename, bytes).map_err(|_| "Cannot write file")?;
+    return Ok(());
+  }
+
+  fn undump(&mut self, args: &str) -> Result<(), &'static str> {
+    let (filename, _) = parse_filename(args)?;
+    let data = fs::read(filename).map_err(|_| "Cannot read file")?;
+    self.undump = Some(data);
+    return Ok(());
+  }
+
+  /**
+   * The snapshot the last command read for the VM to restore, if any
+   */
+  pub fn take_undump(&mut self) -> Option<Vec<u8>> {
+    self.undump.take()
+  }
+
+  /**
+   * Report how restoring the snapshot from take_undump went
+   */
+  pub fn undumped(&mut self, restored: bool, cpu: &CPU) {
+    if restored {
+      self.dump_address = cpu.pc;
+      self.disassemble_address = cpu.pc;
+    } else {
+      self.print("ERROR -- Not a valid C64 snapshot");
+    }
+  }
+
+  /**
+   * ll "file" loads labels from a VICE label file, a ca65 debug file, or an
+   * FCEUX name list.
+   */
+  fn load_labels(&mut self, args: &str) -> Result<(), &'static str> {
+    let (filename, _) = parse_filename(args)?;
+    let text = fs::read_to_string(filename).map_err(|_| "Cannot read file")?;
+    let count = self.symbols.load(&text).map_err(|error| match error {
+      SymbolError::UnknownFormat => "Unknown label file format",
+      SymbolError::InvalidLine(_) => "Invalid line in label file",
+    })?;
+    self.print(&format!("Loaded {} labels", count));
+    return Ok(());
+  }
+
+  fn save_labels(&mut self, args: &str) -> Result<(), &'static str> {
+    let (filename, _) = parse_filename(args)?;
+    fs::write(filename, self.symbols.to_vice()).map_err(|_| "Cannot write file")?;
+    return Ok(());
+  }
+
+  fn add_label(&mut self, args: &str) -> Result<(), &'static str> {
+    let words: Vec<&str> = args.split_whitespace().collect();
+    if words.len() != 2 || !words[1].starts_with('.') || words[1].len() < 2 {
+      return Err("Usage: al <address> .<label>");
+    }
+    let addr = parse_address(words[0], &self.symbols).ok_or("Invalid address")?;
+    self.symbols.add(&words[1][1..], addr);
+    return Ok(());
+  }
+
+  fn show_labels(&mut self) -> Result<(), &'static str> {
+    let lines: Vec<String> = self.symbols.iter()
+      .map(|(addr, name)| format!("${:04x} .{}", addr, name))
+      .collect();
+    for line in lines {
+      self.print(&line);
+    }
+    return Ok(());
+  }
+
+  fn clear_labels(&mut self) -> Result<(), &'static str> {
+    self.symbols.clear();
+    return Ok(());
+  }
+
+  fn select_bank(&mut self, args: &str) -> Result<(), &'static str> {
+    if args.len() == 0 {
+      self.print("Available banks: cpu ram rom io");
+      let current = format!("Current bank: {}", self.bank.name());
+      self.print(&current);
+      return Ok(());
+    }
+    self.bank = MonitorBank::from_name(&args.to_lowercase()).ok_or("Unknown bank")?;
+    return Ok(());
+  }
+}
+
+/**
+ * Addresses may be prefixed with the C: memory space, the only one there is.
+ * A label can be given in place of an address, as ".name", or just "name" if
+ * it can't be read as a number.
+ */
+fn parse_address(text: &str, symbols: &SymbolTable) -> Option<u16> {
+  let text = if text.starts_with("c:") || text.starts_with("C:") { &text[2..] } else { text };
+  if text.starts_with('.') {
+    return symbols.address(&text[1..]);
+  }
+  parse_number(text).map(|(value, _)| value).or_else(|| symbols.address(text))
+}
+
+fn parse_addresses(text: &str, symbols: &SymbolTable) -> Result<Vec<u16>, &'static str> {
+  text.split(|c: char| c.is_whitespace() || c == ',')
+    .filter(|arg| arg.len() > 0)
+    .map(|arg| parse_address(arg, symbols).ok_or("Invalid number"))
+    .collect()
+}
+
+fn parse_filename(text: &str) -> Result<(&str, &str), &'static str> {
+  if !text.starts_with('"') {
+    return Err("Expected a quoted filename");
+  }
+  let end = text[1..].find('"').ok_or("Expected a quoted filename")? + 1;
+  return Ok((&text[1..end], &text[(end + 1)..]));
+}
+
+#[cfg(test)]
+mod tests {
+  use memmap::MemMap;
+  use memmap::mos6510::cpu::CPU;
+  use memmap::mos6510::memory::Memory;
+  use monitor::Monitor;
+
+  fn run(monitor: &mut Monitor, cpu: &mut CPU, mem: &mut MemMap, line: &str) -> String {
+    monitor.command(line, cpu, mem);
+    monitor.take_output()
+  }
+
+  #[test]
+  fn memory_commands() {
+    let mut monitor = Monitor::new();
+    let mut cpu = CPU::new();
+    let mut mem = MemMap::new();
+    run(&mut monitor, &mut cpu, &mut mem, "f 1000 100f 41 42");
+    let dump = run(&mut monitor, &mut cpu, &mut mem, "m 1000 100f");
+    assert_eq!(dump, ">C:1000  41 42 41 42  41 42 41 42  41 42 41 42  41 42 41 42   ABABABABABABABAB\n");
+    run(&mut monitor, &mut cpu, &mut mem, "t 1000 1003 2001");
+    assert_eq!(&mem.ram_rom.ram[0x2000..0x2005], &[0, 0x41, 0x42, 0x41, 0x42]);
+    assert_eq!(run(&mut monitor, &mut cpu, &mut mem, "h 1000 2fff 42 41 42"), "1001 1003 1005 1007 1009 100b 100d 2002\n");
+    assert_eq!(run(&mut monitor, &mut cpu, &mut mem, "z"), "ERROR -- Unknown command\n");
+
+    // Banks select what is underneath the ROMs
+    mem.ram_rom.kernal[0] = 0x4c;
+    mem.ram_rom.ram[0xe000] = 0x60;
+    assert!(run(&mut monitor, &mut cpu, &mut mem, "m e000 e000").starts_with(">C:e000  4c"));
+    run(&mut monitor, &mut cpu, &mut mem, "bank ram");
+    assert!(run(&mut monitor, &mut cpu, &mut mem, "m e000 e000").starts_with(">C:e000  60"));
+    // The default range stops at the top of memory
+    assert_eq!(run(&mut monitor, &mut cpu, &mut mem, "m fff8").lines().count(), 1);
+  }
+
+  #[test]
+  fn assemble_and_disassemble() {
+    let mut monitor = Monitor::new();
+    let mut cpu = CPU::new();
+    let mut mem = MemMap::new();
+    run(&mut monitor, &mut cpu, &mut mem, "a c000 lda #$01");
+    run(&mut monitor, &mut cpu, &mut mem, "a c002");
+    assert_eq!(monitor.prompt(), ".c002  ");
+    run(&mut monitor, &mut cpu, &mut mem, "sta $d020");
+    run(&mut monitor, &mut cpu, &mut mem, "bne c002");
+    run(&mut monitor, &mut cpu, &mut mem, "");
+    assert_eq!(monitor.prompt(), "(C:$c007) ");
+    let listing = run(&mut monitor, &mut cpu, &mut mem, "d c000 c006");
+    assert_eq!(listing, concat!(
+      ".C:c000  A9 01      LDA #$01\n",
+      ".C:c002  8D 20 D0   STA $D020\n",
+      ".C:c005  D0 FB      BNE $C002\n",
+    ));
+    run(&mut monitor, &mut cpu, &mut mem, "r pc = c000, a = 7f");
+    assert_eq!(cpu.pc, 0xc000);
+    assert_eq!(cpu.acc, 0x7f);
+  }
+
+  #[test]
+  fn checkpoints() {
+    let mut monitor = Monitor::new();
+    let mut cpu = CPU::new();
+    let mut mem = MemMap::new();
+    cpu.pc = 0xc000;
+    assert_eq!(run(&mut monitor, &mut cpu, &mut mem, "break c000"), "BREAK: 1  C:$c000  (Stop on exec)\n");
+    assert!(monitor.check_breakpoints(&cpu, &mut mem));
+    assert!(monitor.active);
+    assert!(monitor.take_output().starts_with("#1 (Stop on exec c000)"));
+    // Going on from the breakpoint doesn't stop on it again
+    run(&mut monitor, &mut cpu, &mut mem, "g");
+    assert!(!monitor.active);
+    assert!(!monitor.check_breakpoints(&cpu, &mut mem));
+    assert!(monitor.check_breakpoints(&cpu, &mut mem));
+
+    run(&mut monitor, &mut cpu, &mut mem, "watch store d020 d021");
+    mem.get_byte(0xd020);
+    assert!(!monitor.check_watches(&cpu, &mut mem));
+    mem.set_byte(0xd021, 1);
+    assert!(monitor.check_watches(&cpu, &mut mem));
+    assert!(monitor.take_output().starts_with("#2 (Stop on store d021)"));
+    run(&mut monitor, &mut cpu, &mut mem, "del");
+    mem.set_byte(0xd021, 1);
+    assert!(!monitor.check_watches(&cpu, &mut mem));
+
+    // Load watches trigger on data reads, not on fetching the instruction
+    run(&mut monitor, &mut cpu, &mut mem, "a c000 lda $c100");
+    run(&mut monitor, &mut cpu, &mut mem, "watch load c000 c100");
+    mem.begin_instruction(cpu.pc);
+    cpu.step(&mut mem);
+    mem.end_instruction();
+    assert!(monitor.check_watches(&cpu, &mut mem));
+    assert!(monitor.take_output().starts_with("#3 (Stop on load c100)"));
+  }
+
+  #[test]
+  fn labels() {
+    let mut monitor = Monitor::new();
+    let mut cpu = CPU::new();
+    let mut mem = MemMap::new();
+    monitor.symbols.load("al C:c000 .main_loop\n").unwrap();
+    run(&mut monitor, &mut cpu, &mut mem, "al c003 .border");
+    assert_eq!(run(&mut monitor, &mut cpu, &mut mem, "break main_loop"), "BREAK: 1  C:$c000  (Stop on exec)\n");
+    run(&mut monitor, &mut cpu, &mut mem, "a .main_loop inc $c003");
+    run(&mut monitor, &mut cpu, &mut mem, "a c003 jmp $c000");
+    let listing = run(&mut monitor, &mut cpu, &mut mem, "d main_loop .border");
+    assert_eq!(listing, concat!(
+      "main_loop:\n",
+      ".C:c000  EE 03 C0   INC border\n",
+      "border:\n",
+      ".C:c003  4C 00 C0   JMP main_loop\n",
+    ));
+    assert_eq!(run(&mut monitor, &mut cpu, &mut mem, "shl"), "$c000 .main_loop\n$c003 .border\n");
+  }
+}
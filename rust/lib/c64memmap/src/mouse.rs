@@ -0,0 +1,108 @@
+// The 1351 mouse in proportional mode. It counts movement along each axis,
+// and reports the counts modulo 64 through the SID's potentiometer inputs, in
+// bits 1-6 of POTX and POTY. Drivers work out how far it moved from the
+// difference between two readings, so a move of more than 31 counts between
+// readings looks like a move the other way. The left button closes the fire
+// switch of the joystick lines, and the right button the up switch.
+
+// The counts step by at most one along each axis in this many cycles, which
+// keeps a driver that reads the mouse once a frame from seeing them wrap
+const CYCLES_PER_COUNT: u16 = 1024;
+
+pub struct Mouse {
+  // The control port it's plugged into, 0 or 1
+  pub port: usize,
+  x: u16,
+  y: u16,
+  // Where the host has moved the mouse to. The counts catch up over time.
+  target_x: u16,
+  target_y: u16,
+  cycles: u16,
+}
+
+impl Mouse {
+  pub fn new(port: usize) -> Mouse {
+    Mouse {
+      port: port,
+      x: 0,
+      y: 0,
+      target_x: 0,
+      target_y: 0,
+      cycles: 0,
+    }
+  }
+
+  /**
+   * Move by some number of counts in the host's direction, with y growing
+   * down the screen. The 1351's Y count grows as it moves up.
+   */
+  pub fn move_by(&mut self, x: i32, y: i32) {
+    self.target_x = self.target_x.wrapping_add(x as u16);
+    self.target_y = self.target_y.wrapping_sub(y as u16);
+  }
+
+  /**
+   * Move until the counts reach the given values, taking the shorter way
+   * around
+   */
+  pub fn move_to(&mut self, x: u16, y: u16) {
+    self.target_x = x;
+    self.target_y = y;
+  }
+
+  /**
+   * Set the counts without the driver seeing any motion in between
+   */
+  pub fn place(&mut self, x: u16, y: u16) {
+    self.move_to(x, y);
+    self.x = x;
+    self.y = y;
+  }
+
+  pub fn update(&mut self, cycles: u8) {
+    self.cycles += cycles as u16;
+    while self.cycles >= CYCLES_PER_COUNT {
+      self.cycles -= CYCLES_PER_COUNT;
+      self.x = step_towards(self.x, self.target_x);
+      self.y = step_towards(self.y, self.target_y);
+    }
+  }
+
+  /**
+   * The values the SID measures on its X and Y inputs
+   */
+  pub fn pots(&self) -> (u8, u8) {
+    (((self.x & 0x3f) << 1) as u8, ((self.y & 0x3f) << 1) as u8)
+  }
+}
+
+fn step_towards(count: u16, target: u16) -> u16 {
+  match target.wrapping_sub(count) as i16 {
+    0 => count,
+    distance if distance > 0 => count.wrapping_add(1),
+    _ => count.wrapping_sub(1),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use mouse::Mouse;
+
+  #[test]
+  fn motion() {
+    let mut mouse = Mouse::new(0);
+    mouse.place(0x3f, 0);
+    assert_eq!(mouse.pots(), (0x7e, 0));
+    // A quick move is spread out, so the driver sees no more than 20 counts
+    // in a PAL frame
+    mouse.move_by(40, 3);
+    for _ in 0..77 {
+      mouse.update(255);
+    }
+    assert_eq!(mouse.pots(), (((0x3f + 19) & 0x3f) << 1, (0x40 - 3) << 1));
+    for _ in 0..100 {
+      mouse.update(255);
+    }
+    assert_eq!(mouse.pots(), (((0x3f + 40) & 0x3f) << 1, (0x40 - 3) << 1));
+  }
+}
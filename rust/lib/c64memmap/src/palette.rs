@@ -0,0 +1,33 @@
+// The RGB values of the 16 VIC-II colors, as measured by Pepto, for hosts
+// that want pixels rather than color indexes.
+
+pub const COLORS: [[u8; 3]; 16] = [
+  [0x00, 0x00, 0x00],
+  [0xff, 0xff, 0xff],
+  [0x68, 0x37, 0x2b],
+  [0x70, 0xa4, 0xb2],
+  [0x6f, 0x3d, 0x86],
+  [0x58, 0x8d, 0x43],
+  [0x35, 0x28, 0x79],
+  [0xb8, 0xc7, 0x6f],
+  [0x6f, 0x4f, 0x25],
+  [0x43, 0x39, 0x00],
+  [0x9a, 0x67, 0x59],
+  [0x44, 0x44, 0x44],
+  [0x6c, 0x6c, 0x6c],
+  [0x9a, 0xd2, 0x84],
+  [0x6c, 0x5e, 0xb5],
+  [0x95, 0x95, 0x95],
+];
+
+/**
+ * Convert a frame of color indexes to opaque RGBA pixels, 4 bytes each
+ */
+pub fn to_rgba(indexed: &[u8], rgba: &mut Vec<u8>) {
+  rgba.clear();
+  rgba.reserve(indexed.len() * 4);
+  for &index in indexed {
+    let color = COLORS[(index & 0xf) as usize];
+    rgba.extend_from_slice(&[color[0], color[1], color[2], 0xff]);
+  }
+}
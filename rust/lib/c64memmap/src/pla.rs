@@ -0,0 +1,119 @@
+// The PLA decides what the CPU sees in each region of memory, from the
+// LORAM, HIRAM and CHAREN bits of the processor port and the GAME and EXROM
+// lines of the expansion port.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Bank {
+  Ram,
+  Basic,
+  Kernal,
+  CharRom,
+  Io,
+  // Cartridge ROM, selected by the ROML and ROMH lines
+  RomL,
+  RomH,
+  // Nothing responds, as in the gaps of the Ultimax memory map
+  Open,
+}
+
+use self::Bank::{Ram, Basic, Kernal, CharRom, Io, RomL, RomH, Open};
+
+// Each mode lists the banks at $1000-$7fff, $8000-$9fff, $a000-$bfff,
+// $c000-$cfff, $d000-$dfff and $e000-$ffff. $0000-$0fff is always RAM.
+// Modes are indexed by EXROM, GAME, CHAREN, HIRAM and LORAM, from the high
+// bit down.
+const MODES: [[Bank; 6]; 32] = [
+  // 16K cartridge
+  [Ram, Ram, Ram, Ram, Ram, Ram],
+  [Ram, Ram, Ram, Ram, Ram, Ram],
+  [Ram, Ram, RomH, Ram, CharRom, Kernal],
+  [Ram, RomL, RomH, Ram, CharRom, Kernal],
+  [Ram, Ram, Ram, Ram, Ram, Ram],
+  [Ram, Ram, Ram, Ram, Io, Ram],
+  [Ram, Ram, RomH, Ram, Io, Kernal],
+  [Ram, RomL, RomH, Ram, Io, Kernal],
+  // 8K cartridge
+  [Ram, Ram, Ram, Ram, Ram, Ram],
+  [Ram, Ram, Ram, Ram, CharRom, Ram],
+  [Ram, Ram, Ram, Ram, CharRom, Kernal],
+  [Ram, RomL, Basic, Ram, CharRom, Kernal],
+  [Ram, Ram, Ram, Ram, Ram, Ram],
+  [Ram, Ram, Ram, Ram, Io, Ram],
+  [Ram, Ram, Ram, Ram, Io, Kernal],
+  [Ram, RomL, Basic, Ram, Io, Kernal],
+  // Ultimax, which ignores the processor port
+  [Open, RomL, Open, Open, Io, RomH],
+  [Open, RomL, Open, Open, Io, RomH],
+  [Open, RomL, Open, Open, Io, RomH],
+  [Open, RomL, Open, Open, Io, RomH],
+  [Open, RomL, Open, Open, Io, RomH],
+  [Open, RomL, Open, Open, Io, RomH],
+  [Open, RomL, Open, Open, Io, RomH],
+  [Open, RomL, Open, Open, Io, RomH],
+  // No cartridge
+  [Ram, Ram, Ram, Ram, Ram, Ram],
+  [Ram, Ram, Ram, Ram, CharRom, Ram],
+  [Ram, Ram, Ram, Ram, CharRom, Kernal],
+  [Ram, Ram, Basic, Ram, CharRom, Kernal],
+  [Ram, Ram, Ram, Ram, Ram, Ram],
+  [Ram, Ram, Ram, Ram, Io, Ram],
+  [Ram, Ram, Ram, Ram, Io, Kernal],
+  [Ram, Ram, Basic, Ram, Io, Kernal],
+];
+
+/**
+ * Combine the expansion port lines with the low 3 bits of the processor
+ * port. The lines are active low, so a high level means no cartridge.
+ */
+pub fn mode(exrom: bool, game: bool, port: u8) -> usize {
+  let mut mode = (port & 7) as usize;
+  if game {
+    mode |= 0x08;
+  }
+  if exrom {
+    mode |= 0x10;
+  }
+  return mode;
+}
+
+pub fn is_ultimax(mode: usize) -> bool {
+  mode & 0x18 == 0x10
+}
+
+pub fn bank(mode: usize, addr: u16) -> Bank {
+  let region = match addr >> 12 {
+    0x0 => return Ram,
+    0x1..=0x7 => 0,
+    0x8..=0x9 => 1,
+    0xa..=0xb => 2,
+    0xc => 3,
+    0xd => 4,
+    _ => 5,
+  };
+  return MODES[mode][region];
+}
+
+#[cfg(test)]
+mod tests {
+  use pla::{Bank, mode, bank, is_ultimax};
+
+  #[test]
+  fn cartridge_modes() {
+    // 8K cartridge, with BASIC still visible
+    let m = mode(false, true, 7);
+    assert_eq!(bank(m, 0x8000), Bank::RomL);
+    assert_eq!(bank(m, 0xa000), Bank::Basic);
+    // 16K cartridge
+    let m = mode(false, false, 7);
+    assert_eq!(bank(m, 0x9fff), Bank::RomL);
+    assert_eq!(bank(m, 0xa000), Bank::RomH);
+    assert_eq!(bank(mode(false, false, 6), 0x8000), Bank::Ram);
+    // Ultimax
+    let m = mode(true, false, 0);
+    assert!(is_ultimax(m));
+    assert_eq!(bank(m, 0x0800), Bank::Ram);
+    assert_eq!(bank(m, 0x4000), Bank::Open);
+    assert_eq!(bank(m, 0xd020), Bank::Io);
+    assert_eq!(bank(m, 0xfffc), Bank::RomH);
+  }
+}
@@ -0,0 +1,130 @@
+use memmap::MemMap;
+
+// The KERNAL idles in this loop at $E5CD while waiting for keyboard input,
+// which means BASIC is at its READY prompt
+pub const READY_LOOP_START: u16 = 0xe5cd;
+pub const READY_LOOP_END: u16 = 0xe5d6;
+
+pub const BASIC_START: u16 = 0x0801;
+
+// Zero page pointers that BASIC and the KERNAL expect after a LOAD
+const VARTAB: usize = 0x2d;
+const ARYTAB: usize = 0x2f;
+const STREND: usize = 0x31;
+const LOAD_END: usize = 0xae;
+
+const KEYBOARD_BUFFER: usize = 0x277;
+const KEYBOARD_BUFFER_COUNT: usize = 0xc6;
+pub const KEYBOARD_BUFFER_SIZE: usize = 10;
+
+pub fn is_ready(pc: u16) -> bool {
+  pc >= READY_LOOP_START && pc <= READY_LOOP_END
+}
+
+/**
+ * A PRG file is a 2-byte little-endian load address followed by the data.
+ * Returns None if the file is too short to contain a load address.
+ */
+pub fn load_address(data: &[u8]) -> Option<u16> {
+  if data.len() < 2 {
+    return None;
+  }
+  Some((data[0] as u16) | ((data[1] as u16) << 8))
+}
+
+/**
+ * Copy a PRG file into RAM, returning its load address. Programs loaded at the
+ * start of BASIC memory also get the BASIC variable pointers moved past their
+ * end, the same as a LOAD from BASIC would.
+ */
+pub fn load(mem: &mut MemMap, data: &[u8]) -> Option<u16> {
+  let start = match load_address(data) {
+    Some(addr) => addr,
+    None => return None,
+  };
+  let mut end = start as usize;
+  for byte in data[2..].iter() {
+    if end > 0xffff {
+      break;
+    }
+    mem.ram_rom.ram[end] = *byte;
+    end += 1;
+  }
+  let end_low = (end & 0xff) as u8;
+  let end_high = ((end >> 8) & 0xff) as u8;
+  mem.ram_rom.ram[LOAD_END] = end_low;
+  mem.ram_rom.ram[LOAD_END + 1] = end_high;
+  if start == BASIC_START {
+    for ptr in [VARTAB, ARYTAB, STREND].iter() {
+      mem.ram_rom.ram[*ptr] = end_low;
+      mem.ram_rom.ram[*ptr + 1] = end_high;
+    }
+  }
+  Some(start)
+}
+
+/**
+ * The command that starts a freshly loaded program: RUN for BASIC programs,
+ * and SYS to the load address for everything else.
+ */
+pub fn autostart_command(address: u16) -> String {
+  if address == BASIC_START {
+    return String::from("RUN\r");
+  }
+  format!("SYS{}\r", address)
+}
+
+/**
+ * The number of keypresses waiting in the keyboard buffer.
+ */
+pub fn queued_keys(mem: &MemMap) -> usize {
+  mem.ram_rom.ram[KEYBOARD_BUFFER_COUNT] as usize
+}
+
+/**
+ * Place keypresses in the KERNAL keyboard buffer, as if they had been typed.
+ * The buffer only holds 10 characters; anything beyond that is dropped.
+ */
+pub fn queue_keys(mem: &mut MemMap, petscii: &[u8]) {
+  let mut count = mem.ram_rom.ram[KEYBOARD_BUFFER_COUNT] as usize;
+  for key in petscii.iter() {
+    if count >= KEYBOARD_BUFFER_SIZE {
+      break;
+    }
+    mem.ram_rom.ram[KEYBOARD_BUFFER + count] = *key;
+    count += 1;
+  }
+  mem.ram_rom.ram[KEYBOARD_BUFFER_COUNT] = count as u8;
+}
+
+#[cfg(test)]
+mod tests {
+  use memmap::MemMap;
+  use prg;
+
+  #[test]
+  fn basic_program() {
+    let mut mem = MemMap::new();
+    let data = vec![0x01, 0x08, 0x0b, 0x08, 0x0a, 0x00, 0x99, 0x22, 0x48, 0x22, 0x00, 0x00, 0x00];
+    assert_eq!(prg::load(&mut mem, &data), Some(0x0801));
+    assert_eq!(mem.ram_rom.ram[0x0801], 0x0b);
+    assert_eq!(mem.ram_rom.ram[0x0805], 0x99);
+    assert_eq!(mem.ram_rom.ram[0x2d], 0x0c);
+    assert_eq!(mem.ram_rom.ram[0x2e], 0x08);
+    assert_eq!(mem.ram_rom.ram[0x31], 0x0c);
+    assert_eq!(prg::autostart_command(0x0801), "RUN\r");
+  }
+
+  #[test]
+  fn machine_code() {
+    let mut mem = MemMap::new();
+    assert_eq!(prg::load(&mut mem, &[0x00]), None);
+    assert_eq!(prg::load(&mut mem, &[0x00, 0xc0, 0xee, 0x20, 0xd0]), Some(0xc000));
+    assert_eq!(mem.ram_rom.ram[0xc002], 0xd0);
+    assert_eq!(mem.ram_rom.ram[0x2d], 0x00);
+    assert_eq!(prg::autostart_command(0xc000), "SYS49152\r");
+    prg::queue_keys(&mut mem, prg::autostart_command(0xc000).as_bytes());
+    assert_eq!(mem.ram_rom.ram[0xc6], 9);
+    assert_eq!(mem.ram_rom.ram[0x277], b'S');
+  }
+}
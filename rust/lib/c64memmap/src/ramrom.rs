@@ -19,6 +19,16 @@ impl RamRom {
     };
   }
 
+  /**
+   * Fill RAM with the pattern it holds when the machine is switched on:
+   * alternating blocks of 64 bytes of $00 and 64 bytes of $ff.
+   */
+  pub fn power_on(&mut self) {
+    for (addr, byte) in self.ram.iter_mut().enumerate() {
+      *byte = if addr & 0x40 == 0 { 0x00 } else { 0xff };
+    }
+  }
+
   pub fn kernal_ptr(&mut self) -> *mut u8 {
     let ptr = &mut self.kernal[0] as *mut u8;
     return ptr;
@@ -44,26 +54,39 @@ impl RamRom {
     return ptr;
   }
 
-  pub fn screen_ptr(&mut self) -> *mut u8 {
-    let ptr = &mut self.ram[0x400] as *mut u8;
-    return ptr;
+  /**
+   * The VIC-II sees the character ROM instead of RAM at $1000-$1fff within
+   * banks 0 and 2, and plain RAM everywhere else.
+   */
+  fn vic_sees_char_rom(bank: u16, offset: u16) -> bool {
+    (bank == 0x0000 || bank == 0x8000) && (offset & 0x3000) == 0x1000
   }
 
-  pub fn initialize_kernal_rom(&mut self, rom: &'static [u8;0x2000]) {
-    for i in 0..0x2000 {
-      self.kernal[i] = rom[i];
+  pub fn vic_get_byte(&self, bank: u16, offset: u16) -> u8 {
+    let offset = offset & 0x3fff;
+    if RamRom::vic_sees_char_rom(bank, offset) {
+      return self.char_gen[(offset & 0xfff) as usize];
     }
+    return self.ram[(bank | offset) as usize];
   }
 
-  pub fn initialize_basic_rom(&mut self, rom: &'static [u8;0x2000]) {
-    for i in 0..0x2000 {
-      self.basic[i] = rom[i];
+  pub fn vic_ptr(&mut self, bank: u16, offset: u16) -> *mut u8 {
+    let offset = offset & 0x3fff;
+    if RamRom::vic_sees_char_rom(bank, offset) {
+      return &mut self.char_gen[(offset & 0xfff) as usize] as *mut u8;
     }
+    return &mut self.ram[(bank | offset) as usize] as *mut u8;
   }
 
-  pub fn initialize_char_rom(&mut self, rom: &'static [u8;0x1000]) {
-    for i in 0..0x1000 {
-      self.char_gen[i] = rom[i];
-    }
+  pub fn initialize_kernal_rom(&mut self, rom: &[u8]) {
+    self.kernal.copy_from_slice(rom);
+  }
+
+  pub fn initialize_basic_rom(&mut self, rom: &[u8]) {
+    self.basic.copy_from_slice(rom);
+  }
+
+  pub fn initialize_char_rom(&mut self, rom: &[u8]) {
+    self.char_gen.copy_from_slice(rom);
   }
 }
\ No newline at end of file
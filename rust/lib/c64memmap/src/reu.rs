@@ -0,0 +1,336 @@
+// The 17xx RAM Expansion Units. An REU sits in the expansion port with its
+// registers in I/O 2 at $DF00, and moves data between its own RAM and the
+// C64's by DMA: it pulls the DMA line to hold the CPU, and reads and writes
+// memory through the bus as the CPU would see it, one byte per cycle.
+//
+// The 1700 has 128K, the 1764 256K and the 1750 512K. Later clones extend
+// the bank register to address up to 16MB.
+
+use memmap::mos6510::memory::Memory;
+
+pub const MIN_SIZE: usize = 128 * 1024;
+pub const MAX_SIZE: usize = 16 * 1024 * 1024;
+
+const STATUS_IRQ: u8 = 0x80;
+const STATUS_END_OF_BLOCK: u8 = 0x40;
+const STATUS_FAULT: u8 = 0x20;
+// Set on everything but the 1700, which was built from 64K chips
+const STATUS_256K_CHIPS: u8 = 0x10;
+
+const COMMAND_EXECUTE: u8 = 0x80;
+const COMMAND_AUTOLOAD: u8 = 0x20;
+// When clear, the transfer waits for a write to $FF00, so code can switch
+// the ROMs out of the way before it starts
+const COMMAND_FF00_DISABLED: u8 = 0x10;
+const COMMAND_UNUSED: u8 = 0x4c;
+
+const MASK_IRQ_ENABLE: u8 = 0x80;
+const MASK_END_OF_BLOCK: u8 = 0x40;
+const MASK_FAULT: u8 = 0x20;
+const MASK_UNUSED: u8 = 0x1f;
+
+const CONTROL_FIX_C64: u8 = 0x80;
+const CONTROL_FIX_REU: u8 = 0x40;
+const CONTROL_UNUSED: u8 = 0x3f;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Transfer {
+  // C64 to REU
+  Stash,
+  // REU to C64
+  Fetch,
+  Swap,
+  // Compare, stopping at the first difference
+  Verify,
+}
+
+pub struct Reu {
+  ram: Vec<u8>,
+  status: u8,
+  command: u8,
+  c64_address: u16,
+  reu_address: u32,
+  length: u16,
+  interrupt_mask: u8,
+  address_control: u8,
+  // Autoload restores the address and length registers to these, the
+  // values last written, after each transfer
+  c64_base: u16,
+  reu_base: u32,
+  length_base: u16,
+}
+
+impl Reu {
+  /**
+   * Create an REU with `size` bytes of RAM, which must be a power of two
+   * from 128K to 16MB.
+   */
+  pub fn new(size: usize) -> Option<Reu> {
+    if size < MIN_SIZE || size > MAX_SIZE || !size.is_power_of_two() {
+      return None;
+    }
+    let mut reu = Reu {
+      ram: vec![0; size],
+      status: 0,
+      command: 0,
+      c64_address: 0,
+      reu_address: 0,
+      length: 0,
+      interrupt_mask: 0,
+      address_control: 0,
+      c64_base: 0,
+      reu_base: 0,
+      length_base: 0,
+    };
+    reu.reset();
+    return Some(reu);
+  }
+
+  pub fn size(&self) -> usize {
+    self.ram.len()
+  }
+
+  /**
+   * The registers return to their power-on values. RAM keeps its contents.
+   */
+  pub fn reset(&mut self) {
+    self.status = if self.ram.len() > MIN_SIZE { STATUS_256K_CHIPS } else { 0 };
+    self.command = COMMAND_FF00_DISABLED;
+    self.c64_address = 0;
+    self.reu_address = 0;
+    self.length = 0xffff;
+    self.interrupt_mask = 0;
+    self.address_control = 0;
+    self.c64_base = 0;
+    self.reu_base = 0;
+    self.length_base = 0xffff;
+  }
+
+  /**
+   * True while the REU is asserting the IRQ line
+   */
+  pub fn interrupt_pending(&self) -> bool {
+    self.status & STATUS_IRQ != 0
+  }
+
+  // Bank bits beyond the installed RAM aren't stored, and read as 1
+  fn unused_bank_bits(&self) -> u8 {
+    !(((self.ram.len() - 1) >> 16) as u8)
+  }
+
+  /**
+   * Read a register. The block of 11 registers repeats through I/O 2.
+   * Reading the status clears its interrupt bits, unless it is only a peek.
+   */
+  pub fn read(&mut self, addr: u16, peek: bool) -> u8 {
+    match addr & 0x1f {
+      0 => {
+        let status = self.status;
+        if !peek {
+          self.status &= !(STATUS_IRQ | STATUS_END_OF_BLOCK | STATUS_FAULT);
+        }
+        status
+      },
+      1 => self.command | COMMAND_UNUSED,
+      2 => self.c64_address as u8,
+      3 => (self.c64_address >> 8) as u8,
+      4 => self.reu_address as u8,
+      5 => (self.reu_address >> 8) as u8,
+      6 => (self.reu_address >> 16) as u8 | self.unused_bank_bits(),
+      7 => self.length as u8,
+      8 => (self.length >> 8) as u8,
+      9 => self.interrupt_mask | MASK_UNUSED,
+      0xa => self.address_control | CONTROL_UNUSED,
+      _ => 0xff,
+    }
+  }
+
+  /**
+   * Write a register, returning true if a transfer should start now.
+   */
+  pub fn write(&mut self, addr: u16, value: u8) -> bool {
+    let offset = addr & 0x1f;
+    match offset {
+      1 => {
+        self.command = value;
+        return value & (COMMAND_EXECUTE | COMMAND_FF00_DISABLED) == COMMAND_EXECUTE | COMMAND_FF00_DISABLED;
+      },
+      // Writing an address or length sets both the register and the value
+      // it autoloads from
+      2..=3 => {
+        self.c64_base = set_byte_of(self.c64_base as u32, offset - 2, value) as u16;
+        self.c64_address = self.c64_base;
+      },
+      4..=6 => {
+        self.reu_base = set_byte_of(self.reu_base, offset - 4, value);
+        self.reu_address = self.reu_base & (self.ram.len() as u32 - 1);
+      },
+      7..=8 => {
+        self.length_base = set_byte_of(self.length_base as u32, offset - 7, value) as u16;
+        self.length = self.length_base;
+      },
+      9 => {
+        self.interrupt_mask = value & !MASK_UNUSED;
+        self.update_interrupt();
+      },
+      0xa => self.address_control = value & !CONTROL_UNUSED,
+      _ => (),
+    }
+    return false;
+  }
+
+  /**
+   * A CPU write to $FF00 starts a transfer that was waiting for one.
+   */
+  pub fn write_ff00(&self) -> bool {
+    self.command & (COMMAND_EXECUTE | COMMAND_FF00_DISABLED) == COMMAND_EXECUTE
+  }
+
+  fn update_interrupt(&mut self) {
+    let end = self.interrupt_mask & MASK_END_OF_BLOCK != 0 && self.status & STATUS_END_OF_BLOCK != 0;
+    let fault = self.interrupt_mask & MASK_FAULT != 0 && self.status & STATUS_FAULT != 0;
+    if self.interrupt_mask & MASK_IRQ_ENABLE != 0 && (end || fault) {
+      self.status |= STATUS_IRQ;
+    }
+  }
+
+  /**
+   * Run the transfer set up in the registers against C64 memory, returning
+   * how many cycles the CPU is held for.
+   */
+  pub fn transfer<M: Memory>(&mut self, mem: &mut M) -> u32 {
+    let transfer = match self.command & 3 {
+      0 => Transfer::Stash,
+      1 => Transfer::Fetch,
+      2 => Transfer::Swap,
+      _ => Transfer::Verify,
+    };
+    let reu_mask = self.ram.len() as u32 - 1;
+    let c64_step = if self.address_control & CONTROL_FIX_C64 != 0 { 0 } else { 1 };
+    let reu_step = if self.address_control & CONTROL_FIX_REU != 0 { 0 } else { 1 };
+    // A length of 0 moves 64K
+    let mut remaining = if self.length == 0 { 0x10000 } else { self.length as u32 };
+    let mut cycles = 0;
+    let mut fault = false;
+    while remaining > 0 && !fault {
+      let reu_index = self.reu_address as usize;
+      match transfer {
+        Transfer::Stash => {
+          self.ram[reu_index] = mem.get_byte(self.c64_address);
+          cycles += 1;
+        },
+        Transfer::Fetch => {
+          mem.set_byte(self.c64_address, self.ram[reu_index]);
+          cycles += 1;
+        },
+        Transfer::Swap => {
+          let value = mem.get_byte(self.c64_address);
+          mem.set_byte(self.c64_address, self.ram[reu_index]);
+          self.ram[reu_index] = value;
+          cycles += 2;
+        },
+        Transfer::Verify => {
+          fault = mem.get_byte(self.c64_address) != self.ram[reu_index];
+          cycles += 1;
+        },
+      }
+      self.c64_address = self.c64_address.wrapping_add(c64_step);
+      self.reu_address = self.reu_address.wrapping_add(reu_step) & reu_mask;
+      remaining -= 1;
+    }
+
+    if remaining == 0 {
+      self.status |= STATUS_END_OF_BLOCK;
+    }
+    if fault {
+      self.status |= STATUS_FAULT;
+    }
+    if self.command & COMMAND_AUTOLOAD != 0 {
+      self.c64_address = self.c64_base;
+      self.reu_address = self.reu_base & reu_mask;
+      self.length = self.length_base;
+    } else {
+      // The counter stops at 1 rather than wrapping to 0
+      self.length = if remaining == 0 { 1 } else { remaining as u16 };
+    }
+    self.command = (self.command & !COMMAND_EXECUTE) | COMMAND_FF00_DISABLED;
+    self.update_interrupt();
+    return cycles;
+  }
+}
+
+// Replace one byte of a multi-byte register
+fn set_byte_of(register: u32, byte: u16, value: u8) -> u32 {
+  let shift = byte * 8;
+  (register & !(0xff << shift)) | ((value as u32) << shift)
+}
+
+#[cfg(test)]
+mod tests {
+  use memmap::MemMap;
+  use memmap::mos6510::memory::Memory;
+  use reu::Reu;
+
+  // Set the C64 address, REU address and length, then give a command
+  fn run(mem: &mut MemMap, c64: u16, reu: u32, length: u16, command: u8) {
+    let registers = [
+      (2, c64 as u8), (3, (c64 >> 8) as u8),
+      (4, reu as u8), (5, (reu >> 8) as u8), (6, (reu >> 16) as u8),
+      (7, length as u8), (8, (length >> 8) as u8),
+      (1, command),
+    ];
+    for &(offset, value) in registers.iter() {
+      mem.set_byte(0xdf00 + offset, value);
+    }
+  }
+
+  #[test]
+  fn transfers() {
+    assert!(Reu::new(100000).is_none());
+    let mut mem = MemMap::new();
+    mem.reu = Reu::new(512 * 1024);
+    assert_eq!(mem.get_byte(0xdf00), 0x10);
+    for i in 0..0x10 {
+      mem.set_byte(0x1000 + i, i as u8);
+    }
+
+    // Stash, leaving the addresses after the block
+    run(&mut mem, 0x1000, 0x70000, 0x10, 0x90);
+    assert_eq!(mem.take_dma_stall(), 0x10);
+    assert!(!mem.dma_stalled());
+    assert_eq!(mem.get_byte(0xdf00), 0x50);
+    assert_eq!(mem.get_byte(0xdf00), 0x10);
+    assert_eq!((mem.get_byte(0xdf02), mem.get_byte(0xdf03)), (0x10, 0x10));
+    assert_eq!(mem.get_byte(0xdf06), 0xff);
+    assert_eq!((mem.get_byte(0xdf07), mem.get_byte(0xdf08)), (1, 0));
+
+    // Fetch to a fixed C64 address, waiting for $FF00, with autoload
+    mem.set_byte(0xdf0a, 0x80);
+    run(&mut mem, 0x2000, 0x70000, 0x10, 0xa1);
+    assert_eq!(mem.ram_rom.ram[0x2000], 0);
+    mem.set_byte(0xff00, 0);
+    assert_eq!(mem.ram_rom.ram[0x2000], 0x0f);
+    assert_eq!((mem.get_byte(0xdf02), mem.get_byte(0xdf03)), (0x00, 0x20));
+    assert_eq!(mem.get_byte(0xdf07), 0x10);
+    assert_eq!(mem.get_byte(0xdf00), 0x50);
+    mem.set_byte(0xdf0a, 0);
+
+    // Verify stops at the first difference, raising the IRQ if enabled
+    mem.set_byte(0xdf09, 0xa0);
+    mem.set_byte(0x1004, 0x44);
+    run(&mut mem, 0x1000, 0x70000, 0x10, 0x93);
+    assert!(mem.reu_interrupt_pending());
+    assert_eq!(mem.get_byte(0xdf00), 0xb0);
+    assert!(!mem.reu_interrupt_pending());
+    assert_eq!(mem.get_byte(0xdf02), 0x05);
+    assert_eq!(mem.get_byte(0xdf07), 0x0b);
+
+    // Swap exchanges the two blocks, taking two cycles a byte
+    mem.take_dma_stall();
+    run(&mut mem, 0x1000, 0x70000, 0x10, 0x92);
+    assert_eq!(mem.take_dma_stall(), 0x20);
+    assert_eq!(mem.ram_rom.ram[0x1004], 0x04);
+    run(&mut mem, 0x3000, 0x70004, 1, 0x91);
+    assert_eq!(mem.ram_rom.ram[0x3000], 0x44);
+  }
+}
@@ -1,19 +1,702 @@
+use std::f32::consts::PI;
+use std::mem;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum ChipModel {
+  MOS6581,
+  MOS8580,
+}
+
+const CONTROL_GATE: u8 = 0x01;
+const CONTROL_SYNC: u8 = 0x02;
+const CONTROL_RING: u8 = 0x04;
+const CONTROL_TEST: u8 = 0x08;
+const CONTROL_TRIANGLE: u8 = 0x10;
+const CONTROL_SAWTOOTH: u8 = 0x20;
+const CONTROL_PULSE: u8 = 0x40;
+const CONTROL_NOISE: u8 = 0x80;
+
+// The potentiometer inputs are measured over this many cycles
+pub const POT_PERIOD: u16 = 512;
+
+// Number of cycles between envelope steps for each attack/decay/release value
+const RATE_PERIODS: [u16; 16] = [
+  9, 32, 63, 95, 149, 220, 267, 313, 392, 977, 1954, 3126, 3907, 11720, 19532, 31251,
+];
+
+// Measured 6581 cutoff curve, as (FC register, frequency in Hz). The 6581 is
+// far from linear, and varies a lot from chip to chip; this is a typical one.
+const CUTOFF_6581: [(u16, f32); 16] = [
+  (0, 220.0),
+  (256, 250.0),
+  (512, 420.0),
+  (640, 780.0),
+  (768, 1600.0),
+  (896, 3000.0),
+  (992, 3850.0),
+  (1024, 5300.0),
+  (1088, 6500.0),
+  (1152, 7700.0),
+  (1280, 9000.0),
+  (1408, 11000.0),
+  (1536, 12500.0),
+  (1664, 14500.0),
+  (1792, 16000.0),
+  (2047, 18000.0),
+];
+// The 8580 cutoff is close to linear, topping out around 12.5kHz
+const CUTOFF_8580_MAX: f32 = 12500.0;
+
+// Corner frequency of the high-pass filter on the C64's audio output
+const OUTPUT_HIGH_PASS: f32 = 16.0;
+
+#[derive(Copy, Clone, PartialEq)]
+enum EnvelopeState {
+  Attack,
+  DecaySustain,
+  Release,
+}
+
+struct Envelope {
+  state: EnvelopeState,
+  gate: bool,
+  counter: u8,
+  rate_counter: u16,
+  exponential_counter: u8,
+  exponential_period: u8,
+  hold_zero: bool,
+
+  attack: u8,
+  decay: u8,
+  sustain: u8,
+  release: u8,
+}
+
+impl Envelope {
+  pub fn new() -> Envelope {
+    return Envelope {
+      state: EnvelopeState::Release,
+      gate: false,
+      counter: 0,
+      rate_counter: 0,
+      exponential_counter: 0,
+      exponential_period: 1,
+      hold_zero: true,
+
+      attack: 0,
+      decay: 0,
+      sustain: 0,
+      release: 0,
+    };
+  }
+
+  pub fn set_gate(&mut self, gate: bool) {
+    if gate && !self.gate {
+      self.state = EnvelopeState::Attack;
+      self.hold_zero = false;
+    } else if !gate && self.gate {
+      self.state = EnvelopeState::Release;
+    }
+    self.gate = gate;
+  }
+
+  pub fn clock(&mut self) {
+    let rate = match self.state {
+      EnvelopeState::Attack => self.attack,
+      EnvelopeState::DecaySustain => self.decay,
+      EnvelopeState::Release => self.release,
+    };
+    // The rate counter is 15 bits wide and only resets when it matches the
+    // period exactly. Lowering the rate mid-count makes it run all the way
+    // around first, which is the source of the well-known ADSR delay bug.
+    self.rate_counter = (self.rate_counter + 1) & 0x7fff;
+    if self.rate_counter != RATE_PERIODS[rate as usize] {
+      return;
+    }
+    self.rate_counter = 0;
+
+    if self.state == EnvelopeState::Attack {
+      // Attack is linear, and ignores the exponential counter
+      self.exponential_counter = 0;
+      self.counter = self.counter.wrapping_add(1);
+      if self.counter == 0xff {
+        self.state = EnvelopeState::DecaySustain;
+      }
+      self.update_exponential_period();
+      return;
+    }
+
+    self.exponential_counter += 1;
+    if self.exponential_counter < self.exponential_period {
+      return;
+    }
+    self.exponential_counter = 0;
+    if self.hold_zero {
+      return;
+    }
+    match self.state {
+      EnvelopeState::DecaySustain => {
+        if self.counter != self.sustain * 0x11 {
+          self.counter -= 1;
+        }
+      },
+      _ => self.counter = self.counter.wrapping_sub(1),
+    }
+    self.update_exponential_period();
+  }
+
+  fn update_exponential_period(&mut self) {
+    // Decay and release approximate an exponential curve by slowing down as
+    // the counter passes these levels
+    match self.counter {
+      0xff => self.exponential_period = 1,
+      0x5d => self.exponential_period = 2,
+      0x36 => self.exponential_period = 4,
+      0x1a => self.exponential_period = 8,
+      0x0e => self.exponential_period = 16,
+      0x06 => self.exponential_period = 30,
+      0x00 => {
+        self.exponential_period = 1;
+        self.hold_zero = true;
+      },
+      _ => (),
+    }
+  }
+}
+
+struct Voice {
+  frequency: u16,
+  pulse_width: u16,
+  control: u8,
+  accumulator: u32,
+  shift_register: u32,
+  msb_rising: bool,
+  envelope: Envelope,
+}
+
+impl Voice {
+  pub fn new() -> Voice {
+    return Voice {
+      frequency: 0,
+      pulse_width: 0,
+      control: 0,
+      accumulator: 0,
+      shift_register: 0x7ffff8,
+      msb_rising: false,
+      envelope: Envelope::new(),
+    };
+  }
+
+  pub fn set_control(&mut self, value: u8) {
+    if value & CONTROL_TEST != 0 {
+      // The test bit holds the oscillator at zero and resets the noise LFSR
+      self.accumulator = 0;
+      self.shift_register = 0x7ffff8;
+    }
+    self.control = value;
+    self.envelope.set_gate(value & CONTROL_GATE != 0);
+  }
+
+  pub fn clock_oscillator(&mut self) {
+    if self.control & CONTROL_TEST != 0 {
+      self.msb_rising = false;
+      return;
+    }
+    let previous = self.accumulator;
+    self.accumulator = (previous + self.frequency as u32) & 0xffffff;
+    self.msb_rising = (previous & 0x800000) == 0 && (self.accumulator & 0x800000) != 0;
+    // The noise generator is clocked by bit 19 of the accumulator
+    if (previous & 0x80000) == 0 && (self.accumulator & 0x80000) != 0 {
+      let feedback = ((self.shift_register >> 22) ^ (self.shift_register >> 17)) & 1;
+      self.shift_register = ((self.shift_register << 1) & 0x7fffff) | feedback;
+    }
+  }
+
+  /**
+   * 12-bit waveform output. When several waveforms are selected at once the
+   * real chip pulls the outputs against each other; ANDing them together is a
+   * reasonable approximation of the resulting combined waveforms.
+   */
+  pub fn waveform_output(&self, sync_source: u32) -> u16 {
+    let mut output = 0xfff;
+    if self.control & 0xf0 == 0 {
+      return 0;
+    }
+    if self.control & CONTROL_TRIANGLE != 0 {
+      // Ring modulation replaces the triangle's MSB with an XOR against the
+      // sync source's MSB
+      let msb = if self.control & CONTROL_RING != 0 {
+        (self.accumulator ^ sync_source) & 0x800000
+      } else {
+        self.accumulator & 0x800000
+      };
+      let triangle = if msb != 0 { !self.accumulator } else { self.accumulator };
+      output = output & ((triangle >> 11) & 0xfff) as u16;
+    }
+    if self.control & CONTROL_SAWTOOTH != 0 {
+      output = output & (self.accumulator >> 12) as u16;
+    }
+    if self.control & CONTROL_PULSE != 0 {
+      let high = self.control & CONTROL_TEST != 0 || (self.accumulator >> 12) as u16 >= self.pulse_width;
+      if !high {
+        output = 0;
+      }
+    }
+    if self.control & CONTROL_NOISE != 0 {
+      let sr = self.shift_register;
+      let noise =
+        ((sr & 0x400000) >> 11) |
+        ((sr & 0x100000) >> 10) |
+        ((sr & 0x010000) >> 7) |
+        ((sr & 0x002000) >> 5) |
+        ((sr & 0x000800) >> 4) |
+        ((sr & 0x000080) >> 1) |
+        ((sr & 0x000010) << 1) |
+        ((sr & 0x000004) << 2);
+      output = output & noise as u16;
+    }
+    output
+  }
+}
+
+struct Filter {
+  cutoff: u16,
+  resonance: u8,
+  routing: u8,
+  mode: u8,
+  volume: u8,
+
+  w0: f32,
+  q_inverse: f32,
+  low_pass: f32,
+  band_pass: f32,
+  high_pass: f32,
+}
+
+impl Filter {
+  pub fn new() -> Filter {
+    return Filter {
+      cutoff: 0,
+      resonance: 0,
+      routing: 0,
+      mode: 0,
+      volume: 0,
+
+      w0: 0.0,
+      q_inverse: 1.0,
+      low_pass: 0.0,
+      band_pass: 0.0,
+      high_pass: 0.0,
+    };
+  }
+
+  pub fn update_coefficients(&mut self, model: ChipModel, clock_rate: u32) {
+    let frequency = match model {
+      ChipModel::MOS6581 => {
+        let mut f = CUTOFF_6581[CUTOFF_6581.len() - 1].1;
+        for i in 1..CUTOFF_6581.len() {
+          let (fc_high, f_high) = CUTOFF_6581[i];
+          if self.cutoff <= fc_high {
+            let (fc_low, f_low) = CUTOFF_6581[i - 1];
+            let t = (self.cutoff - fc_low) as f32 / (fc_high - fc_low) as f32;
+            f = f_low + (f_high - f_low) * t;
+            break;
+          }
+        }
+        f
+      },
+      ChipModel::MOS8580 => CUTOFF_8580_MAX * (self.cutoff as f32) / 2048.0,
+    };
+    self.w0 = 2.0 * PI * frequency / clock_rate as f32;
+    let q = 0.707 + (self.resonance as f32) / 15.0;
+    self.q_inverse = 1.0 / q;
+  }
+
+  /**
+   * Two-integrator state variable filter, stepped once per cycle
+   */
+  pub fn clock(&mut self, input: f32) -> f32 {
+    self.high_pass = input - self.low_pass - self.band_pass * self.q_inverse;
+    self.band_pass += self.w0 * self.high_pass;
+    self.low_pass += self.w0 * self.band_pass;
+
+    let mut output = 0.0;
+    if self.mode & 0x1 != 0 {
+      output += self.low_pass;
+    }
+    if self.mode & 0x2 != 0 {
+      output += self.band_pass;
+    }
+    if self.mode & 0x4 != 0 {
+      output += self.high_pass;
+    }
+    output
+  }
+}
+
 pub struct SID {
-  
+  voices: [Voice; 3],
+  filter: Filter,
+  model: ChipModel,
+  // POTX and POTY hold the last measurement of the inputs, which are
+  // measured together every POT_PERIOD cycles
+  pot_x: u8,
+  pot_y: u8,
+  pot_inputs: (u8, u8),
+  pot_cycles: u16,
+
+  clock_rate: u32,
+  sample_rate: u32,
+  cycles_per_sample: f32,
+  sample_cycles: f32,
+  sample_sum: f32,
+  sample_count: u32,
+  dc_level: f32,
+  samples: Vec<f32>,
 }
 
 impl SID {
   pub fn new() -> SID {
-    return SID {
+    let mut sid = SID {
+      voices: [Voice::new(), Voice::new(), Voice::new()],
+      filter: Filter::new(),
+      model: ChipModel::MOS6581,
+      pot_x: 0xff,
+      pot_y: 0xff,
+      pot_inputs: (0xff, 0xff),
+      pot_cycles: 0,
 
+      clock_rate: 985248,
+      sample_rate: 44100,
+      cycles_per_sample: 1.0,
+      sample_cycles: 0.0,
+      sample_sum: 0.0,
+      sample_count: 0,
+      dc_level: 0.0,
+      samples: Vec::new(),
     };
+    sid.set_sampling_parameters(985248, 44100);
+    return sid;
   }
 
   pub fn get_byte(&self, addr: u16) -> u8 {
-    return 0;
+    match addr & 0x1f {
+      0x19 => self.pot_x,
+      0x1a => self.pot_y,
+      // Upper 8 bits of voice 3's waveform, often used as a random source
+      0x1b => (self.voices[2].waveform_output(self.voices[1].accumulator) >> 4) as u8,
+      0x1c => self.voices[2].envelope.counter,
+      // The remaining registers are write-only
+      _ => 0,
+    }
+  }
+
+  /**
+   * Set the values on the two potentiometer inputs. The registers follow
+   * at the end of the current measurement.
+   */
+  pub fn set_pots(&mut self, x: u8, y: u8) {
+    self.pot_inputs = (x, y);
   }
 
   pub fn set_byte(&mut self, addr: u16, value: u8) {
-    
+    let reg = addr & 0x1f;
+    if reg < 0x15 {
+      let voice = &mut self.voices[(reg / 7) as usize];
+      match reg % 7 {
+        0 => voice.frequency = (voice.frequency & 0xff00) | (value as u16),
+        1 => voice.frequency = (voice.frequency & 0xff) | ((value as u16) << 8),
+        2 => voice.pulse_width = (voice.pulse_width & 0xf00) | (value as u16),
+        3 => voice.pulse_width = (voice.pulse_width & 0xff) | (((value & 0xf) as u16) << 8),
+        4 => voice.set_control(value),
+        5 => {
+          voice.envelope.attack = value >> 4;
+          voice.envelope.decay = value & 0xf;
+        },
+        _ => {
+          voice.envelope.sustain = value >> 4;
+          voice.envelope.release = value & 0xf;
+        },
+      }
+      return;
+    }
+    match reg {
+      0x15 => {
+        self.filter.cutoff = (self.filter.cutoff & 0x7f8) | ((value & 7) as u16);
+        self.filter.update_coefficients(self.model, self.clock_rate);
+      },
+      0x16 => {
+        self.filter.cutoff = (self.filter.cutoff & 7) | ((value as u16) << 3);
+        self.filter.update_coefficients(self.model, self.clock_rate);
+      },
+      0x17 => {
+        self.filter.resonance = value >> 4;
+        self.filter.routing = value & 0xf;
+        self.filter.update_coefficients(self.model, self.clock_rate);
+      },
+      0x18 => {
+        self.filter.mode = (value >> 4) & 0xf;
+        self.filter.volume = value & 0xf;
+      },
+      _ => (),
+    }
+  }
+
+  /**
+   * The values last written to each register, followed by the read-only
+   * registers, for snapshots
+   */
+  pub fn registers(&self) -> [u8; 0x20] {
+    let mut registers = [0; 0x20];
+    for i in 0..3 {
+      let voice = &self.voices[i];
+      let base = i * 7;
+      registers[base] = (voice.frequency & 0xff) as u8;
+      registers[base + 1] = (voice.frequency >> 8) as u8;
+      registers[base + 2] = (voice.pulse_width & 0xff) as u8;
+      registers[base + 3] = (voice.pulse_width >> 8) as u8;
+      registers[base + 4] = voice.control;
+      registers[base + 5] = (voice.envelope.attack << 4) | voice.envelope.decay;
+      registers[base + 6] = (voice.envelope.sustain << 4) | voice.envelope.release;
+    }
+    registers[0x15] = (self.filter.cutoff & 7) as u8;
+    registers[0x16] = (self.filter.cutoff >> 3) as u8;
+    registers[0x17] = (self.filter.resonance << 4) | self.filter.routing;
+    registers[0x18] = (self.filter.mode << 4) | self.filter.volume;
+    for reg in 0x19..0x1d {
+      registers[reg] = self.get_byte(reg as u16);
+    }
+    return registers;
+  }
+
+  /**
+   * Silence the voices and clear the filter, as the RESET line does.
+   */
+  pub fn reset(&mut self) {
+    self.voices = [Voice::new(), Voice::new(), Voice::new()];
+    self.filter = Filter::new();
+    self.filter.update_coefficients(self.model, self.clock_rate);
+  }
+
+  pub fn chip_model(&self) -> ChipModel {
+    self.model
+  }
+
+  pub fn set_chip_model(&mut self, model: ChipModel) {
+    self.model = model;
+    self.filter.update_coefficients(model, self.clock_rate);
+  }
+
+  /**
+   * Set the rate the SID is clocked at, and the rate of the sample stream it
+   * produces for the host.
+   */
+  pub fn set_sampling_parameters(&mut self, clock_rate: u32, sample_rate: u32) {
+    self.clock_rate = clock_rate;
+    self.sample_rate = sample_rate;
+    self.cycles_per_sample = clock_rate as f32 / sample_rate as f32;
+    self.filter.update_coefficients(self.model, clock_rate);
+  }
+
+  /**
+   * Change the clock rate for a different machine model, keeping the host's
+   * sample rate.
+   */
+  pub fn set_clock_rate(&mut self, clock_rate: u32) {
+    let sample_rate = self.sample_rate;
+    self.set_sampling_parameters(clock_rate, sample_rate);
+  }
+
+  pub fn clock(&mut self, cycles: u8) {
+    for _ in 0..cycles {
+      self.clock_cycle();
+    }
+    // Each measurement discharges the capacitors for half the period, then
+    // counts how long they take to charge again
+    self.pot_cycles += cycles as u16;
+    if self.pot_cycles >= POT_PERIOD {
+      self.pot_cycles -= POT_PERIOD;
+      self.pot_x = self.pot_inputs.0;
+      self.pot_y = self.pot_inputs.1;
+    }
+  }
+
+  fn clock_cycle(&mut self) {
+    for voice in self.voices.iter_mut() {
+      voice.clock_oscillator();
+      voice.envelope.clock();
+    }
+    // Each voice is hard-synced to the MSB of the voice before it
+    for i in 0..3 {
+      let source = (i + 2) % 3;
+      if self.voices[i].control & CONTROL_SYNC != 0 && self.voices[source].msb_rising {
+        self.voices[i].accumulator = 0;
+      }
+    }
+
+    let mut filtered = 0.0;
+    let mut direct = 0.0;
+    for i in 0..3 {
+      let output = self.voice_output(i);
+      if self.filter.routing & (1 << i) != 0 {
+        filtered += output;
+      } else if i != 2 || self.filter.mode & 0x8 == 0 {
+        // voice 3 can be muted, but only when it bypasses the filter
+        direct += output;
+      }
+    }
+    let mixed = (direct + self.filter.clock(filtered)) * (self.filter.volume as f32) / 15.0;
+
+    self.sample_sum += mixed;
+    self.sample_count += 1;
+    self.sample_cycles += 1.0;
+    if self.sample_cycles >= self.cycles_per_sample {
+      self.sample_cycles -= self.cycles_per_sample;
+      let average = self.sample_sum / self.sample_count as f32;
+      self.sample_sum = 0.0;
+      self.sample_count = 0;
+      self.push_sample(average);
+    }
+  }
+
+  /**
+   * Voice output, scaled so that a full-scale waveform at maximum envelope
+   * spans -1.0 to 1.0. The 6581 waveform DACs are centered off of zero, and
+   * each voice adds a DC offset that the master volume scales; this is what
+   * makes sample playback through $D418 audible on that chip.
+   */
+  fn voice_output(&self, index: usize) -> f32 {
+    let voice = &self.voices[index];
+    let source = self.voices[(index + 2) % 3].accumulator;
+    let wave = voice.waveform_output(source) as i32;
+    let envelope = voice.envelope.counter as i32;
+    let (wave_zero, voice_dc) = match self.model {
+      ChipModel::MOS6581 => (0x380, 1.0),
+      ChipModel::MOS8580 => (0x800, 0.0),
+    };
+    ((wave - wave_zero) * envelope) as f32 / (0x800 * 0xff) as f32 + voice_dc
+  }
+
+  fn push_sample(&mut self, sample: f32) {
+    // Remove the DC offset, like the coupling capacitor on the audio output
+    let alpha = 2.0 * PI * OUTPUT_HIGH_PASS / self.sample_rate as f32;
+    self.dc_level += (sample - self.dc_level) * alpha;
+    let mut output = (sample - self.dc_level) / 3.0;
+    if output > 1.0 {
+      output = 1.0;
+    }
+    if output < -1.0 {
+      output = -1.0;
+    }
+    // Drop samples if the host has stopped collecting them
+    if self.samples.len() < self.sample_rate as usize {
+      self.samples.push(output);
+    }
+  }
+
+  pub fn take_samples(&mut self) -> Vec<f32> {
+    mem::replace(&mut self.samples, Vec::new())
+  }
+
+  pub fn samples_ptr(&self) -> *const f32 {
+    self.samples.as_ptr()
+  }
+
+  pub fn samples_len(&self) -> usize {
+    self.samples.len()
+  }
+
+  pub fn clear_samples(&mut self) {
+    self.samples.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use sid::SID;
+
+  #[test]
+  fn envelope_attack_decay() {
+    let mut sid = SID::new();
+    sid.set_byte(0x05, 0x00); // fastest attack and decay
+    sid.set_byte(0x06, 0x80); // sustain at $88
+    sid.set_byte(0x04, 0x21);
+    for _ in 0..(9 * 0xff) {
+      sid.clock(1);
+    }
+    assert_eq!(sid.voices[0].envelope.counter, 0xff);
+    for _ in 0..(9 * 0x100) {
+      sid.clock(1);
+    }
+    assert_eq!(sid.voices[0].envelope.counter, 0x88);
+    sid.set_byte(0x04, 0x20);
+    for _ in 0..10000 {
+      sid.clock(100);
+    }
+    assert_eq!(sid.voices[0].envelope.counter, 0);
+  }
+
+  #[test]
+  fn oscillator() {
+    let mut sid = SID::new();
+    sid.set_byte(0x0e, 0x00);
+    sid.set_byte(0x0f, 0x10);
+    sid.set_byte(0x12, 0x20);
+    sid.clock(16);
+    assert_eq!(sid.voices[2].accumulator, 0x10000);
+    assert_eq!(sid.voices[2].waveform_output(0), 0x010);
+    // the test bit holds the oscillator at zero
+    sid.set_byte(0x12, 0x28);
+    sid.clock(16);
+    assert_eq!(sid.voices[2].accumulator, 0);
+  }
+
+  #[test]
+  fn hard_sync() {
+    let mut sid = SID::new();
+    // voice 1 syncs voice 2
+    sid.voices[0].accumulator = 0x7ffffe;
+    sid.set_byte(0x00, 0x01);
+    sid.set_byte(0x08, 0x01);
+    sid.set_byte(0x0b, 0x22);
+    sid.clock(1);
+    assert_eq!(sid.voices[1].accumulator, 0x100);
+    sid.clock(1);
+    // voice 1's MSB went high, restarting voice 2
+    assert_eq!(sid.voices[1].accumulator, 0);
+  }
+
+  #[test]
+  fn voice_3_readback() {
+    let mut sid = SID::new();
+    sid.set_byte(0x0e, 0x00);
+    sid.set_byte(0x0f, 0x10);
+    sid.set_byte(0x12, 0x21);
+    sid.clock(64);
+    assert_eq!(sid.get_byte(0x1b), 0x04);
+    assert_eq!(sid.get_byte(0x1c), 7);
+    // noise changes every time bit 19 of the oscillator rises
+    sid.set_byte(0x0f, 0xff);
+    sid.set_byte(0x12, 0x80);
+    let mut values = Vec::new();
+    for _ in 0..16 {
+      sid.clock(64);
+      values.push(sid.get_byte(0x1b));
+    }
+    values.dedup();
+    assert!(values.len() > 4);
+  }
+
+  #[test]
+  fn sample_stream() {
+    let mut sid = SID::new();
+    sid.set_sampling_parameters(1000000, 50000);
+    for _ in 0..100 {
+      sid.clock(10);
+    }
+    assert_eq!(sid.samples_len(), 50);
+    let samples = sid.take_samples();
+    assert_eq!(samples.len(), 50);
+    assert_eq!(sid.samples_len(), 0);
   }
 }
\ No newline at end of file
@@ -0,0 +1,436 @@
+// VICE snapshot files (.vsf) hold the state of a machine as a list of
+// modules, one for each chip, each with a name, a version, and data laid out
+// the way VICE's own structures are. Only the fields that map onto our chips
+// are used; the rest are written as zero and skipped when reading, which
+// VICE tolerates for the module versions written here.
+
+use memmap::MemMap;
+use memmap::mos6510::cpu::CPU;
+use cia::CiaState;
+use sid::ChipModel;
+use vic::VicState;
+
+const MAGIC: &[u8] = b"VICE Snapshot File\x1a";
+const VERSION_MAGIC: &[u8] = b"VICE Version\x1a";
+const MACHINE_NAME: &[u8] = b"C64";
+const NAME_LENGTH: usize = 16;
+// Name, major and minor version, and the length including this header
+const MODULE_HEADER_LENGTH: usize = NAME_LENGTH + 6;
+
+#[derive(Debug, PartialEq)]
+pub enum SnapshotError {
+  InvalidHeader,
+  WrongMachine,
+  MissingModule(&'static str),
+  ModuleTooShort(&'static str),
+}
+
+struct Module {
+  name: String,
+  major: u8,
+  minor: u8,
+  data: Vec<u8>,
+}
+
+/**
+ * Builds the data of a module. VICE stores words and double words little
+ * endian.
+ */
+struct Writer {
+  data: Vec<u8>,
+}
+
+impl Writer {
+  fn new() -> Writer {
+    Writer { data: Vec::new() }
+  }
+
+  fn byte(&mut self, value: u8) {
+    self.data.push(value);
+  }
+
+  fn word(&mut self, value: u16) {
+    self.data.push(value as u8);
+    self.data.push((value >> 8) as u8);
+  }
+
+  fn dword(&mut self, value: u32) {
+    for i in 0..4 {
+      self.data.push((value >> (i * 8)) as u8);
+    }
+  }
+
+  fn bytes(&mut self, values: &[u8]) {
+    self.data.extend_from_slice(values);
+  }
+}
+
+struct Reader<'a> {
+  module: &'static str,
+  data: &'a [u8],
+  position: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn bytes(&mut self, length: usize) -> Result<&'a [u8], SnapshotError> {
+    if self.position + length > self.data.len() {
+      return Err(SnapshotError::ModuleTooShort(self.module));
+    }
+    let bytes = &self.data[self.position..(self.position + length)];
+    self.position += length;
+    return Ok(bytes);
+  }
+
+  fn byte(&mut self) -> Result<u8, SnapshotError> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn word(&mut self) -> Result<u16, SnapshotError> {
+    let bytes = self.bytes(2)?;
+    Ok((bytes[0] as u16) | ((bytes[1] as u16) << 8))
+  }
+
+  fn skip(&mut self, length: usize) -> Result<(), SnapshotError> {
+    self.bytes(length).map(|_| ())
+  }
+}
+
+pub struct Snapshot {
+  modules: Vec<Module>,
+}
+
+impl Snapshot {
+  pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+    let header_length = MAGIC.len() + 2 + NAME_LENGTH;
+    if bytes.len() < header_length || &bytes[..MAGIC.len()] != MAGIC {
+      return Err(SnapshotError::InvalidHeader);
+    }
+    let machine = &bytes[(MAGIC.len() + 2)..header_length];
+    if machine.iter().take_while(|&&c| c != 0).cloned().collect::<Vec<u8>>() != MACHINE_NAME {
+      return Err(SnapshotError::WrongMachine);
+    }
+    let mut offset = header_length;
+    // Snapshots from VICE 2.4 on record the version of VICE that made them
+    if bytes[offset..].starts_with(VERSION_MAGIC) {
+      offset += VERSION_MAGIC.len() + 8;
+    }
+    let mut modules = Vec::new();
+    while offset + MODULE_HEADER_LENGTH <= bytes.len() {
+      let header = &bytes[offset..(offset + MODULE_HEADER_LENGTH)];
+      let name = header[..NAME_LENGTH].iter().take_while(|&&c| c != 0).map(|&c| c as char).collect();
+      let length = (0..4).fold(0, |length, i| length | ((header[NAME_LENGTH + 2 + i] as usize) << (i * 8)));
+      if length < MODULE_HEADER_LENGTH || offset + length > bytes.len() {
+        return Err(SnapshotError::InvalidHeader);
+      }
+      modules.push(Module {
+        name: name,
+        major: header[NAME_LENGTH],
+        minor: header[NAME_LENGTH + 1],
+        data: bytes[(offset + MODULE_HEADER_LENGTH)..(offset + length)].to_vec(),
+      });
+      offset += length;
+    }
+    return Ok(Snapshot { modules: modules });
+  }
+
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&[2, 0]);
+    bytes.extend_from_slice(&padded_name(MACHINE_NAME));
+    bytes.extend_from_slice(VERSION_MAGIC);
+    // VICE 3.1.0.0, revision 0
+    bytes.extend_from_slice(&[3, 1, 0, 0, 0, 0, 0, 0]);
+    for module in self.modules.iter() {
+      bytes.extend_from_slice(&padded_name(module.name.as_bytes()));
+      bytes.push(module.major);
+      bytes.push(module.minor);
+      let length = (module.data.len() + MODULE_HEADER_LENGTH) as u32;
+      for i in 0..4 {
+        bytes.push((length >> (i * 8)) as u8);
+      }
+      bytes.extend_from_slice(&module.data);
+    }
+    return bytes;
+  }
+
+  fn add_module(&mut self, name: &str, major: u8, minor: u8, writer: Writer) {
+    self.modules.push(Module {
+      name: String::from(name),
+      major: major,
+      minor: minor,
+      data: writer.data,
+    });
+  }
+
+  fn module(&self, name: &'static str) -> Result<Reader<'_>, SnapshotError> {
+    match self.modules.iter().find(|m| m.name == name) {
+      Some(module) => Ok(Reader { module: name, data: &module.data, position: 0 }),
+      None => Err(SnapshotError::MissingModule(name)),
+    }
+  }
+
+  /**
+   * Capture the state of the CPU and the chips. Cartridges, drives and tapes
+   * are not included.
+   */
+  pub fn capture(cpu: &CPU, mem: &mut MemMap) -> Snapshot {
+    let mut snapshot = Snapshot { modules: Vec::new() };
+
+    // Registers, then the clock, last opcode and interrupt timing, which
+    // we don't track
+    let mut w = Writer::new();
+    w.dword(0);
+    w.byte(cpu.acc);
+    w.byte(cpu.x);
+    w.byte(cpu.y);
+    w.byte(cpu.stack);
+    w.word(cpu.pc);
+    w.byte(cpu.status);
+    for _ in 0..5 {
+      w.dword(0);
+    }
+    snapshot.add_module("MAINCPU", 1, 1, w);
+
+    let (exrom, game) = match mem.cartridge {
+      Some(ref cartridge) => (cartridge.exrom, cartridge.game),
+      None => (true, true),
+    };
+    let mut w = Writer::new();
+    w.byte(mem.ram_rom.ram[1]);
+    w.byte(mem.ram_rom.ram[0]);
+    // VICE records whether each line is asserted, which pulls it low
+    w.byte(if exrom { 0 } else { 1 });
+    w.byte(if game { 0 } else { 1 });
+    w.bytes(&mem.ram_rom.ram[..]);
+    snapshot.add_module("C64MEM", 0, 0, w);
+
+    for chip in 0..2 {
+      let state = mem.cia.save_state(chip);
+      let mut w = Writer::new();
+      w.byte(state.port_a);
+      w.byte(state.port_b);
+      w.byte(state.ddr_a);
+      w.byte(state.ddr_b);
+      w.word(state.timer_a);
+      w.word(state.timer_b);
+      w.bytes(&state.tod);
+      // Serial data register
+      w.byte(0);
+      w.byte(state.interrupt_mask);
+      w.byte(state.control_a);
+      w.byte(state.control_b);
+      w.word(state.timer_a_latch);
+      w.word(state.timer_b_latch);
+      w.byte(state.interrupt_status);
+      // Timer outputs on port B, and serial shift register bits
+      w.byte(0);
+      w.byte(0);
+      w.bytes(&state.tod_alarm);
+      w.byte(if state.tod_latch.is_some() { 1 } else { 0 });
+      w.bytes(&state.tod_latch.unwrap_or([0; 4]));
+      // Cycles until the next TOD tick
+      w.dword(0);
+      snapshot.add_module(if chip == 0 { "CIA1" } else { "CIA2" }, 1, 0, w);
+    }
+
+    let registers = mem.sid.registers();
+    let mut w = Writer::new();
+    // The fast SID engine, and the chip model
+    w.byte(0);
+    w.byte(if mem.sid.chip_model() == ChipModel::MOS8580 { 1 } else { 0 });
+    w.bytes(&registers);
+    snapshot.add_module("SID", 1, 1, w);
+
+    let state = mem.vic.save_state();
+    let mut w = Writer::new();
+    // Bad line state and blanking, then the color buffer
+    w.bytes(&[1, 0, 0]);
+    w.bytes(&[0; 40]);
+    w.bytes(&mem.ram_rom.color_ram[..]);
+    // Idle state, light pen trigger and position, matrix buffer, new sprite
+    // DMA mask, and the RAM base
+    w.bytes(&[0; 4]);
+    w.bytes(&[0; 40]);
+    w.byte(0);
+    w.dword(0);
+    w.byte(state.raster_cycle as u8);
+    w.word(state.raster_line);
+    w.bytes(&state.registers);
+    w.byte(state.sprite_background_collisions);
+    // Sprite DMA mask
+    w.byte(0);
+    w.byte(state.sprite_sprite_collisions);
+    w.word(mem.cia.get_vic_bank());
+    // Video counters
+    w.bytes(&[0; 5]);
+    w.byte(state.interrupt_status);
+    // Sprite data pointers and expansion flip-flops, and the next fetch
+    w.bytes(&[0; 24]);
+    w.dword(0);
+    w.byte(0);
+    snapshot.add_module("VIC-II", 1, 1, w);
+
+    return snapshot;
+  }
+
+  /**
+   * Load the state of the CPU and chips from the snapshot
+   */
+  pub fn restore(&self, cpu: &mut CPU, mem: &mut MemMap) -> Result<(), SnapshotError> {
+    // Read everything before changing anything, so a bad snapshot leaves the
+    // machine as it was
+    let mut r = self.module("MAINCPU")?;
+    r.skip(4)?;
+    let (acc, x, y, stack) = (r.byte()?, r.byte()?, r.byte()?, r.byte()?);
+    let pc = r.word()?;
+    let status = r.byte()?;
+
+    let mut r = self.module("C64MEM")?;
+    let (port, ddr) = (r.byte()?, r.byte()?);
+    r.skip(2)?;
+    let ram = r.bytes(0x10000)?;
+
+    let mut cias = Vec::new();
+    for &name in ["CIA1", "CIA2"].iter() {
+      let mut r = self.module(name)?;
+      let mut state = CiaState {
+        port_a: r.byte()?,
+        port_b: r.byte()?,
+        ddr_a: r.byte()?,
+        ddr_b: r.byte()?,
+        timer_a: r.word()?,
+        timer_b: r.word()?,
+        timer_a_latch: 0,
+        timer_b_latch: 0,
+        control_a: 0,
+        control_b: 0,
+        tod: [0; 4],
+        tod_alarm: [0; 4],
+        tod_latch: None,
+        interrupt_mask: 0,
+        interrupt_status: 0,
+      };
+      state.tod.copy_from_slice(r.bytes(4)?);
+      r.skip(1)?;
+      state.interrupt_mask = r.byte()?;
+      state.control_a = r.byte()?;
+      state.control_b = r.byte()?;
+      state.timer_a_latch = r.word()?;
+      state.timer_b_latch = r.word()?;
+      state.interrupt_status = r.byte()?;
+      r.skip(2)?;
+      state.tod_alarm.copy_from_slice(r.bytes(4)?);
+      let latched = r.byte()? & 1 != 0;
+      let mut latch = [0; 4];
+      latch.copy_from_slice(r.bytes(4)?);
+      if latched {
+        state.tod_latch = Some(latch);
+      }
+      cias.push(state);
+    }
+
+    let mut r = self.module("SID")?;
+    r.skip(1)?;
+    let chip_model = if r.byte()? == 1 { ChipModel::MOS8580 } else { ChipModel::MOS6581 };
+    let sid_registers = r.bytes(0x19)?;
+
+    let mut r = self.module("VIC-II")?;
+    r.skip(43)?;
+    let color_ram = r.bytes(0x400)?;
+    r.skip(49)?;
+    let raster_cycle = r.byte()? as u16;
+    let raster_line = r.word()?;
+    let mut vic = VicState {
+      registers: [0; 0x40],
+      raster_line: raster_line,
+      raster_cycle: raster_cycle,
+      sprite_sprite_collisions: 0,
+      sprite_background_collisions: 0,
+      interrupt_status: 0,
+    };
+    vic.registers.copy_from_slice(r.bytes(0x40)?);
+    vic.sprite_background_collisions = r.byte()?;
+    r.skip(1)?;
+    vic.sprite_sprite_collisions = r.byte()?;
+    r.skip(7)?;
+    vic.interrupt_status = r.byte()?;
+
+    cpu.acc = acc;
+    cpu.x = x;
+    cpu.y = y;
+    cpu.stack = stack;
+    cpu.pc = pc;
+    cpu.status = status;
+    mem.ram_rom.ram.copy_from_slice(ram);
+    mem.ram_rom.ram[0] = ddr;
+    mem.ram_rom.ram[1] = port;
+    mem.ram_rom.color_ram.copy_from_slice(color_ram);
+    for chip in 0..2 {
+      mem.cia.load_state(chip, &cias[chip]);
+    }
+    mem.sid.set_chip_model(chip_model);
+    for reg in 0..0x19 {
+      mem.sid.set_byte(reg as u16, sid_registers[reg]);
+    }
+    mem.vic.load_state(&vic);
+    return Ok(());
+  }
+}
+
+fn padded_name(name: &[u8]) -> [u8; NAME_LENGTH] {
+  let mut padded = [0; NAME_LENGTH];
+  padded[..name.len()].copy_from_slice(name);
+  return padded;
+}
+
+#[cfg(test)]
+mod tests {
+  use memmap::MemMap;
+  use memmap::mos6510::cpu::CPU;
+  use memmap::mos6510::memory::Memory;
+  use snapshot::{Snapshot, SnapshotError};
+
+  #[test]
+  fn round_trip() {
+    let mut cpu = CPU::new();
+    let mut mem = MemMap::new();
+    cpu.pc = 0xc123;
+    cpu.acc = 0x42;
+    cpu.stack = 0xf0;
+    mem.set_byte(0x2000, 0x99);
+    mem.set_byte(0xd020, 0x05);
+    mem.set_byte(0xd012, 0x80);
+    mem.set_byte(0xd800, 0x07);
+    mem.set_byte(0xdc04, 0x34);
+    mem.set_byte(0xdc05, 0x12);
+    mem.set_byte(0xdd02, 0x03);
+    mem.set_byte(0xdd00, 0x01);
+    mem.set_byte(0xd418, 0x0f);
+    let bytes = Snapshot::capture(&cpu, &mut mem).to_bytes();
+    assert_eq!(&bytes[..19], b"VICE Snapshot File\x1a");
+
+    let mut cpu = CPU::new();
+    let mut mem = MemMap::new();
+    Snapshot::from_bytes(&bytes).unwrap().restore(&mut cpu, &mut mem).unwrap();
+    assert_eq!((cpu.pc, cpu.acc, cpu.stack), (0xc123, 0x42, 0xf0));
+    assert_eq!(mem.get_byte(0x2000), 0x99);
+    assert_eq!(mem.get_byte(0xd020) & 0x0f, 0x05);
+    assert_eq!(mem.vic.save_state().registers[0x12], 0x80);
+    assert_eq!(mem.get_byte(0xd800) & 0x0f, 0x07);
+    assert_eq!(mem.cia.save_state(0).timer_a_latch, 0x1234);
+    assert_eq!(mem.cia.get_vic_bank(), 0x8000);
+    assert_eq!(mem.sid.registers()[0x18], 0x0f);
+  }
+
+  #[test]
+  fn invalid_snapshots() {
+    assert_eq!(Snapshot::from_bytes(b"C64 CARTRIDGE").err(), Some(SnapshotError::InvalidHeader));
+    let mut bytes = b"VICE Snapshot File\x1a\x02\x00".to_vec();
+    bytes.extend_from_slice(b"VIC20\0\0\0\0\0\0\0\0\0\0\0");
+    assert_eq!(Snapshot::from_bytes(&bytes).err(), Some(SnapshotError::WrongMachine));
+    bytes[21..26].copy_from_slice(b"C64\0\0");
+    let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+    let result = snapshot.restore(&mut CPU::new(), &mut MemMap::new());
+    assert_eq!(result, Err(SnapshotError::MissingModule("MAINCPU")));
+  }
+}
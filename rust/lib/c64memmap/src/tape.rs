@@ -0,0 +1,286 @@
+// Tape images, and the Datasette that plays them back.
+//
+// A T64 file is a container of program files, which can be copied straight
+// into memory. A TAP file is a recording of the signal on the tape, stored as
+// the length of each pulse, which the Datasette feeds to the CIA 1 FLAG line
+// so the KERNAL or a turbo loader can decode it.
+
+const T64_SIGNATURE: &[u8] = b"C64";
+const T64_HEADER_SIZE: usize = 0x40;
+const T64_ENTRY_SIZE: usize = 0x20;
+
+const TAP_SIGNATURE: &[u8] = b"C64-TAPE-RAW";
+const TAP_HEADER_SIZE: usize = 0x14;
+
+// In version 0 TAP files, a zero byte stands for any pulse too long to store
+const TAP_OVERFLOW_CYCLES: u32 = 256 * 8;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+  (bytes[offset] as u16) | ((bytes[offset + 1] as u16) << 8)
+}
+
+pub struct T64Entry {
+  pub name: Vec<u8>,
+  pub start: u16,
+  pub end: u16,
+  offset: usize,
+}
+
+pub struct T64 {
+  data: Vec<u8>,
+  entries: Vec<T64Entry>,
+}
+
+impl T64 {
+  pub fn from_bytes(data: Vec<u8>) -> Option<T64> {
+    if data.len() < T64_HEADER_SIZE || &data[0..3] != T64_SIGNATURE {
+      return None;
+    }
+    // Many tools write a bad "used entries" count, so check every slot in the
+    // directory instead
+    let slots = read_u16(&data, 0x22) as usize;
+    let mut entries = Vec::new();
+    for i in 0..slots {
+      let entry = T64_HEADER_SIZE + i * T64_ENTRY_SIZE;
+      if entry + T64_ENTRY_SIZE > data.len() {
+        break;
+      }
+      if data[entry] == 0 {
+        continue;
+      }
+      let offset = (data[entry + 8] as usize)
+        | ((data[entry + 9] as usize) << 8)
+        | ((data[entry + 10] as usize) << 16)
+        | ((data[entry + 11] as usize) << 24);
+      if offset >= data.len() {
+        continue;
+      }
+      let mut name = data[(entry + 0x10)..(entry + 0x20)].to_vec();
+      while name.last() == Some(&0x20) || name.last() == Some(&0) {
+        name.pop();
+      }
+      entries.push(T64Entry {
+        name: name,
+        start: read_u16(&data, entry + 2),
+        end: read_u16(&data, entry + 4),
+        offset: offset,
+      });
+    }
+    if entries.len() == 0 {
+      return None;
+    }
+    Some(T64 {
+      data: data,
+      entries: entries,
+    })
+  }
+
+  pub fn entries(&self) -> &[T64Entry] {
+    &self.entries
+  }
+
+  /**
+   * Returns the entry's contents as a PRG file. End addresses in the
+   * directory are often wrong, so the file is cut short if it runs past the
+   * end of the image.
+   */
+  pub fn read_file(&self, entry: &T64Entry) -> Vec<u8> {
+    let length = (entry.end.wrapping_sub(entry.start) as usize).min(self.data.len() - entry.offset);
+    let mut file = vec![(entry.start & 0xff) as u8, (entry.start >> 8) as u8];
+    file.extend_from_slice(&self.data[entry.offset..(entry.offset + length)]);
+    return file;
+  }
+}
+
+pub struct Tap {
+  version: u8,
+  data: Vec<u8>,
+  position: usize,
+}
+
+impl Tap {
+  pub fn from_bytes(bytes: &[u8]) -> Option<Tap> {
+    if bytes.len() < TAP_HEADER_SIZE || &bytes[0..12] != TAP_SIGNATURE {
+      return None;
+    }
+    Some(Tap {
+      version: bytes[12],
+      data: bytes[TAP_HEADER_SIZE..].to_vec(),
+      position: 0,
+    })
+  }
+
+  /**
+   * The length of the next pulse in CPU cycles, or None at the end of the
+   * tape.
+   */
+  pub fn next_pulse(&mut self) -> Option<u32> {
+    let byte = *self.data.get(self.position)?;
+    self.position += 1;
+    if byte != 0 {
+      return Some(byte as u32 * 8);
+    }
+    if self.version == 0 {
+      return Some(TAP_OVERFLOW_CYCLES);
+    }
+    // Later versions follow a zero with the exact length in 3 bytes
+    if self.position + 3 > self.data.len() {
+      self.position = self.data.len();
+      return None;
+    }
+    let length = (self.data[self.position] as u32)
+      | ((self.data[self.position + 1] as u32) << 8)
+      | ((self.data[self.position + 2] as u32) << 16);
+    self.position += 3;
+    return Some(length);
+  }
+
+  pub fn rewind(&mut self) {
+    self.position = 0;
+  }
+}
+
+pub struct Datasette {
+  pub tape: Option<Tap>,
+  // PLAY is held down
+  pub playing: bool,
+  // Cycles left in the current pulse, or 0 if none has started
+  remaining: u32,
+}
+
+impl Datasette {
+  pub fn new() -> Datasette {
+    return Datasette {
+      tape: None,
+      playing: false,
+      remaining: 0,
+    };
+  }
+
+  pub fn insert(&mut self, tape: Tap) {
+    self.tape = Some(tape);
+    self.playing = false;
+    self.remaining = 0;
+  }
+
+  pub fn eject(&mut self) -> Option<Tap> {
+    self.playing = false;
+    self.tape.take()
+  }
+
+  pub fn play(&mut self) {
+    self.playing = self.tape.is_some();
+  }
+
+  pub fn stop(&mut self) {
+    self.playing = false;
+  }
+
+  pub fn rewind(&mut self) {
+    if let Some(ref mut tape) = self.tape {
+      tape.rewind();
+    }
+    self.remaining = 0;
+  }
+
+  /**
+   * The cassette switch sense line, on bit 4 of the processor port. It reads
+   * low while a button is held down.
+   */
+  pub fn sense(&self) -> u8 {
+    if self.playing {
+      0
+    } else {
+      0x10
+    }
+  }
+
+  /**
+   * Advance the tape while the motor is running. Returns true if a pulse
+   * ended, which signals the CIA 1 FLAG line.
+   */
+  pub fn update(&mut self, cycles: u8, motor_on: bool) -> bool {
+    if !self.playing || !motor_on {
+      return false;
+    }
+    let tape = match self.tape {
+      Some(ref mut tape) => tape,
+      None => return false,
+    };
+    let mut cycles = cycles as u32;
+    let mut pulse = false;
+    while cycles >= self.remaining {
+      cycles -= self.remaining;
+      if self.remaining > 0 {
+        pulse = true;
+      }
+      match tape.next_pulse() {
+        Some(length) => self.remaining = length.max(1),
+        None => {
+          // The tape has run out, and the button pops back up
+          self.remaining = 0;
+          self.playing = false;
+          return pulse;
+        },
+      }
+    }
+    self.remaining -= cycles;
+    return pulse;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use tape::{T64, Tap, Datasette};
+
+  #[test]
+  fn t64_entries() {
+    let mut data = vec![0; 0x60];
+    data[0..19].copy_from_slice(b"C64 tape image file");
+    data[0x22] = 1;
+    data[0x40] = 1;
+    data[0x42] = 0x01;
+    data[0x43] = 0x08;
+    // An end address past the end of the file
+    data[0x44] = 0x00;
+    data[0x45] = 0x09;
+    data[0x48] = 0x60;
+    data[0x50..0x60].copy_from_slice(b"GAME            ");
+    data.extend_from_slice(&[1, 2, 3]);
+    let image = T64::from_bytes(data).unwrap();
+    assert_eq!(image.entries().len(), 1);
+    assert_eq!(image.entries()[0].name, b"GAME".to_vec());
+    assert_eq!(image.read_file(&image.entries()[0]), vec![0x01, 0x08, 1, 2, 3]);
+  }
+
+  #[test]
+  fn tap_pulses() {
+    let mut data = b"C64-TAPE-RAW".to_vec();
+    data.extend_from_slice(&[1, 0, 0, 0, 7, 0, 0, 0]);
+    data.extend_from_slice(&[0x30, 0, 0x10, 0x27, 0, 0x2f]);
+    let mut tap = Tap::from_bytes(&data).unwrap();
+    assert_eq!(tap.next_pulse(), Some(0x180));
+    assert_eq!(tap.next_pulse(), Some(10000));
+    assert_eq!(tap.next_pulse(), Some(0x178));
+    assert_eq!(tap.next_pulse(), None);
+
+    tap.rewind();
+    let mut datasette = Datasette::new();
+    datasette.insert(tap);
+    assert_eq!(datasette.sense(), 0x10);
+    datasette.play();
+    assert_eq!(datasette.sense(), 0);
+    // Nothing moves until the motor is on
+    assert!(!datasette.update(200, false));
+    assert!(!datasette.update(200, true));
+    assert!(datasette.update(200, true));
+    let mut pulses = 0;
+    for _ in 0..100 {
+      if datasette.update(200, true) {
+        pulses += 1;
+      }
+    }
+    assert_eq!(pulses, 2);
+    assert!(!datasette.playing);
+  }
+}
@@ -0,0 +1,242 @@
+use std::collections::VecDeque;
+use memmap::MemMap;
+use prg;
+
+// Keyboard matrix positions of the modifier keys
+pub const KEY_LEFT_SHIFT: u8 = 15;
+pub const KEY_RIGHT_SHIFT: u8 = 52;
+pub const KEY_CONTROL: u8 = 58;
+pub const KEY_COMMODORE: u8 = 61;
+
+// The KERNAL's keyboard decode tables, giving the PETSCII code produced by
+// each key in the matrix on its own, with SHIFT, with the Commodore key, and
+// with CTRL. 0xff marks keys with no code, and 0x01-0x04 are the modifiers.
+const UNSHIFTED: [u8; 64] = [
+  0x14, 0x0d, 0x1d, 0x88, 0x85, 0x86, 0x87, 0x11, 0x33, 0x57, 0x41, 0x34, 0x5a, 0x53, 0x45, 0x01,
+  0x35, 0x52, 0x44, 0x36, 0x43, 0x46, 0x54, 0x58, 0x37, 0x59, 0x47, 0x38, 0x42, 0x48, 0x55, 0x56,
+  0x39, 0x49, 0x4a, 0x30, 0x4d, 0x4b, 0x4f, 0x4e, 0x2b, 0x50, 0x4c, 0x2d, 0x2e, 0x3a, 0x40, 0x2c,
+  0x5c, 0x2a, 0x3b, 0x13, 0x01, 0x3d, 0x5e, 0x2f, 0x31, 0x5f, 0x04, 0x32, 0x20, 0x02, 0x51, 0x03,
+];
+const SHIFTED: [u8; 64] = [
+  0x94, 0x8d, 0x9d, 0x8c, 0x89, 0x8a, 0x8b, 0x91, 0x23, 0xd7, 0xc1, 0x24, 0xda, 0xd3, 0xc5, 0x01,
+  0x25, 0xd2, 0xc4, 0x26, 0xc3, 0xc6, 0xd4, 0xd8, 0x27, 0xd9, 0xc7, 0x28, 0xc2, 0xc8, 0xd5, 0xd6,
+  0x29, 0xc9, 0xca, 0x30, 0xcd, 0xcb, 0xcf, 0xce, 0xdb, 0xd0, 0xcc, 0xdd, 0x3e, 0x5b, 0xba, 0x3c,
+  0xa9, 0xc0, 0x5d, 0x93, 0x01, 0x3d, 0xde, 0x3f, 0x21, 0x5f, 0x04, 0x22, 0xa0, 0x02, 0xd1, 0x83,
+];
+const COMMODORE: [u8; 64] = [
+  0x94, 0x8d, 0x9d, 0x8c, 0x89, 0x8a, 0x8b, 0x91, 0x96, 0xb3, 0xb0, 0x97, 0xad, 0xae, 0xb1, 0x01,
+  0x98, 0xb2, 0xac, 0x99, 0xbc, 0xbb, 0xa3, 0xbd, 0x9a, 0xb7, 0xa5, 0x9b, 0xbf, 0xb4, 0xb8, 0xbe,
+  0x29, 0xa2, 0xb5, 0x30, 0xa7, 0xa1, 0xb9, 0xaa, 0xa6, 0xaf, 0xb6, 0xdc, 0x3e, 0x5b, 0xa4, 0x3c,
+  0xa8, 0xdf, 0x5d, 0x93, 0x01, 0x3d, 0xde, 0x3f, 0x81, 0x5f, 0x04, 0x95, 0xa0, 0x02, 0xab, 0x83,
+];
+const CONTROL: [u8; 64] = [
+  0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x1c, 0x17, 0x01, 0x9f, 0x1a, 0x13, 0x05, 0xff,
+  0x9c, 0x12, 0x04, 0x1e, 0x03, 0x06, 0x14, 0x18, 0x1f, 0x19, 0x07, 0x9e, 0x02, 0x08, 0x15, 0x16,
+  0x12, 0x09, 0x0a, 0x92, 0x0d, 0x0b, 0x0f, 0x0e, 0xff, 0x10, 0x0c, 0xff, 0xff, 0x1b, 0x00, 0xff,
+  0x1c, 0xff, 0x1d, 0xff, 0xff, 0x1f, 0x1e, 0xff, 0x90, 0x06, 0xff, 0x05, 0xff, 0xff, 0x11, 0xff,
+];
+
+// How long each key is held, and the pause before the next, long enough for
+// the KERNAL to see them in its 60Hz keyboard scan
+const HOLD_CYCLES: u32 = 40000;
+const RELEASE_CYCLES: u32 = 20000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Modifier {
+  None,
+  Shift,
+  Commodore,
+  Control,
+}
+
+impl Modifier {
+  fn key(&self) -> Option<u8> {
+    match *self {
+      Modifier::None => None,
+      Modifier::Shift => Some(KEY_LEFT_SHIFT),
+      Modifier::Commodore => Some(KEY_COMMODORE),
+      Modifier::Control => Some(KEY_CONTROL),
+    }
+  }
+}
+
+/**
+ * Convert a character of text to PETSCII. Letters of either case become the
+ * unshifted letters, which appear as capitals in the C64's default character
+ * set. Returns None for characters with no PETSCII equivalent.
+ */
+pub fn ascii_to_petscii(c: char) -> Option<u8> {
+  match c {
+    'a'..='z' => Some(c as u8 - 0x20),
+    ' '..='@' | 'A'..='Z' | '[' | ']' => Some(c as u8),
+    '\r' | '\n' => Some(0x0d),
+    '\u{8}' => Some(0x14),
+    '£' => Some(0x5c),
+    '^' | '↑' => Some(0x5e),
+    '_' | '←' => Some(0x5f),
+    'π' => Some(0xde),
+    _ => None,
+  }
+}
+
+/**
+ * Find the key, and the modifier to hold with it, that types a PETSCII code.
+ */
+pub fn key_for_petscii(code: u8) -> Option<(u8, Modifier)> {
+  if code >= 0x01 && code <= 0x04 {
+    return None;
+  }
+  let tables = [
+    (&UNSHIFTED, Modifier::None),
+    (&SHIFTED, Modifier::Shift),
+    (&COMMODORE, Modifier::Commodore),
+    (&CONTROL, Modifier::Control),
+  ];
+  for &(table, modifier) in tables.iter() {
+    if let Some(key) = table.iter().position(|&c| c == code) {
+      return Some((key as u8, modifier));
+    }
+  }
+  return None;
+}
+
+/**
+ * Types a queue of PETSCII characters into the machine, either by filling
+ * the KERNAL keyboard buffer as it empties, or by pressing and releasing
+ * keys in the keyboard matrix. The buffer is quicker, but only works while
+ * the KERNAL is reading the keyboard; pressing keys also works for programs
+ * that scan the matrix themselves.
+ */
+pub struct Typist {
+  pending: VecDeque<u8>,
+  pub press_keys: bool,
+  held: Option<(u8, Modifier)>,
+  countdown: u32,
+}
+
+impl Typist {
+  pub fn new() -> Typist {
+    return Typist {
+      pending: VecDeque::new(),
+      press_keys: false,
+      held: None,
+      countdown: 0,
+    };
+  }
+
+  pub fn type_petscii(&mut self, codes: &[u8]) {
+    self.pending.extend(codes.iter());
+  }
+
+  /**
+   * Queue a string of text, skipping characters that can't be typed. A CR LF
+   * pair is typed as a single RETURN.
+   */
+  pub fn type_text(&mut self, text: &str) {
+    let mut last = ' ';
+    for c in text.chars() {
+      if !(c == '\n' && last == '\r') {
+        if let Some(code) = ascii_to_petscii(c) {
+          self.pending.push_back(code);
+        }
+      }
+      last = c;
+    }
+  }
+
+  pub fn is_typing(&self) -> bool {
+    self.pending.len() > 0 || self.held.is_some()
+  }
+
+  pub fn update(&mut self, mem: &mut MemMap, cycles: u8) {
+    if !self.press_keys {
+      while self.pending.len() > 0 && prg::queued_keys(mem) < prg::KEYBOARD_BUFFER_SIZE {
+        let code = self.pending.pop_front().unwrap();
+        prg::queue_keys(mem, &[code]);
+      }
+      return;
+    }
+    let cycles = cycles as u32;
+    if self.countdown > cycles {
+      self.countdown -= cycles;
+      return;
+    }
+    self.countdown = 0;
+    if let Some((key, modifier)) = self.held.take() {
+      mem.cia.keyup(key);
+      if let Some(modifier_key) = modifier.key() {
+        mem.cia.keyup(modifier_key);
+      }
+      self.countdown = RELEASE_CYCLES;
+      return;
+    }
+    while let Some(code) = self.pending.pop_front() {
+      if let Some((key, modifier)) = key_for_petscii(code) {
+        if let Some(modifier_key) = modifier.key() {
+          mem.cia.keydown(modifier_key);
+        }
+        mem.cia.keydown(key);
+        self.held = Some((key, modifier));
+        self.countdown = HOLD_CYCLES;
+        return;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use memmap::MemMap;
+  use memmap::mos6510::memory::Memory;
+  use typing::{Typist, Modifier, ascii_to_petscii, key_for_petscii};
+
+  #[test]
+  fn key_lookup() {
+    assert_eq!(ascii_to_petscii('a'), Some(0x41));
+    assert_eq!(ascii_to_petscii('"'), Some(0x22));
+    assert_eq!(ascii_to_petscii('~'), None);
+    assert_eq!(key_for_petscii(0x41), Some((10, Modifier::None)));
+    assert_eq!(key_for_petscii(0x22), Some((59, Modifier::Shift)));
+    assert_eq!(key_for_petscii(0x93), Some((51, Modifier::Shift)));
+    assert_eq!(key_for_petscii(0xb0), Some((10, Modifier::Commodore)));
+    // CTRL-E, like CTRL-2, selects white
+    assert_eq!(key_for_petscii(0x05), Some((14, Modifier::Control)));
+    assert_eq!(key_for_petscii(0x01), None);
+  }
+
+  #[test]
+  fn keyboard_buffer() {
+    let mut mem = MemMap::new();
+    let mut typist = Typist::new();
+    typist.type_text("print 1\r\nlist\r\n");
+    typist.update(&mut mem, 1);
+    assert_eq!(mem.ram_rom.ram[0xc6], 10);
+    assert_eq!(&mem.ram_rom.ram[0x277..0x27a], b"PRI");
+    // Refilled as the KERNAL takes characters out
+    mem.ram_rom.ram[0xc6] = 0;
+    typist.update(&mut mem, 1);
+    assert_eq!(mem.ram_rom.ram[0xc6], 3);
+    assert_eq!(&mem.ram_rom.ram[0x277..0x27a], b"ST\r");
+    assert!(!typist.is_typing());
+  }
+
+  #[test]
+  fn key_presses() {
+    let mut mem = MemMap::new();
+    let mut typist = Typist::new();
+    typist.press_keys = true;
+    typist.type_petscii(&[0x21]);
+    mem.set_byte(0xdc02, 0xff);
+    typist.update(&mut mem, 1);
+    // SHIFT and 1 are held
+    mem.set_byte(0xdc00, 0x7f);
+    assert_eq!(mem.get_byte(0xdc01), 0xfe);
+    mem.set_byte(0xdc00, 0xfd);
+    assert_eq!(mem.get_byte(0xdc01), 0x7f);
+    for _ in 0..400 {
+      typist.update(&mut mem, 100);
+    }
+    assert_eq!(mem.get_byte(0xdc01), 0xff);
+    assert!(!typist.is_typing());
+  }
+}
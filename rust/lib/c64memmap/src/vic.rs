@@ -1,4 +1,6 @@
 use std::cmp;
+use ramrom::RamRom;
+use model::Model;
 
 pub struct Sprite {
   pub x: u16,
@@ -7,6 +9,8 @@ pub struct Sprite {
   pub enabled: bool,
   pub double_height: bool,
   pub double_width: bool,
+  pub multicolor: bool,
+  pub behind_background: bool,
 }
 
 impl Sprite {
@@ -18,6 +22,8 @@ impl Sprite {
       enabled: false,
       double_height: false,
       double_width: false,
+      multicolor: false,
+      behind_background: false,
     };
   }
 
@@ -54,6 +60,26 @@ pub enum Mode {
   Bitmap,
 }
 
+// Sprite X coordinates wrap around after this many pixels on a PAL line.
+// NTSC lines are 16 pixels longer, which only moves the wrap point.
+const PIXELS_PER_LINE: usize = 504;
+
+// The rendered frame includes the visible part of the border
+pub const FRAME_WIDTH: usize = 384;
+pub const FRAME_HEIGHT: usize = 272;
+const FIRST_VISIBLE_LINE: u16 = 15;
+// Sprite X coordinate drawn in the leftmost column of the frame
+const FIRST_VISIBLE_X: usize = PIXELS_PER_LINE - 8;
+// Sprite X coordinate of the beam in the first cycle of a line. It moves 8
+// pixels a cycle.
+const FIRST_CYCLE_X: usize = 0x194;
+
+// Interrupt sources in $D019 / $D01A
+pub const INTERRUPT_RASTER: u8 = 1;
+pub const INTERRUPT_SPRITE_BACKGROUND: u8 = 2;
+pub const INTERRUPT_SPRITE_SPRITE: u8 = 4;
+pub const INTERRUPT_LIGHT_PEN: u8 = 8;
+
 pub enum DerivedGraphicsMode {
   StandardCharMode = 0,
   MulticolorCharMode = 1,
@@ -63,6 +89,20 @@ pub enum DerivedGraphicsMode {
   Invalid = 5,
 }
 
+/**
+ * The registers and raster position of the VIC-II, as saved in a snapshot.
+ * The registers hold what was last written, so $D011 and $D012 give the
+ * raster interrupt line rather than the current one.
+ */
+pub struct VicState {
+  pub registers: [u8; 0x40],
+  pub raster_line: u16,
+  pub raster_cycle: u16,
+  pub sprite_sprite_collisions: u8,
+  pub sprite_background_collisions: u8,
+  pub interrupt_status: u8,
+}
+
 pub struct VIC {
   pub sprites: [Sprite;8],
   pub vertical_scroll: u8,
@@ -72,9 +112,27 @@ pub struct VIC {
   extended_bg: bool,
   raster_interrupt_line: u16,
   current_raster_line: u16,
+  raster_cycle: u16,
+  cycles_per_line: u16,
+  lines_per_frame: u16,
+  interrupt_status: u8,
+  interrupt_enabled: u8,
+  sprite_sprite_collisions: u8,
+  sprite_background_collisions: u8,
+  // The beam position latched by the last light pen pulse: X/2 in sprite
+  // coordinates, and the low 8 bits of the raster line
+  light_pen_x: u8,
+  light_pen_y: u8,
+  // Only the first pulse in each frame is latched
+  light_pen_latched: bool,
+  // The LP input, shared with CIA 1 port B bit 4, is being held low
+  light_pen_line: bool,
+  // The raster line and cycle a light pen held to the screen sees the beam
+  light_pen: Option<(u16, u16)>,
   pub horizontal_scroll: u8,
   screen_width: ScreenWidth,
   multicolor: bool,
+  memory_setup: u8,
 
   pub border_color: u8,
   pub background_color: u8,
@@ -83,6 +141,12 @@ pub struct VIC {
   pub background_color_e3: u8,
   pub sprite_color_e1: u8,
   pub sprite_color_e2: u8,
+
+  buffer: Box<[u8; FRAME_WIDTH * FRAME_HEIGHT]>,
+  // The last complete frame, copied from the buffer as the raster returns to
+  // the top, so it can be shown without tearing
+  frame: Box<[u8; FRAME_WIDTH * FRAME_HEIGHT]>,
+  frame_count: u32,
 }
 
 impl VIC {
@@ -105,9 +169,23 @@ impl VIC {
       extended_bg: false,
       raster_interrupt_line: 0,
       current_raster_line: 0,
+      raster_cycle: 0,
+      cycles_per_line: Model::Pal.cycles_per_line(),
+      lines_per_frame: Model::Pal.lines_per_frame(),
+      interrupt_status: 0,
+      interrupt_enabled: 0,
+      sprite_sprite_collisions: 0,
+      sprite_background_collisions: 0,
+      light_pen_x: 0,
+      light_pen_y: 0,
+      light_pen_latched: false,
+      light_pen_line: false,
+      light_pen: None,
       horizontal_scroll: 0,
       screen_width: ScreenWidth::Forty,
       multicolor: false,
+      // Screen at $0400, characters at $1000, as set up by the KERNAL
+      memory_setup: 0x14,
 
       border_color: 0,
       background_color: 0,
@@ -116,10 +194,14 @@ impl VIC {
       background_color_e3: 0,
       sprite_color_e1: 0,
       sprite_color_e2: 0,
+
+      buffer: box [0; FRAME_WIDTH * FRAME_HEIGHT],
+      frame: box [0; FRAME_WIDTH * FRAME_HEIGHT],
+      frame_count: 0,
     };
   }
 
-  pub fn get_byte(&self, addr: u16) -> u8 {
+  pub fn get_byte(&mut self, addr: u16) -> u8 {
     match addr {
       0x00 => (self.sprites[0].x & 0xff) as u8,
       0x01 => self.sprites[0].y,
@@ -164,8 +246,8 @@ impl VIC {
         register | raster_high
       },
       0x12 => (self.current_raster_line & 0xff) as u8,
-      0x13 => 0, // light pen low
-      0x14 => 0, // light pen high
+      0x13 => self.light_pen_x,
+      0x14 => self.light_pen_y,
       0x15 => {
         let mut enabled: u8 = 0;
         if self.sprites[0].enabled {
@@ -232,26 +314,70 @@ impl VIC {
         }
         double_height
       },
-      0x18 => 0,
+      0x18 => self.memory_setup | 1,
       0x19 => {
-        let mut status = 0;
-        if self.current_raster_line == self.raster_interrupt_line {
-          status = status | 1;
+        let mut status = self.interrupt_status | 0x70;
+        if self.interrupt_pending() {
+          status = status | 0x80;
         }
-        // todo: handle other interrupts
         status
       },
-      0x1a => {
-        // enabled interrupts
-        0
-      },
+      0x1a => self.interrupt_enabled | 0xf0,
       0x1b => {
-        // sprite priority
-        0
+        let mut behind: u8 = 0;
+        if self.sprites[0].behind_background {
+          behind = behind | 1;
+        }
+        if self.sprites[1].behind_background {
+          behind = behind | 2;
+        }
+        if self.sprites[2].behind_background {
+          behind = behind | 4;
+        }
+        if self.sprites[3].behind_background {
+          behind = behind | 8;
+        }
+        if self.sprites[4].behind_background {
+          behind = behind | 16;
+        }
+        if self.sprites[5].behind_background {
+          behind = behind | 32;
+        }
+        if self.sprites[6].behind_background {
+          behind = behind | 64;
+        }
+        if self.sprites[7].behind_background {
+          behind = behind | 128;
+        }
+        behind
       },
       0x1c => {
-        // sprite multicolor
-        0
+        let mut multicolor: u8 = 0;
+        if self.sprites[0].multicolor {
+          multicolor = multicolor | 1;
+        }
+        if self.sprites[1].multicolor {
+          multicolor = multicolor | 2;
+        }
+        if self.sprites[2].multicolor {
+          multicolor = multicolor | 4;
+        }
+        if self.sprites[3].multicolor {
+          multicolor = multicolor | 8;
+        }
+        if self.sprites[4].multicolor {
+          multicolor = multicolor | 16;
+        }
+        if self.sprites[5].multicolor {
+          multicolor = multicolor | 32;
+        }
+        if self.sprites[6].multicolor {
+          multicolor = multicolor | 64;
+        }
+        if self.sprites[7].multicolor {
+          multicolor = multicolor | 128;
+        }
+        multicolor
       },
       0x1d => {
         let mut double_width: u8 = 0;
@@ -282,12 +408,16 @@ impl VIC {
         double_width
       },
       0x1e => {
-        // current sprite-sprite collisions
-        0
+        // sprite-sprite collisions, cleared on read
+        let collisions = self.sprite_sprite_collisions;
+        self.sprite_sprite_collisions = 0;
+        collisions
       },
       0x1f => {
-        // current sprite-background collisions
-        0
+        // sprite-background collisions, cleared on read
+        let collisions = self.sprite_background_collisions;
+        self.sprite_background_collisions = 0;
+        collisions
       },
       0x20 => self.border_color & 0xf,
       0x21 => self.background_color & 0xf,
@@ -308,6 +438,51 @@ impl VIC {
     }
   }
 
+  /**
+   * Read a register without clearing the collision latches, for debuggers
+   */
+  pub fn peek(&mut self, addr: u16) -> u8 {
+    match addr {
+      0x1e => self.sprite_sprite_collisions,
+      0x1f => self.sprite_background_collisions,
+      _ => self.get_byte(addr),
+    }
+  }
+
+  pub fn save_state(&mut self) -> VicState {
+    let mut registers = [0xff; 0x40];
+    for i in 0..0x2f {
+      registers[i] = self.peek(i as u16);
+    }
+    registers[0x11] = (registers[0x11] & 0x7f) | ((self.raster_interrupt_line >> 1) & 0x80) as u8;
+    registers[0x12] = (self.raster_interrupt_line & 0xff) as u8;
+    registers[0x19] = self.interrupt_status;
+    return VicState {
+      registers: registers,
+      raster_line: self.current_raster_line,
+      raster_cycle: self.raster_cycle,
+      sprite_sprite_collisions: self.sprite_sprite_collisions,
+      sprite_background_collisions: self.sprite_background_collisions,
+      interrupt_status: self.interrupt_status,
+    };
+  }
+
+  pub fn load_state(&mut self, state: &VicState) {
+    for i in 0..0x2f {
+      match i {
+        0x19 | 0x1e | 0x1f => (),
+        _ => self.set_byte(i, state.registers[i as usize]),
+      }
+    }
+    self.current_raster_line = state.raster_line % self.lines_per_frame;
+    self.raster_cycle = state.raster_cycle % self.cycles_per_line;
+    self.sprite_sprite_collisions = state.sprite_sprite_collisions;
+    self.sprite_background_collisions = state.sprite_background_collisions;
+    self.interrupt_status = state.interrupt_status & 0x0f;
+    self.light_pen_x = state.registers[0x13];
+    self.light_pen_y = state.registers[0x14];
+  }
+
   pub fn set_byte(&mut self, addr: u16, value: u8) {
     match addr {
       0x00 => self.sprites[0].set_x_low(value),
@@ -343,11 +518,11 @@ impl VIC {
         self.mode = if value & 0x20 == 0 { Mode::Text } else { Mode::Bitmap };
         self.extended_bg = value & 0x40 == 0x40;
         let raster_high = ((value as u16) & 0x80) << 1;
-        let line = self.raster_interrupt_line & 0xf | raster_high;
+        let line = self.raster_interrupt_line & 0xff | raster_high;
         self.raster_interrupt_line = line;
       },
       0x12 => {
-        let line = self.raster_interrupt_line & 0xf0 | (value as u16);
+        let line = self.raster_interrupt_line & 0x100 | (value as u16);
         self.raster_interrupt_line = line;
       },
       0x13 => (),
@@ -377,20 +552,31 @@ impl VIC {
         self.sprites[6].double_height = (value & 64) != 0;
         self.sprites[7].double_height = (value & 128) != 0;
       },
-      0x18 => {
-        // memory setup, to be implemented
-      },
+      0x18 => self.memory_setup = value & 0xfe,
       0x19 => {
-        // acknowledge interrupts
-      },
-      0x1a => {
-        // enable interrupts
+        // acknowledge interrupts by writing 1 to their bits
+        self.interrupt_status = self.interrupt_status & !(value & 0xf);
       },
+      0x1a => self.interrupt_enabled = value & 0xf,
       0x1b => {
-        // sprite priority
+        self.sprites[0].behind_background = (value & 1) != 0;
+        self.sprites[1].behind_background = (value & 2) != 0;
+        self.sprites[2].behind_background = (value & 4) != 0;
+        self.sprites[3].behind_background = (value & 8) != 0;
+        self.sprites[4].behind_background = (value & 16) != 0;
+        self.sprites[5].behind_background = (value & 32) != 0;
+        self.sprites[6].behind_background = (value & 64) != 0;
+        self.sprites[7].behind_background = (value & 128) != 0;
       },
       0x1c => {
-        // sprite multicolor
+        self.sprites[0].multicolor = (value & 1) != 0;
+        self.sprites[1].multicolor = (value & 2) != 0;
+        self.sprites[2].multicolor = (value & 4) != 0;
+        self.sprites[3].multicolor = (value & 8) != 0;
+        self.sprites[4].multicolor = (value & 16) != 0;
+        self.sprites[5].multicolor = (value & 32) != 0;
+        self.sprites[6].multicolor = (value & 64) != 0;
+        self.sprites[7].multicolor = (value & 128) != 0;
       },
       0x1d => {
         self.sprites[0].double_width = (value & 1) != 0;
@@ -402,12 +588,8 @@ impl VIC {
         self.sprites[6].double_width = (value & 64) != 0;
         self.sprites[7].double_width = (value & 128) != 0;
       },
-      0x1e => {
-        // enable sprite-sprite collision
-      },
-      0x1f => {
-        // enable sprite-background collision
-      },
+      0x1e => (), // collision registers are read-only
+      0x1f => (),
       0x20 => self.border_color = value & 0xf,
       0x21 => self.background_color = value & 0xf,
       0x22 => self.background_color_e1 = value & 0xf,
@@ -427,6 +609,22 @@ impl VIC {
     };
   }
 
+  /**
+   * The memory setup register places the screen matrix, character set, and
+   * bitmap within the current 16K bank. These return offsets into that bank.
+   */
+  pub fn get_screen_offset(&self) -> u16 {
+    ((self.memory_setup & 0xf0) as u16) << 6
+  }
+
+  pub fn get_char_offset(&self) -> u16 {
+    ((self.memory_setup & 0x0e) as u16) << 10
+  }
+
+  pub fn get_bitmap_offset(&self) -> u16 {
+    ((self.memory_setup & 0x08) as u16) << 10
+  }
+
   pub fn get_graphics_mode_bits(&self) -> u8 {
     let mcm = if self.multicolor { 1 } else { 0 };
     let bmm = if self.mode == Mode::Bitmap { 2 } else { 0 };
@@ -444,12 +642,498 @@ impl VIC {
       _ => DerivedGraphicsMode::Invalid,
     }
   }
+
+  pub fn interrupt_pending(&self) -> bool {
+    self.interrupt_status & self.interrupt_enabled != 0
+  }
+
+  fn trigger_interrupt(&mut self, source: u8) {
+    self.interrupt_status = self.interrupt_status | source;
+  }
+
+  /**
+   * Set the level of the LP input, with true meaning it is held low. The
+   * beam position is latched on the falling edge.
+   */
+  pub fn set_light_pen_line(&mut self, low: bool) {
+    if low && !self.light_pen_line {
+      let cycle = self.raster_cycle;
+      self.latch_light_pen(cycle);
+    }
+    self.light_pen_line = low;
+  }
+
+  /**
+   * Hold a light pen to the screen at a position in the frame, or take it
+   * away with None. The pen pulses the LP input each time the beam passes
+   * under it.
+   */
+  pub fn set_light_pen(&mut self, position: Option<(usize, usize)>) {
+    self.light_pen = match position {
+      Some((x, y)) if x < FRAME_WIDTH && y < FRAME_HEIGHT => {
+        let width = self.line_width();
+        let beam_x = (FIRST_VISIBLE_X + x) % width;
+        let cycle = (beam_x + width - FIRST_CYCLE_X) % width / 8;
+        Some((FIRST_VISIBLE_LINE + y as u16, cycle as u16))
+      },
+      _ => None,
+    };
+  }
+
+  fn latch_light_pen(&mut self, cycle: u16) {
+    if self.light_pen_latched {
+      return;
+    }
+    self.light_pen_latched = true;
+    let x = (FIRST_CYCLE_X + cycle as usize * 8) % self.line_width();
+    self.light_pen_x = (x / 2) as u8;
+    self.light_pen_y = (self.current_raster_line & 0xff) as u8;
+    self.trigger_interrupt(INTERRUPT_LIGHT_PEN);
+  }
+
+  // Pixels the beam covers in a line, which is longer on NTSC than the
+  // 504 sprite X coordinates
+  fn line_width(&self) -> usize {
+    self.cycles_per_line as usize * 8
+  }
+
+  // Latch a light pen held to the screen if the beam has passed it on the
+  // current line since the given cycle
+  fn check_light_pen(&mut self, from: u16) {
+    if let Some((line, cycle)) = self.light_pen {
+      if line == self.current_raster_line && cycle >= from && cycle < self.raster_cycle {
+        self.latch_light_pen(cycle);
+      }
+    }
+  }
+
+  pub fn buffer_ptr(&self) -> *const u8 {
+    &self.buffer[0] as *const u8
+  }
+
+  /**
+   * The last complete frame, one color index per pixel
+   */
+  pub fn frame(&self) -> &[u8] {
+    &self.frame[..]
+  }
+
+  /**
+   * The number of frames completed, which wraps around
+   */
+  pub fn frame_count(&self) -> u32 {
+    self.frame_count
+  }
+
+  pub fn set_model(&mut self, model: Model) {
+    self.cycles_per_line = model.cycles_per_line();
+    self.lines_per_frame = model.lines_per_frame();
+    if self.current_raster_line >= self.lines_per_frame {
+      self.current_raster_line = 0;
+    }
+  }
+
+  /**
+   * Advance the raster beam. Each raster line is drawn in one pass once the
+   * beam reaches the end of it, which is also when collisions are detected.
+   */
+  pub fn update_raster(&mut self, cycles: u8, mem: &RamRom, bank: u16) {
+    let start = self.raster_cycle;
+    self.raster_cycle += cycles as u16;
+    self.check_light_pen(start);
+    while self.raster_cycle >= self.cycles_per_line {
+      self.raster_cycle -= self.cycles_per_line;
+      let line = self.current_raster_line;
+      self.draw_line(line, mem, bank);
+
+      let next = (line + 1) % self.lines_per_frame;
+      self.current_raster_line = next;
+      if next == 0 {
+        self.frame.copy_from_slice(&self.buffer[..]);
+        self.frame_count = self.frame_count.wrapping_add(1);
+        self.light_pen_latched = false;
+      }
+      if next == self.raster_interrupt_line {
+        self.trigger_interrupt(INTERRUPT_RASTER);
+      }
+      self.check_light_pen(0);
+    }
+  }
+
+  fn draw_line(&mut self, line: u16, mem: &RamRom, bank: u16) {
+    // Pixels are indexed by sprite X coordinate
+    let mut pixels = [self.background_color; PIXELS_PER_LINE];
+    let mut foreground = [false; PIXELS_PER_LINE];
+
+    self.draw_graphics(line, mem, bank, &mut pixels, &mut foreground);
+    self.draw_sprites(line, mem, bank, &mut pixels, &foreground);
+
+    // The border is drawn on top of everything else
+    let (top, bottom) = match self.screen_height {
+      ScreenHeight::TwentyFive => (51, 250),
+      ScreenHeight::TwentyFour => (55, 246),
+    };
+    let (left, right) = match self.screen_width {
+      ScreenWidth::Forty => (24, 343),
+      ScreenWidth::ThirtyEight => (31, 334),
+    };
+    let vertical_border = !self.screen_on || line < top || line > bottom;
+    for x in 0..PIXELS_PER_LINE {
+      if vertical_border || x < left || x > right {
+        pixels[x] = self.border_color;
+      }
+    }
+
+    if line < FIRST_VISIBLE_LINE || line >= FIRST_VISIBLE_LINE + FRAME_HEIGHT as u16 {
+      return;
+    }
+    let row_start = (line - FIRST_VISIBLE_LINE) as usize * FRAME_WIDTH;
+    for i in 0..FRAME_WIDTH {
+      self.buffer[row_start + i] = pixels[(FIRST_VISIBLE_X + i) % PIXELS_PER_LINE];
+    }
+  }
+
+  fn draw_graphics(&self, line: u16, mem: &RamRom, bank: u16, pixels: &mut [u8; PIXELS_PER_LINE], foreground: &mut [bool; PIXELS_PER_LINE]) {
+    if !self.screen_on {
+      return;
+    }
+    let first_line = 0x30 + self.vertical_scroll as u16;
+    if line < first_line || line >= first_line + 200 {
+      return;
+    }
+    let y = line - first_line;
+    let char_line = y & 7;
+    let row_offset = (y >> 3) * 40;
+    let screen = self.get_screen_offset();
+    let chars = self.get_char_offset();
+    let bitmap = self.get_bitmap_offset();
+    let bg = [
+      self.background_color,
+      self.background_color_e1,
+      self.background_color_e2,
+      self.background_color_e3,
+    ];
+    let mode_bits = self.get_graphics_mode_bits();
+    let first_x = 24 + self.horizontal_scroll as usize;
+
+    for col in 0..40 {
+      let index = row_offset + col as u16;
+      let code = mem.vic_get_byte(bank, screen + index);
+      let color = mem.color_ram[index as usize] & 0xf;
+      let data = match mode_bits & 2 {
+        0 => {
+          let code = if mode_bits & 4 == 4 { code & 0x3f } else { code };
+          mem.vic_get_byte(bank, chars + (code as u16) * 8 + char_line)
+        },
+        _ => mem.vic_get_byte(bank, bitmap + index * 8 + char_line),
+      };
+      // Multicolor pixels are twice as wide, and only bit pairs %10 and %11
+      // count as foreground for priority and collisions
+      let multicolor = match mode_bits & 3 {
+        1 => color & 8 == 8,
+        3 => true,
+        _ => false,
+      };
+      for bit in 0..8 {
+        let (value, is_foreground) = if multicolor {
+          let pair = (data >> (6 - (bit & 6))) & 3;
+          let value = match (mode_bits & 2, pair) {
+            (0, 3) => color & 7,
+            (0, _) => bg[pair as usize],
+            (_, 0) => bg[0],
+            (_, 1) => code >> 4,
+            (_, 2) => code & 0xf,
+            (_, _) => color,
+          };
+          (value, pair & 2 == 2)
+        } else {
+          let set = (data >> (7 - bit)) & 1 == 1;
+          let value = match (mode_bits & 6, set) {
+            (0, true) => color & if mode_bits & 1 == 1 { 7 } else { 0xf },
+            (0, false) => bg[0],
+            (2, true) => code >> 4,
+            (2, false) => code & 0xf,
+            (_, true) => color,
+            (_, false) => bg[(code >> 6) as usize],
+          };
+          (value, set)
+        };
+        let x = first_x + col * 8 + bit;
+        // ECM combined with multicolor or bitmap mode only produces black
+        pixels[x] = if mode_bits > 4 { 0 } else { value };
+        foreground[x] = is_foreground;
+      }
+    }
+  }
+
+  fn draw_sprites(&mut self, line: u16, mem: &RamRom, bank: u16, pixels: &mut [u8; PIXELS_PER_LINE], foreground: &[bool; PIXELS_PER_LINE]) {
+    // Bitmask of sprites covering each pixel
+    let mut coverage = [0u8; PIXELS_PER_LINE];
+    let mut colors = [0u8; PIXELS_PER_LINE];
+    let pointers = self.get_screen_offset() + 0x3f8;
+
+    // Draw from lowest to highest priority, so sprite 0 ends up on top
+    for index in (0..8).rev() {
+      let sprite = &self.sprites[index];
+      if !sprite.enabled {
+        continue;
+      }
+      // A sprite starts on the line after its Y coordinate
+      let top = sprite.y as u16 + 1;
+      let height = if sprite.double_height { 42 } else { 21 };
+      if line < top || line >= top + height {
+        continue;
+      }
+      let row = if sprite.double_height { (line - top) >> 1 } else { line - top };
+      let pointer = mem.vic_get_byte(bank, pointers + index as u16) as u16;
+      let addr = pointer * 64 + row * 3;
+      let data =
+        ((mem.vic_get_byte(bank, addr) as u32) << 16) |
+        ((mem.vic_get_byte(bank, addr + 1) as u32) << 8) |
+        (mem.vic_get_byte(bank, addr + 2) as u32);
+      let scale = if sprite.double_width { 2 } else { 1 };
+      for i in 0..(24 * scale) {
+        let bit = i / scale;
+        let color = if sprite.multicolor {
+          match (data >> (22 - (bit & 0x1e))) & 3 {
+            1 => Some(self.sprite_color_e1),
+            2 => Some(sprite.color),
+            3 => Some(self.sprite_color_e2),
+            _ => None,
+          }
+        } else if (data >> (23 - bit)) & 1 == 1 {
+          Some(sprite.color)
+        } else {
+          None
+        };
+        if let Some(color) = color {
+          let x = (sprite.x as usize + i) % PIXELS_PER_LINE;
+          coverage[x] = coverage[x] | (1 << index);
+          colors[x] = color;
+        }
+      }
+    }
+
+    let mut sprite_sprite = 0;
+    let mut sprite_background = 0;
+    for x in 0..PIXELS_PER_LINE {
+      let covered = coverage[x];
+      if covered == 0 {
+        continue;
+      }
+      if covered & (covered - 1) != 0 {
+        sprite_sprite = sprite_sprite | covered;
+      }
+      if foreground[x] {
+        sprite_background = sprite_background | covered;
+      }
+      let top = covered.trailing_zeros() as usize;
+      if !foreground[x] || !self.sprites[top].behind_background {
+        pixels[x] = colors[x];
+      }
+    }
+
+    // Only the first collision after the register was cleared raises an IRQ
+    if sprite_sprite != 0 {
+      if self.sprite_sprite_collisions == 0 {
+        self.trigger_interrupt(INTERRUPT_SPRITE_SPRITE);
+      }
+      self.sprite_sprite_collisions = self.sprite_sprite_collisions | sprite_sprite;
+    }
+    if sprite_background != 0 {
+      if self.sprite_background_collisions == 0 {
+        self.trigger_interrupt(INTERRUPT_SPRITE_BACKGROUND);
+      }
+      self.sprite_background_collisions = self.sprite_background_collisions | sprite_background;
+    }
+  }
 }
 
 #[cfg(test)]
 mod tests {
+  use ramrom::RamRom;
+  use model::Model;
+  use vic::VIC;
+
+  fn run_frame(vic: &mut VIC, mem: &RamRom) {
+    for _ in 0..312 {
+      vic.update_raster(63, mem, 0);
+    }
+  }
+
+  fn place_sprite(vic: &mut VIC, mem: &mut RamRom, index: u16, x: u8, y: u8) {
+    // all sprites share a solid block of data at $2000
+    mem.ram[0x7f8 + index as usize] = 0x80;
+    for i in 0..63 {
+      mem.ram[0x2000 + i] = 0xff;
+    }
+    vic.set_byte(index * 2, x);
+    vic.set_byte(index * 2 + 1, y);
+    let enabled = vic.get_byte(0x15);
+    vic.set_byte(0x15, enabled | (1 << index));
+  }
+
   #[test]
   fn sprite_position() {
 
   }
+
+  #[test]
+  fn ntsc_raster() {
+    let mut vic = VIC::new();
+    let mem = RamRom::new();
+    vic.set_model(Model::Ntsc);
+    vic.set_byte(0x12, 0);
+    vic.set_byte(0x1a, 1);
+    for _ in 0..262 {
+      vic.update_raster(65, &mem, 0);
+    }
+    assert_eq!(vic.get_byte(0x12), 6);
+    assert_eq!(vic.get_byte(0x11) & 0x80, 0x80);
+    assert!(!vic.interrupt_pending());
+    // 263 lines of 65 cycles bring the beam back to line 0
+    vic.update_raster(65, &mem, 0);
+    assert_eq!(vic.get_byte(0x12), 0);
+    assert!(vic.interrupt_pending());
+  }
+
+  #[test]
+  fn memory_setup() {
+    let mut vic = VIC::new();
+    assert_eq!(vic.get_screen_offset(), 0x400);
+    assert_eq!(vic.get_char_offset(), 0x1000);
+    vic.set_byte(0x18, 0x1e);
+    assert_eq!(vic.get_byte(0x18), 0x1f);
+    assert_eq!(vic.get_screen_offset(), 0x400);
+    assert_eq!(vic.get_char_offset(), 0x3800);
+    vic.set_byte(0x18, 0xf8);
+    assert_eq!(vic.get_screen_offset(), 0x3c00);
+    assert_eq!(vic.get_bitmap_offset(), 0x2000);
+  }
+
+  #[test]
+  fn sprite_sprite_collision() {
+    let mut vic = VIC::new();
+    let mut mem = RamRom::new();
+    vic.set_byte(0x1a, 0x4);
+    place_sprite(&mut vic, &mut mem, 0, 100, 100);
+    place_sprite(&mut vic, &mut mem, 3, 200, 100);
+    run_frame(&mut vic, &mem);
+    assert_eq!(vic.get_byte(0x1e), 0);
+    assert!(!vic.interrupt_pending());
+
+    vic.set_byte(6, 110);
+    run_frame(&mut vic, &mem);
+    assert!(vic.interrupt_pending());
+    assert_eq!(vic.get_byte(0x19) & 0x84, 0x84);
+    assert_eq!(vic.get_byte(0x1e), 0b1001);
+    // reading clears the register
+    assert_eq!(vic.get_byte(0x1e), 0);
+    vic.set_byte(0x19, 0x4);
+    assert!(!vic.interrupt_pending());
+  }
+
+  #[test]
+  fn sprite_background_collision() {
+    let mut vic = VIC::new();
+    let mut mem = RamRom::new();
+    // screen is filled with character 0, which is a solid block
+    for i in 0..8 {
+      mem.char_gen[i] = 0xff;
+    }
+    place_sprite(&mut vic, &mut mem, 2, 24, 0);
+    run_frame(&mut vic, &mem);
+    assert_eq!(vic.get_byte(0x1f), 0);
+
+    vic.set_byte(5, 50);
+    run_frame(&mut vic, &mem);
+    assert_eq!(vic.get_byte(0x1f), 0b100);
+    assert_eq!(vic.get_byte(0x19) & 2, 2);
+    assert!(!vic.interrupt_pending());
+  }
+
+  #[test]
+  fn sprite_priority() {
+    let mut vic = VIC::new();
+    let mut mem = RamRom::new();
+    for i in 0..8 {
+      mem.char_gen[i] = 0xff;
+    }
+    for i in 0..1000 {
+      mem.color_ram[i] = 1;
+    }
+    place_sprite(&mut vic, &mut mem, 0, 24, 50);
+    place_sprite(&mut vic, &mut mem, 1, 24, 50);
+    vic.set_byte(0x27, 2);
+    vic.set_byte(0x28, 3);
+    vic.set_byte(0x11, 0x1b);
+    run_frame(&mut vic, &mem);
+    // top left pixel of the text area: sprite 0 wins over sprite 1
+    let offset = 36 * 384 + 32;
+    assert_eq!(unsafe { *vic.buffer_ptr().offset(offset) }, 2);
+    vic.set_byte(0x1b, 1);
+    run_frame(&mut vic, &mem);
+    // sprite 0 is now behind the text, and still hides sprite 1
+    assert_eq!(unsafe { *vic.buffer_ptr().offset(offset) }, 1);
+    assert_eq!(vic.get_byte(0x1b), 1);
+    // each frame is kept once the raster returns to the top
+    assert_eq!(vic.frame_count(), 2);
+    assert_eq!(vic.frame()[offset as usize], 1);
+  }
+
+  #[test]
+  fn raster_interrupt() {
+    let mut vic = VIC::new();
+    let mem = RamRom::new();
+    vic.set_byte(0x1a, 1);
+    vic.set_byte(0x11, 0x9b);
+    vic.set_byte(0x12, 0x08);
+    for _ in 0..263 {
+      vic.update_raster(63, &mem, 0);
+      assert!(!vic.interrupt_pending());
+    }
+    vic.update_raster(63, &mem, 0);
+    assert!(vic.interrupt_pending());
+    assert_eq!(vic.get_byte(0x12), 0x08);
+    assert_eq!(vic.get_byte(0x11) & 0x80, 0x80);
+  }
+
+  #[test]
+  fn light_pen() {
+    let mut vic = VIC::new();
+    let mem = RamRom::new();
+    vic.set_byte(0x1a, 8);
+    // Cycle 16 of line 100, where the beam is at X = 28
+    for _ in 0..100 {
+      vic.update_raster(63, &mem, 0);
+    }
+    vic.update_raster(16, &mem, 0);
+    vic.set_light_pen_line(true);
+    assert!(vic.interrupt_pending());
+    assert_eq!(vic.get_byte(0x13), 14);
+    assert_eq!(vic.get_byte(0x14), 100);
+    // Later pulses in the same frame are ignored
+    vic.set_byte(0x19, 8);
+    vic.set_light_pen_line(false);
+    vic.update_raster(63, &mem, 0);
+    vic.set_light_pen_line(true);
+    assert!(!vic.interrupt_pending());
+    assert_eq!(vic.get_byte(0x14), 100);
+    vic.set_light_pen_line(false);
+
+    // A pen held at the left edge of the text area on line 51 sees the beam
+    // there in the next frame, to the nearest cycle
+    vic.set_light_pen(Some((32, 36)));
+    run_frame(&mut vic, &mem);
+    assert!(vic.interrupt_pending());
+    assert_eq!(vic.get_byte(0x13), 10);
+    assert_eq!(vic.get_byte(0x14), 51);
+
+    // The beam covers 520 pixels in an NTSC line before it wraps around
+    let mut vic = VIC::new();
+    vic.set_model(Model::Ntsc);
+    vic.update_raster(64, &mem, 0);
+    vic.set_light_pen_line(true);
+    assert_eq!(vic.get_byte(0x13), 198);
+  }
 }
\ No newline at end of file
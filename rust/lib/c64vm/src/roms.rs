@@ -0,0 +1,47 @@
+// The machine needs the BASIC, KERNAL and character generator ROMs before it
+// can start. A front-end can pass in its own images, or build the crate with
+// the embedded-roms feature to use the copies included with it.
+
+pub const BASIC_SIZE: usize = 0x2000;
+pub const KERNAL_SIZE: usize = 0x2000;
+pub const CHARACTER_SIZE: usize = 0x1000;
+
+#[derive(Debug, PartialEq)]
+pub enum RomError {
+  // The name of the ROM whose image is the wrong size
+  WrongSize(&'static str),
+}
+
+pub struct Roms<'a> {
+  pub basic: &'a [u8],
+  pub kernal: &'a [u8],
+  pub character: &'a [u8],
+}
+
+impl<'a> Roms<'a> {
+  pub fn new(basic: &'a [u8], kernal: &'a [u8], character: &'a [u8]) -> Result<Roms<'a>, RomError> {
+    if basic.len() != BASIC_SIZE {
+      return Err(RomError::WrongSize("BASIC"));
+    }
+    if kernal.len() != KERNAL_SIZE {
+      return Err(RomError::WrongSize("KERNAL"));
+    }
+    if character.len() != CHARACTER_SIZE {
+      return Err(RomError::WrongSize("character"));
+    }
+    return Ok(Roms {
+      basic: basic,
+      kernal: kernal,
+      character: character,
+    });
+  }
+
+  #[cfg(feature = "embedded-roms")]
+  pub fn embedded() -> Roms<'static> {
+    return Roms {
+      basic: include_bytes!("rom/basic.bin"),
+      kernal: include_bytes!("rom/kernal.bin"),
+      character: include_bytes!("rom/char.bin"),
+    };
+  }
+}
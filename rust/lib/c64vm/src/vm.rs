@@ -0,0 +1,679 @@
+use mos6510::cpu::CPU;
+use mos6510::instructions::trace_line;
+use c64memmap::memmap::MemMap;
+pub use c64memmap::memmap::{FRAME_WIDTH, FRAME_HEIGHT};
+use c64memmap::cia::KEY_RESTORE;
+pub use c64memmap::model::Model;
+use c64memmap::monitor::Monitor;
+use c64memmap::snapshot::Snapshot;
+use c64memmap::prg;
+use c64memmap::basic;
+use c64memmap::d64::D64;
+use c64memmap::disktrap::DiskTrap;
+use c64memmap::tape::{T64, Tap};
+use c64memmap::cartridge::Cartridge;
+use c64memmap::reu::Reu;
+use c64memmap::typing::Typist;
+use c64memmap::keymap::{Keymap, Target};
+pub use c64memmap::keymap::Mode as KeymapMode;
+use c64memmap::palette;
+use c1541::drive::Drive1541;
+use c1541::gcr::GcrDisk;
+use crate::roms::Roms;
+use std::io::Write;
+
+pub struct VM {
+  pub cpu: CPU,
+  pub mem: MemMap,
+  pub disk: DiskTrap,
+  pub drive: Option<Drive1541>,
+  pub typist: Typist,
+  // Turns the host's key events into presses on the keyboard and joysticks
+  pub keymap: Keymap,
+  pub monitor: Monitor,
+  // Each instruction is written here before it runs, labelled with the
+  // monitor's symbols
+  pub trace: Option<Box<dyn Write>>,
+  model: Model,
+  // The level of the NMI line after the last step, since the CPU only
+  // responds as it goes low
+  nmi_line: bool,
+  // RESTORE was pressed, which briefly pulls NMI low
+  restore_pulse: bool,
+
+  // The last frame converted to RGBA pixels
+  rgba_frame: Vec<u8>,
+  autostart: Option<Vec<u8>>,
+  // Commands typed in turn each time BASIC returns to the READY prompt
+  autostart_commands: Vec<&'static [u8]>,
+}
+
+impl VM {
+  pub fn new(model: Model) -> VM {
+    let mut vm = VM {
+      cpu: CPU::new(),
+      mem: MemMap::new(),
+      disk: DiskTrap::new(),
+      drive: None,
+      typist: Typist::new(),
+      keymap: Keymap::new(),
+      monitor: Monitor::new(),
+      trace: None,
+      model: model,
+      nmi_line: false,
+      restore_pulse: false,
+
+      rgba_frame: Vec::new(),
+      autostart: None,
+      autostart_commands: Vec::new(),
+    };
+    vm.mem.set_model(model);
+    vm.mem.sid.set_sampling_parameters(model.clock_rate(), 44100);
+    return vm;
+  }
+
+  /**
+   * Create a machine with the given ROMs, switched on and starting up
+   */
+  pub fn with_roms(model: Model, roms: &Roms) -> VM {
+    let mut vm = VM::new(model);
+    vm.load_roms(roms);
+    vm.reset(true);
+    return vm;
+  }
+
+  /**
+   * Replace the ROMs. Machines created with new() have empty ROMs, and need
+   * these, or the ROM images copied in some other way, then a hard reset
+   * before they can run.
+   */
+  pub fn load_roms(&mut self, roms: &Roms) {
+    self.mem.ram_rom.initialize_basic_rom(roms.basic);
+    self.mem.ram_rom.initialize_kernal_rom(roms.kernal);
+    self.mem.ram_rom.initialize_char_rom(roms.character);
+  }
+
+  pub fn model(&self) -> Model {
+    self.model
+  }
+
+  pub fn step(&mut self) -> u8 {
+    self.mem.begin_instruction(self.cpu.pc);
+    let cycles = self.cpu.step(&mut self.mem);
+    self.mem.end_instruction();
+    return cycles;
+  }
+
+  pub fn run_for_ms(&mut self, ms: u32) {
+    let cycles = (self.model.clock_rate() as u64 * ms as u64 / 1000) as u32;
+    self.run_cycles(cycles);
+  }
+
+  /**
+   * Run until the VIC-II completes a frame, or the monitor stops the machine.
+   */
+  pub fn run_frame(&mut self) {
+    let frame = self.mem.frame_count();
+    while self.mem.frame_count() == frame && !self.monitor.active {
+      let line = self.model.cycles_per_line() as u32;
+      self.run_cycles(line);
+    }
+  }
+
+  /**
+   * The last complete frame as RGBA pixels
+   */
+  pub fn render_rgba(&mut self) -> &[u8] {
+    palette::to_rgba(self.mem.frame(), &mut self.rgba_frame);
+    &self.rgba_frame
+  }
+
+  fn run_cycles(&mut self, cycles: u32) {
+    let mut ran = 0;
+    while ran < cycles {
+      // The CPU doesn't run while a DMA transfer holds the bus
+      let stalled = self.mem.dma_stalled();
+      if self.monitor.active || (!stalled && self.monitor.check_breakpoints(&self.cpu, &mut self.mem)) {
+        break;
+      }
+      if !stalled && self.disk.check(&mut self.cpu, &mut self.mem) {
+        continue;
+      }
+      if !stalled && self.trace.is_some() {
+        self.trace_instruction();
+      }
+      let step_time = if stalled { self.mem.take_dma_stall() } else { self.step() };
+      let cia_interrupt = self.mem.cia.update_timers(step_time);
+      let nmi = self.mem.cia.nmi_pending() || self.restore_pulse;
+      self.restore_pulse = false;
+      if nmi && !self.nmi_line {
+        self.cpu.nonmaskable_interrupt(&mut self.mem);
+      }
+      self.nmi_line = nmi;
+      let vic_interrupt = self.mem.update_vic(step_time);
+      self.mem.update_sid(step_time);
+      if let Some(ref mut drive) = self.drive {
+        let (atn, clk, data) = self.mem.cia.get_serial_outputs();
+        drive.set_c64_lines(atn, clk, data);
+        drive.run(step_time);
+        let (clk, data) = drive.bus_lines();
+        self.mem.cia.set_serial_inputs(clk, data);
+      }
+      if self.typist.is_typing() {
+        self.typist.update(&mut self.mem, step_time);
+      }
+      let tape_interrupt = self.mem.update_tape(step_time);
+      let reu_interrupt = self.mem.reu_interrupt_pending();
+      if cia_interrupt || vic_interrupt || tape_interrupt || reu_interrupt {
+        self.cpu.interrupt_request(&mut self.mem);
+      }
+      if self.autostart.is_some() || self.autostart_commands.len() > 0 {
+        self.check_autostart();
+      }
+      ran += step_time as u32;
+      if self.monitor.check_watches(&self.cpu, &mut self.mem) {
+        break;
+      }
+    }
+    self.sync_drive_writes();
+  }
+
+  fn trace_instruction(&mut self) {
+    let mem = &mut self.mem;
+    let line = trace_line(&self.cpu, |addr| mem.peek(addr), &self.monitor.symbols);
+    let failed = match self.trace {
+      Some(ref mut trace) => writeln!(trace, "{}", line).is_err(),
+      None => false,
+    };
+    if failed {
+      eprintln!("Unable to write the trace log, tracing stopped");
+      self.trace = None;
+    }
+  }
+
+  pub fn set_audio_sample_rate(&mut self, rate: u32) {
+    self.mem.sid.set_sampling_parameters(self.model.clock_rate(), rate);
+  }
+
+  pub fn keydown(&mut self, key: u8) {
+    if key == KEY_RESTORE {
+      self.press_restore();
+    } else {
+      self.mem.cia.keydown(key);
+    }
+  }
+
+  pub fn keyup(&mut self, key: u8) {
+    self.mem.cia.keyup(key);
+  }
+
+  /**
+   * Press RESTORE, which signals an NMI unless CIA 2 is already holding the
+   * line low. The KERNAL's handler does a warm start if RUN/STOP is also held.
+   */
+  pub fn press_restore(&mut self) {
+    self.restore_pulse = true;
+  }
+
+  pub fn joystick_down(&mut self, port: usize, bits: u8) {
+    self.mem.cia.joystick_down(port, bits);
+  }
+
+  pub fn joystick_up(&mut self, port: usize, bits: u8) {
+    self.mem.cia.joystick_up(port, bits);
+  }
+
+  /**
+   * A host key, named as in the DOM's KeyboardEvent.code, was pressed.
+   * `text` is the character it typed, if any, for the keymap's symbolic mode.
+   */
+  pub fn host_key_down(&mut self, code: &str, text: Option<char>) {
+    let changes = self.keymap.key_down(code, text);
+    self.apply_keymap_changes(changes);
+  }
+
+  pub fn host_key_up(&mut self, code: &str) {
+    let changes = self.keymap.key_up(code);
+    self.apply_keymap_changes(changes);
+  }
+
+  /**
+   * Let go of every key the host is holding, as when it loses focus
+   */
+  pub fn release_host_keys(&mut self) {
+    let changes = self.keymap.release_all();
+    self.apply_keymap_changes(changes);
+  }
+
+  fn apply_keymap_changes(&mut self, changes: Vec<(Target, bool)>) {
+    for (target, pressed) in changes {
+      match target {
+        Target::Key(key) if pressed => self.keydown(key),
+        Target::Key(key) => self.keyup(key),
+        Target::Joystick(port, bits) if pressed => self.joystick_down(port, bits),
+        Target::Joystick(port, bits) => self.joystick_up(port, bits),
+      }
+    }
+  }
+
+  /**
+   * Load a PRG file into RAM. With autostart, loading waits until the KERNAL
+   * has reached the READY prompt, and then the program is started with RUN or
+   * SYS. Returns the load address, or None if the file is invalid.
+   */
+  pub fn load_prg(&mut self, data: Vec<u8>, autostart: bool) -> Option<u16> {
+    let address = prg::load_address(&data);
+    if address.is_none() {
+      return None;
+    }
+    if autostart {
+      self.autostart = Some(data);
+    } else {
+      prg::load(&mut self.mem, &data);
+    }
+    return address;
+  }
+
+  /**
+   * Insert a D64 image as drive 8. With autostart, the first file on the disk
+   * is started once the KERNAL reaches the READY prompt. Returns false if the
+   * image is invalid.
+   */
+  pub fn attach_disk(&mut self, data: Vec<u8>, autostart: bool) -> bool {
+    // G64 images hold raw GCR data, which only a true drive can read
+    if let Some(gcr) = GcrDisk::from_g64(&data) {
+      return match self.drive {
+        Some(ref mut drive) => {
+          self.disk.detach();
+          drive.insert_disk(gcr);
+          true
+        },
+        None => false,
+      };
+    }
+    let image = match D64::from_bytes(data) {
+      Ok(image) => image,
+      Err(_) => return false,
+    };
+    if autostart {
+      let program = image.find_file(b"*").and_then(|entry| image.read_file(&entry).ok());
+      if let Some(program) = program {
+        self.load_prg(program, true);
+      }
+    }
+    if let Some(ref mut drive) = self.drive {
+      drive.insert_disk(GcrDisk::from_d64(&image));
+    }
+    self.disk.attach(image);
+    return true;
+  }
+
+  /**
+   * Insert a tape image. Programs in a T64 container are loaded directly, like
+   * a PRG file. A TAP recording goes into the Datasette, and with autostart
+   * PLAY is pressed and the program is loaded and run from BASIC, so that
+   * turbo loaders on the tape work. Returns false if the image is invalid.
+   */
+  pub fn attach_tape(&mut self, data: Vec<u8>, autostart: bool) -> bool {
+    if let Some(tap) = Tap::from_bytes(&data) {
+      self.mem.datasette.insert(tap);
+      if autostart {
+        self.mem.datasette.play();
+        self.autostart_commands = vec![b"LOAD\r", b"RUN\r"];
+      }
+      return true;
+    }
+    let image = match T64::from_bytes(data) {
+      Some(image) => image,
+      None => return false,
+    };
+    let program = image.read_file(&image.entries()[0]);
+    return self.load_prg(program, autostart).is_some();
+  }
+
+  /**
+   * Connect an emulated 1541 to the serial bus, running the given 16K DOS
+   * ROM. LOAD and SAVE then go through the drive instead of being trapped.
+   * Returns false if the ROM is invalid.
+   */
+  pub fn enable_true_drive(&mut self, rom: &[u8]) -> bool {
+    let mut drive = Drive1541::new();
+    if !drive.load_rom(rom) {
+      return false;
+    }
+    drive.reset();
+    if let Some(ref image) = self.disk.image {
+      drive.insert_disk(GcrDisk::from_d64(image));
+    }
+    self.disk.enabled = false;
+    self.drive = Some(drive);
+    return true;
+  }
+
+  /**
+   * Once the drive has finished writing and stopped its motor, copy the
+   * changed sectors back into the D64 image so front-ends can save it.
+   */
+  fn sync_drive_writes(&mut self) {
+    if let Some(ref mut drive) = self.drive {
+      if drive.motor_on() {
+        return;
+      }
+      if let (Some(ref mut gcr), Some(ref mut image)) = (drive.disk.as_mut(), self.disk.image.as_mut()) {
+        if gcr.modified {
+          gcr.write_to_d64(image);
+          gcr.modified = false;
+          self.disk.modified = true;
+        }
+      }
+    }
+  }
+
+  fn check_autostart(&mut self) {
+    if !prg::is_ready(self.cpu.pc) {
+      return;
+    }
+    if let Some(data) = self.autostart.take() {
+      if let Some(address) = prg::load(&mut self.mem, &data) {
+        let command = prg::autostart_command(address);
+        prg::queue_keys(&mut self.mem, command.as_bytes());
+      }
+      return;
+    }
+    // The editor also passes through the READY loop between keypresses, so
+    // wait for the last command to be used up
+    if prg::queued_keys(&self.mem) == 0 {
+      let command = self.autostart_commands.remove(0);
+      prg::queue_keys(&mut self.mem, command);
+    }
+  }
+
+  /**
+   * Type a string of text into the machine, through the keyboard buffer or by
+   * pressing keys in the keyboard matrix.
+   */
+  pub fn type_text(&mut self, text: &str, press_keys: bool) {
+    self.typist.press_keys = press_keys;
+    self.typist.type_text(text);
+  }
+
+  /**
+   * Tokenize a BASIC program written as text and load it, as if it were a PRG
+   * file. Returns false if the source has errors.
+   */
+  pub fn enter_basic(&mut self, source: &str, autostart: bool) -> bool {
+    match basic::to_prg(source) {
+      Ok(data) => self.load_prg(data, autostart).is_some(),
+      Err(_) => false,
+    }
+  }
+
+  pub fn list_basic(&self) -> String {
+    basic::list(&self.mem)
+  }
+
+  /**
+   * Stop the machine and enter the monitor. It stays stopped until a monitor
+   * command resumes it.
+   */
+  pub fn enter_monitor(&mut self) {
+    if !self.monitor.active {
+      self.monitor.stop("", &self.cpu, &mut self.mem);
+    }
+  }
+
+  /**
+   * Run a line of input in the monitor, returning what it printed
+   */
+  pub fn monitor_command(&mut self, line: &str) -> String {
+    self.enter_monitor();
+    self.monitor.command(line, &mut self.cpu, &mut self.mem);
+    if let Some(data) = self.monitor.take_undump() {
+      let restored = self.load_snapshot(&data);
+      self.monitor.undumped(restored, &self.cpu);
+    }
+    self.monitor.take_output()
+  }
+
+  /**
+   * Save the CPU and chips as a VICE snapshot
+   */
+  pub fn save_snapshot(&mut self) -> Vec<u8> {
+    Snapshot::capture(&self.cpu, &mut self.mem).to_bytes()
+  }
+
+  /**
+   * Load a VICE snapshot. Returns false, leaving the machine as it was, if it
+   * isn't a valid C64 snapshot.
+   */
+  pub fn load_snapshot(&mut self, data: &[u8]) -> bool {
+    let restored = Snapshot::from_bytes(data)
+      .and_then(|snapshot| snapshot.restore(&mut self.cpu, &mut self.mem));
+    if restored.is_err() {
+      return false;
+    }
+    self.nmi_line = self.mem.cia.nmi_pending();
+    self.restore_pulse = false;
+    self.autostart = None;
+    self.autostart_commands.clear();
+    return true;
+  }
+
+  /**
+   * Plug a CRT cartridge into the expansion port and reset the machine, so
+   * the cartridge can start itself. Returns false if the image is invalid or
+   * uses unsupported hardware.
+   */
+  pub fn attach_cartridge(&mut self, data: &[u8]) -> bool {
+    let cartridge = match Cartridge::from_bytes(data) {
+      Ok(cartridge) => cartridge,
+      Err(_) => return false,
+    };
+    self.mem.cartridge = Some(cartridge);
+    self.reset(true);
+    return true;
+  }
+
+  pub fn detach_cartridge(&mut self) {
+    if self.mem.cartridge.take().is_some() {
+      self.reset(true);
+    }
+  }
+
+  /**
+   * Plug in a RAM Expansion Unit with the given size in kilobytes, a power of
+   * two from 128 to 16384, and reset. An REU can be used alongside a
+   * cartridge, but takes over I/O 2 from it.
+   */
+  pub fn attach_reu(&mut self, size_kb: u32) -> bool {
+    let reu = match Reu::new(size_kb as usize * 1024) {
+      Some(reu) => reu,
+      None => return false,
+    };
+    self.mem.reu = Some(reu);
+    self.reset(true);
+    return true;
+  }
+
+  pub fn detach_reu(&mut self) {
+    if self.mem.reu.take().is_some() {
+      self.reset(true);
+    }
+  }
+
+  /**
+   * Press the cartridge's freeze button, which maps in its ROM and raises an
+   * NMI.
+   */
+  pub fn freeze_cartridge(&mut self) {
+    let frozen = match self.mem.cartridge {
+      Some(ref mut cartridge) => cartridge.freeze(),
+      None => false,
+    };
+    if frozen {
+      self.cpu.nonmaskable_interrupt(&mut self.mem);
+    }
+  }
+
+  /**
+   * A soft reset is the reset button: the chips are reset and the KERNAL
+   * starts again from the reset vector, but RAM keeps its contents, so a
+   * program can survive it. A hard reset is switching the machine off and on,
+   * which also returns RAM to its power-on pattern.
+   */
+  pub fn reset(&mut self, hard: bool) {
+    if hard {
+      self.mem.ram_rom.power_on();
+    }
+    self.mem.reset();
+    self.nmi_line = false;
+    self.restore_pulse = false;
+    self.cpu.reset(&mut self.mem);
+    if let Some(ref mut drive) = self.drive {
+      drive.reset();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::vm::{VM, Model, FRAME_WIDTH, FRAME_HEIGHT};
+  use c64memmap::cia::KEY_RESTORE;
+  use mos6510::memory::Memory;
+
+  #[test]
+  fn basic_ops() {
+    let mut vm = VM::new(Model::Pal);
+    vm.mem.set_basic_rom(vec![
+      0xa9, 0x22, // LDA #$22
+      0x69, 0x11, // ADC #$11
+    ], 0);
+    vm.cpu.pc = 0xa000;
+    vm.cpu.step(&mut vm.mem);
+    assert_eq!(vm.cpu.acc, 0x22);
+    vm.cpu.step(&mut vm.mem);
+    assert_eq!(vm.cpu.acc, 0x33);
+  }
+
+  #[test]
+  fn memory_ops() {
+    let mut vm = VM::new(Model::Pal);
+    vm.mem.set_basic_rom(vec![
+      0xa9, 0x40, // LDA #$40
+      0x8d, 0x05, 0x20, // STA #$2005
+      0xac, 0x05, 0x20, // LDY #$2005
+    ], 0);
+    vm.cpu.pc = 0xa000;
+    vm.cpu.step(&mut vm.mem);
+    vm.cpu.step(&mut vm.mem);
+    assert_eq!(vm.mem.get_byte(0x2005), 0x40);
+    vm.cpu.step(&mut vm.mem);
+    assert_eq!(vm.cpu.y, 0x40);
+  }
+
+  #[test]
+  fn soft_and_hard_reset() {
+    let mut vm = VM::new(Model::Pal);
+    vm.mem.set_byte(0x2000, 0x55);
+    vm.reset(false);
+    assert_eq!(vm.mem.get_byte(0x2000), 0x55);
+    vm.reset(true);
+    assert_eq!(vm.mem.get_byte(0x2000), 0x00);
+    assert_eq!(vm.mem.get_byte(0x2040), 0xff);
+  }
+
+  #[test]
+  fn restore_nmi() {
+    let mut vm = VM::new(Model::Pal);
+    // NMI vector to $3000, with NOPs from $2000 to $3fff
+    vm.mem.ram_rom.kernal[0x1ffa] = 0x00;
+    vm.mem.ram_rom.kernal[0x1ffb] = 0x30;
+    for addr in 0x2000..0x4000 {
+      vm.mem.ram_rom.ram[addr] = 0xea;
+    }
+    vm.cpu.pc = 0x2000;
+    vm.keydown(KEY_RESTORE);
+    vm.run_for_ms(1);
+    assert!(vm.cpu.pc > 0x3000);
+
+    // Once CIA 2 is holding NMI low, RESTORE has no effect until the
+    // interrupt is acknowledged
+    vm.mem.set_byte(0xdd04, 0x10);
+    vm.mem.set_byte(0xdd05, 0x00);
+    vm.mem.set_byte(0xdd0d, 0x81);
+    vm.mem.set_byte(0xdd0e, 0x19);
+    vm.cpu.pc = 0x2000;
+    vm.run_for_ms(1);
+    assert!(vm.cpu.pc > 0x3000);
+    vm.cpu.pc = 0x2000;
+    vm.keydown(KEY_RESTORE);
+    vm.run_for_ms(1);
+    assert!(vm.cpu.pc < 0x3000);
+    vm.mem.get_byte(0xdd0d);
+    vm.run_for_ms(1);
+    vm.cpu.pc = 0x2000;
+    vm.keydown(KEY_RESTORE);
+    vm.run_for_ms(1);
+    assert!(vm.cpu.pc > 0x3000);
+  }
+
+  #[test]
+  fn monitor_breakpoint() {
+    let mut vm = VM::new(Model::Pal);
+    for addr in 0x2000..0x4000 {
+      vm.mem.ram_rom.ram[addr] = 0xea;
+    }
+    vm.cpu.pc = 0x2000;
+    vm.monitor_command("break 2010");
+    vm.monitor_command("x");
+    vm.run_for_ms(1);
+    assert!(vm.monitor.active);
+    assert_eq!(vm.cpu.pc, 0x2010);
+    // Stopped until a command resumes
+    vm.run_for_ms(1);
+    assert_eq!(vm.cpu.pc, 0x2010);
+    // The reason for stopping is printed with the next command's output
+    assert!(vm.monitor_command("g").starts_with("#1 (Stop on exec 2010)"));
+    vm.run_for_ms(1);
+    assert!(!vm.monitor.active);
+    assert!(vm.cpu.pc > 0x2010);
+  }
+
+  #[test]
+  fn monitor_undump() {
+    let mut vm = VM::new(Model::Pal);
+    let path = std::env::temp_dir().join("c64vm_monitor_undump.vsf");
+    let path = path.to_str().unwrap();
+    vm.cpu.pc = 0x2000;
+    assert!(vm.monitor_command(&format!("dump \"{}\"", path)).starts_with(".C:2000"));
+    // A RESTORE press and an autostart from before the snapshot don't carry
+    // over into it
+    vm.cpu.pc = 0x3000;
+    vm.restore_pulse = true;
+    vm.autostart = Some(vec![0x01, 0x08]);
+    assert_eq!(vm.monitor_command(&format!("undump \"{}\"", path)), "");
+    assert_eq!(vm.cpu.pc, 0x2000);
+    assert!(!vm.restore_pulse);
+    assert!(vm.autostart.is_none());
+    std::fs::write(path, b"not a snapshot").unwrap();
+    let output = vm.monitor_command(&format!("undump \"{}\"", path));
+    assert_eq!(output, "ERROR -- Not a valid C64 snapshot\n");
+    assert_eq!(vm.cpu.pc, 0x2000);
+    let _ = std::fs::remove_file(path);
+  }
+
+  #[test]
+  fn frames() {
+    let mut vm = VM::new(Model::Pal);
+    // JMP $2000
+    vm.mem.ram_rom.ram[0x2000..0x2003].copy_from_slice(&[0x4c, 0x00, 0x20]);
+    vm.cpu.pc = 0x2000;
+    vm.mem.set_byte(0xd020, 2);
+    vm.run_frame();
+    assert_eq!(vm.mem.frame_count(), 1);
+    assert_eq!(vm.mem.frame()[0], 2);
+    let rgba = vm.render_rgba();
+    assert_eq!(rgba.len(), FRAME_WIDTH * FRAME_HEIGHT * 4);
+    assert_eq!(&rgba[0..4], &[0x68, 0x37, 0x2b, 0xff]);
+  }
+}
\ No newline at end of file
@@ -5,12 +5,23 @@ use channels::{ChannelID, ChannelType};
 use manager::ChannelManager;
 use messages::Message;
 
-use std::thread;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::sync::mpsc;
+#[cfg(feature = "output")]
+use std::thread;
 
+/**
+ * Channels either play through the default output device, once start() is
+ * called, or are rendered on demand with render_samples, for hosts like a
+ * browser that pull audio themselves. Clones share the same output.
+ */
+#[derive(Clone)]
 pub struct EmuAudio {
   channels: Vec<ChannelType>,
   tx: Option<mpsc::Sender<Message>>,
+  offline: Option<Rc<RefCell<ChannelManager>>>,
+  sample_rate: u32,
 }
 
 impl EmuAudio {
@@ -18,105 +29,130 @@ impl EmuAudio {
     EmuAudio {
       channels: Vec::new(),
       tx: None,
+      offline: None,
+      sample_rate: 44100,
     }
   }
 
-  pub fn add_channel(&mut self, channel_type: ChannelType) -> ChannelID {
-    self.channels.push(channel_type);
+  /**
+   * Mix the channels without an output device. Nothing plays until the
+   * samples are fetched with render_samples.
+   */
+  pub fn offline(sample_rate: u32) -> EmuAudio {
+    EmuAudio {
+      channels: Vec::new(),
+      tx: None,
+      offline: Some(Rc::new(RefCell::new(ChannelManager::new(sample_rate)))),
+      sample_rate: sample_rate,
+    }
+  }
+
+  fn send(&self, msg: Message) {
     if let Some(tx) = &self.tx {
-      tx.send(Message::AddChannel(channel_type)).unwrap();
+      tx.send(msg).unwrap();
+    } else if let Some(manager) = &self.offline {
+      manager.borrow_mut().handle_message(msg);
+    }
+  }
+
+  /**
+   * Append the next `count` samples of an offline mix to `out`. Does nothing
+   * while playing through a device.
+   */
+  pub fn render_samples(&self, count: usize, out: &mut Vec<f32>) {
+    if let Some(manager) = &self.offline {
+      let mut manager = manager.borrow_mut();
+      for _ in 0..count {
+        out.push(manager.get_next_sample());
+      }
     }
+  }
+
+  pub fn add_channel(&mut self, channel_type: ChannelType) -> ChannelID {
+    self.channels.push(channel_type);
+    self.send(Message::AddChannel(channel_type));
     self.channels.len() as ChannelID - 1
   }
 
   pub fn enable_channel(&self, id: ChannelID) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::EnableChannel(id)).unwrap();
-    }
+    self.send(Message::EnableChannel(id));
   }
 
   pub fn disable_channel(&self, id: ChannelID) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::DisableChannel(id)).unwrap();
-    }
+    self.send(Message::DisableChannel(id));
   }
 
   pub fn set_frequency(&self, id: ChannelID, freq: f32) {
     //println!("SEND FREQ {}", freq);
-    if let Some(tx) = &self.tx {
-      tx.send(Message::SetFrequency(id, freq)).unwrap();
-    }
+    self.send(Message::SetFrequency(id, freq));
   }
 
   pub fn set_volume(&self, id: ChannelID, vol: f32) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::SetVolume(id, vol)).unwrap();
-    }
+    self.send(Message::SetVolume(id, vol));
   }
 
   pub fn set_duty(&self, id: ChannelID, duty: f32) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::SetVolume(id, duty)).unwrap();
-    }
+    self.send(Message::SetVolume(id, duty));
   }
 
   pub fn enable_envelope(&self, id: ChannelID) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::EnableEnvelope(id)).unwrap();
-    }
+    self.send(Message::EnableEnvelope(id));
   }
 
   pub fn disable_envelope(&self, id: ChannelID) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::DisableEnvelope(id)).unwrap();
-    }
+    self.send(Message::DisableEnvelope(id));
   }
 
   pub fn set_attack_time(&self, id: ChannelID, time: f32) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::SetAttackTime(id, time)).unwrap();
-    }
+    self.send(Message::SetAttackTime(id, time));
   }
 
   pub fn set_decay_time(&self, id: ChannelID, time: f32) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::SetDecayTime(id, time)).unwrap();
-    }
+    self.send(Message::SetDecayTime(id, time));
   }
 
   pub fn set_sustain_level(&self, id: ChannelID, level: f32) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::SetSustainLevel(id, level)).unwrap();
-    }
+    self.send(Message::SetSustainLevel(id, level));
   }
 
   pub fn set_release_time(&self, id: ChannelID, time: f32) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::SetReleaseTime(id, time)).unwrap();
-    }
+    self.send(Message::SetReleaseTime(id, time));
   }
 
   pub fn press_note(&self, id: ChannelID) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::PressNote(id)).unwrap();
-    }
+    self.send(Message::PressNote(id));
   }
 
   pub fn release_note(&self, id: ChannelID) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::ReleaseNote(id)).unwrap();
-    }
+    self.send(Message::ReleaseNote(id));
   }
 
   pub fn play_note_for_time(&self, id: ChannelID, time: f32) {
-    if let Some(tx) = &self.tx {
-      tx.send(Message::PlayNoteForTime(id, time)).unwrap();
-    }
+    self.send(Message::PlayNoteForTime(id, time));
   }
 
+  /**
+   * Queue samples produced at the output sample rate, to be mixed in with the
+   * channels. Used by emulators that generate their own waveforms.
+   */
+  pub fn queue_samples(&self, samples: Vec<f32>) {
+    self.send(Message::QueueSamples(samples));
+  }
+
+  pub fn get_sample_rate(&self) -> u32 {
+    self.sample_rate
+  }
+
+  #[cfg(feature = "output")]
   pub fn start(&mut self) {
     let (tx, rx): (mpsc::Sender<Message>, mpsc::Receiver<Message>) = mpsc::channel();
 
+    if let Some(device) = cpal::default_output_device() {
+      if let Ok(format) = device.default_output_format() {
+        self.sample_rate = format.sample_rate.0 as u32;
+      }
+    }
+
     thread::spawn(move || {
       let device = cpal::default_output_device().unwrap();
       let format = device.default_output_format().unwrap();
@@ -1,9 +1,11 @@
 use crate::channels::{Channel, ChannelType};
 use crate::messages::Message;
+use std::collections::VecDeque;
 
 pub struct ChannelManager {
   channels: Vec<Channel>,
   sample_rate: u32,
+  stream: VecDeque<f32>,
 }
 
 impl ChannelManager {
@@ -11,6 +13,7 @@ impl ChannelManager {
     ChannelManager {
       channels: Vec::new(),
       sample_rate: sample_rate,
+      stream: VecDeque::new(),
     }
   }
 
@@ -19,7 +22,13 @@ impl ChannelManager {
     for chan in self.channels.iter_mut() {
       total += chan.get_next_sample();
     }
-    total / self.channels.len() as f32
+    if self.channels.len() > 0 {
+      total = total / self.channels.len() as f32;
+    }
+    if let Some(sample) = self.stream.pop_front() {
+      total += sample;
+    }
+    total
   }
 
   pub fn handle_message(&mut self, msg: Message) {
@@ -142,6 +151,13 @@ impl ChannelManager {
           None => (),
         }
       },
+      Message::QueueSamples(samples) => {
+        // Don't let latency build up if the producer runs ahead
+        if self.stream.len() > self.sample_rate as usize / 4 {
+          self.stream.clear();
+        }
+        self.stream.extend(samples);
+      },
     }
   }
 }
\ No newline at end of file
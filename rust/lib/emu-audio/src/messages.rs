@@ -17,4 +17,5 @@ pub enum Message {
   PressNote(ChannelID),
   ReleaseNote(ChannelID),
   PlayNoteForTime(ChannelID, f32),
+  QueueSamples(Vec<f32>), // raw samples from an emulated sound chip
 }
\ No newline at end of file
@@ -1,8 +1,18 @@
-use glutin::{ContextBuilder, ElementState, Event, EventsLoop, VirtualKeyCode, WindowedContext, WindowBuilder, WindowEvent};
+use glutin::{ContextBuilder, DeviceEvent, ElementState, Event, EventsLoop, MouseButton, VirtualKeyCode, WindowedContext, WindowBuilder, WindowEvent};
 use glutin::dpi::{LogicalSize};
 use glutin::ContextTrait;
 use std::collections::HashSet;
 
+/**
+ * A key pressed or released, in the order they happened. A press carries the
+ * character it typed, if any.
+ */
+pub struct KeyEvent {
+  pub code: VirtualKeyCode,
+  pub pressed: bool,
+  pub text: Option<char>,
+}
+
 pub struct EmuShell {
   events_loop: EventsLoop,
   context: WindowedContext,
@@ -15,6 +25,15 @@ pub struct EmuShell {
 
   pub keys_down: HashSet<VirtualKeyCode>,
   pub keys_up: HashSet<VirtualKeyCode>,
+  // The key events since the last update
+  pub key_events: Vec<KeyEvent>,
+
+  // The pointer's position in unscaled pixels, while it's over the window
+  pub mouse_position: Option<(u32, u32)>,
+  // How far the mouse moved since the last update, in unscaled pixels,
+  // whether or not the pointer could follow
+  pub mouse_motion: (f64, f64),
+  pub mouse_buttons_down: HashSet<MouseButton>,
 }
 
 impl EmuShell {
@@ -44,6 +63,11 @@ impl EmuShell {
       foregrounded: false,
       keys_down: HashSet::new(),
       keys_up: HashSet::new(),
+      key_events: Vec::new(),
+
+      mouse_position: None,
+      mouse_motion: (0.0, 0.0),
+      mouse_buttons_down: HashSet::new(),
     }
   }
 
@@ -87,6 +111,13 @@ impl EmuShell {
     let mut foregrounded = self.foregrounded;
     let keys_down = &mut self.keys_down;
     let keys_up = &mut self.keys_up;
+    let key_events = &mut self.key_events;
+    let scale = self.scale as f64;
+    let mouse_position = &mut self.mouse_position;
+    let mouse_motion = &mut self.mouse_motion;
+    let mouse_buttons_down = &mut self.mouse_buttons_down;
+    *mouse_motion = (0.0, 0.0);
+    key_events.clear();
     self.events_loop.poll_events(|event| {
       match event {
         Event::WindowEvent {event, ..} => match event {
@@ -94,17 +125,43 @@ impl EmuShell {
           WindowEvent::Focused(flag) => foregrounded = flag,
           WindowEvent::KeyboardInput {input, ..} => {
             if let Some(code) = input.virtual_keycode {
-              if input.state == ElementState::Pressed {
+              let pressed = input.state == ElementState::Pressed;
+              if pressed {
                 keys_up.remove(&code);
                 keys_down.insert(code);
               } else {
                 keys_down.remove(&code);
                 keys_up.insert(code);
               }
+              key_events.push(KeyEvent {code: code, pressed: pressed, text: None});
+            }
+          },
+          // Arrives after the press that typed it
+          WindowEvent::ReceivedCharacter(c) => {
+            let last_press = key_events.iter_mut().rev().find(|e| e.pressed);
+            if let Some(event) = last_press {
+              if event.text.is_none() {
+                event.text = Some(c);
+              }
+            }
+          },
+          WindowEvent::CursorMoved {position, ..} => {
+            *mouse_position = Some(((position.x / scale) as u32, (position.y / scale) as u32));
+          },
+          WindowEvent::CursorLeft {..} => *mouse_position = None,
+          WindowEvent::MouseInput {state, button, ..} => {
+            if state == ElementState::Pressed {
+              mouse_buttons_down.insert(button);
+            } else {
+              mouse_buttons_down.remove(&button);
             }
           },
           _ => (),
         },
+        Event::DeviceEvent {event: DeviceEvent::MouseMotion {delta}, ..} => {
+          mouse_motion.0 += delta.0 / scale;
+          mouse_motion.1 += delta.1 / scale;
+        },
         _ => (),
       }
     });
@@ -1,3 +1,7 @@
+use cpu::CPU;
+use symbols::SymbolTable;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum AddressMode {
   None,
   Immediate,
@@ -15,7 +19,7 @@ pub enum AddressMode {
 
 type Instruction = (&'static str, AddressMode);
 
-static INSTRUCTIONS: [Instruction; 0x60] = [
+static INSTRUCTIONS: [Instruction; 0x100] = [
   ("BRK", AddressMode::None),
   ("ORA", AddressMode::IndirectX),
   ("KIL", AddressMode::None),
@@ -37,10 +41,10 @@ static INSTRUCTIONS: [Instruction; 0x60] = [
   ("ORA", AddressMode::IndirectY),
   ("KIL", AddressMode::None),
   ("SLO", AddressMode::IndirectY),
-  ("DOP", AddressMode::IndirectX),
-  ("ORA", AddressMode::IndirectX),
-  ("ASL", AddressMode::IndirectX),
-  ("SLO", AddressMode::IndirectX),
+  ("DOP", AddressMode::ZeroPageX),
+  ("ORA", AddressMode::ZeroPageX),
+  ("ASL", AddressMode::ZeroPageX),
+  ("SLO", AddressMode::ZeroPageX),
   ("CLC", AddressMode::None),
   ("ORA", AddressMode::AbsoluteY),
   ("NOP", AddressMode::None),
@@ -70,7 +74,7 @@ static INSTRUCTIONS: [Instruction; 0x60] = [
   ("BMI", AddressMode::Relative),
   ("AND", AddressMode::IndirectY),
   ("KIL", AddressMode::None),
-  ("RLA", AddressMode::IndirectX),
+  ("RLA", AddressMode::IndirectY),
   ("DOP", AddressMode::ZeroPageX),
   ("AND", AddressMode::ZeroPageX),
   ("ROL", AddressMode::ZeroPageX),
@@ -113,8 +117,442 @@ static INSTRUCTIONS: [Instruction; 0x60] = [
   ("EOR", AddressMode::AbsoluteY),
   ("NOP", AddressMode::None),
   ("SRE", AddressMode::AbsoluteY),
-  ("NOP", AddressMode::AbsoluteX),
+  ("TOP", AddressMode::AbsoluteX),
   ("EOR", AddressMode::AbsoluteX),
   ("LSR", AddressMode::AbsoluteX),
   ("SRE", AddressMode::AbsoluteX),
-];
\ No newline at end of file
+
+  ("RTS", AddressMode::None),
+  ("ADC", AddressMode::IndirectX),
+  ("KIL", AddressMode::None),
+  ("RRA", AddressMode::IndirectX),
+  ("DOP", AddressMode::ZeroPage),
+  ("ADC", AddressMode::ZeroPage),
+  ("ROR", AddressMode::ZeroPage),
+  ("RRA", AddressMode::ZeroPage),
+  ("PLA", AddressMode::None),
+  ("ADC", AddressMode::Immediate),
+  ("ROR", AddressMode::None),
+  ("ARR", AddressMode::Immediate),
+  ("JMP", AddressMode::Indirect),
+  ("ADC", AddressMode::Absolute),
+  ("ROR", AddressMode::Absolute),
+  ("RRA", AddressMode::Absolute),
+
+  ("BVS", AddressMode::Relative),
+  ("ADC", AddressMode::IndirectY),
+  ("KIL", AddressMode::None),
+  ("RRA", AddressMode::IndirectY),
+  ("DOP", AddressMode::ZeroPageX),
+  ("ADC", AddressMode::ZeroPageX),
+  ("ROR", AddressMode::ZeroPageX),
+  ("RRA", AddressMode::ZeroPageX),
+  ("SEI", AddressMode::None),
+  ("ADC", AddressMode::AbsoluteY),
+  ("NOP", AddressMode::None),
+  ("RRA", AddressMode::AbsoluteY),
+  ("TOP", AddressMode::AbsoluteX),
+  ("ADC", AddressMode::AbsoluteX),
+  ("ROR", AddressMode::AbsoluteX),
+  ("RRA", AddressMode::AbsoluteX),
+
+  ("DOP", AddressMode::Immediate),
+  ("STA", AddressMode::IndirectX),
+  ("DOP", AddressMode::Immediate),
+  ("SAX", AddressMode::IndirectX),
+  ("STY", AddressMode::ZeroPage),
+  ("STA", AddressMode::ZeroPage),
+  ("STX", AddressMode::ZeroPage),
+  ("SAX", AddressMode::ZeroPage),
+  ("DEY", AddressMode::None),
+  ("DOP", AddressMode::Immediate),
+  ("TXA", AddressMode::None),
+  ("XAA", AddressMode::Immediate),
+  ("STY", AddressMode::Absolute),
+  ("STA", AddressMode::Absolute),
+  ("STX", AddressMode::Absolute),
+  ("SAX", AddressMode::Absolute),
+
+  ("BCC", AddressMode::Relative),
+  ("STA", AddressMode::IndirectY),
+  ("KIL", AddressMode::None),
+  ("AHX", AddressMode::IndirectY),
+  ("STY", AddressMode::ZeroPageX),
+  ("STA", AddressMode::ZeroPageX),
+  ("STX", AddressMode::ZeroPageY),
+  ("SAX", AddressMode::ZeroPageY),
+  ("TYA", AddressMode::None),
+  ("STA", AddressMode::AbsoluteY),
+  ("TXS", AddressMode::None),
+  ("TAS", AddressMode::AbsoluteY),
+  ("SHY", AddressMode::AbsoluteX),
+  ("STA", AddressMode::AbsoluteX),
+  ("SHX", AddressMode::AbsoluteY),
+  ("AHX", AddressMode::AbsoluteY),
+
+  ("LDY", AddressMode::Immediate),
+  ("LDA", AddressMode::IndirectX),
+  ("LDX", AddressMode::Immediate),
+  ("LAX", AddressMode::IndirectX),
+  ("LDY", AddressMode::ZeroPage),
+  ("LDA", AddressMode::ZeroPage),
+  ("LDX", AddressMode::ZeroPage),
+  ("LAX", AddressMode::ZeroPage),
+  ("TAY", AddressMode::None),
+  ("LDA", AddressMode::Immediate),
+  ("TAX", AddressMode::None),
+  ("LAX", AddressMode::Immediate),
+  ("LDY", AddressMode::Absolute),
+  ("LDA", AddressMode::Absolute),
+  ("LDX", AddressMode::Absolute),
+  ("LAX", AddressMode::Absolute),
+
+  ("BCS", AddressMode::Relative),
+  ("LDA", AddressMode::IndirectY),
+  ("KIL", AddressMode::None),
+  ("LAX", AddressMode::IndirectY),
+  ("LDY", AddressMode::ZeroPageX),
+  ("LDA", AddressMode::ZeroPageX),
+  ("LDX", AddressMode::ZeroPageY),
+  ("LAX", AddressMode::ZeroPageY),
+  ("CLV", AddressMode::None),
+  ("LDA", AddressMode::AbsoluteY),
+  ("TSX", AddressMode::None),
+  ("LAS", AddressMode::AbsoluteY),
+  ("LDY", AddressMode::AbsoluteX),
+  ("LDA", AddressMode::AbsoluteX),
+  ("LDX", AddressMode::AbsoluteY),
+  ("LAX", AddressMode::AbsoluteY),
+
+  ("CPY", AddressMode::Immediate),
+  ("CMP", AddressMode::IndirectX),
+  ("DOP", AddressMode::Immediate),
+  ("DCP", AddressMode::IndirectX),
+  ("CPY", AddressMode::ZeroPage),
+  ("CMP", AddressMode::ZeroPage),
+  ("DEC", AddressMode::ZeroPage),
+  ("DCP", AddressMode::ZeroPage),
+  ("INY", AddressMode::None),
+  ("CMP", AddressMode::Immediate),
+  ("DEX", AddressMode::None),
+  ("AXS", AddressMode::Immediate),
+  ("CPY", AddressMode::Absolute),
+  ("CMP", AddressMode::Absolute),
+  ("DEC", AddressMode::Absolute),
+  ("DCP", AddressMode::Absolute),
+
+  ("BNE", AddressMode::Relative),
+  ("CMP", AddressMode::IndirectY),
+  ("KIL", AddressMode::None),
+  ("DCP", AddressMode::IndirectY),
+  ("DOP", AddressMode::ZeroPageX),
+  ("CMP", AddressMode::ZeroPageX),
+  ("DEC", AddressMode::ZeroPageX),
+  ("DCP", AddressMode::ZeroPageX),
+  ("CLD", AddressMode::None),
+  ("CMP", AddressMode::AbsoluteY),
+  ("NOP", AddressMode::None),
+  ("DCP", AddressMode::AbsoluteY),
+  ("TOP", AddressMode::AbsoluteX),
+  ("CMP", AddressMode::AbsoluteX),
+  ("DEC", AddressMode::AbsoluteX),
+  ("DCP", AddressMode::AbsoluteX),
+
+  ("CPX", AddressMode::Immediate),
+  ("SBC", AddressMode::IndirectX),
+  ("DOP", AddressMode::Immediate),
+  ("ISC", AddressMode::IndirectX),
+  ("CPX", AddressMode::ZeroPage),
+  ("SBC", AddressMode::ZeroPage),
+  ("INC", AddressMode::ZeroPage),
+  ("ISC", AddressMode::ZeroPage),
+  ("INX", AddressMode::None),
+  ("SBC", AddressMode::Immediate),
+  ("NOP", AddressMode::None),
+  ("SBC", AddressMode::Immediate),
+  ("CPX", AddressMode::Absolute),
+  ("SBC", AddressMode::Absolute),
+  ("INC", AddressMode::Absolute),
+  ("ISC", AddressMode::Absolute),
+
+  ("BEQ", AddressMode::Relative),
+  ("SBC", AddressMode::IndirectY),
+  ("KIL", AddressMode::None),
+  ("ISC", AddressMode::IndirectY),
+  ("DOP", AddressMode::ZeroPageX),
+  ("SBC", AddressMode::ZeroPageX),
+  ("INC", AddressMode::ZeroPageX),
+  ("ISC", AddressMode::ZeroPageX),
+  ("SED", AddressMode::None),
+  ("SBC", AddressMode::AbsoluteY),
+  ("NOP", AddressMode::None),
+  ("ISC", AddressMode::AbsoluteY),
+  ("TOP", AddressMode::AbsoluteX),
+  ("SBC", AddressMode::AbsoluteX),
+  ("INC", AddressMode::AbsoluteX),
+  ("ISC", AddressMode::AbsoluteX),
+];
+
+/**
+ * A line of a trace log for the instruction the CPU is about to run, with
+ * its registers, like
+ * "C000  A9 00      LDA #$00                  A:00 X:00 Y:00 P:24 SP:FD".
+ * If the instruction has a label, it is given on a line of its own first.
+ */
+pub fn trace_line<F: FnMut(u16) -> u8>(cpu: &CPU, read: F, symbols: &SymbolTable) -> String {
+  let (bytes, text) = disassemble_with_symbols(cpu.pc, read, symbols);
+  let hex = bytes.iter().map(|b| format!("{:02X}", b)).collect::<Vec<String>>().join(" ");
+  let label = match symbols.label(cpu.pc) {
+    Some(label) => format!("{}:\n", label),
+    None => String::new(),
+  };
+  return format!(
+    "{}{:04X}  {:<9}  {:<24}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+    label, cpu.pc, hex, text, cpu.acc, cpu.x, cpu.y, cpu.status, cpu.stack,
+  );
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AssembleError {
+  UnknownInstruction,
+  InvalidOperand,
+  BranchOutOfRange,
+}
+
+/**
+ * The mnemonic and addressing mode of an opcode. Undocumented opcodes use
+ * their common names, like LAX and DCP.
+ */
+pub fn decode(opcode: u8) -> (&'static str, AddressMode) {
+  let instruction = &INSTRUCTIONS[opcode as usize];
+  (instruction.0, instruction.1)
+}
+
+/**
+ * The number of bytes following the opcode
+ */
+pub fn operand_length(mode: AddressMode) -> u16 {
+  match mode {
+    AddressMode::None => 0,
+    AddressMode::Absolute | AddressMode::AbsoluteX | AddressMode::AbsoluteY | AddressMode::Indirect => 2,
+    _ => 1,
+  }
+}
+
+/**
+ * Disassemble the instruction at `addr`, reading memory through `read`.
+ * Returns the bytes of the instruction and its text, like "LDA $1000,X".
+ * Branch targets are shown as absolute addresses.
+ */
+pub fn disassemble<F: FnMut(u16) -> u8>(addr: u16, read: F) -> (Vec<u8>, String) {
+  disassemble_operands(addr, read, None)
+}
+
+/**
+ * Disassemble an instruction, showing addresses that have labels by name
+ */
+pub fn disassemble_with_symbols<F: FnMut(u16) -> u8>(addr: u16, read: F, symbols: &SymbolTable) -> (Vec<u8>, String) {
+  disassemble_operands(addr, read, Some(symbols))
+}
+
+fn disassemble_operands<F: FnMut(u16) -> u8>(addr: u16, mut read: F, symbols: Option<&SymbolTable>) -> (Vec<u8>, String) {
+  let opcode = read(addr);
+  let (name, mode) = decode(opcode);
+  let mut bytes = vec![opcode];
+  for i in 0..operand_length(mode) {
+    bytes.push(read(addr.wrapping_add(1 + i)));
+  }
+  let byte = if bytes.len() > 1 { bytes[1] } else { 0 };
+  let word = if bytes.len() > 2 { ((bytes[2] as u16) << 8) | (byte as u16) } else { byte as u16 };
+  let target = match mode {
+    AddressMode::Relative => addr.wrapping_add(2).wrapping_add(byte as i8 as u16),
+    _ => word,
+  };
+  if let Some(label) = symbols.and_then(|symbols| symbols.label(target)) {
+    let operand = match mode {
+      AddressMode::None | AddressMode::Immediate => None,
+      AddressMode::ZeroPageX | AddressMode::AbsoluteX => Some(format!(" {},X", label)),
+      AddressMode::ZeroPageY | AddressMode::AbsoluteY => Some(format!(" {},Y", label)),
+      AddressMode::Indirect => Some(format!(" ({})", label)),
+      AddressMode::IndirectX => Some(format!(" ({},X)", label)),
+      AddressMode::IndirectY => Some(format!(" ({}),Y", label)),
+      _ => Some(format!(" {}", label)),
+    };
+    if let Some(operand) = operand {
+      return (bytes, format!("{}{}", name, operand));
+    }
+  }
+  let operand = match mode {
+    AddressMode::None => String::new(),
+    AddressMode::Immediate => format!(" #${:02X}", byte),
+    AddressMode::Relative => format!(" ${:04X}", target),
+    AddressMode::ZeroPage => format!(" ${:02X}", byte),
+    AddressMode::ZeroPageX => format!(" ${:02X},X", byte),
+    AddressMode::ZeroPageY => format!(" ${:02X},Y", byte),
+    AddressMode::Absolute => format!(" ${:04X}", word),
+    AddressMode::AbsoluteX => format!(" ${:04X},X", word),
+    AddressMode::AbsoluteY => format!(" ${:04X},Y", word),
+    AddressMode::Indirect => format!(" (${:04X})", word),
+    AddressMode::IndirectX => format!(" (${:02X},X)", byte),
+    AddressMode::IndirectY => format!(" (${:02X}),Y", byte),
+  };
+  return (bytes, format!("{}{}", name, operand));
+}
+
+/**
+ * Parse a number the way monitors write them, which is hex unless it starts with + for decimal or % for
+ * binary. Hex may also start with $. The flag is set if the number is too
+ * wide for zero page addressing, either in value or in how it was written.
+ */
+pub fn parse_number(text: &str) -> Option<(u16, bool)> {
+  let (digits, radix) = if text.starts_with('$') {
+    (&text[1..], 16)
+  } else if text.starts_with('+') {
+    (&text[1..], 10)
+  } else if text.starts_with('%') {
+    (&text[1..], 2)
+  } else {
+    (text, 16)
+  };
+  let value = u16::from_str_radix(digits, radix).ok()?;
+  let wide = value > 0xff || (radix == 16 && digits.len() > 2);
+  return Some((value, wide));
+}
+
+fn find_opcode(name: &str, mode: AddressMode) -> Option<u8> {
+  // The undocumented implied NOPs come before the real one in the table
+  if name == "NOP" && mode == AddressMode::None {
+    return Some(0xea);
+  }
+  INSTRUCTIONS.iter()
+    .position(|&(n, m)| n == name && m == mode)
+    .map(|opcode| opcode as u8)
+}
+
+/**
+ * Assemble one instruction to be placed at `addr`, written the way
+ * `disassemble` prints it. A value that fits in a byte uses zero page
+ * addressing when the instruction has it.
+ */
+pub fn assemble(text: &str, addr: u16) -> Result<Vec<u8>, AssembleError> {
+  let text = text.trim().to_uppercase();
+  let (name, operand) = match text.find(char::is_whitespace) {
+    Some(space) => (&text[..space], text[space..].replace(char::is_whitespace, "")),
+    None => (&text[..], String::new()),
+  };
+  if find_opcode_any(name).is_none() {
+    return Err(AssembleError::UnknownInstruction);
+  }
+  if operand.len() == 0 || operand == "A" {
+    let opcode = find_opcode(name, AddressMode::None).ok_or(AssembleError::InvalidOperand)?;
+    return Ok(vec![opcode]);
+  }
+
+  // Split the operand into a value and the modes it could be written for,
+  // narrowest first
+  let (value, modes) = if operand.starts_with('#') {
+    (&operand[1..], [AddressMode::Immediate, AddressMode::Immediate])
+  } else if operand.starts_with('(') && operand.ends_with(",X)") {
+    (&operand[1..(operand.len() - 3)], [AddressMode::IndirectX, AddressMode::IndirectX])
+  } else if operand.starts_with('(') && operand.ends_with("),Y") {
+    (&operand[1..(operand.len() - 3)], [AddressMode::IndirectY, AddressMode::IndirectY])
+  } else if operand.starts_with('(') && operand.ends_with(')') {
+    (&operand[1..(operand.len() - 1)], [AddressMode::Indirect, AddressMode::Indirect])
+  } else if operand.ends_with(",X") {
+    (&operand[..(operand.len() - 2)], [AddressMode::ZeroPageX, AddressMode::AbsoluteX])
+  } else if operand.ends_with(",Y") {
+    (&operand[..(operand.len() - 2)], [AddressMode::ZeroPageY, AddressMode::AbsoluteY])
+  } else {
+    (&operand[..], [AddressMode::ZeroPage, AddressMode::Absolute])
+  };
+  let (value, wide) = parse_number(value).ok_or(AssembleError::InvalidOperand)?;
+
+  if let Some(opcode) = find_opcode(name, AddressMode::Relative) {
+    let offset = value as i32 - (addr as i32 + 2);
+    if offset < -128 || offset > 127 {
+      return Err(AssembleError::BranchOutOfRange);
+    }
+    return Ok(vec![opcode, offset as u8]);
+  }
+  for &mode in modes.iter() {
+    let length = operand_length(mode);
+    if length == 1 && wide {
+      continue;
+    }
+    if let Some(opcode) = find_opcode(name, mode) {
+      if length == 1 {
+        return Ok(vec![opcode, value as u8]);
+      }
+      return Ok(vec![opcode, (value & 0xff) as u8, (value >> 8) as u8]);
+    }
+  }
+  return Err(AssembleError::InvalidOperand);
+}
+
+fn find_opcode_any(name: &str) -> Option<u8> {
+  INSTRUCTIONS.iter().position(|&(n, _)| n == name).map(|opcode| opcode as u8)
+}
+
+#[cfg(test)]
+mod tests {
+  use cpu::CPU;
+  use instructions::{AssembleError, assemble, disassemble, disassemble_with_symbols, trace_line};
+  use symbols::SymbolTable;
+
+  fn disassemble_bytes(addr: u16, bytes: &[u8]) -> String {
+    disassemble(addr, |a| bytes[(a - addr) as usize]).1
+  }
+
+  #[test]
+  fn disassembly() {
+    assert_eq!(disassemble_bytes(0x1000, &[0xa9, 0x00]), "LDA #$00");
+    assert_eq!(disassemble_bytes(0x1000, &[0xbd, 0x34, 0x12]), "LDA $1234,X");
+    assert_eq!(disassemble_bytes(0x1000, &[0xb1, 0xfb]), "LDA ($FB),Y");
+    assert_eq!(disassemble_bytes(0x1000, &[0x6c, 0xfc, 0xff]), "JMP ($FFFC)");
+    assert_eq!(disassemble_bytes(0x1000, &[0xd0, 0xfe]), "BNE $1000");
+    assert_eq!(disassemble_bytes(0x1000, &[0xa7, 0x02]), "LAX $02");
+  }
+
+  #[test]
+  fn labels() {
+    let mut symbols = SymbolTable::new();
+    symbols.add("loop", 0x1000);
+    symbols.add("ptr", 0xfb);
+    let code = [0xd0, 0xfe, 0xb1, 0xfb];
+    let read = |a: u16| code[(a - 0x1000) as usize];
+    assert_eq!(disassemble_with_symbols(0x1000, read, &symbols).1, "BNE loop");
+    assert_eq!(disassemble_with_symbols(0x1002, read, &symbols).1, "LDA (ptr),Y");
+    let mut cpu = CPU::new();
+    cpu.pc = 0x1000;
+    cpu.stack = 0xfd;
+    assert_eq!(
+      trace_line(&cpu, read, &symbols),
+      "loop:\n1000  D0 FE      BNE loop                  A:00 X:00 Y:00 P:00 SP:FD",
+    );
+  }
+
+  #[test]
+  fn assembly() {
+    assert_eq!(assemble("lda #$00", 0x1000), Ok(vec![0xa9, 0x00]));
+    assert_eq!(assemble("sta d020", 0x1000), Ok(vec![0x8d, 0x20, 0xd0]));
+    assert_eq!(assemble("lda 02", 0x1000), Ok(vec![0xa5, 0x02]));
+    assert_eq!(assemble("lda $0002", 0x1000), Ok(vec![0xad, 0x02, 0x00]));
+    assert_eq!(assemble("lda $02,y", 0x1000), Ok(vec![0xb9, 0x02, 0x00]));
+    assert_eq!(assemble("lda #+10", 0x1000), Ok(vec![0xa9, 0x0a]));
+    assert_eq!(assemble("asl", 0x1000), Ok(vec![0x0a]));
+    assert_eq!(assemble("nop", 0x1000), Ok(vec![0xea]));
+    assert_eq!(assemble("bne $1000", 0x1000), Ok(vec![0xd0, 0xfe]));
+    assert_eq!(assemble("bne $2000", 0x1000), Err(AssembleError::BranchOutOfRange));
+    assert_eq!(assemble("lda ($fb,x)", 0x1000), Ok(vec![0xa1, 0xfb]));
+    assert_eq!(assemble("foo", 0x1000), Err(AssembleError::UnknownInstruction));
+    assert_eq!(assemble("rts #$00", 0x1000), Err(AssembleError::InvalidOperand));
+
+    // Everything the disassembler prints assembles back to the same thing
+    for opcode in 0..0x100 {
+      let bytes = [opcode as u8, 0x34, 0x12];
+      let text = disassemble_bytes(0x1000, &bytes);
+      let assembled = assemble(&text, 0x1000).unwrap();
+      assert_eq!(disassemble_bytes(0x1000, &assembled), text);
+    }
+  }
+}
\ No newline at end of file
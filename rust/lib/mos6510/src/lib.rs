@@ -4,4 +4,5 @@ pub mod flags;
 pub mod instructions;
 pub mod interrupts;
 pub mod memory;
-pub mod ops;
\ No newline at end of file
+pub mod ops;
+pub mod symbols;
\ No newline at end of file
@@ -0,0 +1,258 @@
+// Labels for addresses, loaded from the symbol files assemblers and other
+// emulators write, so debugging output can show names instead of numbers.
+// Three formats are understood:
+//   VICE label files, with lines like "al C:080d .main"
+//   ca65/ld65 debug files (.dbg), which list symbols as
+//     "sym id=0,name="main",...,val=0x80d,...,type=lab"
+//   FCEUX name lists (.nl), with lines like "$C000#Reset#comment"
+
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, PartialEq)]
+pub enum SymbolError {
+  UnknownFormat,
+  // The line number, counting from 1, of a line that couldn't be read
+  InvalidLine(usize),
+}
+
+pub struct SymbolTable {
+  labels: BTreeMap<u16, String>,
+  addresses: HashMap<String, u16>,
+}
+
+impl SymbolTable {
+  pub fn new() -> SymbolTable {
+    return SymbolTable {
+      labels: BTreeMap::new(),
+      addresses: HashMap::new(),
+    };
+  }
+
+  pub fn len(&self) -> usize {
+    self.addresses.len()
+  }
+
+  /**
+   * Add a label, or move an existing one to a new address. An address with
+   * several labels shows the first one added.
+   */
+  pub fn add(&mut self, name: &str, addr: u16) {
+    if let Some(old) = self.addresses.insert(String::from(name), addr) {
+      if old != addr && self.labels.get(&old).map_or(false, |label| label == name) {
+        self.labels.remove(&old);
+        // Show one of the address's other labels, if it has any
+        let other = self.addresses.iter()
+          .filter(|&(_, &a)| a == old)
+          .map(|(other, _)| other)
+          .min()
+          .cloned();
+        if let Some(other) = other {
+          self.labels.insert(old, other);
+        }
+      }
+    }
+    self.labels.entry(addr).or_insert_with(|| String::from(name));
+  }
+
+  pub fn clear(&mut self) {
+    self.labels.clear();
+    self.addresses.clear();
+  }
+
+  pub fn label(&self, addr: u16) -> Option<&str> {
+    self.labels.get(&addr).map(|name| &name[..])
+  }
+
+  pub fn address(&self, name: &str) -> Option<u16> {
+    self.addresses.get(name).cloned()
+  }
+
+  /**
+   * Every label in address order
+   */
+  pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+    self.labels.iter().map(|(&addr, name)| (addr, &name[..]))
+  }
+
+  /**
+   * Load symbols from a file in any of the supported formats, returning how
+   * many were added.
+   */
+  pub fn load(&mut self, text: &str) -> Result<usize, SymbolError> {
+    let first = text.lines().map(|line| line.trim()).find(|line| line.len() > 0).unwrap_or("");
+    if first.starts_with("version") && first.contains("major=") {
+      return self.load_ca65(text);
+    }
+    if first.starts_with("al ") {
+      return self.load_vice(text);
+    }
+    if first.starts_with('$') || first.starts_with('#') {
+      return self.load_fceux(text);
+    }
+    return Err(SymbolError::UnknownFormat);
+  }
+
+  pub fn load_vice(&mut self, text: &str) -> Result<usize, SymbolError> {
+    let mut symbols = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+      let words: Vec<&str> = line.split_whitespace().collect();
+      if words.len() == 0 {
+        continue;
+      }
+      if words.len() != 3 || words[0] != "al" {
+        return Err(SymbolError::InvalidLine(index + 1));
+      }
+      let addr = words[1].trim_start_matches("C:").trim_start_matches("c:");
+      let addr = u16::from_str_radix(addr, 16).map_err(|_| SymbolError::InvalidLine(index + 1))?;
+      symbols.push((words[2].trim_start_matches('.'), addr));
+    }
+    return Ok(self.add_all(&symbols));
+  }
+
+  /**
+   * Only symbols of type lab are loaded. Equates are usually constants
+   * rather than addresses.
+   */
+  pub fn load_ca65(&mut self, text: &str) -> Result<usize, SymbolError> {
+    let mut symbols = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+      if !line.starts_with("sym") {
+        continue;
+      }
+      let mut name = None;
+      let mut value = None;
+      let mut label = false;
+      for field in line[3..].trim().split(',') {
+        let mut parts = field.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+          (Some("name"), Some(v)) => name = Some(v.trim_matches('"')),
+          (Some("val"), Some(v)) => value = Some(v),
+          (Some("type"), Some(v)) => label = v == "lab",
+          _ => (),
+        }
+      }
+      if !label {
+        continue;
+      }
+      let value = value.and_then(|v| {
+        if v.starts_with("0x") {
+          u32::from_str_radix(&v[2..], 16).ok()
+        } else {
+          v.parse::<u32>().ok()
+        }
+      });
+      match (name, value) {
+        (Some(name), Some(value)) if value <= 0xffff => symbols.push((name, value as u16)),
+        _ => return Err(SymbolError::InvalidLine(index + 1)),
+      }
+    }
+    return Ok(self.add_all(&symbols));
+  }
+
+  /**
+   * FCEUX keeps a name list for RAM and for each PRG bank. The bank isn't
+   * recorded in the file, so loading several lists for banks at the same
+   * address gives each address the first name it was given.
+   */
+  pub fn load_fceux(&mut self, text: &str) -> Result<usize, SymbolError> {
+    let mut symbols = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.len() == 0 {
+        continue;
+      }
+      let mut fields = line.splitn(3, '#');
+      let addr = fields.next().unwrap_or("");
+      let name = fields.next().unwrap_or("");
+      if !addr.starts_with('$') || name.len() == 0 {
+        return Err(SymbolError::InvalidLine(index + 1));
+      }
+      // An array is written as $0200/10, giving its size
+      let addr = addr[1..].split('/').next().unwrap_or("");
+      let addr = u16::from_str_radix(addr, 16).map_err(|_| SymbolError::InvalidLine(index + 1))?;
+      symbols.push((name, addr));
+    }
+    return Ok(self.add_all(&symbols));
+  }
+
+  // The loaders parse the whole file before adding anything, so a file with
+  // an invalid line leaves the table as it was
+  fn add_all(&mut self, symbols: &[(&str, u16)]) -> usize {
+    for &(name, addr) in symbols {
+      self.add(name, addr);
+    }
+    return symbols.len();
+  }
+
+  /**
+   * Write the table as a VICE label file
+   */
+  pub fn to_vice(&self) -> String {
+    let mut entries: Vec<(&String, &u16)> = self.addresses.iter().collect();
+    entries.sort_by_key(|&(name, &addr)| (addr, name.clone()));
+    let mut text = String::new();
+    for (name, addr) in entries {
+      text.push_str(&format!("al C:{:04x} .{}\n", addr, name));
+    }
+    return text;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use symbols::{SymbolTable, SymbolError};
+
+  #[test]
+  fn file_formats() {
+    let mut symbols = SymbolTable::new();
+    assert_eq!(symbols.load("al C:080d .main\nal C:0820 .main_loop\n\n"), Ok(2));
+    assert_eq!(symbols.address("main_loop"), Some(0x0820));
+    assert_eq!(symbols.label(0x080d), Some("main"));
+
+    let dbg = concat!(
+      "version\tmajor=2,minor=0\n",
+      "seg\tid=0,name=\"CODE\",start=0x000810,size=0x0062,addrsize=absolute,type=ro\n",
+      "sym\tid=0,name=\"irq\",addrsize=absolute,scope=0,def=3,ref=7,val=0xC000,seg=0,type=lab\n",
+      "sym\tid=1,name=\"COUNT\",addrsize=zeropage,scope=0,def=1,val=0x10,type=equ\n",
+    );
+    assert_eq!(symbols.load(dbg), Ok(1));
+    assert_eq!(symbols.address("irq"), Some(0xc000));
+    assert_eq!(symbols.address("COUNT"), None);
+
+    assert_eq!(symbols.load("$8000#Reset#Start here\n$0200/100#OAM#\n"), Ok(2));
+    assert_eq!(symbols.label(0x0200), Some("OAM"));
+    assert_eq!(symbols.len(), 5);
+
+    assert_eq!(symbols.load("hello"), Err(SymbolError::UnknownFormat));
+    assert_eq!(symbols.load_vice("al C:080d .main\nal zz .x"), Err(SymbolError::InvalidLine(2)));
+  }
+
+  #[test]
+  fn invalid_files_add_nothing() {
+    let mut symbols = SymbolTable::new();
+    assert_eq!(symbols.load("al C:1000 .start\nal C:2000\n"), Err(SymbolError::InvalidLine(2)));
+    let dbg = concat!(
+      "version\tmajor=2,minor=0\n",
+      "sym\tid=0,name=\"irq\",val=0xC000,type=lab\n",
+      "sym\tid=1,name=\"far\",val=0x10000,type=lab\n",
+    );
+    assert_eq!(symbols.load(dbg), Err(SymbolError::InvalidLine(3)));
+    assert_eq!(symbols.load("$8000#Reset#\n8000#Reset#\n"), Err(SymbolError::InvalidLine(2)));
+    assert_eq!(symbols.len(), 0);
+    assert_eq!(symbols.iter().count(), 0);
+  }
+
+  #[test]
+  fn moving_a_label() {
+    let mut symbols = SymbolTable::new();
+    symbols.add("start", 0x1000);
+    symbols.add("init", 0x1000);
+    symbols.add("start", 0x2000);
+    assert_eq!(symbols.address("start"), Some(0x2000));
+    assert_eq!(symbols.label(0x2000), Some("start"));
+    assert_eq!(symbols.label(0x1000), Some("init"));
+    symbols.add("init", 0x3000);
+    assert_eq!(symbols.label(0x1000), None);
+    assert_eq!(symbols.iter().count(), 2);
+  }
+}
@@ -1,6 +1,9 @@
+// An APU that plays through emu-audio channels, approximating each of the
+// NES's tone generators with one of its waveforms.
+
+use crate::apu::APU;
 use emuaudio::EmuAudio;
 use emuaudio::channels::{ChannelID, ChannelType};
-use nesmemmap::apu::APU;
 
 const NTSC_CLOCK: f32 = 1789773.0;
 
@@ -27,9 +30,11 @@ pub struct APUImpl {
 }
 
 impl APUImpl {
-  pub fn new() -> APUImpl {
-    let mut audio = EmuAudio::new();
-    audio.start();
+  /**
+   * Channels are added to `audio`, which should already be started if it
+   * plays through a device.
+   */
+  pub fn new(mut audio: EmuAudio) -> APUImpl {
     let square_channel_0 = audio.add_channel(ChannelType::Square);
     let square_channel_1 = audio.add_channel(ChannelType::Square);
     let triangle_channel = audio.add_channel(ChannelType::Triangle);
@@ -1,9 +1,11 @@
 #![feature(box_syntax)]
 
 pub mod apu;
+pub mod audio;
 pub mod controller;
 pub mod mapper;
 pub mod memmap;
+pub mod palette;
 pub mod ppu;
 pub mod ppu2;
 pub mod ram;
@@ -1,5 +1,10 @@
 use crate::mapper::mapper::{ChrMem, Config, Mapper};
 
+// The most 16KB PRG banks and 8KB CHR banks a cartridge can have, 256KB and
+// 128KB
+pub const MAX_PRG_BANKS: u8 = 16;
+pub const MAX_CHR_BANKS: u8 = 16;
+
 pub struct MMC1 {
   shifter: u8,
 
@@ -20,9 +25,8 @@ impl MMC1 {
     let chr = if config.chr_rom_size == 0 {
       ChrMem::Ram(box [0; 0x2000])
     } else {
-      let size = (config.chr_rom_size as usize) * 16 * 1024;
-      let mem = Vec::with_capacity(size);
-      ChrMem::Rom(mem.into_boxed_slice())
+      let size = (config.chr_rom_size as usize) * 0x2000;
+      ChrMem::Rom(vec![0; size].into_boxed_slice())
     };
     MMC1 {
       shifter: 0x10,
@@ -160,6 +164,8 @@ impl Mapper for MMC1 {
         mem[addr as usize]
       },
       ChrMem::Rom(mem) => {
+        // The registers select 4KB banks, wrapping around the ROM
+        let bank_mask = (mem.len() / 0x1000) as u32 - 1;
         let separate_bank_mode = self.register_control & 0x10 != 0;
         if addr < 0x1000 {
           // CHR 0
@@ -167,7 +173,7 @@ impl Mapper for MMC1 {
           if !separate_bank_mode {
             bank = bank & 0x1e;
           }
-          return mem[(addr as u32 + bank * 0x2000) as usize];
+          return mem[(addr as u32 + (bank & bank_mask) * 0x1000) as usize];
         }
         if addr < 0x2000 {
           // CHR 1
@@ -175,7 +181,7 @@ impl Mapper for MMC1 {
           if !separate_bank_mode {
             bank = (self.register_chr0 as u32 & 0x1e) | 1;
           }
-          return mem[((addr as u32 & 0xfff) + bank * 0x2000) as usize];
+          return mem[((addr as u32 & 0xfff) + (bank & bank_mask) * 0x1000) as usize];
         }
         // invalid
         return 0;
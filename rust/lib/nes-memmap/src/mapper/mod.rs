@@ -4,11 +4,22 @@ mod nrom;
 
 pub use self::mapper::Mapper;
 
+#[derive(Debug, PartialEq)]
+pub enum MapperError {
+  InvalidHeader,
+  UnsupportedMapper(u8),
+  // The file is shorter than the ROM sizes in its header
+  Truncated,
+}
+
 // Create a Mapper instance from an iNes ROM
-pub fn create_mapper(rom: &Vec<u8>) -> Box<Mapper> {
+pub fn create_mapper(rom: &[u8]) -> Result<Box<Mapper>, MapperError> {
+  if rom.len() < 16 {
+    return Err(MapperError::InvalidHeader);
+  }
   let header = &rom[0..16];
   if header[0] != 0x4e || header[1] != 0x45 || header[2] != 0x53 || header[3] != 0x1a {
-    panic!("Invalid ROM header");
+    return Err(MapperError::InvalidHeader);
   }
 
   let config = mapper::Config {
@@ -21,16 +32,28 @@ pub fn create_mapper(rom: &Vec<u8>) -> Box<Mapper> {
   let mapper_high = header[7] & 0xf0;
   let mapper_id = mapper_low | mapper_high;
 
+  let (max_prg_banks, max_chr_banks) = match mapper_id {
+    0x00 => (nrom::MAX_PRG_BANKS, nrom::MAX_CHR_BANKS),
+    0x01 => (mmc1::MAX_PRG_BANKS, mmc1::MAX_CHR_BANKS),
+    _ => return Err(MapperError::UnsupportedMapper(mapper_id)),
+  };
+  // The mappers' buffers only hold so many banks
+  if config.prg_rom_size == 0 || config.prg_rom_size > max_prg_banks || config.chr_rom_size > max_chr_banks {
+    return Err(MapperError::InvalidHeader);
+  }
+
   let mut mapper: Box<Mapper> = match mapper_id {
     0x00 => Box::new(nrom::NROM::new(config)),
-    0x01 => Box::new(mmc1::MMC1::new(config)),
-    _ => panic!("Unsupported Mapper ID"),
+    _ => Box::new(mmc1::MMC1::new(config)),
   };
 
   let prg_start = 16;
   let prg_end = 16 + 0x4000 * header[4] as usize;
   let chr_end = prg_end + 0x2000 * header[5] as usize;
+  if rom.len() < chr_end {
+    return Err(MapperError::Truncated);
+  }
   mapper.set_prg_rom(&rom[prg_start..prg_end]);
   mapper.set_chr_rom(&rom[prg_end..chr_end]);
-  mapper
+  Ok(mapper)
 }
\ No newline at end of file
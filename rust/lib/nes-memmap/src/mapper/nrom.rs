@@ -1,5 +1,9 @@
 use crate::mapper::mapper::{ChrMem, Config, Mapper, Mirroring};
 
+// The most 16KB PRG banks and 8KB CHR banks a cartridge can have
+pub const MAX_PRG_BANKS: u8 = 2;
+pub const MAX_CHR_BANKS: u8 = 1;
+
 pub struct NROM {
   prg_ram: Box<[u8; 0x2000]>,
   prg_rom: Box<[u8; 0x8000]>,
@@ -13,6 +13,7 @@ pub struct MemMap {
   pub ram: RAM,
   pub mapper: Box<Mapper>,
   pub controller_0: Controller,
+  pub controller_1: Controller,
 
   needs_dma: bool,
   pub dma_source: u16,
@@ -33,6 +34,9 @@ impl Memory for MemMap {
       if addr == 0x4016 {
         return self.controller_0.read_latch();
       }
+      if addr == 0x4017 {
+        return self.controller_1.read_latch();
+      }
       let dest = addr - 0x4000;
       //return self.apu.get_byte(dest);
       return 0;
@@ -119,8 +123,10 @@ impl Memory for MemMap {
       if addr == 0x4016 {
         if value & 1 == 1 {
           self.controller_0.begin_latch();
+          self.controller_1.begin_latch();
         } else {
           self.controller_0.end_latch();
+          self.controller_1.end_latch();
         }
         return;
       }
@@ -143,6 +149,7 @@ impl MemMap {
     let mut map = MemMap {
       apu: apu,
       controller_0: Controller::new(),
+      controller_1: Controller::new(),
       ppu: PPU::new(),
       ppu2: PPU2::new(),
       ram: RAM::new(),
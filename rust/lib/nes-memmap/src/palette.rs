@@ -66,4 +66,16 @@ pub const COLORS: [u8; 4 * 16 * 4] = [
   160, 162, 160, 255,
   0, 0, 0, 255,
   0, 0, 0, 255,
-];
\ No newline at end of file
+];
+
+/**
+ * Convert a frame of PPU color indexes, from 0 to 63, to RGBA pixels
+ */
+pub fn to_rgba(indexed: &[u8], rgba: &mut Vec<u8>) {
+  rgba.clear();
+  rgba.reserve(indexed.len() * 4);
+  for &index in indexed {
+    let offset = (index & 0x3f) as usize * 4;
+    rgba.extend_from_slice(&COLORS[offset..offset + 4]);
+  }
+}
\ No newline at end of file
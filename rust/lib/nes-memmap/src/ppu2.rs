@@ -580,6 +580,14 @@ impl PPU2 {
     &self.buffer[0] as *const u8
   }
 
+  /**
+   * The picture drawn so far, 256x240 color indexes. It is complete from the
+   * start of vblank until rendering starts again.
+   */
+  pub fn buffer(&self) -> &[u8] {
+    &self.buffer[..]
+  }
+
   pub fn in_vblank(&self) -> bool {
     self.scanline >= 241
   }
@@ -0,0 +1,126 @@
+// The NES's wasm exports, in the same shape as the C64's: each takes a handle
+// from create_vm, and abi.json lists them all along with their errors.
+
+pub mod vm;
+
+use crate::vm::VM;
+use crate::vm::{FRAME_WIDTH, FRAME_HEIGHT};
+use std::mem;
+use wasmabi::wasm_exports;
+use wasmabi::Handle;
+use wasmabi::{OK, ERROR_INVALID_DATA, ERROR_INVALID_ARGUMENT, ERROR_NO_ROM};
+
+// The largest file JS can copy in, well past the largest cartridge
+const MAX_FILE_SIZE: u32 = 4 * 1024 * 1024;
+
+wasm_exports!(VM, "abi.json", 1, MAX_FILE_SIZE);
+
+fn status(loaded: bool) -> i32 {
+  if loaded { OK } else { ERROR_NO_ROM }
+}
+
+/**
+ * Create a machine with no cartridge, mixing its audio at `sample_rate`.
+ * Returns its handle, or 0 if the rate is 0.
+ */
+#[no_mangle]
+pub fn create_vm(sample_rate: u32) -> Handle {
+  if sample_rate == 0 {
+    return 0;
+  }
+  return VMS.with(|vms| vms.borrow_mut().insert(VM::new(sample_rate)));
+}
+
+/**
+ * Plug in the iNES cartridge in the file buffer, and switch on. Fails with
+ * ERROR_INVALID_DATA if the image is invalid or uses an unsupported mapper.
+ */
+#[no_mangle]
+pub fn load_rom(handle: Handle) -> i32 {
+  call(handle, |vm| {
+    let rom = mem::replace(&mut vm.file_buffer, Vec::new());
+    match vm.load_rom(&rom) {
+      Ok(()) => OK,
+      Err(_) => ERROR_INVALID_DATA,
+    }
+  })
+}
+
+#[no_mangle]
+pub fn reset(handle: Handle) -> i32 {
+  call(handle, |vm| status(vm.reset()))
+}
+
+/**
+ * Run the machine until it completes a frame. Call it once per frame of the
+ * display, and read the picture and sound it made.
+ */
+#[no_mangle]
+pub fn run_frame(handle: Handle) -> i32 {
+  call(handle, |vm| status(vm.run_frame()))
+}
+
+/**
+ * How many frames the machine has completed, which wraps around
+ */
+#[no_mangle]
+pub fn get_frame_count(handle: Handle) -> u32 {
+  with_vm(handle, |vm| vm.frame_count()).unwrap_or(0)
+}
+
+#[no_mangle]
+pub fn get_frame_pointer(handle: Handle) -> *const u8 {
+  pointer(handle, |vm| vm.frame().as_ptr())
+}
+
+/**
+ * Convert the last complete frame to RGBA, 4 bytes per pixel, and return
+ * where it is. It stays valid until the next call.
+ */
+#[no_mangle]
+pub fn get_rgba_frame_pointer(handle: Handle) -> *const u8 {
+  pointer(handle, |vm| vm.render_rgba().as_ptr())
+}
+
+#[no_mangle]
+pub fn get_frame_width() -> u32 {
+  FRAME_WIDTH as u32
+}
+
+#[no_mangle]
+pub fn get_frame_height() -> u32 {
+  FRAME_HEIGHT as u32
+}
+
+/**
+ * The audio mixed since the buffer was last cleared, as mono f32 samples at
+ * the rate given to create_vm
+ */
+#[no_mangle]
+pub fn get_audio_buffer_pointer(handle: Handle) -> *const f32 {
+  pointer(handle, |vm| vm.samples().as_ptr())
+}
+
+#[no_mangle]
+pub fn get_audio_buffer_length(handle: Handle) -> i32 {
+  call(handle, |vm| vm.samples().len() as i32)
+}
+
+#[no_mangle]
+pub fn clear_audio_buffer(handle: Handle) -> i32 {
+  call(handle, |vm| { vm.clear_samples(); OK })
+}
+
+/**
+ * Set the buttons held on controller 0 or 1, one bit each: A, B, Select,
+ * Start, Up, Down, Left and Right from bit 0 up.
+ */
+#[no_mangle]
+pub fn set_controller(handle: Handle, port: u32, buttons: u32) -> i32 {
+  call(handle, |vm| {
+    if port > 1 || buttons > 0xff {
+      return ERROR_INVALID_ARGUMENT;
+    }
+    status(vm.set_controller(port as usize, buttons as u8))
+  })
+}
@@ -0,0 +1,36 @@
+// The machine itself lives in the nesvm crate, where the native front-end
+// shares it. The wasm build adds the buffer that ROMs are copied through from
+// JS, and otherwise hands everything to the machine.
+
+use std::ops::{Deref, DerefMut};
+use nesvm::vm::VM as Machine;
+pub use nesvm::vm::{FRAME_WIDTH, FRAME_HEIGHT};
+
+pub struct VM {
+  pub machine: Machine,
+  // Files are copied here from JS before being handed to the VM
+  pub file_buffer: Vec<u8>,
+}
+
+impl VM {
+  pub fn new(sample_rate: u32) -> VM {
+    return VM {
+      machine: Machine::new(sample_rate),
+      file_buffer: Vec::new(),
+    };
+  }
+}
+
+impl Deref for VM {
+  type Target = Machine;
+
+  fn deref(&self) -> &Machine {
+    &self.machine
+  }
+}
+
+impl DerefMut for VM {
+  fn deref_mut(&mut self) -> &mut Machine {
+    &mut self.machine
+  }
+}
@@ -0,0 +1,288 @@
+// A NES for hosts that draw the picture and play the sound themselves, shared
+// by the native and wasm front-ends. It runs a frame at a time, keeping the
+// last complete picture and the audio mixed while the frame ran.
+
+use emuaudio::EmuAudio;
+use mos6510::cpu::CPU;
+use mos6510::instructions::trace_line;
+use mos6510::memory::Memory;
+use mos6510::symbols::SymbolTable;
+use nesmemmap::audio::APUImpl;
+use nesmemmap::controller::Controller;
+use nesmemmap::mapper::{self, MapperError};
+use nesmemmap::memmap::MemMap;
+use nesmemmap::palette;
+use std::io::Write;
+
+pub const FRAME_WIDTH: usize = 256;
+pub const FRAME_HEIGHT: usize = 240;
+
+const FRAMES_PER_SECOND: f32 = 60.0988;
+
+/**
+ * A console with a cartridge plugged in
+ */
+pub struct Console {
+  pub cpu: CPU,
+  pub mem: MemMap,
+  // Shares its channels with the APU, and mixes them on demand
+  audio: EmuAudio,
+}
+
+pub struct VM {
+  pub console: Option<Console>,
+  pub symbols: SymbolTable,
+  // Each instruction is written here before it runs
+  pub trace: Option<Box<dyn Write>>,
+
+  frame: Vec<u8>,
+  rgba_frame: Vec<u8>,
+  frame_count: u32,
+
+  sample_rate: u32,
+  sample_time: f32,
+  samples: Vec<f32>,
+}
+
+impl VM {
+  pub fn new(sample_rate: u32) -> VM {
+    VM {
+      console: None,
+      symbols: SymbolTable::new(),
+      trace: None,
+
+      frame: vec![0; FRAME_WIDTH * FRAME_HEIGHT],
+      rgba_frame: Vec::new(),
+      frame_count: 0,
+
+      sample_rate: sample_rate,
+      sample_time: 0.0,
+      samples: Vec::new(),
+    }
+  }
+
+  /**
+   * Plug in an iNES cartridge, replacing any before it, and switch on.
+   */
+  pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), MapperError> {
+    let mapper = mapper::create_mapper(rom)?;
+    let audio = EmuAudio::offline(self.sample_rate);
+    let apu = Box::new(APUImpl::new(audio.clone()));
+    let mut console = Console {
+      cpu: CPU::new(),
+      mem: MemMap::new(mapper, apu),
+      audio: audio,
+    };
+    console.cpu.reset(&mut console.mem);
+    self.console = Some(console);
+    for pixel in self.frame.iter_mut() {
+      *pixel = 0;
+    }
+    self.samples.clear();
+    return Ok(());
+  }
+
+  /**
+   * Press the reset button. Returns false if there is no cartridge.
+   */
+  pub fn reset(&mut self) -> bool {
+    match self.console {
+      Some(ref mut console) => {
+        console.cpu.reset(&mut console.mem);
+        true
+      },
+      None => false,
+    }
+  }
+
+  /**
+   * Run until the PPU enters vblank, when its picture is complete. Returns
+   * false if there is no cartridge.
+   */
+  pub fn run_frame(&mut self) -> bool {
+    let console = match self.console {
+      Some(ref mut console) => console,
+      None => return false,
+    };
+    let mut in_vblank = console.mem.ppu2.in_vblank();
+    let mut finished = false;
+    while !finished {
+      if self.trace.is_some() {
+        trace_instruction(console, &self.symbols, &mut self.trace);
+      }
+      let mut cycles = console.cpu.step(&mut console.mem) as u32;
+      if console.mem.dma_requested() {
+        console.mem.dma_copy();
+        cycles += 514;
+      }
+      for _ in 0..(cycles * 3) {
+        console.mem.increment_clock();
+        let now = console.mem.ppu2.in_vblank();
+        if now && !in_vblank {
+          finished = true;
+        }
+        in_vblank = now;
+      }
+      if console.mem.ppu2.should_interrupt() {
+        console.cpu.nonmaskable_interrupt(&mut console.mem);
+      }
+    }
+    self.frame.copy_from_slice(console.mem.ppu2.buffer());
+    self.frame_count = self.frame_count.wrapping_add(1);
+
+    self.sample_time += self.sample_rate as f32 / FRAMES_PER_SECOND;
+    let count = self.sample_time as usize;
+    self.sample_time -= count as f32;
+    // Drop samples if the host has stopped collecting them
+    if self.samples.len() < self.sample_rate as usize {
+      console.audio.render_samples(count, &mut self.samples);
+    }
+    return true;
+  }
+
+  /**
+   * The last complete frame, with one byte per pixel giving its color index
+   * from 0 to 63
+   */
+  pub fn frame(&self) -> &[u8] {
+    &self.frame
+  }
+
+  pub fn frame_count(&self) -> u32 {
+    self.frame_count
+  }
+
+  /**
+   * Convert the last complete frame to RGBA, 4 bytes per pixel
+   */
+  pub fn render_rgba(&mut self) -> &[u8] {
+    palette::to_rgba(&self.frame, &mut self.rgba_frame);
+    &self.rgba_frame
+  }
+
+  pub fn samples(&self) -> &[f32] {
+    &self.samples
+  }
+
+  pub fn clear_samples(&mut self) {
+    self.samples.clear();
+  }
+
+  /**
+   * Set the buttons held on controller 0 or 1, one bit each in the order the
+   * NES reads them: A, B, Select, Start, Up, Down, Left and Right. Returns
+   * false for any other port, or if there is no cartridge.
+   */
+  pub fn set_controller(&mut self, port: usize, buttons: u8) -> bool {
+    let console = match self.console {
+      Some(ref mut console) => console,
+      None => return false,
+    };
+    let controller: &mut Controller = match port {
+      0 => &mut console.mem.controller_0,
+      1 => &mut console.mem.controller_1,
+      _ => return false,
+    };
+    controller.a = buttons & 0x01 != 0;
+    controller.b = buttons & 0x02 != 0;
+    controller.select = buttons & 0x04 != 0;
+    controller.start = buttons & 0x08 != 0;
+    controller.up = buttons & 0x10 != 0;
+    controller.down = buttons & 0x20 != 0;
+    controller.left = buttons & 0x40 != 0;
+    controller.right = buttons & 0x80 != 0;
+    return true;
+  }
+}
+
+// Code runs from RAM and cartridge space, so reading the instruction again
+// doesn't disturb any registers
+fn trace_instruction(console: &mut Console, symbols: &SymbolTable, trace: &mut Option<Box<dyn Write>>) {
+  let mem = &mut console.mem;
+  let line = trace_line(&console.cpu, |addr| mem.get_byte(addr), symbols);
+  let failed = match trace {
+    Some(ref mut trace) => writeln!(trace, "{}", line).is_err(),
+    None => false,
+  };
+  if failed {
+    eprintln!("Unable to write the trace log, tracing stopped");
+    *trace = None;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::vm::{VM, FRAME_WIDTH, FRAME_HEIGHT};
+  use nesmemmap::mapper::MapperError;
+
+  // An NROM cartridge whose program turns on NMIs and the background, then
+  // spins. The NMI handler counts frames at $00.
+  fn test_rom() -> Vec<u8> {
+    let mut rom = vec![0x4e, 0x45, 0x53, 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    let mut prg = vec![0xea; 0x4000];
+    let program = [
+      0xa9, 0x80, 0x8d, 0x00, 0x20, // LDA #$80, STA $2000
+      0xa9, 0x08, 0x8d, 0x01, 0x20, // LDA #$08, STA $2001
+      0x4c, 0x0a, 0xc0, // JMP *
+      0xe6, 0x00, 0x40, // NMI: INC $00, RTI
+    ];
+    prg[..program.len()].copy_from_slice(&program);
+    prg[0x3ffa..].copy_from_slice(&[0x0d, 0xc0, 0x00, 0xc0, 0x0d, 0xc0]);
+    rom.extend(prg);
+    rom.extend(vec![0; 0x2000]);
+    return rom;
+  }
+
+  #[test]
+  fn frames() {
+    let mut vm = VM::new(44100);
+    assert!(!vm.run_frame());
+    assert!(vm.load_rom(&[0; 16]).is_err());
+    assert!(vm.load_rom(&test_rom()).is_ok());
+
+    for _ in 0..3 {
+      assert!(vm.run_frame());
+    }
+    assert_eq!(vm.frame_count(), 3);
+    assert_eq!(vm.frame().len(), FRAME_WIDTH * FRAME_HEIGHT);
+    assert_eq!(vm.render_rgba().len(), FRAME_WIDTH * FRAME_HEIGHT * 4);
+    let nmis = vm.console.as_mut().unwrap().mem.ram.get_byte(0);
+    assert!(nmis >= 2);
+    // 3 frames of audio at 44.1kHz
+    assert!(vm.samples().len() >= 2200);
+
+    assert!(vm.set_controller(1, 0x09));
+    assert!(!vm.set_controller(2, 0x09));
+  }
+
+  #[test]
+  fn oversized_header() {
+    let mut vm = VM::new(44100);
+    // An NROM cartridge only has room for two PRG banks, and needs one
+    let mut rom = test_rom();
+    rom[4] = 3;
+    rom.extend(vec![0; 0x4000]);
+    assert_eq!(vm.load_rom(&rom), Err(MapperError::InvalidHeader));
+    rom[4] = 0;
+    assert_eq!(vm.load_rom(&rom), Err(MapperError::InvalidHeader));
+    assert!(vm.console.is_none());
+  }
+
+  #[test]
+  fn chr_rom_banks() {
+    let mut vm = VM::new(44100);
+    // An MMC1 cartridge with one 8KB CHR ROM bank, drawing the background
+    // from its upper 4KB
+    let mut rom = test_rom();
+    rom[4] = 2;
+    rom[6] = 0x10;
+    rom[17] = 0x90;
+    rom.splice(16..16, vec![0xea; 0x4000]);
+    assert!(vm.load_rom(&rom).is_ok());
+    for _ in 0..3 {
+      assert!(vm.run_frame());
+    }
+    let nmis = vm.console.as_mut().unwrap().mem.ram.get_byte(0);
+    assert!(nmis >= 2);
+  }
+}
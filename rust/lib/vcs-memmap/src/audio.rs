@@ -0,0 +1,213 @@
+// The TIA's two sound generators. Each counts down audio clocks, two per
+// scanline, by its frequency register, and every time it runs out it steps
+// the waveform picked by its control register: a square wave of some
+// division, one of the polynomial counters, or a mix of both.
+
+use std::mem;
+
+const COLOR_CLOCK_RATE: f32 = 3579545.0;
+// An audio clock happens every 114 color clocks, twice a scanline
+const COLOR_CLOCKS_PER_AUDIO_CLOCK: u32 = 114;
+
+struct Channel {
+  control: u8,
+  frequency: u8,
+  volume: u8,
+
+  divider: u8,
+  counter: u8,
+  poly4: u8,
+  poly5: u8,
+  poly9: u16,
+  output: bool,
+}
+
+impl Channel {
+  fn new() -> Channel {
+    Channel {
+      control: 0,
+      frequency: 0,
+      volume: 0,
+
+      divider: 0,
+      counter: 0,
+      poly4: 0x0f,
+      poly5: 0x1f,
+      poly9: 0x1ff,
+      output: false,
+    }
+  }
+
+  fn clock(&mut self) {
+    if self.divider > 0 {
+      self.divider -= 1;
+      return;
+    }
+    self.divider = self.frequency;
+
+    let poly5_bit = self.poly5 & 1 != 0;
+    self.poly5 = (self.poly5 >> 1) | (((self.poly5 ^ (self.poly5 >> 2)) & 1) << 4);
+    match self.control & 0x0f {
+      0x0 | 0xb => self.output = true,
+      0x1 => self.step_poly4(),
+      0x2 => {
+        self.counter = (self.counter + 1) % 15;
+        if self.counter == 0 {
+          self.step_poly4();
+        }
+      },
+      0x3 => if poly5_bit { self.step_poly4() },
+      0x4 | 0x5 => self.output = !self.output,
+      0x6 | 0xa => self.divide(31),
+      0x7 => if poly5_bit { self.output = !self.output },
+      0x8 => {
+        self.poly9 = (self.poly9 >> 1) | (((self.poly9 ^ (self.poly9 >> 4)) & 1) << 8);
+        self.output = self.poly9 & 1 != 0;
+      },
+      0x9 => self.output = poly5_bit,
+      0xc | 0xd => self.divide(6),
+      0xe => self.divide(93),
+      _ => if poly5_bit { self.divide(6) },
+    }
+  }
+
+  fn step_poly4(&mut self) {
+    self.poly4 = (self.poly4 >> 1) | (((self.poly4 ^ (self.poly4 >> 1)) & 1) << 3);
+    self.output = self.poly4 & 1 != 0;
+  }
+
+  // A square wave with a period of `period` steps
+  fn divide(&mut self, period: u8) {
+    self.counter = (self.counter + 1) % period;
+    self.output = self.counter < period / 2;
+  }
+
+  fn level(&self) -> f32 {
+    if self.output {
+      self.volume as f32 / 15.0
+    } else {
+      0.0
+    }
+  }
+}
+
+pub struct Audio {
+  channels: [Channel; 2],
+  color_clocks: u32,
+
+  sample_rate: u32,
+  clocks_per_sample: f32,
+  sample_clocks: f32,
+  sample_sum: f32,
+  sample_count: u32,
+  samples: Vec<f32>,
+}
+
+impl Audio {
+  pub fn new() -> Audio {
+    let mut audio = Audio {
+      channels: [Channel::new(), Channel::new()],
+      color_clocks: 0,
+
+      sample_rate: 44100,
+      clocks_per_sample: 1.0,
+      sample_clocks: 0.0,
+      sample_sum: 0.0,
+      sample_count: 0,
+      samples: Vec::new(),
+    };
+    audio.set_sample_rate(44100);
+    return audio;
+  }
+
+  pub fn set_sample_rate(&mut self, sample_rate: u32) {
+    let audio_clock_rate = COLOR_CLOCK_RATE / COLOR_CLOCKS_PER_AUDIO_CLOCK as f32;
+    self.sample_rate = sample_rate;
+    self.clocks_per_sample = audio_clock_rate / sample_rate as f32;
+  }
+
+  /**
+   * Write one of AUDC0 ($15) to AUDV1 ($1A)
+   */
+  pub fn set_byte(&mut self, addr: u16, value: u8) {
+    match addr {
+      0x15 => self.channels[0].control = value & 0x0f,
+      0x16 => self.channels[1].control = value & 0x0f,
+      0x17 => self.channels[0].frequency = value & 0x1f,
+      0x18 => self.channels[1].frequency = value & 0x1f,
+      0x19 => self.channels[0].volume = value & 0x0f,
+      0x1a => self.channels[1].volume = value & 0x0f,
+      _ => (),
+    }
+  }
+
+  pub fn increment_clock(&mut self, color_clocks: u8) {
+    self.color_clocks += color_clocks as u32;
+    while self.color_clocks >= COLOR_CLOCKS_PER_AUDIO_CLOCK {
+      self.color_clocks -= COLOR_CLOCKS_PER_AUDIO_CLOCK;
+      self.audio_clock();
+    }
+  }
+
+  fn audio_clock(&mut self) {
+    self.channels[0].clock();
+    self.channels[1].clock();
+    self.sample_sum += (self.channels[0].level() + self.channels[1].level()) / 2.0;
+    self.sample_count += 1;
+    self.sample_clocks += 1.0;
+    if self.sample_clocks < self.clocks_per_sample {
+      return;
+    }
+    // The output rate is usually higher than the audio clock, so one average
+    // can make more than one sample
+    let average = self.sample_sum / self.sample_count as f32;
+    self.sample_sum = 0.0;
+    self.sample_count = 0;
+    while self.sample_clocks >= self.clocks_per_sample {
+      self.sample_clocks -= self.clocks_per_sample;
+      // Drop samples if the host has stopped collecting them
+      if self.samples.len() < self.sample_rate as usize {
+        self.samples.push(average);
+      }
+    }
+  }
+
+  pub fn take_samples(&mut self) -> Vec<f32> {
+    mem::replace(&mut self.samples, Vec::new())
+  }
+
+  pub fn samples_ptr(&self) -> *const f32 {
+    self.samples.as_ptr()
+  }
+
+  pub fn samples_len(&self) -> usize {
+    self.samples.len()
+  }
+
+  pub fn clear_samples(&mut self) {
+    self.samples.clear();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn pure_tone() {
+    let mut audio = Audio::new();
+    audio.set_byte(0x15, 0x4);
+    audio.set_byte(0x17, 3);
+    audio.set_byte(0x19, 0xf);
+    // Division by 2 toggles the output every 4 audio clocks with AUDF of 3
+    let mut levels = Vec::new();
+    for _ in 0..16 {
+      audio.increment_clock(114);
+      levels.push(audio.channels[0].output);
+    }
+    let toggles = levels.windows(2).filter(|w| w[0] != w[1]).count();
+    assert_eq!(toggles, 3);
+    assert!(audio.samples_len() > 0);
+    assert!(audio.take_samples().iter().any(|&s| s > 0.0));
+  }
+}
@@ -1,3 +1,5 @@
+pub mod audio;
 pub mod memmap;
+pub mod palette;
 pub mod riot;
 pub mod tia;
\ No newline at end of file
@@ -41,7 +41,7 @@ impl Memory for MemMap {
       // For reading, the TIA only uses 4 lines
       let tia_addr = dest & 0xf;
 
-      return 0;
+      return self.tia.get_byte(tia_addr);
     }
     if dest < 0x100 {
       return self.ram[(dest - 0x80) as usize];
@@ -61,6 +61,9 @@ impl Memory for MemMap {
       if dest == 0x280 {
         return self.riot.get_port_a_data();
       }
+      if dest == 0x282 {
+        return self.riot.get_port_b_data();
+      }
       if dest == 0x284 {
         return self.riot.timer_count_remaining();
       }
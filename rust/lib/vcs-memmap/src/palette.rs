@@ -142,4 +142,17 @@ pub const COLORS: [u8; 16 * 8 * 4] = [
   0xd0, 0xb4, 0x6c, 0xff,
   0xe8, 0xcc, 0x7c, 0xff,
   0xfc, 0xe0, 0x8c, 0xff,
-];
\ No newline at end of file
+];
+
+/**
+ * Convert a frame of TIA color values to RGBA pixels. The lowest bit of a
+ * color value isn't used, so there are 128 colors.
+ */
+pub fn to_rgba(indexed: &[u8], rgba: &mut Vec<u8>) {
+  rgba.clear();
+  rgba.reserve(indexed.len() * 4);
+  for &color in indexed {
+    let offset = (color >> 1) as usize * 4;
+    rgba.extend_from_slice(&COLORS[offset..offset + 4]);
+  }
+}
\ No newline at end of file
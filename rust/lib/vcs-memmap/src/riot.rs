@@ -14,6 +14,17 @@ pub struct RIOT {
   pub joystick_0_right: bool,
   pub joystick_0_up: bool,
   pub joystick_0_down: bool,
+  pub joystick_1_left: bool,
+  pub joystick_1_right: bool,
+  pub joystick_1_up: bool,
+  pub joystick_1_down: bool,
+
+  // Console switches, read through port B
+  pub reset_switch: bool,
+  pub select_switch: bool,
+  pub black_and_white: bool,
+  pub difficulty_0_a: bool,
+  pub difficulty_1_a: bool,
 }
 
 impl RIOT {
@@ -27,6 +38,16 @@ impl RIOT {
       joystick_0_right: false,
       joystick_0_up: false,
       joystick_0_down: false,
+      joystick_1_left: false,
+      joystick_1_right: false,
+      joystick_1_up: false,
+      joystick_1_down: false,
+
+      reset_switch: false,
+      select_switch: false,
+      black_and_white: false,
+      difficulty_0_a: false,
+      difficulty_1_a: false,
     }
   }
 
@@ -79,6 +100,18 @@ impl RIOT {
     (if self.joystick_0_right { 0 } else { 0x80 }) |
     (if self.joystick_0_left { 0 } else { 0x40 }) |
     (if self.joystick_0_down { 0 } else { 0x20 }) |
-    (if self.joystick_0_up { 0 } else { 0x10 }) | 0xf
+    (if self.joystick_0_up { 0 } else { 0x10 }) |
+    (if self.joystick_1_right { 0 } else { 0x08 }) |
+    (if self.joystick_1_left { 0 } else { 0x04 }) |
+    (if self.joystick_1_down { 0 } else { 0x02 }) |
+    (if self.joystick_1_up { 0 } else { 0x01 })
+  }
+
+  pub fn get_port_b_data(&self) -> u8 {
+    (if self.difficulty_1_a { 0x80 } else { 0 }) |
+    (if self.difficulty_0_a { 0x40 } else { 0 }) |
+    (if self.black_and_white { 0 } else { 0x08 }) |
+    (if self.select_switch { 0 } else { 0x02 }) |
+    (if self.reset_switch { 0 } else { 0x01 })
   }
 }
\ No newline at end of file
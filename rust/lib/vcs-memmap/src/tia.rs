@@ -1,3 +1,5 @@
+use crate::audio::Audio;
+
 pub enum ScanlineState {
   VSync,
   VBlank,
@@ -49,6 +51,11 @@ pub struct TIA {
   ball_position: u8,
   ball_offset: u8,
   ball_enabled: bool,
+
+  pub audio: Audio,
+  // The fire buttons of the two joysticks, read through INPT4 and INPT5
+  pub fire_0: bool,
+  pub fire_1: bool,
 }
 
 impl TIA {
@@ -91,6 +98,18 @@ impl TIA {
       ball_position: 240,
       ball_offset: 0,
       ball_enabled: false,
+
+      audio: Audio::new(),
+      fire_0: false,
+      fire_1: false,
+    }
+  }
+
+  pub fn get_byte(&self, addr: u16) -> u8 {
+    match addr {
+      0x0c => if self.fire_0 { 0 } else { 0x80 }, // INPT4
+      0x0d => if self.fire_1 { 0 } else { 0x80 }, // INPT5
+      _ => 0,
     }
   }
 
@@ -186,6 +205,9 @@ impl TIA {
         self.ball_position = self.horiz_clock + 9;
       },
 
+      0x15..=0x1a => { // AUDC0, AUDC1, AUDF0, AUDF1, AUDV0, AUDV1
+        self.audio.set_byte(addr, value);
+      },
       0x1b => { // Player 0 Graphics
         self.player_0_graphics = value;
       },
@@ -293,6 +315,7 @@ impl TIA {
   }
 
   pub fn increment_clock(&mut self, cycles: u8) {
+    self.audio.increment_clock(cycles);
     self.horiz_clock += cycles;
     if self.horiz_clock >= 228 {
       self.block_until_hsync = false;
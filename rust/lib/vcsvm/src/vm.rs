@@ -0,0 +1,230 @@
+// An Atari 2600 for hosts that draw the picture and play the sound
+// themselves, shared by the native and wasm front-ends. It runs a frame at a
+// time, from one VSYNC to the next, keeping the picture the TIA drew along
+// the way.
+
+use mos6510::cpu::CPU;
+use vcsmemmap::memmap::MemMap;
+use vcsmemmap::palette;
+use vcsmemmap::tia::{ExecState, ScanlineState};
+
+pub const FRAME_WIDTH: usize = 160;
+pub const FRAME_HEIGHT: usize = 192;
+
+pub struct VM {
+  pub cpu: CPU,
+  pub mem: MemMap,
+  rom_loaded: bool,
+  sample_rate: u32,
+
+  drawing: Vec<u8>,
+  frame: Vec<u8>,
+  rgba_frame: Vec<u8>,
+  frame_count: u32,
+}
+
+impl VM {
+  pub fn new(sample_rate: u32) -> VM {
+    let mut vm = VM {
+      cpu: CPU::new(),
+      mem: MemMap::new(),
+      rom_loaded: false,
+      sample_rate: sample_rate,
+
+      drawing: vec![0; FRAME_WIDTH * FRAME_HEIGHT],
+      frame: vec![0; FRAME_WIDTH * FRAME_HEIGHT],
+      rgba_frame: Vec::new(),
+      frame_count: 0,
+    };
+    vm.mem.tia.audio.set_sample_rate(sample_rate);
+    return vm;
+  }
+
+  /**
+   * Plug in a 2K, 4K or 8K cartridge, replacing any before it, and switch on.
+   * Returns false for any other size.
+   */
+  pub fn load_rom(&mut self, rom: &[u8]) -> bool {
+    let image = match rom.len() {
+      // A 2K cartridge appears twice in the 4K window
+      0x800 => [rom, rom].concat(),
+      0x1000 | 0x2000 => rom.to_vec(),
+      _ => return false,
+    };
+    self.mem = MemMap::new();
+    self.mem.tia.audio.set_sample_rate(self.sample_rate);
+    self.mem.load_rom(image.into_boxed_slice());
+    self.cpu = CPU::new();
+    self.cpu.reset(&mut self.mem);
+    self.rom_loaded = true;
+    return true;
+  }
+
+  /**
+   * Switch off and on again. The RESET switch on the console is a button
+   * the program reads, set with set_console_switches. Returns false if there
+   * is no cartridge.
+   */
+  pub fn reset(&mut self) -> bool {
+    if !self.rom_loaded {
+      return false;
+    }
+    self.cpu.reset(&mut self.mem);
+    return true;
+  }
+
+  fn step(&mut self) -> u8 {
+    match self.mem.tia.get_exec_state() {
+      ExecState::Run => self.cpu.step(&mut self.mem) * 3,
+      // WSYNC holds the CPU until the end of the scanline
+      ExecState::Block => 1,
+    }
+  }
+
+  /**
+   * Run through the next VSYNC, and on until the next one starts. Returns
+   * false if there is no cartridge.
+   */
+  pub fn run_frame(&mut self) -> bool {
+    if !self.rom_loaded {
+      return false;
+    }
+    while let ScanlineState::VSync = self.mem.tia.get_scanline_state() {
+      let cycles = self.step();
+      self.mem.tia.increment_clock(cycles);
+      for _ in 0..cycles {
+        self.mem.riot.increment_clock();
+      }
+    }
+    let mut vsync = false;
+    while !vsync {
+      let cycles = self.step();
+      for _ in 0..cycles {
+        self.mem.tia.increment_clock(1);
+        self.mem.riot.increment_clock();
+
+        match self.mem.tia.get_scanline_state() {
+          ScanlineState::Pixel(x, y, color) => {
+            self.drawing[y as usize * FRAME_WIDTH + x as usize] = color;
+          },
+          ScanlineState::VSync => vsync = true,
+          _ => (),
+        }
+      }
+    }
+    self.frame.copy_from_slice(&self.drawing);
+    self.frame_count = self.frame_count.wrapping_add(1);
+    return true;
+  }
+
+  /**
+   * The last complete frame, with one byte per pixel giving its TIA color
+   */
+  pub fn frame(&self) -> &[u8] {
+    &self.frame
+  }
+
+  pub fn frame_count(&self) -> u32 {
+    self.frame_count
+  }
+
+  /**
+   * Convert the last complete frame to RGBA, 4 bytes per pixel
+   */
+  pub fn render_rgba(&mut self) -> &[u8] {
+    palette::to_rgba(&self.frame, &mut self.rgba_frame);
+    &self.rgba_frame
+  }
+
+  /**
+   * Set the directions and button held on joystick 0 or 1. Bits 0 to 3 are
+   * up, down, left and right, and bit 4 is fire. Returns false for any other
+   * port.
+   */
+  pub fn set_joystick(&mut self, port: usize, bits: u8) -> bool {
+    let riot = &mut self.mem.riot;
+    let tia = &mut self.mem.tia;
+    match port {
+      0 => {
+        riot.joystick_0_up = bits & 0x01 != 0;
+        riot.joystick_0_down = bits & 0x02 != 0;
+        riot.joystick_0_left = bits & 0x04 != 0;
+        riot.joystick_0_right = bits & 0x08 != 0;
+        tia.fire_0 = bits & 0x10 != 0;
+      },
+      1 => {
+        riot.joystick_1_up = bits & 0x01 != 0;
+        riot.joystick_1_down = bits & 0x02 != 0;
+        riot.joystick_1_left = bits & 0x04 != 0;
+        riot.joystick_1_right = bits & 0x08 != 0;
+        tia.fire_1 = bits & 0x10 != 0;
+      },
+      _ => return false,
+    }
+    return true;
+  }
+
+  /**
+   * Set the console switches: bit 0 holds RESET, bit 1 holds SELECT, bit 3
+   * sets black and white, and bits 6 and 7 put the left and right difficulty
+   * switches in the A position.
+   */
+  pub fn set_console_switches(&mut self, switches: u8) {
+    let riot = &mut self.mem.riot;
+    riot.reset_switch = switches & 0x01 != 0;
+    riot.select_switch = switches & 0x02 != 0;
+    riot.black_and_white = switches & 0x08 != 0;
+    riot.difficulty_0_a = switches & 0x40 != 0;
+    riot.difficulty_1_a = switches & 0x80 != 0;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::vm::{VM, FRAME_WIDTH};
+
+  // A 2K cartridge that draws every line in color $1E, with a tone on
+  // channel 0
+  fn test_rom() -> Vec<u8> {
+    let mut rom = vec![0xea; 0x800];
+    let program = [
+      0xa9, 0x1e, 0x85, 0x09, // LDA #$1E, STA COLUBK
+      0xa9, 0x04, 0x85, 0x15, // LDA #4, STA AUDC0
+      0xa9, 0x08, 0x85, 0x17, // LDA #8, STA AUDF0
+      0xa9, 0x0f, 0x85, 0x19, // LDA #15, STA AUDV0
+      // frame:
+      0xa9, 0x02, 0x85, 0x00, // LDA #2, STA VSYNC
+      0x85, 0x02, 0x85, 0x02, 0x85, 0x02, // STA WSYNC x3
+      0xa9, 0x00, 0x85, 0x00, // LDA #0, STA VSYNC
+      0xa2, 0x00, // LDX #0
+      // line:
+      0x85, 0x02, // STA WSYNC
+      0xca, // DEX
+      0xd0, 0xfb, // BNE line
+      0x4c, 0x10, 0xf0, // JMP frame
+    ];
+    rom[..program.len()].copy_from_slice(&program);
+    rom[0x7fc] = 0x00;
+    rom[0x7fd] = 0xf0;
+    return rom;
+  }
+
+  #[test]
+  fn frames() {
+    let mut vm = VM::new(44100);
+    assert!(!vm.run_frame());
+    assert!(!vm.load_rom(&[0; 100]));
+    assert!(vm.load_rom(&test_rom()));
+
+    for _ in 0..3 {
+      assert!(vm.run_frame());
+    }
+    assert_eq!(vm.frame_count(), 3);
+    assert_eq!(vm.frame()[FRAME_WIDTH * 100 + 80], 0x1e);
+    assert_eq!(vm.render_rgba().len(), vm.frame().len() * 4);
+    assert!(vm.mem.tia.audio.samples_len() > 0);
+
+    assert!(vm.set_joystick(0, 0x10));
+    assert!(!vm.set_joystick(2, 0x10));
+  }
+}
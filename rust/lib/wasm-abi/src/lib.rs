@@ -0,0 +1,319 @@
+// What the wasm builds of the emulators have in common. JS never sees a Rust
+// pointer to a machine, only a handle: a slot number in the low 16 bits and
+// the slot's generation in the high 16, so a handle to a destroyed machine
+// stays invalid after its slot is reused, and a stale or made-up handle gets
+// ERROR_INVALID_HANDLE rather than touching memory it shouldn't. Handle 0 is
+// never issued.
+//
+// wasm32-unknown-unknown can't unwind, so a panic there aborts: the export
+// traps with a WebAssembly.RuntimeError rather than returning ERROR_PANIC, and
+// leaves the table borrowed, so every later call on the instance traps too.
+// JS has to throw the instance away and start a new one.
+//
+// Each build describes its exports in an abi.json, along with an ABI version
+// that is bumped whenever an export is removed or changes its parameters,
+// result or meaning. Adding an export doesn't change it.
+
+use std::panic::{self, AssertUnwindSafe};
+
+// Exports that return a status give OK or one of these. Exports that return a
+// count, length or value give a negative error in its place. Exports that
+// return a pointer give null on any error.
+pub const OK: i32 = 0;
+// The file, text or ROM passed in through the file buffer isn't valid
+pub const ERROR_INVALID_DATA: i32 = -1;
+// The handle was never issued, or its machine has been destroyed
+pub const ERROR_INVALID_HANDLE: i32 = -2;
+// An argument is out of range, like a control port other than 0 or 1
+pub const ERROR_INVALID_ARGUMENT: i32 = -3;
+// The machine hit a bug. It may be left in an inconsistent state, and the
+// safest thing to do is destroy it. Only native builds return this.
+pub const ERROR_PANIC: i32 = -4;
+// A console can't run until a cartridge is loaded
+pub const ERROR_NO_ROM: i32 = -5;
+
+pub type Handle = u32;
+
+struct Slot<T> {
+  generation: u16,
+  value: Option<Box<T>>,
+}
+
+/**
+ * The machines a build has created, looked up by handle. Each build keeps one
+ * in a thread_local, since wasm exports can't carry any state of their own.
+ */
+pub struct HandleTable<T> {
+  slots: Vec<Slot<T>>,
+}
+
+fn split(handle: Handle) -> (usize, u16) {
+  let index = (handle & 0xffff) as usize;
+  return (index.wrapping_sub(1), (handle >> 16) as u16);
+}
+
+impl<T> HandleTable<T> {
+  pub fn new() -> HandleTable<T> {
+    HandleTable {
+      slots: Vec::new(),
+    }
+  }
+
+  /**
+   * Store a machine, returning its handle, or 0 if the table is full.
+   */
+  pub fn insert(&mut self, value: T) -> Handle {
+    let index = match self.slots.iter().position(|s| s.value.is_none()) {
+      Some(index) => index,
+      None => {
+        if self.slots.len() >= 0xffff {
+          return 0;
+        }
+        self.slots.push(Slot { generation: 0, value: None });
+        self.slots.len() - 1
+      },
+    };
+    let slot = &mut self.slots[index];
+    slot.value = Some(Box::new(value));
+    return ((slot.generation as u32) << 16) | (index as u32 + 1);
+  }
+
+  /**
+   * Take a machine out and retire its handle. Returns None if the handle
+   * isn't valid.
+   */
+  pub fn remove(&mut self, handle: Handle) -> Option<Box<T>> {
+    let (index, generation) = split(handle);
+    match self.slots.get_mut(index) {
+      Some(slot) if slot.generation == generation && slot.value.is_some() => {
+        slot.generation = slot.generation.wrapping_add(1);
+        slot.value.take()
+      },
+      _ => None,
+    }
+  }
+
+  /**
+   * Run `f` on the machine behind `handle`. A panic inside `f` is caught where
+   * the target unwinds, and reported as ERROR_PANIC. In wasm it traps.
+   */
+  pub fn with<R, F: FnOnce(&mut T) -> R>(&mut self, handle: Handle, f: F) -> Result<R, i32> {
+    let (index, generation) = split(handle);
+    let slot = match self.slots.get_mut(index) {
+      Some(slot) => slot,
+      None => return Err(ERROR_INVALID_HANDLE),
+    };
+    let value = match slot.value {
+      Some(ref mut value) if slot.generation == generation => value,
+      _ => return Err(ERROR_INVALID_HANDLE),
+    };
+    return panic::catch_unwind(AssertUnwindSafe(|| f(value))).map_err(|_| ERROR_PANIC);
+  }
+
+  /**
+   * `with` for exports returning a status or a value: errors take the place
+   * of the result.
+   */
+  pub fn call<F: FnOnce(&mut T) -> i32>(&mut self, handle: Handle, f: F) -> i32 {
+    match self.with(handle, f) {
+      Ok(result) => result,
+      Err(error) => error,
+    }
+  }
+
+  /**
+   * `with` for exports returning a pointer, which is null on any error.
+   */
+  pub fn pointer<P, F: FnOnce(&mut T) -> *const P>(&mut self, handle: Handle, f: F) -> *const P {
+    return self.with(handle, f).unwrap_or(0 as *const P);
+  }
+}
+
+// The exports wasm_exports! defines in every build
+pub const SHARED_EXPORTS: [&str; 5] = [
+  "get_abi_version",
+  "get_abi_description_pointer",
+  "get_abi_description_length",
+  "destroy_vm",
+  "get_file_buffer_pointer",
+];
+
+/**
+ * Check that every `#[no_mangle] pub fn` in a build's source, and every
+ * shared export, is described in its abi.json, that nothing else is, and that
+ * the versions agree. For each build's tests.
+ */
+pub fn check_description(source: &str, description: &str, version: u32) -> Result<(), String> {
+  let mut exports = 0;
+  let names = source.split("#[no_mangle]\npub fn ").skip(1)
+    .map(|export| &export[..export.find('(').unwrap_or(0)])
+    .chain(SHARED_EXPORTS.iter().cloned());
+  for name in names {
+    if !description.contains(&format!("\"{}\": {{", name)) {
+      return Err(format!("{} isn't described", name));
+    }
+    exports += 1;
+  }
+  let described = description.matches("\"params\"").count();
+  if described != exports {
+    return Err(format!("{} exports are described, but there are {}", described, exports));
+  }
+  if !description.contains(&format!("\"version\": {},", version)) {
+    return Err(format!("the description isn't for version {}", version));
+  }
+  return Ok(());
+}
+
+/**
+ * Define what every build exports the same way, for machines of type `$vm`
+ * with a `file_buffer: Vec<u8>` field:
+ *
+ * - the handle table, and `with_vm`, `call` and `pointer` to reach a machine
+ *   in it by handle
+ * - get_abi_version and the abi.json description, loaded from `$description`
+ *   and checked against lib.rs by a test
+ * - destroy_vm
+ * - get_file_buffer_pointer, refusing files over `$max_file_size` bytes
+ *
+ * The build defines create_vm, which inserts into `VMS`, and its own exports.
+ * `$version` is bumped whenever an export is removed or changes.
+ */
+#[macro_export]
+macro_rules! wasm_exports {
+  ($vm:ty, $description:expr, $version:expr, $max_file_size:expr) => {
+    const ABI_VERSION: u32 = $version;
+    const ABI_DESCRIPTION: &'static str = include_str!($description);
+
+    thread_local! {
+      static VMS: ::std::cell::RefCell<$crate::HandleTable<$vm>> =
+        ::std::cell::RefCell::new($crate::HandleTable::new());
+    }
+
+    #[allow(dead_code)]
+    fn with_vm<T, F: FnOnce(&mut $vm) -> T>(handle: $crate::Handle, f: F) -> Result<T, i32> {
+      VMS.with(|vms| vms.borrow_mut().with(handle, f))
+    }
+
+    #[allow(dead_code)]
+    fn call<F: FnOnce(&mut $vm) -> i32>(handle: $crate::Handle, f: F) -> i32 {
+      VMS.with(|vms| vms.borrow_mut().call(handle, f))
+    }
+
+    #[allow(dead_code)]
+    fn pointer<T, F: FnOnce(&mut $vm) -> *const T>(handle: $crate::Handle, f: F) -> *const T {
+      VMS.with(|vms| vms.borrow_mut().pointer(handle, f))
+    }
+
+    /**
+     * The version of this ABI. JS should refuse to run against a build whose
+     * version it doesn't know.
+     */
+    #[no_mangle]
+    pub fn get_abi_version() -> u32 {
+      ABI_VERSION
+    }
+
+    /**
+     * abi.json, as UTF-8 text of get_abi_description_length bytes
+     */
+    #[no_mangle]
+    pub fn get_abi_description_pointer() -> *const u8 {
+      ABI_DESCRIPTION.as_ptr()
+    }
+
+    #[no_mangle]
+    pub fn get_abi_description_length() -> u32 {
+      ABI_DESCRIPTION.len() as u32
+    }
+
+    /**
+     * Free a machine. Its handle, and every pointer fetched through it, is
+     * invalid afterwards.
+     */
+    #[no_mangle]
+    pub fn destroy_vm(handle: $crate::Handle) -> i32 {
+      // The machine is dropped once the table is no longer borrowed
+      let vm = VMS.with(|vms| vms.borrow_mut().remove(handle));
+      if vm.is_some() { $crate::OK } else { $crate::ERROR_INVALID_HANDLE }
+    }
+
+    /**
+     * Make room for a file of `len` bytes, returning the address JS should
+     * copy it to before calling the export that reads it. Returns null if the
+     * file is too large.
+     */
+    #[no_mangle]
+    pub fn get_file_buffer_pointer(handle: $crate::Handle, len: u32) -> *mut u8 {
+      if len > $max_file_size {
+        return 0 as *mut u8;
+      }
+      pointer(handle, |vm| {
+        vm.file_buffer = vec![0; len as usize];
+        vm.file_buffer.as_mut_ptr()
+      }) as *mut u8
+    }
+
+    #[cfg(test)]
+    mod abi_tests {
+      #[test]
+      fn description() {
+        let source = include_str!("lib.rs");
+        assert_eq!($crate::check_description(source, super::ABI_DESCRIPTION, super::ABI_VERSION), Ok(()));
+      }
+    }
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn handles() {
+    let mut table = HandleTable::new();
+    let first = table.insert(vec![1]);
+    let second = table.insert(vec![2, 3]);
+    assert!(first != 0 && second != 0 && first != second);
+    assert_eq!(table.call(second, |v| v.len() as i32), 2);
+    assert_eq!(table.call(first, |v| v.len() as i32), 1);
+
+    assert_eq!(table.remove(first), Some(Box::new(vec![1])));
+    assert_eq!(table.remove(first), None);
+    assert_eq!(table.call(first, |_| OK), ERROR_INVALID_HANDLE);
+    assert_eq!(table.call(0, |_| OK), ERROR_INVALID_HANDLE);
+    assert!(table.pointer(first, |v| v.as_ptr()).is_null());
+
+    // The freed slot is reused, but the old handle doesn't reach the new
+    // machine
+    let third = table.insert(vec![]);
+    assert_eq!(third & 0xffff, first & 0xffff);
+    assert!(third != first);
+    assert_eq!(table.call(first, |_| OK), ERROR_INVALID_HANDLE);
+    assert_eq!(table.call(third, |_| OK), OK);
+
+    assert_eq!(table.call(third, |_| panic!("bug")), ERROR_PANIC);
+    assert_eq!(table.call(third, |_| OK), OK);
+    assert!(table.remove(second).is_some());
+    assert!(table.remove(third).is_some());
+  }
+
+  #[test]
+  fn description() {
+    let source = "#[no_mangle]\npub fn reset(handle: Handle) -> i32 {}\n";
+    let shared: String = SHARED_EXPORTS.iter()
+      .map(|name| format!("    \"{}\": {{\"params\": []}},\n", name))
+      .collect();
+    let description = format!(
+      "{{\n  \"version\": 2,\n  \"exports\": {{\n{}    \"reset\": {{\"params\": [\"handle\"], \"result\": \"status\"}}\n  }}\n}}",
+      shared,
+    );
+    assert_eq!(check_description(source, &description, 2), Ok(()));
+    assert!(check_description(source, &description, 1).is_err());
+    assert!(check_description("", &description, 2).is_err());
+    let missing = "#[no_mangle]\npub fn step(handle: Handle) -> i32 {}\n";
+    assert!(check_description(&(String::from(source) + missing), &description, 2).is_err());
+    // The shared exports have to be described too
+    let unshared = description.replace("destroy_vm", "destroy");
+    assert!(check_description(source, &unshared, 2).is_err());
+  }
+}